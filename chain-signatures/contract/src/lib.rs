@@ -1,8 +1,8 @@
 pub mod primitives;
 
 use crypto_shared::{
-    derive_epsilon, derive_key, kdf::check_ec_signature, near_public_key_to_affine_point,
-    types::SignatureResponse, ScalarExt as _, SerializableScalar,
+    derive_child_tweak, derive_epsilon, derive_key, kdf::check_ec_signature,
+    near_public_key_to_affine_point, types::SignatureResponse, ScalarExt as _, SerializableScalar,
 };
 use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
 use near_sdk::collections::LookupMap;
@@ -20,6 +20,10 @@ use std::collections::{BTreeMap, HashSet};
 
 const GAS_FOR_SIGN_CALL: Gas = Gas::from_tgas(250);
 
+/// Amount of times `sign_helper` calls itself while waiting for a signature before giving up.
+/// Each call lands at least one block after the previous one.
+pub const MAX_SIGN_HELPER_DEPTH: usize = 30;
+
 #[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Debug)]
 pub struct InitializingContractState {
     pub candidates: Candidates,
@@ -36,6 +40,41 @@ pub struct RunningContractState {
     pub candidates: Candidates,
     pub join_votes: Votes,
     pub leave_votes: Votes,
+    /// Participants that voted to refresh their key shares in this epoch.
+    #[serde(default)]
+    pub refresh_votes: HashSet<AccountId>,
+    /// Block timestamp in nanoseconds at which the contract started running in this epoch.
+    #[serde(default)]
+    pub epoch_started_at: u64,
+}
+
+/// Layout of [`RunningContractState`] before participants could vote to refresh their shares.
+#[derive(BorshDeserialize, BorshSerialize, Debug)]
+pub struct RunningContractStateV0 {
+    pub epoch: u64,
+    pub participants: Participants,
+    pub threshold: usize,
+    pub public_key: PublicKey,
+    pub candidates: Candidates,
+    pub join_votes: Votes,
+    pub leave_votes: Votes,
+}
+
+impl From<RunningContractStateV0> for RunningContractState {
+    /// The epoch is taken to have started when the state got migrated.
+    fn from(state: RunningContractStateV0) -> Self {
+        RunningContractState {
+            epoch: state.epoch,
+            participants: state.participants,
+            threshold: state.threshold,
+            public_key: state.public_key,
+            candidates: state.candidates,
+            join_votes: state.join_votes,
+            leave_votes: state.leave_votes,
+            refresh_votes: HashSet::new(),
+            epoch_started_at: env::block_timestamp(),
+        }
+    }
 }
 
 #[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Debug)]
@@ -57,6 +96,28 @@ pub enum ProtocolContractState {
     Resharing(ResharingContractState),
 }
 
+/// Layout of [`ProtocolContractState`] in [`MpcContractV0`].
+#[derive(BorshDeserialize, BorshSerialize, Debug)]
+pub enum ProtocolContractStateV0 {
+    NotInitialized,
+    Initializing(InitializingContractState),
+    Running(RunningContractStateV0),
+    Resharing(ResharingContractState),
+}
+
+impl From<ProtocolContractStateV0> for ProtocolContractState {
+    fn from(state: ProtocolContractStateV0) -> Self {
+        match state {
+            ProtocolContractStateV0::NotInitialized => ProtocolContractState::NotInitialized,
+            ProtocolContractStateV0::Initializing(state) => {
+                ProtocolContractState::Initializing(state)
+            }
+            ProtocolContractStateV0::Running(state) => ProtocolContractState::Running(state.into()),
+            ProtocolContractStateV0::Resharing(state) => ProtocolContractState::Resharing(state),
+        }
+    }
+}
+
 #[derive(BorshSerialize, BorshDeserialize, BorshStorageKey, Hash, Clone, Debug, PartialEq, Eq)]
 pub enum StorageKey {
     PendingRequests,
@@ -65,7 +126,10 @@ pub enum StorageKey {
 #[near_bindgen]
 #[derive(BorshDeserialize, BorshSerialize, Debug)]
 pub enum VersionedMpcContract {
-    V0(MpcContract),
+    /// State of contracts deployed before participants could vote to refresh their shares. Only
+    /// read to be migrated to the latest version.
+    V0(MpcContractV0),
+    V1(MpcContract),
 }
 
 impl Default for VersionedMpcContract {
@@ -91,6 +155,38 @@ impl SignatureRequest {
     }
 }
 
+/// Layout of [`MpcContract`] in [`VersionedMpcContract::V0`].
+#[derive(BorshDeserialize, BorshSerialize, Debug)]
+pub struct MpcContractV0 {
+    protocol_state: ProtocolContractStateV0,
+    pending_requests: LookupMap<SignatureRequest, Option<SignatureResponse>>,
+    request_counter: u32,
+}
+
+impl MpcContractV0 {
+    pub fn init(threshold: usize, candidates: BTreeMap<AccountId, CandidateInfo>) -> Self {
+        MpcContractV0 {
+            protocol_state: ProtocolContractStateV0::Initializing(InitializingContractState {
+                candidates: Candidates { candidates },
+                threshold,
+                pk_votes: PkVotes::new(),
+            }),
+            pending_requests: LookupMap::new(StorageKey::PendingRequests),
+            request_counter: 0,
+        }
+    }
+}
+
+impl From<MpcContractV0> for MpcContract {
+    fn from(contract: MpcContractV0) -> Self {
+        MpcContract {
+            protocol_state: contract.protocol_state.into(),
+            pending_requests: contract.pending_requests,
+            request_counter: contract.request_counter,
+        }
+    }
+}
+
 #[derive(BorshDeserialize, BorshSerialize, Debug)]
 pub struct MpcContract {
     protocol_state: ProtocolContractState,
@@ -155,6 +251,7 @@ impl VersionedMpcContract {
             payload,
             path,
             key_version,
+            child_path,
         } = request;
         let latest_key_version: u32 = self.latest_key_version();
         assert!(
@@ -180,14 +277,26 @@ impl VersionedMpcContract {
         );
         let predecessor = env::predecessor_account_id();
         log!(
-            "sign: predecessor={}, payload={:?}, path={:?}, key_version={}",
+            "sign: predecessor={}, payload={:?}, path={:?}, key_version={}, child_path={:?}",
             predecessor,
             payload,
             path,
-            key_version
+            key_version,
+            child_path
         );
 
-        let request = SignatureRequest::new(payload, &predecessor, &path);
+        let mut request = SignatureRequest::new(payload, &predecessor, &path);
+        if !child_path.is_empty() {
+            // The child key is the derived key tweaked once more, so the tweaks can be folded
+            // into a single epsilon which `respond` then verifies against as usual.
+            let child_tweak = derive_child_tweak(
+                near_public_key_to_affine_point(self.public_key()),
+                request.epsilon.scalar,
+                &child_path,
+            )
+            .unwrap_or_else(|err| env::panic_str(&format!("invalid child path: {err}")));
+            request.epsilon.scalar += child_tweak;
+        }
         match self.sign_result(&request) {
             None => {
                 self.add_sign_request(&request);
@@ -220,6 +329,12 @@ impl VersionedMpcContract {
         env!("CARGO_PKG_VERSION").to_string()
     }
 
+    /// Whether `request` is still awaiting a signature. Requests stop being pending once they get
+    /// answered or once they time out.
+    pub fn is_request_pending(&self, request: SignatureRequest) -> bool {
+        matches!(self.sign_result(&request), Some(None))
+    }
+
     pub fn respond(&mut self, request: SignatureRequest, response: SignatureResponse) {
         let protocol_state = self.mutable_state();
         if let ProtocolContractState::Running(_) = protocol_state {
@@ -392,6 +507,52 @@ impl VersionedMpcContract {
         }
     }
 
+    /// Votes to refresh the key shares without changing the participants. Once `threshold`
+    /// participants voted in the same epoch, the network reshares among the current participants,
+    /// producing fresh shares of the same key in the next epoch.
+    pub fn vote_refresh(&mut self, epoch: u64) -> bool {
+        log!(
+            "vote_refresh: signer={}, epoch={}",
+            env::signer_account_id(),
+            epoch
+        );
+        let protocol_state = self.mutable_state();
+        match protocol_state {
+            ProtocolContractState::Running(RunningContractState {
+                epoch: current_epoch,
+                participants,
+                threshold,
+                public_key,
+                refresh_votes,
+                ..
+            }) => {
+                if *current_epoch != epoch {
+                    env::panic_str("mismatched epochs");
+                }
+                let signer_account_id = env::signer_account_id();
+                if !participants.contains_key(&signer_account_id) {
+                    env::panic_str("calling account is not in the participant set");
+                }
+                refresh_votes.insert(signer_account_id);
+                if refresh_votes.len() >= *threshold {
+                    *protocol_state = ProtocolContractState::Resharing(ResharingContractState {
+                        old_epoch: *current_epoch,
+                        old_participants: participants.clone(),
+                        new_participants: participants.clone(),
+                        threshold: *threshold,
+                        public_key: public_key.clone(),
+                        finished_votes: HashSet::new(),
+                    });
+                    true
+                } else {
+                    false
+                }
+            }
+            ProtocolContractState::Resharing(state) if state.old_epoch == epoch => true,
+            _ => env::panic_str("protocol state can't refresh key shares right now"),
+        }
+    }
+
     pub fn vote_pk(&mut self, public_key: PublicKey) -> bool {
         log!(
             "vote_pk: signer={}, public_key={:?}",
@@ -420,6 +581,8 @@ impl VersionedMpcContract {
                         candidates: Candidates::new(),
                         join_votes: Votes::new(),
                         leave_votes: Votes::new(),
+                        refresh_votes: HashSet::new(),
+                        epoch_started_at: env::block_timestamp(),
                     });
                     true
                 } else {
@@ -465,6 +628,8 @@ impl VersionedMpcContract {
                         candidates: Candidates::new(),
                         join_votes: Votes::new(),
                         leave_votes: Votes::new(),
+                        refresh_votes: HashSet::new(),
+                        epoch_started_at: env::block_timestamp(),
                     });
                     true
                 } else {
@@ -494,7 +659,7 @@ impl VersionedMpcContract {
             threshold,
             serde_json::to_string(&candidates).unwrap()
         );
-        Self::V0(MpcContract::init(threshold, candidates))
+        Self::V1(MpcContract::init(threshold, candidates))
     }
 
     // This function can be used to transfer the MPC network to a new contract.
@@ -514,7 +679,7 @@ impl VersionedMpcContract {
             threshold,
             public_key
         );
-        Self::V0(MpcContract {
+        Self::V1(MpcContract {
             protocol_state: ProtocolContractState::Running(RunningContractState {
                 epoch,
                 participants: Participants { participants },
@@ -523,6 +688,8 @@ impl VersionedMpcContract {
                 candidates: Candidates::new(),
                 join_votes: Votes::new(),
                 leave_votes: Votes::new(),
+                refresh_votes: HashSet::new(),
+                epoch_started_at: env::block_timestamp(),
             }),
             pending_requests: LookupMap::new(b"m"),
             request_counter: 0,
@@ -551,7 +718,7 @@ impl VersionedMpcContract {
                     // Observationally 30 calls < 300 TGas so 2 calls < 20 TGas
                     // We keep one call back so we can cleanup then call panic on the next call
                     // Start cleaning up if there's less than 25 teragas left regardless of how deep you are.
-                    if depth > 30 || env::prepaid_gas() < Gas::from_tgas(25) {
+                    if depth > MAX_SIGN_HELPER_DEPTH || env::prepaid_gas() < Gas::from_tgas(25) {
                         self.remove_sign_request(&request);
                         let self_id = env::current_account_id();
                         PromiseOrValue::Promise(Self::ext(self_id).fail_helper(
@@ -581,9 +748,7 @@ impl VersionedMpcContract {
     }
 
     pub fn state(&self) -> &ProtocolContractState {
-        match self {
-            Self::V0(mpc_contract) => &mpc_contract.protocol_state,
-        }
+        &self.contract().protocol_state
     }
 
     #[private]
@@ -593,7 +758,7 @@ impl VersionedMpcContract {
         for key in keys.iter() {
             env::storage_remove(&key.0);
         }
-        Self::V0(MpcContract {
+        Self::V1(MpcContract {
             protocol_state: ProtocolContractState::NotInitialized,
             pending_requests: LookupMap::new(StorageKey::PendingRequests),
             request_counter: 0,
@@ -602,65 +767,77 @@ impl VersionedMpcContract {
 
     #[private]
     pub fn clean_payloads(&mut self, requests: Vec<SignatureRequest>, counter: u32) {
-        match self {
-            Self::V0(mpc_contract) => {
-                mpc_contract.clean_payloads(requests, counter);
-            }
-        }
+        self.contract_mut().clean_payloads(requests, counter);
     }
 
     #[private]
     #[init(ignore_state)]
     pub fn migrate_state_old_to_v0() -> Self {
-        let old_contract: MpcContract = env::state_read().expect("Old state doesn't exist");
-        Self::V0(MpcContract {
+        let old_contract: MpcContractV0 = env::state_read().expect("Old state doesn't exist");
+        Self::V0(MpcContractV0 {
             protocol_state: old_contract.protocol_state,
             pending_requests: old_contract.pending_requests,
             request_counter: old_contract.request_counter,
         })
     }
 
-    fn remove_sign_request(&mut self, request: &SignatureRequest) {
-        match self {
-            Self::V0(mpc_contract) => {
-                mpc_contract.remove_request(request);
-            }
+    /// Migrates the state of an earlier version to the latest one. Has to be called along with
+    /// deploying a contract that changes the layout of the state.
+    #[private]
+    #[init(ignore_state)]
+    pub fn migrate() -> Self {
+        let old_contract: Self = env::state_read().expect("Old state doesn't exist");
+        log!("migrate: from={}", old_contract.version_name());
+        match old_contract {
+            Self::V0(mpc_contract) => Self::V1(mpc_contract.into()),
+            Self::V1(mpc_contract) => Self::V1(mpc_contract),
         }
     }
 
-    fn add_sign_request(&mut self, request: &SignatureRequest) {
+    fn version_name(&self) -> &'static str {
         match self {
-            Self::V0(mpc_contract) => {
-                mpc_contract.add_request(request, &None);
-            }
+            Self::V0(_) => "V0",
+            Self::V1(_) => "V1",
         }
     }
 
-    fn add_sign_result(&mut self, request: &SignatureRequest, response: SignatureResponse) {
+    fn contract(&self) -> &MpcContract {
         match self {
-            Self::V0(mpc_contract) => {
-                mpc_contract.add_sign_result(request, response);
-            }
+            Self::V1(mpc_contract) => mpc_contract,
+            Self::V0(_) => env::panic_str("contract state has to be migrated first"),
         }
     }
 
-    fn mutable_state(&mut self) -> &mut ProtocolContractState {
+    fn contract_mut(&mut self) -> &mut MpcContract {
         match self {
-            Self::V0(ref mut mpc_contract) => &mut mpc_contract.protocol_state,
+            Self::V1(mpc_contract) => mpc_contract,
+            Self::V0(_) => env::panic_str("contract state has to be migrated first"),
         }
     }
 
+    fn remove_sign_request(&mut self, request: &SignatureRequest) {
+        self.contract_mut().remove_request(request);
+    }
+
+    fn add_sign_request(&mut self, request: &SignatureRequest) {
+        self.contract_mut().add_request(request, &None);
+    }
+
+    fn add_sign_result(&mut self, request: &SignatureRequest, response: SignatureResponse) {
+        self.contract_mut().add_sign_result(request, response);
+    }
+
+    fn mutable_state(&mut self) -> &mut ProtocolContractState {
+        &mut self.contract_mut().protocol_state
+    }
+
     fn sign_result(&self, request: &SignatureRequest) -> Option<Option<SignatureResponse>> {
-        match self {
-            Self::V0(mpc_contract) => mpc_contract.pending_requests.get(request),
-        }
+        self.contract().pending_requests.get(request)
     }
 
     fn signature_deposit(&self) -> u128 {
         const CHEAP_REQUESTS: u32 = 3;
-        let pending_requests = match self {
-            Self::V0(mpc_contract) => mpc_contract.request_counter,
-        };
+        let pending_requests = self.contract().request_counter;
         match pending_requests {
             0..=CHEAP_REQUESTS => 1,
             _ => {
@@ -213,6 +213,9 @@ pub struct SignRequest {
     pub payload: [u8; 32],
     pub path: String,
     pub key_version: u32,
+    /// Non-hardened BIP32 child path of the key derived from `path`. Empty signs with that key.
+    #[serde(default)]
+    pub child_path: Vec<u32>,
 }
 
 #[derive(Serialize, Deserialize, BorshDeserialize, BorshSerialize, Debug)]
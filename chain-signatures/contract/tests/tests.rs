@@ -1,4 +1,4 @@
-use mpc_contract::{primitives::CandidateInfo, MpcContract, VersionedMpcContract};
+use mpc_contract::{primitives::CandidateInfo, MpcContract, MpcContractV0, VersionedMpcContract};
 use near_sdk::env;
 use near_workspaces::AccountId;
 use std::collections::{BTreeMap, HashMap};
@@ -40,7 +40,7 @@ async fn test_contract_can_not_be_reinitialized() -> anyhow::Result<()> {
 
 #[test]
 fn test_old_state_can_be_migrated_to_v0() -> anyhow::Result<()> {
-    let old_contract = MpcContract::init(3, BTreeMap::new());
+    let old_contract = MpcContractV0::init(3, BTreeMap::new());
     env::state_write(&old_contract);
 
     let v0_contract = VersionedMpcContract::migrate_state_old_to_v0();
@@ -53,3 +53,21 @@ fn test_old_state_can_be_migrated_to_v0() -> anyhow::Result<()> {
 
     Ok(())
 }
+
+#[test]
+fn test_v0_state_can_be_migrated_to_v1() -> anyhow::Result<()> {
+    env::state_write(&VersionedMpcContract::V0(MpcContractV0::init(
+        3,
+        BTreeMap::new(),
+    )));
+
+    let v1_contract = VersionedMpcContract::migrate();
+    let expected_contract = VersionedMpcContract::V1(MpcContract::init(3, BTreeMap::new()));
+
+    assert_eq!(
+        format!("{v1_contract:#?}"),
+        format!("{expected_contract:#?}")
+    );
+
+    Ok(())
+}
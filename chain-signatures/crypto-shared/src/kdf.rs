@@ -1,12 +1,14 @@
 use crate::types::{PublicKey, ScalarExt};
 use anyhow::Context;
+use hmac::{Hmac, Mac};
 use k256::{
     ecdsa::{RecoveryId, Signature, VerifyingKey},
-    elliptic_curve::{point::AffineCoordinates, sec1::ToEncodedPoint, CurveArithmetic},
-    sha2::{Digest, Sha256},
-    Scalar, Secp256k1,
+    elliptic_curve::{point::AffineCoordinates, sec1::ToEncodedPoint, CurveArithmetic, PrimeField},
+    sha2::{Digest, Sha256, Sha512},
+    FieldBytes, Scalar, Secp256k1,
 };
 use near_account_id::AccountId;
+use serde::{Deserialize, Serialize};
 
 // Constant prefix that ensures epsilon derivation values are used specifically for
 // near-mpc-recovery with key derivation protocol vX.Y.Z.
@@ -32,6 +34,102 @@ pub fn derive_key(public_key: PublicKey, epsilon: Scalar) -> PublicKey {
     (<Secp256k1 as CurveArithmetic>::ProjectivePoint::GENERATOR * epsilon + public_key).to_affine()
 }
 
+// Constant prefix that ensures chain code derivation values are used specifically for
+// near-mpc-recovery with key derivation protocol vX.Y.Z.
+const CHAIN_CODE_DERIVATION_PREFIX: &str = "near-mpc-recovery v0.1.0 chain code derivation:";
+
+/// Indices at or above this value are hardened in BIP32 terms. Hardened derivation needs the
+/// private key, which nobody holds in full, so only indices below this bound can be derived.
+pub const HARDENED_CHILD_INDEX: u32 = 1 << 31;
+
+pub type ChainCode = [u8; 32];
+
+/// Chain code of the key derived with `epsilon`. Together with `derive_key` this yields an
+/// extended public key from which BIP32 non-hardened children can be derived.
+pub fn derive_chain_code(epsilon: Scalar) -> ChainCode {
+    let mut hasher = Sha256::new();
+    hasher.update(CHAIN_CODE_DERIVATION_PREFIX);
+    hasher.update(epsilon.to_bytes());
+    hasher.finalize().into()
+}
+
+/// Public key along with the chain code used to derive its children.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ExtendedPublicKey {
+    pub public_key: PublicKey,
+    pub chain_code: ChainCode,
+}
+
+impl ExtendedPublicKey {
+    /// Non-hardened child key derivation (`CKDpub` in BIP32). Returns the child key and the
+    /// scalar tweak that was added to this key to obtain it.
+    pub fn derive_child(&self, index: u32) -> anyhow::Result<(ExtendedPublicKey, Scalar)> {
+        if index >= HARDENED_CHILD_INDEX {
+            anyhow::bail!("hardened child index {index} cannot be derived from a public key");
+        }
+
+        let mut mac = Hmac::<Sha512>::new_from_slice(&self.chain_code)
+            .expect("HMAC can take a key of any size");
+        mac.update(self.public_key.to_encoded_point(true).as_bytes());
+        mac.update(&index.to_be_bytes());
+        let output = mac.finalize().into_bytes();
+        let (tweak, chain_code) = output.split_at(32);
+
+        let mut tweak_bytes = FieldBytes::default();
+        tweak_bytes.copy_from_slice(tweak);
+        let tweak: Option<Scalar> = Scalar::from_repr(tweak_bytes).into();
+        let tweak =
+            tweak.with_context(|| format!("child index {index} yields an invalid tweak"))?;
+        let public_key = derive_key(self.public_key, tweak);
+        if public_key == PublicKey::IDENTITY {
+            anyhow::bail!("child index {index} yields the point at infinity");
+        }
+
+        let child = ExtendedPublicKey {
+            public_key,
+            chain_code: chain_code
+                .try_into()
+                .expect("HMAC-SHA512 output is 64 bytes"),
+        };
+        Ok((child, tweak))
+    }
+
+    /// Derives the descendant at `child_path`. Returns it along with the sum of the tweaks
+    /// applied along the way.
+    pub fn derive_path(&self, child_path: &[u32]) -> anyhow::Result<(ExtendedPublicKey, Scalar)> {
+        let mut key = *self;
+        let mut total_tweak = Scalar::ZERO;
+        for index in child_path {
+            let (child, tweak) = key.derive_child(*index)?;
+            key = child;
+            total_tweak += tweak;
+        }
+        Ok((key, total_tweak))
+    }
+}
+
+/// Extended public key of the key derived with `epsilon`.
+pub fn derive_extended_key(public_key: PublicKey, epsilon: Scalar) -> ExtendedPublicKey {
+    ExtendedPublicKey {
+        public_key: derive_key(public_key, epsilon),
+        chain_code: derive_chain_code(epsilon),
+    }
+}
+
+/// Tweak that has to be added on top of `epsilon` to sign for the child at `child_path` of the
+/// key derived with `epsilon`. An empty path yields a zero tweak.
+pub fn derive_child_tweak(
+    public_key: PublicKey,
+    epsilon: Scalar,
+    child_path: &[u32],
+) -> anyhow::Result<Scalar> {
+    if child_path.is_empty() {
+        return Ok(Scalar::ZERO);
+    }
+    let (_, tweak) = derive_extended_key(public_key, epsilon).derive_path(child_path)?;
+    Ok(tweak)
+}
+
 /// Get the x coordinate of a point, as a scalar
 pub fn x_coordinate(
     point: &<Secp256k1 as CurveArithmetic>::AffinePoint,
@@ -87,3 +185,31 @@ fn recover(
 //             .context("Unable to recover public key")?;
 //     VerifyingKey::try_from(&recovered_key[..]).context("Failed to parse returned key")
 // }
+
+#[test]
+fn child_keys_match_tweaked_secret() {
+    let secret = Scalar::from_bytes(&[7; 32]);
+    let public_key =
+        (<Secp256k1 as CurveArithmetic>::ProjectivePoint::GENERATOR * secret).to_affine();
+    let epsilon = derive_epsilon(&"alice.near".parse().unwrap(), "bitcoin");
+    let extended = derive_extended_key(public_key, epsilon);
+
+    let child_path = [0, 7, HARDENED_CHILD_INDEX - 1];
+    let (child, tweak) = extended.derive_path(&child_path).unwrap();
+    assert_eq!(
+        tweak,
+        derive_child_tweak(public_key, epsilon, &child_path).unwrap()
+    );
+    assert_eq!(child.public_key, derive_key(public_key, epsilon + tweak));
+
+    let child_secret = secret + epsilon + tweak;
+    let expected =
+        (<Secp256k1 as CurveArithmetic>::ProjectivePoint::GENERATOR * child_secret).to_affine();
+    assert_eq!(child.public_key, expected);
+
+    assert_eq!(
+        derive_child_tweak(public_key, epsilon, &[]).unwrap(),
+        Scalar::ZERO
+    );
+    assert!(extended.derive_child(HARDENED_CHILD_INDEX).is_err());
+}
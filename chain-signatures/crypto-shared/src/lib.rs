@@ -1,9 +1,13 @@
 pub mod kdf;
+pub mod tx;
 pub mod types;
 
 use k256::elliptic_curve::sec1::FromEncodedPoint;
 use k256::EncodedPoint;
-pub use kdf::{derive_epsilon, derive_key, x_coordinate};
+pub use kdf::{
+    derive_child_tweak, derive_epsilon, derive_extended_key, derive_key, x_coordinate,
+    ExtendedPublicKey,
+};
 pub use types::{
     PublicKey, ScalarExt, SerializableAffinePoint, SerializableScalar, SignatureResponse,
 };
@@ -0,0 +1,432 @@
+use super::normalized_signature;
+use crate::types::{PublicKey, SignatureResponse};
+use k256::ecdsa::Signature;
+use k256::elliptic_curve::sec1::ToEncodedPoint;
+use k256::sha2::{Digest, Sha256};
+use ripemd::Ripemd160;
+use serde::{Deserialize, Serialize};
+
+pub const SIGHASH_DEFAULT: u8 = 0x00;
+pub const SIGHASH_ALL: u8 = 0x01;
+
+pub fn sha256d(data: &[u8]) -> [u8; 32] {
+    Sha256::digest(Sha256::digest(data)).into()
+}
+
+pub fn hash160(data: &[u8]) -> [u8; 20] {
+    Ripemd160::digest(Sha256::digest(data)).into()
+}
+
+fn tagged_hash(tag: &str, data: &[u8]) -> [u8; 32] {
+    let tag = Sha256::digest(tag.as_bytes());
+    let mut hasher = Sha256::new();
+    hasher.update(tag);
+    hasher.update(tag);
+    hasher.update(data);
+    hasher.finalize().into()
+}
+
+/// Output script paying to the P2WPKH address of the given public key.
+pub fn p2wpkh_script_pubkey(public_key: &PublicKey) -> Vec<u8> {
+    let mut script = vec![0x00, 0x14];
+    script.extend(hash160(public_key.to_encoded_point(true).as_bytes()));
+    script
+}
+
+/// BIP143 script code used when signing a P2WPKH input of the given public key.
+pub fn p2wpkh_script_code(public_key: &PublicKey) -> Vec<u8> {
+    let mut script = vec![0x76, 0xa9, 0x14];
+    script.extend(hash160(public_key.to_encoded_point(true).as_bytes()));
+    script.extend([0x88, 0xac]);
+    script
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct OutPoint {
+    /// Transaction id in internal byte order, i.e. reversed relative to how explorers display it.
+    pub txid: [u8; 32],
+    pub vout: u32,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TxIn {
+    pub previous_output: OutPoint,
+    pub sequence: u32,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TxOut {
+    pub value: u64,
+    pub script_pubkey: Vec<u8>,
+}
+
+impl TxOut {
+    fn encode(&self, out: &mut Vec<u8>) {
+        out.extend(self.value.to_le_bytes());
+        encode_bytes(&self.script_pubkey, out);
+    }
+}
+
+/// Unsigned transaction spending segwit inputs only, so every `scriptSig` is empty.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Transaction {
+    pub version: i32,
+    pub lock_time: u32,
+    pub inputs: Vec<TxIn>,
+    pub outputs: Vec<TxOut>,
+}
+
+impl Transaction {
+    /// Serialized outpoints of every input, hashed into the BIP143 `hashPrevouts` and the BIP341
+    /// `sha_prevouts`.
+    fn prevouts_preimage(&self) -> Vec<u8> {
+        let mut data = Vec::new();
+        for input in &self.inputs {
+            data.extend(input.previous_output.txid);
+            data.extend(input.previous_output.vout.to_le_bytes());
+        }
+        data
+    }
+
+    /// Serialized sequences of every input, hashed into the BIP143 `hashSequence` and the BIP341
+    /// `sha_sequences`.
+    fn sequences_preimage(&self) -> Vec<u8> {
+        self.inputs
+            .iter()
+            .flat_map(|input| input.sequence.to_le_bytes())
+            .collect()
+    }
+
+    /// Serialized outputs, hashed into the BIP143 `hashOutputs` and the BIP341 `sha_outputs`.
+    fn outputs_preimage(&self) -> Vec<u8> {
+        let mut data = Vec::new();
+        for output in &self.outputs {
+            output.encode(&mut data);
+        }
+        data
+    }
+
+    fn input(&self, input_index: usize) -> anyhow::Result<&TxIn> {
+        self.inputs.get(input_index).ok_or_else(|| {
+            anyhow::anyhow!(
+                "input {input_index} out of range, transaction has {} inputs",
+                self.inputs.len()
+            )
+        })
+    }
+
+    /// BIP143 `SIGHASH_ALL` digest of a segwit v0 input. This is the `payload` to sign for it.
+    pub fn segwit_v0_sighash(
+        &self,
+        input_index: usize,
+        script_code: &[u8],
+        value: u64,
+    ) -> anyhow::Result<[u8; 32]> {
+        let input = self.input(input_index)?;
+        let mut preimage = Vec::new();
+        preimage.extend(self.version.to_le_bytes());
+        preimage.extend(sha256d(&self.prevouts_preimage()));
+        preimage.extend(sha256d(&self.sequences_preimage()));
+        preimage.extend(input.previous_output.txid);
+        preimage.extend(input.previous_output.vout.to_le_bytes());
+        encode_bytes(script_code, &mut preimage);
+        preimage.extend(value.to_le_bytes());
+        preimage.extend(input.sequence.to_le_bytes());
+        preimage.extend(sha256d(&self.outputs_preimage()));
+        preimage.extend(self.lock_time.to_le_bytes());
+        preimage.extend((SIGHASH_ALL as u32).to_le_bytes());
+        Ok(sha256d(&preimage))
+    }
+
+    /// BIP341 `SIGHASH_DEFAULT` digest of a taproot key path spend. `prevouts` are the outputs
+    /// spent by every input of the transaction, in order.
+    ///
+    /// Note that key path spends require a BIP340 Schnorr signature while the MPC network only
+    /// produces ECDSA signatures, so this digest cannot be turned into a witness yet.
+    pub fn taproot_key_spend_sighash(
+        &self,
+        input_index: usize,
+        prevouts: &[TxOut],
+    ) -> anyhow::Result<[u8; 32]> {
+        self.input(input_index)?;
+        if prevouts.len() != self.inputs.len() {
+            anyhow::bail!(
+                "expected {} prevouts, got {}",
+                self.inputs.len(),
+                prevouts.len()
+            );
+        }
+        let amounts = prevouts
+            .iter()
+            .flat_map(|prevout| prevout.value.to_le_bytes())
+            .collect::<Vec<_>>();
+        let mut script_pubkeys = Vec::new();
+        for prevout in prevouts {
+            encode_bytes(&prevout.script_pubkey, &mut script_pubkeys);
+        }
+
+        // Epoch byte followed by `SigMsg(hash_type, ext_flag = 0)`.
+        let mut message = vec![0x00, SIGHASH_DEFAULT];
+        message.extend(self.version.to_le_bytes());
+        message.extend(self.lock_time.to_le_bytes());
+        message.extend(Sha256::digest(self.prevouts_preimage()));
+        message.extend(Sha256::digest(amounts));
+        message.extend(Sha256::digest(script_pubkeys));
+        message.extend(Sha256::digest(self.sequences_preimage()));
+        message.extend(Sha256::digest(self.outputs_preimage()));
+        // spend_type: no annex, key path.
+        message.push(0x00);
+        message.extend((input_index as u32).to_le_bytes());
+        Ok(tagged_hash("TapSighash", &message))
+    }
+
+    /// Serializes the transaction, spending every input as P2WPKH. Each signature is the one
+    /// returned for the `segwit_v0_sighash` of the matching input, along with the signing key.
+    pub fn signed_p2wpkh(
+        &self,
+        signatures: &[(SignatureResponse, PublicKey)],
+    ) -> anyhow::Result<Vec<u8>> {
+        if signatures.len() != self.inputs.len() {
+            anyhow::bail!(
+                "expected {} signatures, got {}",
+                self.inputs.len(),
+                signatures.len()
+            );
+        }
+        let witnesses = signatures
+            .iter()
+            .map(|(signature, public_key)| {
+                let (r, s, _) = normalized_signature(signature);
+                let signature = Signature::from_scalars(r, s)?;
+                let mut signature = signature.to_der().as_bytes().to_vec();
+                signature.push(SIGHASH_ALL);
+                Ok(vec![
+                    signature,
+                    public_key.to_encoded_point(true).as_bytes().to_vec(),
+                ])
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        Ok(self.serialize(&witnesses))
+    }
+
+    fn serialize(&self, witnesses: &[Vec<Vec<u8>>]) -> Vec<u8> {
+        let mut out = Vec::new();
+        out.extend(self.version.to_le_bytes());
+        // Segwit marker and flag.
+        out.extend([0x00, 0x01]);
+        encode_compact_size(self.inputs.len() as u64, &mut out);
+        for input in &self.inputs {
+            out.extend(input.previous_output.txid);
+            out.extend(input.previous_output.vout.to_le_bytes());
+            // Empty scriptSig.
+            out.push(0x00);
+            out.extend(input.sequence.to_le_bytes());
+        }
+        encode_compact_size(self.outputs.len() as u64, &mut out);
+        for output in &self.outputs {
+            output.encode(&mut out);
+        }
+        for witness in witnesses {
+            encode_compact_size(witness.len() as u64, &mut out);
+            for item in witness {
+                encode_bytes(item, &mut out);
+            }
+        }
+        out.extend(self.lock_time.to_le_bytes());
+        out
+    }
+}
+
+fn encode_compact_size(n: u64, out: &mut Vec<u8>) {
+    match n {
+        0..=0xfc => out.push(n as u8),
+        0xfd..=0xffff => {
+            out.push(0xfd);
+            out.extend((n as u16).to_le_bytes());
+        }
+        0x10000..=0xffff_ffff => {
+            out.push(0xfe);
+            out.extend((n as u32).to_le_bytes());
+        }
+        _ => {
+            out.push(0xff);
+            out.extend(n.to_le_bytes());
+        }
+    }
+}
+
+fn encode_bytes(bytes: &[u8], out: &mut Vec<u8>) {
+    encode_compact_size(bytes.len() as u64, out);
+    out.extend_from_slice(bytes);
+}
+
+#[test]
+fn bip143_native_p2wpkh_sighash() {
+    // Native P2WPKH example from the BIP143 specification.
+    fn txid(hex: &str) -> [u8; 32] {
+        hex::decode(hex).unwrap().try_into().unwrap()
+    }
+    let tx = Transaction {
+        version: 1,
+        lock_time: 0x11,
+        inputs: vec![
+            TxIn {
+                previous_output: OutPoint {
+                    txid: txid("fff7f7881a8099afa6940d42d1e7f6362bec38171ea3edf433541db4e4ad969f"),
+                    vout: 0,
+                },
+                sequence: 0xffffffee,
+            },
+            TxIn {
+                previous_output: OutPoint {
+                    txid: txid("ef51e1b804cc89d182d279655c3aa89e815b1b309fe287d9b2b55d57b90ec68a"),
+                    vout: 1,
+                },
+                sequence: 0xffffffff,
+            },
+        ],
+        outputs: vec![
+            TxOut {
+                value: 112340000,
+                script_pubkey: hex::decode("76a9148280b37df378db99f66f85c95a783a76ac7a6d5988ac")
+                    .unwrap(),
+            },
+            TxOut {
+                value: 223450000,
+                script_pubkey: hex::decode("76a9143bde42dbee7e4dbe6a21b2d50ce2f0167faa815988ac")
+                    .unwrap(),
+            },
+        ],
+    };
+    let script_code = hex::decode("76a9141d0f172a0ecb48aee1be1f2687d2963ae33f71a188ac").unwrap();
+    let sighash = tx.segwit_v0_sighash(1, &script_code, 600000000).unwrap();
+    assert_eq!(
+        hex::encode(sighash),
+        "c37af31116d1b27caf68aae9e3ac82f1477929014d5b917657d0eb49478cb670"
+    );
+    assert!(tx.segwit_v0_sighash(2, &script_code, 600000000).is_err());
+}
+
+#[test]
+fn bip341_key_path_sighash() {
+    // Key path spending example from the BIP341 wallet test vectors, whose input 4 is signed with
+    // `SIGHASH_DEFAULT`.
+    fn input(txid: &str, vout: u32, sequence: u32) -> TxIn {
+        TxIn {
+            previous_output: OutPoint {
+                txid: hex::decode(txid).unwrap().try_into().unwrap(),
+                vout,
+            },
+            sequence,
+        }
+    }
+    fn output(script_pubkey: &str, value: u64) -> TxOut {
+        TxOut {
+            value,
+            script_pubkey: hex::decode(script_pubkey).unwrap(),
+        }
+    }
+    let tx = Transaction {
+        version: 2,
+        lock_time: 500000000,
+        inputs: vec![
+            input(
+                "7de20cbff686da83a54981d2b9bab3586f4ca7e48f57f5b55963115f3b334e9c",
+                1,
+                0x00000000,
+            ),
+            input(
+                "d7b7cab57b1393ace2d064f4d4a2cb8af6def61273e127517d44759b6dafdd99",
+                0,
+                0xffffffff,
+            ),
+            input(
+                "f8e1f583384333689228c5d28eac13366be082dc57441760d957275419a41842",
+                0,
+                0xffffffff,
+            ),
+            input(
+                "f0689180aa63b30cb162a73c6d2a38b7eeda2a83ece74310fda0843ad604853b",
+                1,
+                0xfffffffe,
+            ),
+            input(
+                "aa5202bdf6d8ccd2ee0f0202afbbb7461d9264a25e5bfd3c5a52ee1239e0ba6c",
+                0,
+                0xfffffffe,
+            ),
+            input(
+                "956149bdc66faa968eb2be2d2faa29718acbfe3941215893a2a3446d32acd050",
+                0,
+                0x00000000,
+            ),
+            input(
+                "e664b9773b88c09c32cb70a2a3e4da0ced63b7ba3b22f848531bbb1d5d5f4c94",
+                1,
+                0x00000000,
+            ),
+            input(
+                "e9aa6b8e6c9de67619e6a3924ae25696bb7b694bb677a632a74ef7eadfd4eabf",
+                0,
+                0xffffffff,
+            ),
+            input(
+                "a778eb6a263dc090464cd125c466b5a99667720b1c110468831d058aa1b82af1",
+                1,
+                0xffffffff,
+            ),
+        ],
+        outputs: vec![
+            output(
+                "76a91406afd46bcdfd22ef94ac122aa11f241244a37ecc88ac",
+                1000000000,
+            ),
+            output(
+                "ac9a87f5594be208f8532db38cff670c450ed2fea8fcdefcc9a663f78bab962b",
+                3410000000,
+            ),
+        ],
+    };
+    let prevouts = [
+        output(
+            "512053a1f6e454df1aa2776a2814a721372d6258050de330b3c6d10ee8f4e0dda343",
+            420000000,
+        ),
+        output(
+            "5120147c9c57132f6e7ecddba9800bb0c4449251c92a1e60371ee77557b6620f3ea3",
+            462000000,
+        ),
+        output(
+            "76a914751e76e8199196d454941c45d1b3a323f1433bd688ac",
+            294000000,
+        ),
+        output(
+            "5120e4d810fd50586274face62b8a807eb9719cef49c04177cc6b76a9a4251d5450e",
+            504000000,
+        ),
+        output(
+            "512091b64d5324723a985170e4dc5a0f84c041804f2cd12660fa5dec09fc21783605",
+            630000000,
+        ),
+        output("00147dd65592d0ab2fe0d0257d571abf032cd9db93dc", 378000000),
+        output(
+            "512075169f4001aa68f15bbed28b218df1d0a62cbbcf1188c6665110c293c907b831",
+            672000000,
+        ),
+        output(
+            "5120712447206d7a5238acc7ff53fbe94a3b64539ad291c7cdbc490b7577e4b17df5",
+            546000000,
+        ),
+        output(
+            "512077e30a5522dd9f894c3f8b8bd4c4b2cf82ca7da8a3ea6a239655c39c050ab220",
+            588000000,
+        ),
+    ];
+    let sighash = tx.taproot_key_spend_sighash(4, &prevouts).unwrap();
+    assert_eq!(
+        hex::encode(sighash),
+        "4f900a0bae3f1446fd48490c2958b5a023228f01661cda3496a11da502a7f7ef"
+    );
+    assert!(tx.taproot_key_spend_sighash(9, &prevouts).is_err());
+    assert!(tx.taproot_key_spend_sighash(4, &prevouts[1..]).is_err());
+}
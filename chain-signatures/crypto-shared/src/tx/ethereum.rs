@@ -0,0 +1,272 @@
+use super::normalized_signature;
+use crate::types::{PublicKey, SignatureResponse};
+use k256::elliptic_curve::sec1::ToEncodedPoint;
+use serde::{Deserialize, Serialize};
+use sha3::{Digest, Keccak256};
+
+pub type Address = [u8; 20];
+
+/// Type byte of EIP-1559 transactions as per EIP-2718.
+const EIP_1559_TX_TYPE: u8 = 0x02;
+
+pub fn keccak256(data: &[u8]) -> [u8; 32] {
+    Keccak256::digest(data).into()
+}
+
+/// Ethereum address controlled by the given public key.
+pub fn address(public_key: &PublicKey) -> Address {
+    let encoded = public_key.to_encoded_point(false);
+    let hash = keccak256(&encoded.as_bytes()[1..]);
+    let mut address = [0u8; 20];
+    address.copy_from_slice(&hash[12..]);
+    address
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AccessListItem {
+    pub address: Address,
+    pub storage_keys: Vec<[u8; 32]>,
+}
+
+/// Pre-EIP-1559 transaction. Signed with EIP-155 replay protection when `chain_id` is set.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LegacyTransaction {
+    pub chain_id: Option<u64>,
+    pub nonce: u64,
+    pub gas_price: u128,
+    pub gas_limit: u128,
+    pub to: Option<Address>,
+    pub value: u128,
+    pub data: Vec<u8>,
+}
+
+impl LegacyTransaction {
+    fn fields(&self) -> Vec<Vec<u8>> {
+        vec![
+            rlp::uint(self.nonce as u128),
+            rlp::uint(self.gas_price),
+            rlp::uint(self.gas_limit),
+            rlp::bytes(self.to.as_ref().map_or(&[][..], |to| &to[..])),
+            rlp::uint(self.value),
+            rlp::bytes(&self.data),
+        ]
+    }
+
+    /// Payload to be signed, i.e. the keccak hash of the signing encoding.
+    pub fn payload(&self) -> [u8; 32] {
+        let mut fields = self.fields();
+        if let Some(chain_id) = self.chain_id {
+            fields.extend([rlp::uint(chain_id as u128), rlp::uint(0), rlp::uint(0)]);
+        }
+        keccak256(&rlp::list(&fields))
+    }
+
+    /// Raw signed transaction, ready for `eth_sendRawTransaction`.
+    pub fn signed(&self, signature: &SignatureResponse) -> Vec<u8> {
+        let (r, s, recovery_id) = normalized_signature(signature);
+        let v = match self.chain_id {
+            Some(chain_id) => chain_id as u128 * 2 + 35 + recovery_id as u128,
+            None => 27 + recovery_id as u128,
+        };
+        let mut fields = self.fields();
+        fields.extend([rlp::uint(v), rlp::uint_bytes(&r), rlp::uint_bytes(&s)]);
+        rlp::list(&fields)
+    }
+}
+
+/// EIP-1559 (type 2) transaction.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Eip1559Transaction {
+    pub chain_id: u64,
+    pub nonce: u64,
+    pub max_priority_fee_per_gas: u128,
+    pub max_fee_per_gas: u128,
+    pub gas_limit: u128,
+    pub to: Option<Address>,
+    pub value: u128,
+    pub data: Vec<u8>,
+    pub access_list: Vec<AccessListItem>,
+}
+
+impl Eip1559Transaction {
+    fn fields(&self) -> Vec<Vec<u8>> {
+        let access_list = self
+            .access_list
+            .iter()
+            .map(|item| {
+                let storage_keys = item
+                    .storage_keys
+                    .iter()
+                    .map(|key| rlp::bytes(key))
+                    .collect::<Vec<_>>();
+                rlp::list(&[rlp::bytes(&item.address), rlp::list(&storage_keys)])
+            })
+            .collect::<Vec<_>>();
+        vec![
+            rlp::uint(self.chain_id as u128),
+            rlp::uint(self.nonce as u128),
+            rlp::uint(self.max_priority_fee_per_gas),
+            rlp::uint(self.max_fee_per_gas),
+            rlp::uint(self.gas_limit),
+            rlp::bytes(self.to.as_ref().map_or(&[][..], |to| &to[..])),
+            rlp::uint(self.value),
+            rlp::bytes(&self.data),
+            rlp::list(&access_list),
+        ]
+    }
+
+    fn typed(fields: &[Vec<u8>]) -> Vec<u8> {
+        let mut encoded = vec![EIP_1559_TX_TYPE];
+        encoded.extend(rlp::list(fields));
+        encoded
+    }
+
+    /// Payload to be signed, i.e. the keccak hash of the signing encoding.
+    pub fn payload(&self) -> [u8; 32] {
+        keccak256(&Self::typed(&self.fields()))
+    }
+
+    /// Raw signed transaction, ready for `eth_sendRawTransaction`.
+    pub fn signed(&self, signature: &SignatureResponse) -> Vec<u8> {
+        let (r, s, recovery_id) = normalized_signature(signature);
+        let mut fields = self.fields();
+        fields.extend([
+            rlp::uint(recovery_id as u128),
+            rlp::uint_bytes(&r),
+            rlp::uint_bytes(&s),
+        ]);
+        Self::typed(&fields)
+    }
+}
+
+/// Minimal recursive length prefix encoding, enough to serialize transactions.
+mod rlp {
+    pub fn bytes(bytes: &[u8]) -> Vec<u8> {
+        if bytes.len() == 1 && bytes[0] < 0x80 {
+            return bytes.to_vec();
+        }
+        let mut encoded = prefix(0x80, bytes.len());
+        encoded.extend_from_slice(bytes);
+        encoded
+    }
+
+    /// Big endian integer with leading zeroes stripped.
+    pub fn uint_bytes(be: &[u8]) -> Vec<u8> {
+        let start = be.iter().position(|b| *b != 0).unwrap_or(be.len());
+        bytes(&be[start..])
+    }
+
+    pub fn uint(value: u128) -> Vec<u8> {
+        uint_bytes(&value.to_be_bytes())
+    }
+
+    pub fn list(items: &[Vec<u8>]) -> Vec<u8> {
+        let payload = items.concat();
+        let mut encoded = prefix(0xc0, payload.len());
+        encoded.extend(payload);
+        encoded
+    }
+
+    fn prefix(offset: u8, len: usize) -> Vec<u8> {
+        if len < 56 {
+            return vec![offset + len as u8];
+        }
+        let len = (len as u64).to_be_bytes();
+        let start = len.iter().position(|b| *b != 0).unwrap_or(len.len());
+        let mut encoded = vec![offset + 55 + (len.len() - start) as u8];
+        encoded.extend_from_slice(&len[start..]);
+        encoded
+    }
+}
+
+/// Signs `payload` with the given secret key, as the MPC network would for that key.
+#[cfg(test)]
+fn test_sign(secret_key: &[u8; 32], payload: &[u8; 32]) -> (SignatureResponse, PublicKey) {
+    use k256::ecdsa::SigningKey;
+    use k256::elliptic_curve::sec1::FromEncodedPoint;
+
+    let signing_key = SigningKey::from_slice(secret_key).unwrap();
+    let (signature, recovery_id) = signing_key.sign_prehash_recoverable(payload).unwrap();
+    let (r, s) = signature.split_scalars();
+    let public_key =
+        PublicKey::from_encoded_point(&signing_key.verifying_key().to_encoded_point(false))
+            .unwrap();
+
+    // k256 only exposes `r`, so rebuild `R` from it and the recovery id.
+    let mut r_point = [0u8; 33];
+    r_point[0] = 0x02 | recovery_id.is_y_odd() as u8;
+    r_point[1..].copy_from_slice(&r.to_bytes());
+    let big_r =
+        PublicKey::from_encoded_point(&k256::EncodedPoint::from_bytes(r_point).unwrap()).unwrap();
+    (
+        SignatureResponse::new(big_r, *s, recovery_id.to_byte()),
+        public_key,
+    )
+}
+
+#[test]
+fn eip155_example_roundtrip() {
+    // Example from the EIP-155 specification.
+    let tx = LegacyTransaction {
+        chain_id: Some(1),
+        nonce: 9,
+        gas_price: 20_000_000_000,
+        gas_limit: 21_000,
+        to: Some([0x35; 20]),
+        value: 1_000_000_000_000_000_000,
+        data: vec![],
+    };
+    let payload = tx.payload();
+    assert_eq!(
+        hex::encode(payload),
+        "daf5a779ae972f972197303d7b574746c7ef83eadac0f2791ad23db92e4c8e53"
+    );
+
+    let (response, public_key) = test_sign(&[0x46; 32], &payload);
+    assert_eq!(
+        hex::encode(tx.signed(&response)),
+        "f86c098504a817c800825208943535353535353535353535353535353535353535880de0b6b3a76400008025a028ef61340bd939bc2195fe537567866003e1a15d3c71ff63e1590620aa636276a067cbe9d8997f761aecb703304b3800ccf555c9f3dc64214b297fb1966a3b6d83"
+    );
+    assert_eq!(
+        hex::encode(address(&public_key)),
+        "9d8a62f656a8d1615c1294fd71e9cfb3e4855a4f"
+    );
+}
+
+#[test]
+fn eip1559_known_answer() {
+    // Expected encodings were produced by an independent implementation, checked against the
+    // EIP-155 example above, signing with the RFC6979 nonce.
+    let tx = Eip1559Transaction {
+        chain_id: 1,
+        nonce: 7,
+        max_priority_fee_per_gas: 2_000_000_000,
+        max_fee_per_gas: 30_000_000_000,
+        gas_limit: 50_000,
+        to: Some([0x35; 20]),
+        value: 10_000_000_000_000_000,
+        data: hex::decode("a9059cbb").unwrap(),
+        access_list: vec![AccessListItem {
+            address: [0xde; 20],
+            storage_keys: vec![
+                {
+                    let mut key = [0u8; 32];
+                    key[31] = 1;
+                    key
+                },
+                [0x02; 32],
+            ],
+        }],
+    };
+    let payload = tx.payload();
+    assert_eq!(
+        hex::encode(payload),
+        "c5998bb474f777258014598cbf7ba64a641feec0a2e0703f8b4038203eae07b2"
+    );
+
+    let (response, _) = test_sign(&[0x46; 32], &payload);
+    assert_eq!(
+        hex::encode(tx.signed(&response)),
+        "02f8d2010784773594008506fc23ac0082c350943535353535353535353535353535353535353535872386f26fc1000084a9059cbbf85bf85994dedededededededededededededededededededef842a00000000000000000000000000000000000000000000000000000000000000001a0020202020202020202020202020202020202020202020202020202020202020201a0fa49cfbb7b293a9fc817d7bdb41e13fe873f82f4902e6c9494579edc89f95de0a02f6b16bd965528c04d13656a03cab84e8f869137e1cb3de1133d6823d436ba0c"
+    );
+}
@@ -0,0 +1,24 @@
+//! Builders for transactions of foreign chains. Each builder yields the 32-byte `payload` that has
+//! to be passed along in a sign request, and assembles the final broadcastable transaction out of
+//! the `SignatureResponse` returned by the contract.
+
+pub mod bitcoin;
+pub mod ethereum;
+
+use crate::kdf::x_coordinate;
+use crate::types::SignatureResponse;
+use k256::elliptic_curve::scalar::IsHigh;
+
+/// Splits a signature into its `(r, s, recovery_id)` components, normalizing `s` to the lower half
+/// of the curve order as both Ethereum and Bitcoin consensus/relay rules require.
+pub(crate) fn normalized_signature(signature: &SignatureResponse) -> ([u8; 32], [u8; 32], u8) {
+    let r = x_coordinate(&signature.big_r.affine_point);
+    let mut s = signature.s.scalar;
+    let mut recovery_id = signature.recovery_id;
+    if bool::from(s.is_high()) {
+        // Negating `s` flips the parity of the `R` point that recovers the public key.
+        s = -s;
+        recovery_id ^= 1;
+    }
+    (r.to_bytes().into(), s.to_bytes().into(), recovery_id)
+}
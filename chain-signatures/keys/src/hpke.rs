@@ -23,6 +23,9 @@ pub type Kdf = HkdfSha384;
 #[derive(Serialize, Deserialize)]
 pub struct Ciphered {
     pub encapped_key: EncappedKey,
+    /// Serialized as bytes, which binary formats keep as is and JSON still writes as an array of
+    /// numbers.
+    #[serde(with = "serde_bytes")]
     pub text: CipherText,
     pub tag: Tag,
 }
@@ -0,0 +1,120 @@
+use cait_sith::protocol::Participant;
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use mpc_keys::hpke;
+use mpc_recovery_node::protocol::encoding::MessageFormat;
+use mpc_recovery_node::protocol::message::{MessageHeader, SignedMessage, TripleMessage};
+use mpc_recovery_node::protocol::MpcMessage;
+use mpc_recovery_node::transport::Frame;
+use near_crypto::{KeyType, SecretKey};
+use rand::RngCore;
+
+/// Sizes of the cait-sith message data in a triple message, from a single triple up to a large
+/// batch.
+const DATA_SIZES: &[usize] = &[1024, 64 * 1024, 1024 * 1024];
+
+fn triple_message(data_size: usize) -> MpcMessage {
+    let mut data = vec![0; data_size];
+    // Mostly curve points and scalars.
+    rand::thread_rng().fill_bytes(&mut data[data_size / 4..]);
+    MpcMessage::Triple(TripleMessage {
+        batch_id: 1234,
+        batch_size: 64,
+        epoch: 0,
+        from: Participant::from(0),
+        data,
+        timestamp: 1_700_000_000,
+    })
+}
+
+fn formats() -> [(&'static str, MessageFormat); 2] {
+    [("json", MessageFormat::Json), ("cbor", MessageFormat::Cbor)]
+}
+
+/// Keys to sign and encrypt messages with, the way a node sends them to a peer.
+struct Keys {
+    sign_sk: SecretKey,
+    cipher_pk: hpke::PublicKey,
+}
+
+impl Keys {
+    fn new() -> Self {
+        Self {
+            sign_sk: SecretKey::from_random(KeyType::ED25519),
+            cipher_pk: hpke::generate().1,
+        }
+    }
+
+    /// Encrypts `message` in `format` and puts it in a frame of the message stream, which is
+    /// what goes over the wire.
+    fn frame(&self, message: &MpcMessage, format: MessageFormat) -> Frame {
+        let header = MessageHeader {
+            from: Participant::from(0),
+            epoch: 0,
+            session: 1,
+            seq: 1,
+            sent_at: 1_700_000_000,
+        };
+        let sealed =
+            SignedMessage::encrypt(message, header, format, &self.sign_sk, &self.cipher_pk)
+                .unwrap();
+        Frame::Batch {
+            seq: 1,
+            messages: vec![sealed],
+        }
+    }
+}
+
+fn bench_message_encoding(c: &mut Criterion) {
+    let keys = Keys::new();
+    for &data_size in DATA_SIZES {
+        let message = triple_message(data_size);
+        let sizes = formats().map(|(name, format)| {
+            let encoded = format.encode(&message).unwrap().len();
+            let framed = keys.frame(&message, format).encode().len();
+            format!("{name}={encoded} framed={framed}")
+        });
+        println!(
+            "triple message with {data_size} bytes of data: {}",
+            sizes.join(" ")
+        );
+    }
+
+    let mut group = c.benchmark_group("encode_triple_message");
+    for &data_size in DATA_SIZES {
+        let message = triple_message(data_size);
+        for (name, format) in formats() {
+            group.bench_with_input(BenchmarkId::new(name, data_size), &message, |b, message| {
+                b.iter(|| format.encode(message).unwrap())
+            });
+        }
+    }
+    group.finish();
+
+    let mut group = c.benchmark_group("decode_triple_message");
+    for &data_size in DATA_SIZES {
+        let message = triple_message(data_size);
+        for (name, format) in formats() {
+            let encoded = format.encode(&message).unwrap();
+            group.bench_with_input(BenchmarkId::new(name, data_size), &encoded, |b, encoded| {
+                b.iter(|| MessageFormat::decode::<MpcMessage>(encoded).unwrap())
+            });
+        }
+    }
+    group.finish();
+
+    // Everything a message goes through before it is sent: encoding, signing, encrypting and
+    // framing.
+    let mut group = c.benchmark_group("frame_triple_message");
+    for &data_size in DATA_SIZES {
+        let message = triple_message(data_size);
+        for (name, format) in formats() {
+            group.bench_with_input(BenchmarkId::new(name, data_size), &message, |b, message| {
+                b.iter(|| keys.frame(message, format).encode())
+            });
+        }
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_message_encoding);
+criterion_main!(benches);
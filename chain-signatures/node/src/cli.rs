@@ -3,12 +3,20 @@ use crate::mesh::NetworkConfig;
 use crate::protocol::presignature::PresignatureConfig;
 use crate::protocol::triple::TripleConfig;
 use crate::protocol::{Config, MpcSignProtocol, SignQueue};
+use crate::storage::backup::{verify_against_contract, KeyShareBackup};
+use crate::storage::cipher::StorageCipher;
+use crate::storage::presignature_storage::LockPresignatureNodeStorageBox;
+use crate::storage::secret_storage::SecretNodeStorageBox;
+use crate::storage::sign_request_storage::LockSignRequestNodeStorageBox;
 use crate::storage::triple_storage::LockTripleNodeStorageBox;
-use crate::{indexer, storage, web};
+use crate::tls::NodeTls;
+use crate::types::BlockHeightStorage;
+use crate::{indexer, rpc_client, storage, web};
 use clap::Parser;
 use local_ip_address::local_ip;
 use near_account_id::AccountId;
 use near_crypto::{InMemorySigner, SecretKey};
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
 use tokio::sync::{mpsc, RwLock};
 use tracing_subscriber::EnvFilter;
@@ -16,6 +24,9 @@ use url::Url;
 
 use mpc_keys::hpke;
 
+/// Key shares of earlier epochs are kept for a day by default.
+const DEFAULT_KEY_SHARE_RETENTION_SECS: u64 = 24 * 60 * 60;
+
 #[derive(Parser, Debug)]
 pub enum Cli {
     Start {
@@ -86,6 +97,13 @@ pub enum Cli {
         )]
         max_concurrent_generation: usize,
 
+        /// At maximum, how many triples a single triple protocol generates at once.
+        /// Bigger batches share communication rounds between triples. Nodes that predate
+        /// batching only generate triples one at a time, so this has to stay at 1 until every
+        /// node is upgraded.
+        #[arg(long, env("MPC_RECOVERY_MAX_TRIPLE_BATCH_SIZE"), default_value("1"))]
+        max_triple_batch_size: usize,
+
         /// At minimum, how many presignatures to stockpile on this node.
         #[arg(long, env("MPC_RECOVERY_MIN_PRESIGNATURES"), default_value("10"))]
         min_presignatures: usize,
@@ -93,10 +111,116 @@ pub enum Cli {
         /// At maximum, how many presignatures to stockpile on the network.
         #[arg(long, env("MPC_RECOVERY_MAX_PRESIGNATURES"), default_value("320"))]
         max_presignatures: usize,
+
+        /// How many threads to run the CPU heavy parts of triple and presignature
+        /// generation on. Defaults to the number of available cores.
+        #[arg(long, env("MPC_RECOVERY_COMPUTE_THREADS"))]
+        compute_threads: Option<usize>,
+
+        /// How many seconds to keep the key shares of earlier epochs after the contract started
+        /// running with a newer one, as of the block it started in. Defaults to a day.
+        #[arg(long, env("MPC_RECOVERY_KEY_SHARE_RETENTION_SECS"))]
+        key_share_retention_secs: Option<u64>,
+
+        /// How many seconds into an epoch to vote for refreshing the key shares among the same
+        /// participants. Shares are not refreshed periodically if unset.
+        #[arg(long, env("MPC_RECOVERY_REFRESH_INTERVAL_SECS"))]
+        refresh_interval_secs: Option<u64>,
+
+        /// Serve and connect to the other nodes over TLS, with a certificate for the sign key.
+        /// Only nodes presenting the certificate of a participant or candidate in the contract
+        /// are accepted, so all nodes must enable this together.
+        #[arg(long, env("MPC_RECOVERY_MUTUAL_TLS"), requires("public_port"))]
+        mutual_tls: bool,
+        /// The port to serve the healthcheck and metrics on over plain HTTP with `--mutual-tls`,
+        /// for load balancers and scrapers that hold no peer certificate.
+        #[arg(long, env("MPC_RECOVERY_PUBLIC_PORT"))]
+        public_port: Option<u16>,
+    },
+    /// Exports the key share of this node, encrypted to the recovery keys of offline custodians,
+    /// once verified against the contract.
+    ExportKeyShare {
+        /// NEAR RPC address
+        #[arg(
+            long,
+            env("MPC_RECOVERY_NEAR_RPC"),
+            default_value("https://rpc.testnet.near.org")
+        )]
+        near_rpc: String,
+        /// MPC contract id
+        #[arg(
+            long,
+            env("MPC_RECOVERY_CONTRACT_ID"),
+            default_value("v5.multichain-mpc-dev.testnet")
+        )]
+        mpc_contract_id: AccountId,
+        /// This node's account id
+        #[arg(long, env("MPC_RECOVERY_ACCOUNT_ID"))]
+        account_id: AccountId,
+        /// Storage options
+        #[clap(flatten)]
+        storage_options: storage::Options,
+        /// Hex encoded recovery public key of a custodian. Can be repeated, once per custodian.
+        #[arg(long = "recovery-pk", required = true)]
+        recovery_pks: Vec<String>,
+        /// How many of the custodians are required to restore the key share.
+        #[arg(long, default_value("1"))]
+        recovery_threshold: usize,
+        /// Epoch of the key share to export. Defaults to the latest one. Either way, the latest
+        /// key version of the epoch is exported.
+        #[arg(long)]
+        epoch: Option<u64>,
+        /// File to write the backup to.
+        #[arg(long)]
+        out: PathBuf,
+    },
+    /// Restores the key share of this node from a backup, once verified against the contract.
+    ImportKeyShare {
+        /// NEAR RPC address
+        #[arg(
+            long,
+            env("MPC_RECOVERY_NEAR_RPC"),
+            default_value("https://rpc.testnet.near.org")
+        )]
+        near_rpc: String,
+        /// MPC contract id
+        #[arg(
+            long,
+            env("MPC_RECOVERY_CONTRACT_ID"),
+            default_value("v5.multichain-mpc-dev.testnet")
+        )]
+        mpc_contract_id: AccountId,
+        /// This node's account id
+        #[arg(long, env("MPC_RECOVERY_ACCOUNT_ID"))]
+        account_id: AccountId,
+        /// Storage options
+        #[clap(flatten)]
+        storage_options: storage::Options,
+        /// File holding the hex encoded recovery secret key of a custodian. Can be repeated.
+        #[arg(long = "recovery-sk-file", required = true)]
+        recovery_sk_files: Vec<PathBuf>,
+        /// File the backup was exported to.
+        #[arg(long)]
+        backup: PathBuf,
     },
 }
 
 impl Cli {
+    /// Environment variables to run a node with along with [`Self::into_str_args`].
+    pub fn str_envs(&self) -> Vec<(String, String)> {
+        match self {
+            Cli::Start {
+                storage_options, ..
+            }
+            | Cli::ExportKeyShare {
+                storage_options, ..
+            }
+            | Cli::ImportKeyShare {
+                storage_options, ..
+            } => storage_options.str_envs(),
+        }
+    }
+
     pub fn into_str_args(self) -> Vec<String> {
         match self {
             Cli::Start {
@@ -115,8 +239,14 @@ impl Cli {
                 max_triples,
                 max_concurrent_introduction,
                 max_concurrent_generation,
+                max_triple_batch_size,
                 min_presignatures,
                 max_presignatures,
+                compute_threads,
+                key_share_retention_secs,
+                refresh_interval_secs,
+                mutual_tls,
+                public_port,
             } => {
                 let mut args = vec![
                     "start".to_string(),
@@ -142,6 +272,8 @@ impl Cli {
                     max_concurrent_introduction.to_string(),
                     "--max-concurrent-generation".to_string(),
                     max_concurrent_generation.to_string(),
+                    "--max-triple-batch-size".to_string(),
+                    max_triple_batch_size.to_string(),
                     "--min-presignatures".to_string(),
                     min_presignatures.to_string(),
                     "--max-presignatures".to_string(),
@@ -153,10 +285,91 @@ impl Cli {
                 if let Some(my_address) = my_address {
                     args.extend(["--my-address".to_string(), my_address.to_string()]);
                 }
+                if let Some(compute_threads) = compute_threads {
+                    args.extend(["--compute-threads".to_string(), compute_threads.to_string()]);
+                }
+                if let Some(key_share_retention_secs) = key_share_retention_secs {
+                    args.extend([
+                        "--key-share-retention-secs".to_string(),
+                        key_share_retention_secs.to_string(),
+                    ]);
+                }
+                if let Some(refresh_interval_secs) = refresh_interval_secs {
+                    args.extend([
+                        "--refresh-interval-secs".to_string(),
+                        refresh_interval_secs.to_string(),
+                    ]);
+                }
+                if mutual_tls {
+                    args.push("--mutual-tls".to_string());
+                }
+                if let Some(public_port) = public_port {
+                    args.extend(["--public-port".to_string(), public_port.to_string()]);
+                }
                 args.extend(indexer_options.into_str_args());
                 args.extend(storage_options.into_str_args());
                 args
             }
+            Cli::ExportKeyShare {
+                near_rpc,
+                mpc_contract_id,
+                account_id,
+                storage_options,
+                recovery_pks,
+                recovery_threshold,
+                epoch,
+                out,
+            } => {
+                let mut args = vec![
+                    "export-key-share".to_string(),
+                    "--near-rpc".to_string(),
+                    near_rpc,
+                    "--mpc-contract-id".to_string(),
+                    mpc_contract_id.to_string(),
+                    "--account-id".to_string(),
+                    account_id.to_string(),
+                    "--recovery-threshold".to_string(),
+                    recovery_threshold.to_string(),
+                    "--out".to_string(),
+                    out.display().to_string(),
+                ];
+                for recovery_pk in recovery_pks {
+                    args.extend(["--recovery-pk".to_string(), recovery_pk]);
+                }
+                if let Some(epoch) = epoch {
+                    args.extend(["--epoch".to_string(), epoch.to_string()]);
+                }
+                args.extend(storage_options.into_str_args());
+                args
+            }
+            Cli::ImportKeyShare {
+                near_rpc,
+                mpc_contract_id,
+                account_id,
+                storage_options,
+                recovery_sk_files,
+                backup,
+            } => {
+                let mut args = vec![
+                    "import-key-share".to_string(),
+                    "--near-rpc".to_string(),
+                    near_rpc,
+                    "--mpc-contract-id".to_string(),
+                    mpc_contract_id.to_string(),
+                    "--account-id".to_string(),
+                    account_id.to_string(),
+                    "--backup".to_string(),
+                    backup.display().to_string(),
+                ];
+                for recovery_sk_file in recovery_sk_files {
+                    args.extend([
+                        "--recovery-sk-file".to_string(),
+                        recovery_sk_file.display().to_string(),
+                    ]);
+                }
+                args.extend(storage_options.into_str_args());
+                args
+            }
         }
     }
 }
@@ -174,18 +387,122 @@ fn is_running_on_gcp() -> bool {
     }
 }
 
+/// Opens the storage the key share is persisted in, refusing to fall back to memory.
+async fn open_secret_storage(
+    account_id: &AccountId,
+    storage_options: &storage::Options,
+) -> anyhow::Result<SecretNodeStorageBox> {
+    if storage_options.sk_share_secret_id.is_none()
+        && storage_options.local_db_path.is_none()
+        && storage_options.sk_share_local_path.is_none()
+    {
+        anyhow::bail!("no persistent storage is configured for the key share");
+    }
+    let local_db = storage_options.open_local_db()?;
+    let gcp_service = match (&local_db, &storage_options.sk_share_secret_id) {
+        (None, Some(_)) => Some(GcpService::init(account_id, storage_options).await?),
+        _ => None,
+    };
+    Ok(storage::secret_storage::init(
+        gcp_service.as_ref(),
+        local_db.as_ref(),
+        storage_options,
+        account_id,
+    )?)
+}
+
+async fn export_key_share(
+    near_rpc: &str,
+    mpc_contract_id: &AccountId,
+    account_id: &AccountId,
+    storage_options: &storage::Options,
+    recovery_pks: &[String],
+    recovery_threshold: usize,
+    epoch: Option<u64>,
+    out: &Path,
+) -> anyhow::Result<()> {
+    let recipients = recovery_pks
+        .iter()
+        .map(|pk| Ok(hpke::PublicKey::try_from_bytes(&hex::decode(pk)?)?))
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    let key_shares = open_secret_storage(account_id, storage_options)
+        .await?
+        .load()
+        .await?;
+    let data = key_shares
+        .into_iter()
+        .filter(|data| epoch.map_or(true, |epoch| data.epoch == epoch))
+        .max_by_key(|data| (data.epoch, data.key_version))
+        .ok_or_else(|| anyhow::anyhow!("no key share to export"))?;
+
+    // A backup that the contract would not take back is of no use to the custodians.
+    let client = near_fetch::Client::new(near_rpc);
+    let contract_state = rpc_client::fetch_mpc_contract_state(&client, mpc_contract_id).await?;
+    verify_against_contract(&data, &contract_state)?;
+
+    let backup = KeyShareBackup::seal(account_id, &data, &recipients, recovery_threshold)?;
+    storage::key_file::write_atomic(out, &serde_json::to_vec_pretty(&backup)?).await?;
+    tracing::info!(
+        epoch = data.epoch,
+        key_version = data.key_version,
+        custodians = recipients.len(),
+        recovery_threshold,
+        out = %out.display(),
+        "exported key share"
+    );
+    Ok(())
+}
+
+async fn import_key_share(
+    near_rpc: &str,
+    mpc_contract_id: &AccountId,
+    account_id: &AccountId,
+    storage_options: &storage::Options,
+    recovery_sk_files: &[PathBuf],
+    backup: &Path,
+) -> anyhow::Result<()> {
+    let backup: KeyShareBackup = serde_json::from_slice(&tokio::fs::read(backup).await?)?;
+    if &backup.account_id != account_id {
+        anyhow::bail!(
+            "backup belongs to {} rather than {account_id}",
+            backup.account_id
+        );
+    }
+    let mut secret_keys = Vec::with_capacity(recovery_sk_files.len());
+    for path in recovery_sk_files {
+        storage::key_file::check_permissions(path).await?;
+        let sk = tokio::fs::read_to_string(path).await?;
+        secret_keys.push(hpke::SecretKey::try_from_bytes(&hex::decode(sk.trim())?)?);
+    }
+    let data = backup.open(&secret_keys)?;
+
+    let client = near_fetch::Client::new(near_rpc);
+    let contract_state = rpc_client::fetch_mpc_contract_state(&client, mpc_contract_id).await?;
+    verify_against_contract(&data, &contract_state)?;
+
+    open_secret_storage(account_id, storage_options)
+        .await?
+        .store(&data)
+        .await?;
+    tracing::info!(epoch = data.epoch, "imported key share");
+    Ok(())
+}
+
 fn spinup_indexer(
     options: &indexer::Options,
+    near_rpc: &str,
     mpc_contract_id: &AccountId,
     account_id: &AccountId,
     sign_queue: &Arc<RwLock<SignQueue>>,
-    gcp: &GcpService,
+    sign_request_storage: &LockSignRequestNodeStorageBox,
+    block_height_storage: BlockHeightStorage,
 ) -> std::thread::JoinHandle<()> {
     let options = options.clone();
+    let near_rpc = near_rpc.to_string();
     let mpc_contract_id = mpc_contract_id.clone();
     let account_id = account_id.clone();
     let sign_queue = sign_queue.clone();
-    let gcp = gcp.clone();
+    let sign_request_storage = sign_request_storage.clone();
     std::thread::spawn(move || {
         // If indexer fails for whatever reason, let's spin it back up:
         let mut i = 0;
@@ -195,16 +512,25 @@ fn spinup_indexer(
             }
 
             let options = options.clone();
+            let near_rpc = near_rpc.clone();
             let mpc_contract_id = mpc_contract_id.clone();
             let account_id = account_id.clone();
             let sign_queue = sign_queue.clone();
-            let gcp = gcp.clone();
+            let sign_request_storage = sign_request_storage.clone();
+            let block_height_storage = block_height_storage.clone();
 
             // TODO/NOTE: currently indexer does not have any interrupt handlers and will never yield back
             // as successful. We can add interrupt handlers in the future but this is not important right
             // now since we managing nodes through integration tests that can kill it or through docker.
-            let Err(err) = indexer::run(options, mpc_contract_id, account_id, sign_queue, gcp)
-            else {
+            let Err(err) = indexer::run(
+                options,
+                near_rpc,
+                mpc_contract_id,
+                account_id,
+                sign_queue,
+                sign_request_storage,
+                block_height_storage,
+            ) else {
                 break;
             };
             tracing::error!(%err, "indexer failed");
@@ -252,34 +578,84 @@ pub fn run(cmd: Cli) -> anyhow::Result<()> {
             max_triples,
             max_concurrent_introduction,
             max_concurrent_generation,
+            max_triple_batch_size,
             min_presignatures,
             max_presignatures,
+            compute_threads,
+            key_share_retention_secs,
+            refresh_interval_secs,
+            mutual_tls,
+            public_port,
         } => {
+            let compute_threads = compute_threads.unwrap_or_else(|| {
+                std::thread::available_parallelism().map_or(1, |threads| threads.get())
+            });
+            let key_share_retention = std::time::Duration::from_secs(
+                key_share_retention_secs.unwrap_or(DEFAULT_KEY_SHARE_RETENTION_SECS),
+            );
             let sign_queue = Arc::new(RwLock::new(SignQueue::new()));
             tokio::runtime::Builder::new_multi_thread()
                 .enable_all()
                 .build()?
                 .block_on(async {
                     let (sender, receiver) = mpsc::channel(16384);
-                    let gcp_service = GcpService::init(&account_id, &storage_options).await?;
+                    let local_db = storage_options.open_local_db()?;
+                    // Everything is kept in the local database when there is one.
+                    let gcp_service = match local_db {
+                        Some(_) => None,
+                        None => Some(GcpService::init(&account_id, &storage_options).await?),
+                    };
+                    let sign_request_storage: LockSignRequestNodeStorageBox =
+                        Arc::new(RwLock::new(storage::sign_request_storage::init(
+                            gcp_service.as_ref(),
+                            local_db.as_ref(),
+                            &account_id,
+                        )));
+                    // Requests indexed before a restart that were never resolved.
+                    let pending = sign_request_storage.read().await.load().await?;
+                    tracing::info!(count = pending.len(), "reloaded pending sign requests");
+                    {
+                        let mut sign_queue = sign_queue.write().await;
+                        for request in pending {
+                            sign_queue.add(request);
+                        }
+                    }
                     let indexer_handle = spinup_indexer(
                         &indexer_options,
+                        &near_rpc,
                         &mpc_contract_id,
                         &account_id,
                         &sign_queue,
-                        &gcp_service,
+                        &sign_request_storage,
+                        BlockHeightStorage::init(gcp_service.as_ref(), local_db.as_ref()),
                     );
 
                     let key_storage = storage::secret_storage::init(
-                        Some(&gcp_service),
+                        gcp_service.as_ref(),
+                        local_db.as_ref(),
                         &storage_options,
                         &account_id,
-                    );
-                    let triple_storage: LockTripleNodeStorageBox = Arc::new(RwLock::new(
-                        storage::triple_storage::init(Some(&gcp_service), &account_id),
-                    ));
+                    )?;
+                    // Secret shares are encrypted at rest with a key derived from the cipher key.
+                    let storage_cipher = StorageCipher::derive(&hex::decode(&cipher_sk)?);
+                    let triple_storage: LockTripleNodeStorageBox =
+                        Arc::new(RwLock::new(storage::triple_storage::init(
+                            gcp_service.as_ref(),
+                            local_db.as_ref(),
+                            &storage_cipher,
+                            &account_id,
+                        )));
+                    let presignature_storage: LockPresignatureNodeStorageBox =
+                        Arc::new(RwLock::new(storage::presignature_storage::init(
+                            gcp_service.as_ref(),
+                            local_db.as_ref(),
+                            &storage_cipher,
+                            &account_id,
+                        )));
 
                     let sign_sk = sign_sk.unwrap_or_else(|| account_sk.clone());
+                    let tls = mutual_tls.then(|| NodeTls::new(&sign_sk)).transpose()?;
+                    let scheme = if tls.is_some() { "https" } else { "http" };
                     let my_address = my_address
                         .map(|mut addr| {
                             addr.set_port(Some(web_port)).unwrap();
@@ -287,7 +663,7 @@ pub fn run(cmd: Cli) -> anyhow::Result<()> {
                         })
                         .unwrap_or_else(|| {
                             let my_ip = local_ip().unwrap();
-                            Url::parse(&format!("http://{my_ip}:{web_port}")).unwrap()
+                            Url::parse(&format!("{scheme}://{my_ip}:{web_port}")).unwrap()
                         });
                     tracing::info!(%my_address, "address detected");
                     let rpc_client = near_fetch::Client::new(&near_rpc);
@@ -303,12 +679,15 @@ pub fn run(cmd: Cli) -> anyhow::Result<()> {
                         sign_queue,
                         key_storage,
                         triple_storage,
+                        presignature_storage,
+                        sign_request_storage,
                         Config {
                             triple_cfg: TripleConfig {
                                 min_triples,
                                 max_triples,
                                 max_concurrent_introduction,
                                 max_concurrent_generation,
+                                max_batch_size: max_triple_batch_size,
                             },
                             presig_cfg: PresignatureConfig {
                                 min_presignatures,
@@ -319,15 +698,34 @@ pub fn run(cmd: Cli) -> anyhow::Result<()> {
                                     cipher_pk,
                                 )?)?,
                                 sign_sk,
+                                tls: tls.clone(),
                             },
+                            compute_threads,
+                            key_share_retention,
+                            refresh_interval: refresh_interval_secs
+                                .map(std::time::Duration::from_secs),
                         },
                     );
                     tracing::debug!("protocol initialized");
+                    let slow_view = protocol.slow_view();
+                    let liveness_view = protocol.liveness_view();
+                    let replay_guard = protocol.replay_guard();
                     let protocol_handle = tokio::spawn(async move { protocol.run().await });
                     tracing::debug!("protocol thread spawned");
                     let cipher_sk = hpke::SecretKey::try_from_bytes(&hex::decode(cipher_sk)?)?;
                     let web_handle = tokio::spawn(async move {
-                        web::run(web_port, sender, cipher_sk, protocol_state).await
+                        web::run(
+                            web_port,
+                            sender,
+                            cipher_sk,
+                            protocol_state,
+                            slow_view,
+                            liveness_view,
+                            replay_guard,
+                            tls,
+                            public_port,
+                        )
+                        .await
                     });
                     tracing::debug!("protocol http server spawned");
 
@@ -339,6 +737,50 @@ pub fn run(cmd: Cli) -> anyhow::Result<()> {
                     anyhow::Ok(())
                 })?;
         }
+        Cli::ExportKeyShare {
+            near_rpc,
+            mpc_contract_id,
+            account_id,
+            storage_options,
+            recovery_pks,
+            recovery_threshold,
+            epoch,
+            out,
+        } => {
+            tokio::runtime::Builder::new_current_thread()
+                .enable_all()
+                .build()?
+                .block_on(export_key_share(
+                    &near_rpc,
+                    &mpc_contract_id,
+                    &account_id,
+                    &storage_options,
+                    &recovery_pks,
+                    recovery_threshold,
+                    epoch,
+                    &out,
+                ))?;
+        }
+        Cli::ImportKeyShare {
+            near_rpc,
+            mpc_contract_id,
+            account_id,
+            storage_options,
+            recovery_sk_files,
+            backup,
+        } => {
+            tokio::runtime::Builder::new_current_thread()
+                .enable_all()
+                .build()?
+                .block_on(import_key_share(
+                    &near_rpc,
+                    &mpc_contract_id,
+                    &account_id,
+                    &storage_options,
+                    &recovery_sk_files,
+                    &backup,
+                ))?;
+        }
     }
 
     Ok(())
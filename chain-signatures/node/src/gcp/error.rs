@@ -18,6 +18,10 @@ pub enum SecretStorageError {
     IoError(#[from] std::io::Error),
     #[error("(de)serialization error: {0}")]
     SerdeError(#[from] serde_json::Error),
+    #[error("local database error: {0}")]
+    LocalDbError(#[from] rusqlite::Error),
+    #[error("key share file error: {0}")]
+    KeyFileError(String),
 }
 
 #[derive(thiserror::Error, Debug)]
@@ -34,6 +38,10 @@ pub enum DatastoreStorageError {
     FetchEntitiesError(String),
     #[error("could not find entity: {0}")]
     EntityNotFound(String),
+    #[error("decryption error: {0}")]
+    DecryptionError(String),
+    #[error("local database error: {0}")]
+    LocalDbError(#[from] rusqlite::Error),
 }
 
 impl From<ConvertError> for DatastoreStorageError {
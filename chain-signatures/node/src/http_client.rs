@@ -1,8 +1,9 @@
 use crate::protocol::contract::primitives::{ParticipantInfo, Participants};
-use crate::protocol::message::SignedMessage;
+use crate::protocol::message::{MessageHeader, SignedMessage, WireMessage};
 use crate::protocol::MpcMessage;
+use crate::transport::Transport;
 use cait_sith::protocol::Participant;
-use mpc_keys::hpke::Ciphered;
+use chrono::Utc;
 use reqwest::{Client, IntoUrl};
 use std::collections::{HashMap, HashSet, VecDeque};
 use std::str::Utf8Error;
@@ -31,13 +32,19 @@ pub enum SendError {
     Timeout(String),
     #[error("participant is not alive: {0}")]
     ParticipantNotAlive(String),
+    #[error("participant is not keeping up with the messages sent to it: {0}")]
+    Backpressure(String),
+    #[error("message stream to participant is closed: {0}")]
+    StreamClosed(String),
+    #[error("message stream to participant is not connected yet: {0}")]
+    NotConnected(String),
 }
 
-async fn send_encrypted<U: IntoUrl>(
+pub(crate) async fn send_encrypted<U: IntoUrl>(
     from: Participant,
     client: &Client,
     url: U,
-    message: Vec<Ciphered>,
+    message: Vec<WireMessage>,
 ) -> Result<(), SendError> {
     let _span = tracing::info_span!("message_request");
     let mut url = url.into_url()?;
@@ -75,12 +82,29 @@ async fn send_encrypted<U: IntoUrl>(
     Retry::spawn(retry_strategy, action).await
 }
 
+/// A message waiting to be sent to a participant, along with when it was queued.
+pub type QueuedMessage = (ParticipantInfo, MpcMessage, Instant);
+
 // TODO: add in retry logic either in struct or at call site.
 // TODO: add check for participant list to see if the messages to be sent are still valid.
-#[derive(Default)]
 pub struct MessageQueue {
-    deque: VecDeque<(ParticipantInfo, MpcMessage, Instant)>,
+    deque: VecDeque<QueuedMessage>,
     seen_counts: HashSet<String>,
+    /// Names the sequence numbers of this run of the node, which restart from one.
+    session: u64,
+    /// Sequence number of the last message to each participant.
+    last_seq: HashMap<Participant, u64>,
+}
+
+impl Default for MessageQueue {
+    fn default() -> Self {
+        Self {
+            deque: VecDeque::new(),
+            seen_counts: HashSet::new(),
+            session: rand::random(),
+            last_seq: HashMap::new(),
+        }
+    }
 }
 
 impl MessageQueue {
@@ -96,17 +120,26 @@ impl MessageQueue {
         self.deque.push_back((info, msg, Instant::now()));
     }
 
+    fn next_seq(&mut self, to: Participant) -> u64 {
+        let seq = self.last_seq.entry(to).or_default();
+        *seq += 1;
+        *seq
+    }
+
     pub async fn send_encrypted(
         &mut self,
         from: Participant,
         sign_sk: &near_crypto::SecretKey,
-        client: &Client,
+        epoch: u64,
+        transport: &Transport,
         participants: &Participants,
     ) -> Vec<SendError> {
         let mut failed = VecDeque::new();
         let mut errors = Vec::new();
         let mut participant_counter = HashMap::new();
 
+        // Messages the stream to a peer could not deliver before it turned out not to serve one.
+        self.deque.extend(transport.take_requeued());
         let outer = Instant::now();
         let uncompacted = self.deque.len();
         let mut encrypted = HashMap::new();
@@ -125,7 +158,23 @@ impl MessageQueue {
                 failed.push_back((info, msg, instant));
                 continue;
             }
-            let encrypted_msg = match SignedMessage::encrypt(&msg, from, sign_sk, &info.cipher_pk) {
+            // Participants that do not serve the message stream predate sealed messages.
+            let encrypted_msg = if transport.is_legacy(&info) {
+                SignedMessage::encrypt_legacy(&msg, from, sign_sk, &info.cipher_pk)
+                    .map(WireMessage::Legacy)
+            } else {
+                let header = MessageHeader {
+                    from,
+                    epoch,
+                    session: self.session,
+                    seq: self.next_seq(Participant::from(info.id)),
+                    sent_at: Utc::now().timestamp() as u64,
+                };
+                let format = transport.format(&info);
+                SignedMessage::encrypt(&msg, header, format, sign_sk, &info.cipher_pk)
+                    .map(WireMessage::Sealed)
+            };
+            let encrypted_msg = match encrypted_msg {
                 Ok(encrypted) => encrypted,
                 Err(err) => {
                     errors.push(SendError::EncryptionError(err.to_string()));
@@ -139,7 +188,9 @@ impl MessageQueue {
         let mut compacted = 0;
         for (id, encrypted) in encrypted {
             for partition in partition_ciphered_256kb(encrypted) {
-                let (encrypted_partition, msgs): (Vec<_>, Vec<_>) = partition.into_iter().unzip();
+                let (encrypted_partition, mut msgs): (Vec<_>, Vec<_>) =
+                    partition.into_iter().unzip();
+                let count = msgs.len();
                 // guaranteed to unwrap due to our previous loop check:
                 let info = participants.get(&Participant::from(id)).unwrap();
                 let account_id = &info.account_id;
@@ -148,7 +199,9 @@ impl MessageQueue {
                 crate::metrics::NUM_SEND_ENCRYPTED_TOTAL
                     .with_label_values(&[account_id.as_str()])
                     .inc();
-                if let Err(err) = send_encrypted(from, client, &info.url, encrypted_partition).await
+                if let Err(err) = transport
+                    .send(from, info, encrypted_partition, &mut msgs)
+                    .await
                 {
                     crate::metrics::NUM_SEND_ENCRYPTED_FAILURE
                         .with_label_values(&[account_id.as_str()])
@@ -161,7 +214,7 @@ impl MessageQueue {
                     failed.extend(msgs);
                     errors.push(err);
                 } else {
-                    compacted += msgs.len();
+                    compacted += count;
                     crate::metrics::SEND_ENCRYPTED_LATENCY
                         .with_label_values(&[account_id.as_str()])
                         .observe(start.elapsed().as_millis() as f64);
@@ -194,7 +247,7 @@ impl MessageQueue {
 /// Encrypted message with a reference to the old message. Only the ciphered portion of this
 /// type will be sent over the wire, while the original message is kept just in case things
 /// go wrong somewhere and the message needs to be requeued to be sent later.
-type EncryptedMessage = (Ciphered, (ParticipantInfo, MpcMessage, Instant));
+type EncryptedMessage = (WireMessage, QueuedMessage);
 
 fn partition_ciphered_256kb(encrypted: Vec<EncryptedMessage>) -> Vec<Vec<EncryptedMessage>> {
     let mut result = Vec::new();
@@ -202,7 +255,7 @@ fn partition_ciphered_256kb(encrypted: Vec<EncryptedMessage>) -> Vec<Vec<Encrypt
     let mut current_size: usize = 0;
 
     for ciphered in encrypted {
-        let bytesize = ciphered.0.text.len();
+        let bytesize = ciphered.0.ciphered().text.len();
         if current_size + bytesize > 256 * 1024 {
             // If adding this byte vector exceeds 256kb, start a new partition
             result.push(current_partition);
@@ -0,0 +1,533 @@
+//! Minimal NEAR light client used to verify the blocks and receipt outcomes handed to the
+//! indexer, following https://nomicon.io/ChainSpec/LightClient.
+
+use super::source::{ContractCall, IndexedBlock};
+use super::SignArguments;
+
+use near_account_id::AccountId;
+use near_jsonrpc_client::{methods, JsonRpcClient};
+use near_jsonrpc_primitives::types::receipts::ReceiptReference;
+use near_primitives::block_header::{Approval, ApprovalInner};
+use near_primitives::hash::CryptoHash;
+use near_primitives::merkle::{combine_hash, compute_root_from_path};
+use near_primitives::types::validator_stake::ValidatorStake;
+use near_primitives::types::{
+    BlockHeight, BlockId, BlockReference, Finality, TransactionOrReceiptId,
+};
+use near_primitives::views::validator_stake_view::ValidatorStakeView;
+use near_primitives::views::{
+    ActionView, ExecutionStatusView, LightClientBlockLiteView, LightClientBlockView,
+    ReceiptEnumView,
+};
+use std::collections::HashMap;
+use std::time::Duration;
+
+/// How many times the head gets synced while waiting for it to move past a block to verify.
+const HEAD_SYNC_ATTEMPTS: usize = 10;
+const HEAD_SYNC_INTERVAL: Duration = Duration::from_millis(500);
+
+/// The outcome of a receipt is included by the first block after the one it got executed in
+/// that carries a new chunk of its shard. At most this many blocks are accepted in between, for
+/// when chunks went missing.
+const OUTCOME_INCLUSION_WINDOW: u64 = 5;
+
+#[derive(Debug, thiserror::Error)]
+pub enum VerificationError {
+    #[error("rpc request failed: {0}")]
+    Rpc(String),
+    #[error("light client block at height {0} is invalid: {1}")]
+    InvalidLightClientBlock(BlockHeight, &'static str),
+    #[error("header of block {0} does not hash to it")]
+    HeaderMismatch(CryptoHash),
+    #[error("block {0} is not part of the verified chain")]
+    BlockNotInChain(CryptoHash),
+    #[error("outcome of receipt {0} is invalid: {1}")]
+    InvalidOutcome(CryptoHash, &'static str),
+    #[error("light client head at height {head} did not move past block height {height}")]
+    HeadBehind {
+        head: BlockHeight,
+        height: BlockHeight,
+    },
+}
+
+impl VerificationError {
+    /// Whether verifying the same block again may succeed, in which case the indexer must not
+    /// move past it. A light client block failing validation only says that the head could not
+    /// be synced from that reply, not that the indexed block is bad.
+    pub fn is_transient(&self) -> bool {
+        matches!(
+            self,
+            VerificationError::Rpc(_)
+                | VerificationError::HeadBehind { .. }
+                | VerificationError::InvalidLightClientBlock(..)
+        )
+    }
+
+    /// Label used for the verification failure metrics.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            VerificationError::Rpc(_) => "rpc",
+            VerificationError::InvalidLightClientBlock(..) => "light_client_block",
+            VerificationError::HeaderMismatch(_) | VerificationError::BlockNotInChain(_) => {
+                "header"
+            }
+            VerificationError::InvalidOutcome(..) => "outcome",
+            VerificationError::HeadBehind { .. } => "head_behind",
+        }
+    }
+}
+
+fn rpc_error(err: impl std::fmt::Display) -> VerificationError {
+    VerificationError::Rpc(err.to_string())
+}
+
+pub struct LightClient {
+    client: JsonRpcClient,
+    head: LightClientBlockLiteView,
+    /// Block producers of every epoch the light client knows about, by epoch id.
+    epoch_block_producers: HashMap<CryptoHash, Vec<ValidatorStakeView>>,
+}
+
+impl LightClient {
+    /// Starts a light client from the trusted block `checkpoint`. The block producers of the
+    /// checkpoint's epoch are checked against the `next_bp_hash` of the last block of the epoch
+    /// before, which the checkpoint's block merkle root commits to.
+    pub async fn bootstrap(
+        rpc_url: &str,
+        checkpoint: CryptoHash,
+    ) -> Result<Self, VerificationError> {
+        let client = JsonRpcClient::connect(rpc_url);
+        let final_block = client
+            .call(methods::block::RpcBlockRequest {
+                block_reference: BlockReference::Finality(Finality::Final),
+            })
+            .await
+            .map_err(rpc_error)?;
+        let proof = client
+            .call(
+                methods::EXPERIMENTAL_light_client_block_proof::RpcLightClientBlockProofRequest {
+                    block_hash: checkpoint,
+                    light_client_head: final_block.header.hash,
+                },
+            )
+            .await
+            .map_err(rpc_error)?;
+        let head = proof.block_header_lite;
+        if head.hash() != checkpoint {
+            return Err(VerificationError::HeaderMismatch(checkpoint));
+        }
+        // Epochs are named after the last block of the epoch two before them, so the epoch
+        // after the checkpoint's is named after the last block of the epoch before it.
+        let previous_epoch_block = head.inner_lite.next_epoch_id;
+        let proof = client
+            .call(
+                methods::EXPERIMENTAL_light_client_block_proof::RpcLightClientBlockProofRequest {
+                    block_hash: previous_epoch_block,
+                    light_client_head: checkpoint,
+                },
+            )
+            .await
+            .map_err(rpc_error)?;
+        if proof.block_header_lite.hash() != previous_epoch_block {
+            return Err(VerificationError::HeaderMismatch(previous_epoch_block));
+        }
+        if compute_root_from_path(&proof.block_proof, previous_epoch_block)
+            != head.inner_lite.block_merkle_root
+        {
+            return Err(VerificationError::BlockNotInChain(previous_epoch_block));
+        }
+        let block_producers = client
+            .call(
+                methods::EXPERIMENTAL_validators_ordered::RpcValidatorsOrderedRequest {
+                    block_id: Some(BlockId::Hash(checkpoint)),
+                },
+            )
+            .await
+            .map_err(rpc_error)?;
+        if block_producers_hash(&block_producers) != proof.block_header_lite.inner_lite.next_bp_hash
+        {
+            return Err(VerificationError::InvalidLightClientBlock(
+                head.inner_lite.height,
+                "block producers of the checkpoint's epoch do not match next_bp_hash",
+            ));
+        }
+
+        let mut light_client = Self::new(client, head, block_producers);
+        light_client.sync().await?;
+        Ok(light_client)
+    }
+
+    fn new(
+        client: JsonRpcClient,
+        head: LightClientBlockLiteView,
+        block_producers: Vec<ValidatorStakeView>,
+    ) -> Self {
+        let epoch_block_producers = HashMap::from([(head.inner_lite.epoch_id, block_producers)]);
+        Self {
+            client,
+            head,
+            epoch_block_producers,
+        }
+    }
+
+    pub fn head_height(&self) -> BlockHeight {
+        self.head.inner_lite.height
+    }
+
+    /// Moves the head up to the latest final block the RPC node knows about.
+    pub async fn sync(&mut self) -> Result<(), VerificationError> {
+        loop {
+            let next = self
+                .client
+                .call(
+                    methods::next_light_client_block::RpcLightClientNextBlockRequest {
+                        last_block_hash: self.head.hash(),
+                    },
+                )
+                .await
+                .map_err(rpc_error)?;
+            match next {
+                Some(block) if block.inner_lite.height > self.head_height() => {
+                    self.validate_and_update_head(block)?;
+                }
+                _ => return Ok(()),
+            }
+        }
+    }
+
+    fn validate_and_update_head(
+        &mut self,
+        block: LightClientBlockView,
+    ) -> Result<(), VerificationError> {
+        let height = block.inner_lite.height;
+        let invalid = |reason| VerificationError::InvalidLightClientBlock(height, reason);
+
+        if height <= self.head_height() {
+            return Err(invalid("not ahead of the current head"));
+        }
+        let epoch_id = block.inner_lite.epoch_id;
+        if epoch_id != self.head.inner_lite.epoch_id
+            && epoch_id != self.head.inner_lite.next_epoch_id
+        {
+            return Err(invalid(
+                "block is neither in the current nor the next epoch",
+            ));
+        }
+        if epoch_id == self.head.inner_lite.next_epoch_id && block.next_bps.is_none() {
+            return Err(invalid(
+                "first block of the next epoch lacks its block producers",
+            ));
+        }
+        let block_producers = self
+            .epoch_block_producers
+            .get(&epoch_id)
+            .ok_or_else(|| invalid("block producers of the epoch are unknown"))?;
+
+        let lite = LightClientBlockLiteView {
+            prev_block_hash: block.prev_block_hash,
+            inner_rest_hash: block.inner_rest_hash,
+            inner_lite: block.inner_lite.clone(),
+        };
+        let current_block_hash = lite.hash();
+        let next_block_hash = combine_hash(&block.next_block_inner_hash, &current_block_hash);
+        let message =
+            Approval::get_data_for_sig(&ApprovalInner::Endorsement(next_block_hash), height + 2);
+
+        // Every block producer counts towards the total, so that a truncated list of approvals
+        // cannot shrink it.
+        let total_stake = block_producers
+            .iter()
+            .map(|block_producer| block_producer.clone().into_validator_stake().stake())
+            .sum::<u128>();
+        let mut approved_stake = 0;
+        for (approval, block_producer) in block
+            .approvals_after_next
+            .iter()
+            .zip(block_producers.iter())
+        {
+            let block_producer = block_producer.clone().into_validator_stake();
+            let Some(signature) = approval else {
+                continue;
+            };
+            if !signature.verify(&message, block_producer.public_key()) {
+                return Err(invalid("approval signature is invalid"));
+            }
+            approved_stake += block_producer.stake();
+        }
+        if approved_stake * 3 <= total_stake * 2 {
+            return Err(invalid("approvals hold less than 2/3 of the stake"));
+        }
+
+        if let Some(next_bps) = &block.next_bps {
+            if block_producers_hash(next_bps) != block.inner_lite.next_bp_hash {
+                return Err(invalid("next block producers do not match next_bp_hash"));
+            }
+            self.epoch_block_producers
+                .insert(block.inner_lite.next_epoch_id, next_bps.clone());
+        }
+
+        self.head = lite;
+        Ok(())
+    }
+
+    /// Checks that `block` is part of the chain verified by the light client.
+    pub async fn verify_block(&mut self, block: &IndexedBlock) -> Result<(), VerificationError> {
+        // The block merkle root of the head covers every block before it, so the head has to be
+        // strictly ahead of the block being verified.
+        for _ in 0..HEAD_SYNC_ATTEMPTS {
+            if self.head_height() > block.height {
+                break;
+            }
+            self.sync().await?;
+            if self.head_height() <= block.height {
+                tokio::time::sleep(HEAD_SYNC_INTERVAL).await;
+            }
+        }
+        if self.head_height() <= block.height {
+            return Err(VerificationError::HeadBehind {
+                head: self.head_height(),
+                height: block.height,
+            });
+        }
+
+        let proof = self
+            .client
+            .call(
+                methods::EXPERIMENTAL_light_client_block_proof::RpcLightClientBlockProofRequest {
+                    block_hash: block.hash,
+                    light_client_head: self.head.hash(),
+                },
+            )
+            .await
+            .map_err(rpc_error)?;
+        if proof.block_header_lite.hash() != block.hash
+            || proof.block_header_lite.inner_lite.height != block.height
+        {
+            return Err(VerificationError::HeaderMismatch(block.hash));
+        }
+        if compute_root_from_path(&proof.block_proof, block.hash)
+            != self.head.inner_lite.block_merkle_root
+        {
+            return Err(VerificationError::BlockNotInChain(block.hash));
+        }
+        Ok(())
+    }
+
+    /// Checks the outcome of the `sign` call in `block` against its execution proof, and that it
+    /// matches what the block source reported. `block` has to be verified already.
+    ///
+    /// The proof does not cover the receipt itself, so the arguments and the predecessor are
+    /// checked against the log `sign` emits, which the proof does cover. The deposit is not part
+    /// of that log and is checked against the receipt served by the RPC node instead.
+    pub async fn verify_call(
+        &self,
+        block: &IndexedBlock,
+        call: &ContractCall,
+        receiver_id: &AccountId,
+    ) -> Result<(), VerificationError> {
+        let invalid = |reason| VerificationError::InvalidOutcome(call.receipt_id, reason);
+        let proof = self
+            .client
+            .call(
+                methods::light_client_proof::RpcLightClientExecutionProofRequest {
+                    id: TransactionOrReceiptId::Receipt {
+                        receipt_id: call.receipt_id,
+                        receiver_id: receiver_id.clone(),
+                    },
+                    light_client_head: self.head.hash(),
+                },
+            )
+            .await
+            .map_err(rpc_error)?;
+
+        let outcome_hash = CryptoHash::hash_borsh(proof.outcome_proof.to_hashes());
+        let shard_outcome_root = compute_root_from_path(&proof.outcome_proof.proof, outcome_hash);
+        let block_outcome_root = compute_root_from_path(
+            &proof.outcome_root_proof,
+            CryptoHash::hash_borsh(shard_outcome_root),
+        );
+        if block_outcome_root != proof.block_header_lite.inner_lite.outcome_root {
+            return Err(invalid("outcome is not part of its block"));
+        }
+        let included_in = &proof.block_header_lite;
+        let block_hash = included_in.hash();
+        if compute_root_from_path(&proof.block_proof, block_hash)
+            != self.head.inner_lite.block_merkle_root
+        {
+            return Err(VerificationError::BlockNotInChain(block_hash));
+        }
+        // Both blocks are on the verified chain, so their heights order them.
+        let included_height = included_in.inner_lite.height;
+        if included_in.prev_block_hash != block.hash
+            && (included_height <= block.height
+                || included_height > block.height + OUTCOME_INCLUSION_WINDOW)
+        {
+            return Err(invalid("outcome is not of the indexed block"));
+        }
+
+        let outcome = &proof.outcome_proof;
+        if outcome.id != call.receipt_id {
+            return Err(invalid("proof is for another receipt"));
+        }
+        if outcome.outcome.logs != call.logs {
+            return Err(invalid("logs do not match the indexed ones"));
+        }
+        let promise_receipt_id = match outcome.outcome.status {
+            ExecutionStatusView::SuccessReceiptId(receipt_id) => Some(receipt_id),
+            _ => None,
+        };
+        if promise_receipt_id != call.promise_receipt_id {
+            return Err(invalid("status does not match the indexed one"));
+        }
+
+        if call.method_name != "sign" {
+            return Err(invalid("method is not `sign`"));
+        }
+        let arguments: SignArguments =
+            serde_json::from_slice(&call.args).map_err(|_| invalid("arguments are malformed"))?;
+        let logged = super::sign_log(&call.predecessor_id, &arguments.request);
+        if call.logs.first() != Some(&logged) {
+            return Err(invalid("arguments do not match the logged ones"));
+        }
+        self.verify_deposit(call).await
+    }
+
+    async fn verify_deposit(&self, call: &ContractCall) -> Result<(), VerificationError> {
+        let invalid = |reason| VerificationError::InvalidOutcome(call.receipt_id, reason);
+        let receipt = self
+            .client
+            .call(methods::EXPERIMENTAL_receipt::RpcReceiptRequest {
+                receipt_reference: ReceiptReference {
+                    receipt_id: call.receipt_id,
+                },
+            })
+            .await
+            .map_err(rpc_error)?;
+        if receipt.predecessor_id != call.predecessor_id {
+            return Err(invalid("predecessor does not match the receipt"));
+        }
+        let ReceiptEnumView::Action { actions, .. } = receipt.receipt else {
+            return Err(invalid("receipt is not an action receipt"));
+        };
+        let deposit = actions.into_iter().find_map(|action| match action {
+            ActionView::FunctionCall {
+                method_name,
+                deposit,
+                ..
+            } if method_name == call.method_name => Some(deposit),
+            _ => None,
+        });
+        if deposit != Some(call.deposit) {
+            return Err(invalid("deposit does not match the receipt"));
+        }
+        Ok(())
+    }
+}
+
+/// Hash the `next_bp_hash` of a block commits the block producers of the next epoch with.
+fn block_producers_hash(block_producers: &[ValidatorStakeView]) -> CryptoHash {
+    let stakes = block_producers
+        .iter()
+        .cloned()
+        .map(ValidatorStakeView::into_validator_stake)
+        .collect::<Vec<ValidatorStake>>();
+    CryptoHash::hash_borsh(stakes)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use near_crypto::{KeyType, SecretKey};
+    use near_primitives::views::validator_stake_view::ValidatorStakeViewV1;
+    use near_primitives::views::BlockHeaderInnerLiteView;
+
+    fn inner_lite(height: BlockHeight, epoch_id: CryptoHash) -> BlockHeaderInnerLiteView {
+        BlockHeaderInnerLiteView {
+            height,
+            epoch_id,
+            next_epoch_id: CryptoHash::hash_bytes(b"next epoch"),
+            prev_state_root: CryptoHash::default(),
+            outcome_root: CryptoHash::default(),
+            timestamp: 0,
+            timestamp_nanosec: 0,
+            next_bp_hash: CryptoHash::default(),
+            block_merkle_root: CryptoHash::default(),
+        }
+    }
+
+    fn signed_block(
+        head: &LightClientBlockLiteView,
+        keys: &[Option<&SecretKey>],
+    ) -> LightClientBlockView {
+        let height = head.inner_lite.height + 1;
+        let inner_lite = inner_lite(height, head.inner_lite.epoch_id);
+        let lite = LightClientBlockLiteView {
+            prev_block_hash: head.hash(),
+            inner_rest_hash: CryptoHash::hash_bytes(b"inner rest"),
+            inner_lite: inner_lite.clone(),
+        };
+        let next_block_inner_hash = CryptoHash::hash_bytes(b"next block");
+        let next_block_hash = combine_hash(&next_block_inner_hash, &lite.hash());
+        let message =
+            Approval::get_data_for_sig(&ApprovalInner::Endorsement(next_block_hash), height + 2);
+        LightClientBlockView {
+            prev_block_hash: lite.prev_block_hash,
+            next_block_inner_hash,
+            inner_lite,
+            inner_rest_hash: lite.inner_rest_hash,
+            next_bps: None,
+            approvals_after_next: keys
+                .iter()
+                .map(|key| key.map(|key| Box::new(key.sign(&message))))
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn test_light_client_requires_two_thirds_of_stake() {
+        let keys = (0..3)
+            .map(|i| SecretKey::from_seed(KeyType::ED25519, &format!("bp{i}")))
+            .collect::<Vec<_>>();
+        let block_producers = keys
+            .iter()
+            .enumerate()
+            .map(|(i, key)| {
+                ValidatorStakeView::V1(ValidatorStakeViewV1 {
+                    account_id: format!("bp{i}.near").parse().unwrap(),
+                    public_key: key.public_key(),
+                    stake: 100,
+                })
+            })
+            .collect::<Vec<_>>();
+        let head = LightClientBlockLiteView {
+            prev_block_hash: CryptoHash::default(),
+            inner_rest_hash: CryptoHash::default(),
+            inner_lite: inner_lite(10, CryptoHash::hash_bytes(b"epoch")),
+        };
+        let mut light_client = LightClient::new(
+            JsonRpcClient::connect("http://localhost:3030"),
+            head.clone(),
+            block_producers,
+        );
+
+        // Exactly 2/3 of the stake is not enough.
+        let block = signed_block(&head, &[Some(&keys[0]), Some(&keys[1]), None]);
+        assert!(light_client.validate_and_update_head(block).is_err());
+
+        // Producers missing from a truncated list of approvals still count towards the total.
+        let block = signed_block(&head, &[Some(&keys[0])]);
+        let err = light_client.validate_and_update_head(block).unwrap_err();
+        // A bad reply only holds the indexer back until the head syncs.
+        assert!(err.is_transient());
+        let block = signed_block(&head, &[Some(&keys[0]), Some(&keys[1])]);
+        assert!(light_client.validate_and_update_head(block).is_err());
+
+        // A signature from a key other than the block producer's is rejected.
+        let block = signed_block(&head, &[Some(&keys[0]), Some(&keys[2]), Some(&keys[2])]);
+        assert!(light_client.validate_and_update_head(block).is_err());
+
+        let block = signed_block(&head, &keys.iter().map(Some).collect::<Vec<_>>());
+        light_client.validate_and_update_head(block).unwrap();
+        assert_eq!(light_client.head_height(), 11);
+    }
+}
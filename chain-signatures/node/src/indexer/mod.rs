@@ -0,0 +1,505 @@
+pub mod light_client;
+pub mod source;
+
+use self::light_client::{LightClient, VerificationError};
+use self::source::IndexedBlock;
+use crate::kdf;
+use crate::protocol::{SignQueue, SignRequest};
+use crate::storage::sign_request_storage::LockSignRequestNodeStorageBox;
+use crate::types::{BlockHeightStorage, LatestBlockHeight};
+use crypto_shared::derive_epsilon;
+use near_account_id::AccountId;
+use near_primitives::hash::CryptoHash;
+
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::RwLock;
+
+/// How long to wait before verifying a block again after a transient failure.
+const VERIFICATION_RETRY_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Where the indexer gets its blocks from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum SourceKind {
+    /// NEAR Lake over S3.
+    Lake,
+    /// Final blocks polled from a NEAR RPC endpoint.
+    Rpc,
+}
+
+impl SourceKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            SourceKind::Lake => "lake",
+            SourceKind::Rpc => "rpc",
+        }
+    }
+}
+
+/// Configures indexer.
+#[derive(Debug, Clone, clap::Parser)]
+#[group(id = "indexer_options")]
+pub struct Options {
+    /// Source of the blocks to index.
+    #[clap(
+        long = "indexer-source",
+        env("MPC_RECOVERY_INDEXER_SOURCE"),
+        value_enum,
+        default_value = "lake"
+    )]
+    pub source: SourceKind,
+
+    /// NEAR RPC endpoint polled by the `rpc` source and the light client. Defaults to the node's
+    /// `--near-rpc`.
+    #[clap(long = "indexer-rpc-url", env("MPC_RECOVERY_INDEXER_RPC_URL"))]
+    pub rpc_url: Option<String>,
+
+    /// AWS S3 bucket name for NEAR Lake Indexer
+    #[clap(
+        long,
+        env("MPC_RECOVERY_INDEXER_S3_BUCKET"),
+        default_value = "near-lake-data-testnet"
+    )]
+    pub s3_bucket: String,
+
+    /// AWS S3 region name for NEAR Lake Indexer
+    #[clap(
+        long,
+        env("MPC_RECOVERY_INDEXER_S3_REGION"),
+        default_value = "eu-central-1"
+    )]
+    pub s3_region: String,
+
+    /// AWS S3 URL for NEAR Lake Indexer (can be used to point to LocalStack)
+    #[clap(long, env("MPC_RECOVERY_INDEXER_S3_URL"))]
+    pub s3_url: Option<String>,
+
+    /// Hash of a trusted block to bootstrap the light client from. When set, every indexed block
+    /// and `sign` call is verified against the light client, which talks to `--indexer-rpc-url`.
+    #[clap(long, env("MPC_RECOVERY_INDEXER_LIGHT_CLIENT_CHECKPOINT"))]
+    pub light_client_checkpoint: Option<CryptoHash>,
+
+    /// The block height to start indexing from.
+    // Defaults to the latest block on 2023-11-14 07:40:22 AM UTC
+    #[clap(
+        long,
+        env("MPC_RECOVERY_INDEXER_START_BLOCK_HEIGHT"),
+        default_value = "145964826"
+    )]
+    pub start_block_height: u64,
+}
+
+impl Options {
+    pub fn into_str_args(self) -> Vec<String> {
+        let mut opts = vec![
+            "--indexer-source".to_string(),
+            self.source.as_str().to_string(),
+            "--s3-bucket".to_string(),
+            self.s3_bucket,
+            "--s3-region".to_string(),
+            self.s3_region,
+            "--start-block-height".to_string(),
+            self.start_block_height.to_string(),
+        ];
+
+        if let Some(s3_url) = self.s3_url {
+            opts.extend(vec!["--s3-url".to_string(), s3_url]);
+        }
+        if let Some(rpc_url) = self.rpc_url {
+            opts.extend(vec!["--indexer-rpc-url".to_string(), rpc_url]);
+        }
+        if let Some(checkpoint) = self.light_client_checkpoint {
+            opts.extend(vec![
+                "--light-client-checkpoint".to_string(),
+                checkpoint.to_string(),
+            ]);
+        }
+
+        opts
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct SignArguments {
+    pub request: ContractSignRequest,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct ContractSignRequest {
+    pub payload: [u8; 32],
+    pub path: String,
+    pub key_version: u32,
+    /// Non-hardened BIP32 child path applied on top of the key derived from `path`.
+    #[serde(default)]
+    pub child_path: Vec<u32>,
+}
+
+/// The log the contract emits first when `predecessor_id` calls `sign` with `request`.
+pub fn sign_log(predecessor_id: &AccountId, request: &ContractSignRequest) -> String {
+    format!(
+        "sign: predecessor={}, payload={:?}, path={:?}, key_version={}, child_path={:?}",
+        predecessor_id, request.payload, request.path, request.key_version, request.child_path
+    )
+}
+
+struct Context {
+    mpc_contract_id: AccountId,
+    node_account_id: AccountId,
+    block_height_storage: BlockHeightStorage,
+    queue: Arc<RwLock<SignQueue>>,
+    sign_request_storage: LockSignRequestNodeStorageBox,
+    latest_block_height: Arc<RwLock<LatestBlockHeight>>,
+}
+
+async fn handle_block(block: IndexedBlock, ctx: &Context) -> anyhow::Result<()> {
+    for call in block.calls {
+        let Some(receipt_id) = call.promise_receipt_id else {
+            continue;
+        };
+        if call.method_name == "sign" {
+            if let Ok(arguments) = serde_json::from_slice::<'_, SignArguments>(&call.args) {
+                if call.logs.is_empty() {
+                    tracing::warn!("`sign` did not produce entropy");
+                    continue;
+                }
+                let Some(Ok(entropy)) = call
+                    .logs
+                    .get(1)
+                    .map(|log| serde_json::from_str::<'_, [u8; 32]>(log))
+                else {
+                    tracing::warn!(
+                        "`sign` did not produce entropy correctly: {:?}",
+                        call.logs[0]
+                    );
+                    continue;
+                };
+                let epsilon = derive_epsilon(&call.predecessor_id, &arguments.request.path);
+                let delta = kdf::derive_delta(receipt_id, entropy);
+                tracing::info!(
+                    receipt_id = %receipt_id,
+                    caller_id = call.predecessor_id.to_string(),
+                    our_account = ctx.node_account_id.to_string(),
+                    payload = hex::encode(arguments.request.payload),
+                    key_version = arguments.request.key_version,
+                    deposit = call.deposit,
+                    entropy = hex::encode(entropy),
+                    "indexed new `sign` function call"
+                );
+                let request = SignRequest {
+                    receipt_id,
+                    request: arguments.request,
+                    epsilon,
+                    delta,
+                    entropy,
+                    time_added: Instant::now(),
+                    block_height: block.height,
+                    deposit: call.deposit,
+                };
+                // Persisted before the block is marked as indexed, so that a restart in between
+                // does not lose the request.
+                ctx.sign_request_storage
+                    .write()
+                    .await
+                    .insert(&request)
+                    .await?;
+                let mut queue = ctx.queue.write().await;
+                queue.add(request);
+                crate::metrics::NUM_SIGN_REQUESTS
+                    .with_label_values(&[ctx.node_account_id.as_str()])
+                    .inc();
+                drop(queue);
+            }
+        }
+    }
+
+    ctx.queue.write().await.set_block_height(block.height);
+    ctx.latest_block_height
+        .write()
+        .await
+        .set(block.height)
+        .store(&ctx.block_height_storage)
+        .await?;
+
+    crate::metrics::LATEST_BLOCK_HEIGHT
+        .with_label_values(&[ctx.node_account_id.as_str()])
+        .set(block.height as i64);
+
+    if block.height % 1000 == 0 {
+        tracing::info!(block_height = block.height, "indexed block");
+    }
+    Ok(())
+}
+
+pub fn run(
+    options: Options,
+    near_rpc: String,
+    mpc_contract_id: AccountId,
+    node_account_id: AccountId,
+    queue: Arc<RwLock<SignQueue>>,
+    sign_request_storage: LockSignRequestNodeStorageBox,
+    block_height_storage: BlockHeightStorage,
+) -> anyhow::Result<()> {
+    tracing::info!(
+        source = options.source.as_str(),
+        s3_bucket = options.s3_bucket,
+        s3_region = options.s3_region,
+        s3_url = options.s3_url,
+        rpc_url = options.rpc_url,
+        light_client_checkpoint = ?options.light_client_checkpoint,
+        start_block_height = options.start_block_height,
+        %mpc_contract_id,
+        "starting indexer"
+    );
+
+    let rt = tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+
+    rt.block_on(async {
+        let latest = match LatestBlockHeight::fetch(&block_height_storage, &node_account_id).await {
+            Ok(latest) => latest,
+            Err(err) => {
+                tracing::error!(%err, "failed to fetch latest block height; using start_block_height={} instead", options.start_block_height);
+                LatestBlockHeight {
+                    account_id: node_account_id.clone(),
+                    block_height: options.start_block_height,
+                }
+            }
+        };
+        let light_client = match options.light_client_checkpoint {
+            Some(checkpoint) => {
+                let rpc_url = options.rpc_url.as_deref().unwrap_or(&near_rpc);
+                Some(LightClient::bootstrap(rpc_url, checkpoint).await?)
+            }
+            None => None,
+        };
+        let source = source::init(
+            &options,
+            &near_rpc,
+            mpc_contract_id.clone(),
+            latest.block_height,
+        )
+        .await?;
+        run_with_source(
+            source,
+            light_client,
+            mpc_contract_id,
+            node_account_id,
+            queue,
+            sign_request_storage,
+            block_height_storage,
+            latest,
+        )
+        .await
+    })
+}
+
+/// Drops the `sign` calls of `block` that fail verification, or all of them if the header or
+/// inclusion proof of the block itself does not check out. The block is still handled so that
+/// the indexer moves past it, unless verification failed for a transient reason, such as the
+/// light client head failing to sync, which is returned for the block to be verified again.
+async fn verify_block(
+    light_client: &mut LightClient,
+    block: &mut IndexedBlock,
+    ctx: &Context,
+) -> Result<(), VerificationError> {
+    let account_id = ctx.node_account_id.as_str();
+    if !block.calls.iter().any(|call| call.method_name == "sign") {
+        return Ok(());
+    }
+    if let Err(err) = light_client.verify_block(block).await {
+        crate::metrics::NUM_LIGHT_CLIENT_VERIFICATION_FAILURES
+            .with_label_values(&[account_id, err.kind()])
+            .inc();
+        if err.is_transient() {
+            return Err(err);
+        }
+        tracing::warn!(block_height = block.height, %err, "indexed block failed verification; rejecting its sign requests");
+        block.calls.clear();
+        return Ok(());
+    }
+    crate::metrics::LIGHT_CLIENT_HEAD_HEIGHT
+        .with_label_values(&[account_id])
+        .set(light_client.head_height() as i64);
+
+    let mut verified = Vec::with_capacity(block.calls.len());
+    for call in &block.calls {
+        if call.method_name == "sign" {
+            if let Err(err) = light_client
+                .verify_call(block, call, &ctx.mpc_contract_id)
+                .await
+            {
+                crate::metrics::NUM_LIGHT_CLIENT_VERIFICATION_FAILURES
+                    .with_label_values(&[account_id, err.kind()])
+                    .inc();
+                if err.is_transient() {
+                    return Err(err);
+                }
+                tracing::warn!(receipt_id = %call.receipt_id, %err, "sign call failed verification; rejecting it");
+                continue;
+            }
+        }
+        verified.push(call.clone());
+    }
+    block.calls = verified;
+    Ok(())
+}
+
+/// Feeds every block of `source` through the indexer until the source is exhausted.
+/// `latest_block_height` is the height `source` starts from. Blocks are verified against
+/// `light_client` when one is given.
+pub async fn run_with_source(
+    mut source: source::IndexerSourceBox,
+    mut light_client: Option<LightClient>,
+    mpc_contract_id: AccountId,
+    node_account_id: AccountId,
+    queue: Arc<RwLock<SignQueue>>,
+    sign_request_storage: LockSignRequestNodeStorageBox,
+    block_height_storage: BlockHeightStorage,
+    latest_block_height: LatestBlockHeight,
+) -> anyhow::Result<()> {
+    let ctx = Context {
+        mpc_contract_id,
+        node_account_id,
+        block_height_storage,
+        queue,
+        sign_request_storage,
+        latest_block_height: Arc::new(RwLock::new(latest_block_height)),
+    };
+    while let Some(mut block) = source.next_block().await? {
+        if let Some(light_client) = &mut light_client {
+            while let Err(err) = verify_block(light_client, &mut block, &ctx).await {
+                tracing::warn!(block_height = block.height, %err, "failed to verify indexed block; retrying");
+                tokio::time::sleep(VERIFICATION_RETRY_INTERVAL).await;
+            }
+        }
+        handle_block(block, &ctx).await?;
+    }
+    tracing::info!(mpc_contract_id = %ctx.mpc_contract_id, "indexer source exhausted");
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::source::{ContractCall, IndexedBlock, MemoryIndexerSource};
+    use super::{run_with_source, sign_log, ContractSignRequest, SignArguments};
+    use crate::protocol::SignQueue;
+    use crate::storage::local_db::LocalDb;
+    use crate::storage::sign_request_storage::{self, SignRequestNodeStorage};
+    use crate::types::{BlockHeightStorage, LatestBlockHeight};
+
+    use near_account_id::AccountId;
+    use near_primitives::hash::CryptoHash;
+    use std::sync::Arc;
+    use tokio::sync::RwLock;
+
+    fn sign_call(
+        predecessor_id: &AccountId,
+        payload: u8,
+        logs: Option<Vec<String>>,
+    ) -> ContractCall {
+        let request = ContractSignRequest {
+            payload: [payload; 32],
+            path: "test".to_string(),
+            key_version: 0,
+            child_path: Vec::new(),
+        };
+        let logs = logs.unwrap_or_else(|| {
+            vec![
+                sign_log(predecessor_id, &request),
+                serde_json::to_string(&[payload; 32]).unwrap(),
+            ]
+        });
+        ContractCall {
+            receipt_id: CryptoHash::hash_bytes(&[payload]),
+            predecessor_id: predecessor_id.clone(),
+            method_name: "sign".to_string(),
+            args: serde_json::to_vec(&SignArguments { request }).unwrap(),
+            deposit: 1,
+            logs,
+            promise_receipt_id: Some(CryptoHash::hash_bytes(&[payload, payload])),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_indexer_runs_through_memory_source() {
+        let path = std::env::temp_dir().join(format!(
+            "multichain-indexer-test-{}.sqlite",
+            rand::random::<u64>()
+        ));
+        let db = LocalDb::open(&path).unwrap();
+        let node_account_id: AccountId = "node.testnet".parse().unwrap();
+        let predecessor_id: AccountId = "caller.testnet".parse().unwrap();
+        let queue = Arc::new(RwLock::new(SignQueue::new()));
+        let sign_request_storage = Arc::new(RwLock::new(sign_request_storage::init(
+            None,
+            Some(&db),
+            &node_account_id,
+        )));
+        let block_height_storage = BlockHeightStorage::init(None, Some(&db));
+
+        let (source, blocks) = MemoryIndexerSource::new();
+        let mut no_promise = sign_call(&predecessor_id, 2, None);
+        no_promise.promise_receipt_id = None;
+        let mut other_method = sign_call(&predecessor_id, 3, None);
+        other_method.method_name = "vote_join".to_string();
+        blocks
+            .send(IndexedBlock {
+                height: 10,
+                hash: CryptoHash::hash_bytes(b"10"),
+                calls: vec![
+                    sign_call(&predecessor_id, 1, None),
+                    no_promise,
+                    other_method,
+                    sign_call(&predecessor_id, 4, Some(vec!["no entropy".to_string()])),
+                ],
+            })
+            .unwrap();
+        blocks
+            .send(IndexedBlock {
+                height: 12,
+                hash: CryptoHash::hash_bytes(b"12"),
+                calls: Vec::new(),
+            })
+            .unwrap();
+        drop(blocks);
+
+        run_with_source(
+            Box::new(source),
+            None,
+            "mpc.testnet".parse().unwrap(),
+            node_account_id.clone(),
+            queue.clone(),
+            sign_request_storage.clone(),
+            block_height_storage.clone(),
+            LatestBlockHeight {
+                account_id: node_account_id.clone(),
+                block_height: 9,
+            },
+        )
+        .await
+        .unwrap();
+
+        let queue = queue.read().await;
+        assert_eq!(queue.len(), 1);
+        assert_eq!(queue.block_height(), 12);
+        let stored = sign_request_storage.read().await.load().await.unwrap();
+        assert_eq!(stored.len(), 1);
+        assert_eq!(stored[0].request.payload, [1; 32]);
+        assert_eq!(stored[0].entropy, [1; 32]);
+        assert_eq!(stored[0].block_height, 10);
+        assert_eq!(
+            stored[0].receipt_id,
+            CryptoHash::hash_bytes(&[1, 1]),
+            "requests are keyed by the receipt of the returned promise"
+        );
+        let latest = LatestBlockHeight::fetch(&block_height_storage, &node_account_id)
+            .await
+            .unwrap();
+        assert_eq!(latest.block_height, 12);
+
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,440 @@
+use super::{Options, SourceKind};
+
+use anyhow::Context as _;
+use async_trait::async_trait;
+use futures_util::future::try_join_all;
+use near_account_id::AccountId;
+use near_jsonrpc_client::{methods, JsonRpcClient};
+use near_jsonrpc_primitives::types::blocks::RpcBlockError;
+use near_jsonrpc_primitives::types::chunks::ChunkReference;
+use near_lake_framework::{LakeBuilder, LakeContext};
+use near_lake_primitives::actions::ActionMetaDataExt;
+use near_lake_primitives::receipts::ExecutionStatus;
+use near_primitives::hash::CryptoHash;
+use near_primitives::types::{BlockId, BlockReference, Finality, TransactionOrReceiptId};
+use near_primitives::views::{
+    ActionView, BlockView, ExecutionOutcomeView, ExecutionStatusView, ReceiptEnumView,
+};
+use std::time::Duration;
+use tokio::sync::mpsc;
+
+/// How long the RPC source waits before asking for a new final block again.
+const RPC_POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Amount of blocks the lake source buffers ahead of the indexer.
+const LAKE_BUFFER_SIZE: usize = 100;
+
+/// A block reduced to what the indexer acts upon, independently of where it came from.
+#[derive(Debug, Clone)]
+pub struct IndexedBlock {
+    pub height: u64,
+    pub hash: CryptoHash,
+    /// Function calls executed on the MPC contract in this block.
+    pub calls: Vec<ContractCall>,
+}
+
+#[derive(Debug, Clone)]
+pub struct ContractCall {
+    pub receipt_id: CryptoHash,
+    pub predecessor_id: AccountId,
+    pub method_name: String,
+    pub args: Vec<u8>,
+    /// Deposit attached to the call, in yoctoNEAR.
+    pub deposit: u128,
+    pub logs: Vec<String>,
+    /// Receipt of the promise the call returned, if it succeeded by returning one.
+    pub promise_receipt_id: Option<CryptoHash>,
+}
+
+#[async_trait]
+pub trait IndexerSource {
+    /// Waits for the next block to be indexed. Returns `None` once the source is exhausted.
+    async fn next_block(&mut self) -> anyhow::Result<Option<IndexedBlock>>;
+}
+
+pub type IndexerSourceBox = Box<dyn IndexerSource + Send>;
+
+#[derive(LakeContext)]
+struct LakeSourceContext {
+    mpc_contract_id: AccountId,
+    blocks: mpsc::Sender<IndexedBlock>,
+}
+
+async fn forward_block(
+    mut block: near_lake_primitives::block::Block,
+    ctx: &LakeSourceContext,
+) -> anyhow::Result<()> {
+    let mut calls = Vec::new();
+    for action in block.actions().cloned().collect::<Vec<_>>() {
+        if action.receiver_id() != ctx.mpc_contract_id {
+            continue;
+        }
+        let Some(function_call) = action.as_function_call() else {
+            continue;
+        };
+        let receipt = block.receipt_by_id(&action.receipt_id()).with_context(|| {
+            format!(
+                "indexer unable to find block for receipt_id={}",
+                action.receipt_id()
+            )
+        })?;
+        let promise_receipt_id = match receipt.status() {
+            ExecutionStatus::SuccessReceiptId(receipt_id) => Some(receipt_id),
+            _ => None,
+        };
+        calls.push(ContractCall {
+            receipt_id: action.receipt_id(),
+            predecessor_id: action.predecessor_id(),
+            method_name: function_call.method_name().to_string(),
+            args: function_call.args().to_vec(),
+            deposit: function_call.deposit(),
+            logs: receipt.logs().to_vec(),
+            promise_receipt_id,
+        });
+    }
+
+    ctx.blocks
+        .send(IndexedBlock {
+            height: block.block_height(),
+            hash: block.block_hash(),
+            calls,
+        })
+        .await
+        .context("indexer stopped consuming blocks")
+}
+
+/// Streams blocks from NEAR Lake over S3. The lake framework drives its own runtime, so it is
+/// run on a dedicated thread that forwards the blocks.
+pub struct LakeIndexerSource {
+    blocks: mpsc::Receiver<IndexedBlock>,
+    handle: Option<std::thread::JoinHandle<anyhow::Result<()>>>,
+}
+
+impl LakeIndexerSource {
+    pub async fn new(
+        options: &Options,
+        mpc_contract_id: AccountId,
+        start_block_height: u64,
+    ) -> anyhow::Result<Self> {
+        let mut lake_builder = LakeBuilder::default()
+            .s3_bucket_name(&options.s3_bucket)
+            .s3_region_name(&options.s3_region)
+            .start_block_height(start_block_height);
+
+        if let Some(s3_url) = &options.s3_url {
+            let aws_config = aws_config::from_env().load().await;
+            let s3_config = aws_sdk_s3::config::Builder::from(&aws_config)
+                .endpoint_url(s3_url)
+                .build();
+            lake_builder = lake_builder.s3_config(s3_config);
+        }
+        let lake = lake_builder
+            .build()
+            .context("could not build lake indexer")?;
+
+        let (sender, blocks) = mpsc::channel(LAKE_BUFFER_SIZE);
+        let handle = std::thread::spawn(move || {
+            let context = LakeSourceContext {
+                mpc_contract_id,
+                blocks: sender,
+            };
+            lake.run_with_context(forward_block, &context)?;
+            Ok(())
+        });
+
+        Ok(Self {
+            blocks,
+            handle: Some(handle),
+        })
+    }
+}
+
+#[async_trait]
+impl IndexerSource for LakeIndexerSource {
+    async fn next_block(&mut self) -> anyhow::Result<Option<IndexedBlock>> {
+        if let Some(block) = self.blocks.recv().await {
+            return Ok(Some(block));
+        }
+        match self.handle.take().map(|handle| handle.join()) {
+            Some(Ok(Err(err))) => Err(err),
+            Some(Err(_)) => Err(anyhow::anyhow!("lake indexer thread panicked")),
+            _ => Ok(None),
+        }
+    }
+}
+
+/// Polls a NEAR RPC endpoint for final blocks and the chunks within them.
+///
+/// Calls are picked up from the receipts carried by chunks, and from the transactions the
+/// contract signed to itself, whose receipts are local to the shard and never carried by a chunk.
+pub struct RpcIndexerSource {
+    client: JsonRpcClient,
+    mpc_contract_id: AccountId,
+    next_height: u64,
+}
+
+/// A receipt to the contract, with the function calls it carries.
+struct ContractReceipt {
+    receipt_id: CryptoHash,
+    predecessor_id: AccountId,
+    actions: Vec<ActionView>,
+}
+
+impl RpcIndexerSource {
+    pub fn new(rpc_url: &str, mpc_contract_id: AccountId, start_block_height: u64) -> Self {
+        Self {
+            client: JsonRpcClient::connect(rpc_url),
+            mpc_contract_id,
+            next_height: start_block_height,
+        }
+    }
+
+    async fn block(&self, block_reference: BlockReference) -> anyhow::Result<Option<BlockView>> {
+        match self
+            .client
+            .call(methods::block::RpcBlockRequest { block_reference })
+            .await
+        {
+            Ok(block) => Ok(Some(block)),
+            // Heights without a block are skipped by the chain.
+            Err(err)
+                if matches!(
+                    err.handler_error(),
+                    Some(RpcBlockError::UnknownBlock { .. })
+                ) =>
+            {
+                Ok(None)
+            }
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    /// Outcome of a transaction or receipt, as proven against `final_head`. The outcome is only
+    /// known once the receipt got executed, which may happen in a later block than the one
+    /// carrying it. Until then the request fails and the block is retried.
+    async fn outcome(
+        &self,
+        id: TransactionOrReceiptId,
+        final_head: CryptoHash,
+    ) -> anyhow::Result<ExecutionOutcomeView> {
+        let proof = self
+            .client
+            .call(
+                methods::light_client_proof::RpcLightClientExecutionProofRequest {
+                    id: id.clone(),
+                    light_client_head: final_head,
+                },
+            )
+            .await
+            .with_context(|| format!("outcome of {id:?} is unavailable"))?;
+        Ok(proof.outcome_proof.outcome)
+    }
+
+    /// Receipts to the contract in the chunks new to `block`.
+    async fn contract_receipts(
+        &self,
+        block: &BlockView,
+        final_head: CryptoHash,
+    ) -> anyhow::Result<Vec<ContractReceipt>> {
+        // Chunks missing from this block were already indexed along with an earlier block.
+        let chunks = try_join_all(
+            block
+                .chunks
+                .iter()
+                .filter(|chunk_header| chunk_header.height_included == block.header.height)
+                .map(|chunk_header| {
+                    self.client.call(methods::chunk::RpcChunkRequest {
+                        chunk_reference: ChunkReference::ChunkHash {
+                            chunk_id: chunk_header.chunk_hash,
+                        },
+                    })
+                }),
+        )
+        .await?;
+
+        let mut receipts = Vec::new();
+        let mut local_transactions = Vec::new();
+        for chunk in chunks {
+            for receipt in chunk.receipts {
+                if receipt.receiver_id != self.mpc_contract_id {
+                    continue;
+                }
+                if let ReceiptEnumView::Action { actions, .. } = receipt.receipt {
+                    receipts.push(ContractReceipt {
+                        receipt_id: receipt.receipt_id,
+                        predecessor_id: receipt.predecessor_id,
+                        actions,
+                    });
+                }
+            }
+            local_transactions.extend(chunk.transactions.into_iter().filter(|transaction| {
+                transaction.signer_id == self.mpc_contract_id
+                    && transaction.receiver_id == self.mpc_contract_id
+            }));
+        }
+
+        // The receipt of a transaction is only named by the outcome of the transaction.
+        let outcomes = try_join_all(local_transactions.iter().map(|transaction| {
+            self.outcome(
+                TransactionOrReceiptId::Transaction {
+                    transaction_hash: transaction.hash,
+                    sender_id: transaction.signer_id.clone(),
+                },
+                final_head,
+            )
+        }))
+        .await?;
+        for (transaction, outcome) in local_transactions.into_iter().zip(outcomes) {
+            let ExecutionStatusView::SuccessReceiptId(receipt_id) = outcome.status else {
+                continue;
+            };
+            receipts.push(ContractReceipt {
+                receipt_id,
+                predecessor_id: transaction.signer_id,
+                actions: transaction.actions,
+            });
+        }
+        Ok(receipts)
+    }
+
+    /// Function calls on the contract in `block`. The outcomes of all of its receipts are
+    /// requested at once, one per receipt.
+    async fn contract_calls(
+        &self,
+        block: &BlockView,
+        final_head: CryptoHash,
+    ) -> anyhow::Result<Vec<ContractCall>> {
+        let receipts = self
+            .contract_receipts(block, final_head)
+            .await?
+            .into_iter()
+            .filter(|receipt| {
+                receipt
+                    .actions
+                    .iter()
+                    .any(|action| matches!(action, ActionView::FunctionCall { .. }))
+            })
+            .collect::<Vec<_>>();
+        let outcomes = try_join_all(receipts.iter().map(|receipt| {
+            self.outcome(
+                TransactionOrReceiptId::Receipt {
+                    receipt_id: receipt.receipt_id,
+                    receiver_id: self.mpc_contract_id.clone(),
+                },
+                final_head,
+            )
+        }))
+        .await?;
+
+        let mut calls = Vec::new();
+        for (receipt, outcome) in receipts.into_iter().zip(outcomes) {
+            let promise_receipt_id = match outcome.status {
+                ExecutionStatusView::SuccessReceiptId(receipt_id) => Some(receipt_id),
+                _ => None,
+            };
+            for action in receipt.actions {
+                let ActionView::FunctionCall {
+                    method_name,
+                    args,
+                    deposit,
+                    ..
+                } = action
+                else {
+                    continue;
+                };
+                calls.push(ContractCall {
+                    receipt_id: receipt.receipt_id,
+                    predecessor_id: receipt.predecessor_id.clone(),
+                    method_name,
+                    args: args.to_vec(),
+                    deposit,
+                    logs: outcome.logs.clone(),
+                    promise_receipt_id,
+                });
+            }
+        }
+        Ok(calls)
+    }
+}
+
+#[async_trait]
+impl IndexerSource for RpcIndexerSource {
+    async fn next_block(&mut self) -> anyhow::Result<Option<IndexedBlock>> {
+        loop {
+            let final_head = self
+                .block(BlockReference::Finality(Finality::Final))
+                .await?
+                .context("final block is unavailable")?;
+            if final_head.header.height < self.next_height {
+                tokio::time::sleep(RPC_POLL_INTERVAL).await;
+                continue;
+            }
+
+            let Some(block) = self
+                .block(BlockReference::BlockId(BlockId::Height(self.next_height)))
+                .await?
+            else {
+                self.next_height += 1;
+                continue;
+            };
+            match self.contract_calls(&block, final_head.header.hash).await {
+                Ok(calls) => {
+                    self.next_height = block.header.height + 1;
+                    return Ok(Some(IndexedBlock {
+                        height: block.header.height,
+                        hash: block.header.hash,
+                        calls,
+                    }));
+                }
+                Err(err) => {
+                    tracing::warn!(
+                        block_height = block.header.height,
+                        ?err,
+                        "failed to fetch contract calls from rpc; retrying"
+                    );
+                    tokio::time::sleep(RPC_POLL_INTERVAL).await;
+                }
+            }
+        }
+    }
+}
+
+/// Source fed by hand, used to drive the indexer in tests.
+pub struct MemoryIndexerSource {
+    blocks: mpsc::UnboundedReceiver<IndexedBlock>,
+}
+
+impl MemoryIndexerSource {
+    /// Creates the source along with the handle used to push blocks into it. The source is
+    /// exhausted once the handle is dropped and all pushed blocks were consumed.
+    pub fn new() -> (Self, mpsc::UnboundedSender<IndexedBlock>) {
+        let (sender, blocks) = mpsc::unbounded_channel();
+        (Self { blocks }, sender)
+    }
+}
+
+#[async_trait]
+impl IndexerSource for MemoryIndexerSource {
+    async fn next_block(&mut self) -> anyhow::Result<Option<IndexedBlock>> {
+        Ok(self.blocks.recv().await)
+    }
+}
+
+pub async fn init(
+    options: &Options,
+    near_rpc: &str,
+    mpc_contract_id: AccountId,
+    start_block_height: u64,
+) -> anyhow::Result<IndexerSourceBox> {
+    Ok(match options.source {
+        SourceKind::Lake => {
+            Box::new(LakeIndexerSource::new(options, mpc_contract_id, start_block_height).await?)
+                as IndexerSourceBox
+        }
+        SourceKind::Rpc => Box::new(RpcIndexerSource::new(
+            options.rpc_url.as_deref().unwrap_or(near_rpc),
+            mpc_contract_id,
+            start_block_height,
+        )) as IndexerSourceBox,
+    })
+}
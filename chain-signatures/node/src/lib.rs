@@ -9,6 +9,8 @@ pub mod protocol;
 pub mod rpc_client;
 pub mod storage;
 pub mod test_utils;
+pub mod tls;
+pub mod transport;
 pub mod types;
 pub mod util;
 pub mod web;
@@ -1,13 +1,188 @@
+use std::collections::{BTreeSet, HashMap, VecDeque};
+use std::sync::Arc;
 use std::time::{Duration, Instant};
 
+use cait_sith::protocol::Participant;
+use futures_util::future::join_all;
+use serde::{Deserialize, Serialize};
 use tokio::sync::RwLock;
 
-use crate::protocol::contract::primitives::Participants;
+use crate::protocol::contract::primitives::{ParticipantInfo, Participants};
 use crate::protocol::ProtocolState;
+use crate::tls::TrustedPeers;
 use crate::web::StateView;
 
+/// How long the result of pinging the participants is reused for, which is also the interval
+/// heartbeats are expected at.
 const DEFAULT_TIMEOUT: Duration = Duration::from_secs(1);
 
+/// How long to wait for a participant to answer a heartbeat.
+const HEARTBEAT_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Round trip time above which a participant is considered slow.
+const SLOW_PEER_RTT: Duration = Duration::from_millis(500);
+
+/// Amount of recent failures at which a participant is considered slow. Applies to heartbeats
+/// and protocols separately.
+const SLOW_PEER_FAILURES: u32 = 3;
+
+/// Weight of the latest sample in the round trip time moving average.
+const RTT_SMOOTHING: f64 = 0.2;
+
+/// Suspicion level above which a participant is considered down.
+const PHI_THRESHOLD: f64 = 8.0;
+
+/// Suspicion levels are capped at this, well beyond the threshold.
+const MAX_PHI: f64 = 100.0;
+
+/// Successful heartbeats in a row after which a participant that was down is considered back up.
+const RECOVERY_HEARTBEATS: u32 = 2;
+
+/// Intervals between heartbeats the suspicion level is computed from.
+const HEARTBEAT_HISTORY: usize = 100;
+
+/// Lower bound of the deviation of the heartbeat intervals, so that a run of very regular
+/// heartbeats does not make a participant suspected on the first late one.
+const MIN_HEARTBEAT_STD_DEV: Duration = Duration::from_millis(500);
+
+/// Participants this node considers slow, shared with the web server for operators to inspect.
+pub type SlowView = Arc<RwLock<BTreeSet<Participant>>>;
+
+/// Liveness of every participant as seen by this node, shared with the web server.
+pub type LivenessView = Arc<RwLock<Vec<PeerLiveness>>>;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct PeerLiveness {
+    pub participant: Participant,
+    pub alive: bool,
+    /// Phi-accrual suspicion level, unknown until the participant answered a heartbeat.
+    pub suspicion: Option<f64>,
+    /// Moving average of the heartbeat round trip time in milliseconds.
+    pub rtt_ms: Option<u64>,
+    /// Recent protocols the participant failed to take part in.
+    #[serde(default)]
+    pub protocol_failures: u32,
+}
+
+/// Responsiveness of a participant, measured by sending heartbeats to its `/state` endpoint.
+/// Whether it is alive is decided by a phi-accrual failure detector over the intervals between
+/// its heartbeats, with hysteresis so that a participant flapping around the threshold does not
+/// keep leaving and rejoining the active participants.
+#[derive(Debug, Clone, Default)]
+pub struct PeerStats {
+    /// Exponential moving average of the round trip time.
+    pub rtt: Option<Duration>,
+    /// Failed pings, minus one for every successful ping since.
+    pub failures: u32,
+    /// Protocols the participant never sent its share for, minus one for every protocol it
+    /// completed since.
+    pub protocol_failures: u32,
+    /// Whether the participant is considered alive.
+    pub alive: bool,
+    /// Seconds between the latest successful heartbeats.
+    intervals: VecDeque<f64>,
+    last_heartbeat: Option<Instant>,
+    /// Successful heartbeats in a row since the participant was considered down.
+    recovering: u32,
+}
+
+impl PeerStats {
+    fn record_heartbeat(&mut self, at: Instant, rtt: Duration) {
+        self.rtt = Some(match self.rtt {
+            Some(avg) => avg.mul_f64(1.0 - RTT_SMOOTHING) + rtt.mul_f64(RTT_SMOOTHING),
+            None => rtt,
+        });
+        self.failures = self.failures.saturating_sub(1);
+
+        match self.last_heartbeat {
+            Some(last) => {
+                if self.intervals.len() == HEARTBEAT_HISTORY {
+                    self.intervals.pop_front();
+                }
+                self.intervals
+                    .push_back(at.saturating_duration_since(last).as_secs_f64());
+                self.recovering = self.recovering.saturating_add(1);
+            }
+            // Participants we never heard from before are trusted right away.
+            None => self.recovering = RECOVERY_HEARTBEATS,
+        }
+        self.last_heartbeat = Some(at);
+    }
+
+    fn record_failure(&mut self) {
+        self.failures = (self.failures + 1).min(2 * SLOW_PEER_FAILURES);
+        self.recovering = 0;
+    }
+
+    fn record_protocol_outcome(&mut self, completed: bool) {
+        self.protocol_failures = if completed {
+            self.protocol_failures.saturating_sub(1)
+        } else {
+            (self.protocol_failures + 1).min(2 * SLOW_PEER_FAILURES)
+        };
+    }
+
+    /// Suspicion level that the participant is down, which is the negative log10 of the
+    /// probability of a heartbeat arriving even later than it already is, assuming the heartbeat
+    /// intervals are normally distributed.
+    pub fn phi(&self, now: Instant) -> Option<f64> {
+        let elapsed = now
+            .saturating_duration_since(self.last_heartbeat?)
+            .as_secs_f64();
+        let (mean, std_dev) = if self.intervals.is_empty() {
+            let expected = DEFAULT_TIMEOUT.as_secs_f64();
+            (expected, expected / 4.0)
+        } else {
+            let count = self.intervals.len() as f64;
+            let mean = self.intervals.iter().sum::<f64>() / count;
+            let variance = self
+                .intervals
+                .iter()
+                .map(|interval| (interval - mean).powi(2))
+                .sum::<f64>()
+                / count;
+            (mean, variance.sqrt())
+        };
+        let std_dev = std_dev.max(MIN_HEARTBEAT_STD_DEV.as_secs_f64());
+
+        // Logistic approximation of the normal distribution's cumulative distribution function.
+        let y = (elapsed - mean) / std_dev;
+        let e = (-y * (1.5976 + 0.070566 * y * y)).exp();
+        let phi = if elapsed > mean {
+            -(e / (1.0 + e)).log10()
+        } else {
+            -(1.0 - 1.0 / (1.0 + e)).log10()
+        };
+        // Far beyond the threshold the approximation overflows, which tells nothing more.
+        Some(phi.clamp(0.0, MAX_PHI))
+    }
+
+    /// Updates whether the participant is alive, returning whether that changed.
+    fn update_liveness(&mut self, now: Instant) -> bool {
+        let alive = if self.alive {
+            self.phi(now).is_some_and(|phi| phi < PHI_THRESHOLD)
+        } else {
+            self.recovering >= RECOVERY_HEARTBEATS
+        };
+        if self.alive && !alive {
+            self.recovering = 0;
+        }
+        std::mem::replace(&mut self.alive, alive) != alive
+    }
+
+    pub fn is_slow(&self) -> bool {
+        self.failures >= SLOW_PEER_FAILURES
+            || self.protocol_failures >= SLOW_PEER_FAILURES
+            || self.rtt.is_some_and(|rtt| rtt > SLOW_PEER_RTT)
+    }
+}
+
+/// Round trip of a heartbeat that a participant answered.
+struct Heartbeat {
+    received_at: Instant,
+    rtt: Duration,
+}
+
 // TODO: this is a basic connection pool and does not do most of the work yet. This is
 //       mostly here just to facilitate offline node handling for now.
 // TODO/NOTE: we can use libp2p to facilitate most the of low level TCP connection work.
@@ -21,9 +196,52 @@ pub struct Pool {
     current_active: RwLock<Option<(Participants, Instant)>>,
     // Potentially active participants that we can use to establish a connection in the next epoch.
     potential_active: RwLock<Option<(Participants, Instant)>>,
+
+    stats: RwLock<HashMap<Participant, PeerStats>>,
+    potential_stats: RwLock<HashMap<Participant, PeerStats>>,
+    slow_view: SlowView,
+    liveness_view: LivenessView,
+    /// Keys peers are accepted with when mutual TLS is enabled.
+    trusted_peers: TrustedPeers,
 }
 
 impl Pool {
+    pub fn new(http: reqwest::Client, trusted_peers: TrustedPeers) -> Self {
+        Self {
+            http,
+            trusted_peers,
+            ..Default::default()
+        }
+    }
+
+    /// Sends a heartbeat to every participant at once, so that a slow participant does not hold
+    /// up hearing back from the others.
+    async fn heartbeat(
+        &self,
+        participants: &Participants,
+    ) -> Vec<(Participant, ParticipantInfo, Option<Heartbeat>)> {
+        let heartbeats = participants.iter().map(|(participant, info)| async move {
+            let start = Instant::now();
+            let heartbeat = async {
+                let resp = self
+                    .http
+                    .get(format!("{}/state", info.url))
+                    .timeout(HEARTBEAT_TIMEOUT)
+                    .send()
+                    .await
+                    .ok()?;
+                // Only a node that serves its state counts as having answered.
+                let _: StateView = resp.json().await.ok()?;
+                Some(Heartbeat {
+                    received_at: Instant::now(),
+                    rtt: start.elapsed(),
+                })
+            };
+            (*participant, info.clone(), heartbeat.await)
+        });
+        join_all(heartbeats).await
+    }
+
     pub async fn ping(&self) -> Participants {
         if let Some((ref active, timestamp)) = *self.current_active.read().await {
             if timestamp.elapsed() < DEFAULT_TIMEOUT {
@@ -31,25 +249,81 @@ impl Pool {
             }
         }
 
-        let connections = self.connections.read().await;
+        let connections = self.connections.read().await.clone();
+        let heartbeats = self.heartbeat(&connections).await;
 
         let mut participants = Participants::default();
-        for (participant, info) in connections.iter() {
-            let Ok(resp) = self.http.get(format!("{}/state", info.url)).send().await else {
-                continue;
-            };
-
-            let Ok(_state): Result<StateView, _> = resp.json().await else {
-                continue;
-            };
-            participants.insert(participant, info.clone());
+        let mut stats = self.stats.write().await;
+        stats.retain(|participant, _| connections.contains_key(participant));
+        let now = Instant::now();
+        for (participant, info, heartbeat) in heartbeats {
+            let peer = stats.entry(participant).or_default();
+            match heartbeat {
+                Some(heartbeat) => {
+                    peer.record_heartbeat(heartbeat.received_at, heartbeat.rtt);
+                    crate::metrics::PEER_HEARTBEAT_LATENCY
+                        .with_label_values(&[info.account_id.as_str()])
+                        .observe(heartbeat.rtt.as_secs_f64());
+                }
+                None => peer.record_failure(),
+            }
+            observe_liveness(peer, &info, now);
+            if peer.alive {
+                participants.insert(&participant, info);
+            }
         }
 
+        let slow = stats
+            .iter()
+            .filter(|(_, stats)| stats.is_slow())
+            .map(|(participant, _)| *participant)
+            .collect::<BTreeSet<_>>();
+        let liveness = stats
+            .iter()
+            .map(|(participant, stats)| PeerLiveness {
+                participant: *participant,
+                alive: stats.alive,
+                suspicion: stats.phi(now),
+                rtt_ms: stats.rtt.map(|rtt| rtt.as_millis() as u64),
+                protocol_failures: stats.protocol_failures,
+            })
+            .collect::<Vec<_>>();
+        tracing::debug!(?liveness, ?slow, "measured participant responsiveness");
+        *self.slow_view.write().await = slow;
+        *self.liveness_view.write().await = liveness;
+
         let mut active = self.current_active.write().await;
         *active = Some((participants.clone(), Instant::now()));
         participants
     }
 
+    /// Records which participants of a finished protocol sent their share, as `(participant,
+    /// completed)`. Slow participants are taken into account the next time the pool is pinged.
+    pub async fn record_protocol_outcomes(&self, outcomes: &[(Participant, bool)]) {
+        if outcomes.is_empty() {
+            return;
+        }
+        let mut stats = self.stats.write().await;
+        for (participant, completed) in outcomes {
+            if let Some(peer) = stats.get_mut(participant) {
+                peer.record_protocol_outcome(*completed);
+            }
+        }
+    }
+
+    /// Participants this node considers slow, as of the latest ping.
+    pub async fn slow_participants(&self) -> BTreeSet<Participant> {
+        self.slow_view.read().await.clone()
+    }
+
+    pub fn slow_view(&self) -> SlowView {
+        self.slow_view.clone()
+    }
+
+    pub fn liveness_view(&self) -> LivenessView {
+        self.liveness_view.clone()
+    }
+
     pub async fn ping_potential(&self) -> Participants {
         if let Some((ref active, timestamp)) = *self.potential_active.read().await {
             if timestamp.elapsed() < DEFAULT_TIMEOUT {
@@ -57,18 +331,23 @@ impl Pool {
             }
         }
 
-        let connections = self.potential_connections.read().await;
+        let connections = self.potential_connections.read().await.clone();
+        let heartbeats = self.heartbeat(&connections).await;
 
         let mut participants = Participants::default();
-        for (participant, info) in connections.iter() {
-            let Ok(resp) = self.http.get(format!("{}/state", info.url)).send().await else {
-                continue;
-            };
-
-            let Ok(_state): Result<StateView, _> = resp.json().await else {
-                continue;
-            };
-            participants.insert(participant, info.clone());
+        let mut stats = self.potential_stats.write().await;
+        stats.retain(|participant, _| connections.contains_key(participant));
+        let now = Instant::now();
+        for (participant, info, heartbeat) in heartbeats {
+            let peer = stats.entry(participant).or_default();
+            match heartbeat {
+                Some(heartbeat) => peer.record_heartbeat(heartbeat.received_at, heartbeat.rtt),
+                None => peer.record_failure(),
+            }
+            observe_liveness(peer, &info, now);
+            if peer.alive {
+                participants.insert(&participant, info);
+            }
         }
 
         let mut potential_active = self.potential_active.write().await;
@@ -77,6 +356,7 @@ impl Pool {
     }
 
     pub async fn establish_participants(&self, contract_state: &ProtocolState) {
+        self.trusted_peers.update(contract_state);
         match contract_state {
             ProtocolState::Initializing(contract_state) => {
                 let participants: Participants = contract_state.candidates.clone().into();
@@ -106,3 +386,73 @@ impl Pool {
         self.potential_connections.read().await.clone()
     }
 }
+
+fn observe_liveness(peer: &mut PeerStats, info: &ParticipantInfo, now: Instant) {
+    if peer.update_liveness(now) {
+        if peer.alive {
+            tracing::info!(account_id = %info.account_id, "participant is back up");
+        } else {
+            tracing::warn!(
+                account_id = %info.account_id,
+                suspicion = ?peer.phi(now),
+                "participant is suspected to be down"
+            );
+        }
+    }
+    let account_id = info.account_id.as_str();
+    crate::metrics::PEER_SUSPICION
+        .with_label_values(&[account_id])
+        .set(peer.phi(now).unwrap_or_default());
+    crate::metrics::PEER_ALIVE
+        .with_label_values(&[account_id])
+        .set(peer.alive as i64);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{PeerStats, RECOVERY_HEARTBEATS, SLOW_PEER_FAILURES};
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn test_failure_detector_hysteresis() {
+        let start = Instant::now();
+        let second = |n| start + Duration::from_secs(n);
+        let rtt = Duration::from_millis(10);
+
+        let mut peer = PeerStats::default();
+        for n in 0..10 {
+            peer.record_heartbeat(second(n), rtt);
+            peer.update_liveness(second(n));
+        }
+        assert!(peer.alive);
+
+        // A single missed heartbeat is tolerated, a long silence is not.
+        peer.record_failure();
+        assert!(!peer.update_liveness(second(11)));
+        assert!(peer.update_liveness(second(20)));
+        assert!(!peer.alive);
+
+        // Coming back takes more than a single heartbeat.
+        for n in 0..RECOVERY_HEARTBEATS as u64 {
+            assert!(!peer.alive);
+            peer.record_heartbeat(second(21 + n), rtt);
+            peer.update_liveness(second(21 + n));
+        }
+        assert!(peer.alive);
+    }
+
+    #[test]
+    fn test_protocol_failures_make_peer_slow() {
+        let mut peer = PeerStats::default();
+        peer.record_heartbeat(Instant::now(), Duration::from_millis(10));
+        for _ in 0..SLOW_PEER_FAILURES {
+            assert!(!peer.is_slow());
+            peer.record_protocol_outcome(false);
+        }
+        assert!(peer.is_slow());
+
+        // Completing protocols again wins the participant back, one at a time.
+        peer.record_protocol_outcome(true);
+        assert!(!peer.is_slow());
+    }
+}
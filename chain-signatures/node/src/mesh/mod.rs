@@ -1,7 +1,11 @@
+use std::collections::BTreeSet;
+
+use cait_sith::protocol::Participant;
 use mpc_keys::hpke;
 
 use crate::protocol::contract::primitives::Participants;
 use crate::protocol::ProtocolState;
+use crate::tls::NodeTls;
 
 pub mod connection;
 
@@ -9,6 +13,8 @@ pub mod connection;
 pub struct NetworkConfig {
     pub sign_sk: near_crypto::SecretKey,
     pub cipher_pk: hpke::PublicKey,
+    /// Mutual TLS with the other nodes, if enabled.
+    pub tls: Option<NodeTls>,
 }
 
 #[derive(Default)]
@@ -22,6 +28,9 @@ pub struct Mesh {
     /// Potential participants that are active at the beginning of each protocol loop. This
     /// includes participants belonging to the next epoch.
     pub active_potential_participants: Participants,
+
+    /// Participants this node considers slow to respond at the beginning of each protocol loop.
+    pub slow_participants: BTreeSet<Participant>,
 }
 
 impl Mesh {
@@ -36,6 +45,13 @@ impl Mesh {
         &self.active_potential_participants
     }
 
+    /// Participants this node considers slow to respond, from the round trip times of its
+    /// heartbeats and the protocols they failed to take part in. Signatures this node proposes
+    /// avoid these where possible.
+    pub fn slow_participants(&self) -> &BTreeSet<Participant> {
+        &self.slow_participants
+    }
+
     /// Get all pontential participants, but they not necessarily be active.
     pub async fn potential_participants(&self) -> Participants {
         self.connections.potential_participants().await
@@ -67,5 +83,6 @@ impl Mesh {
     pub async fn ping(&mut self) {
         self.active_participants = self.connections.ping().await;
         self.active_potential_participants = self.connections.ping_potential().await;
+        self.slow_participants = self.connections.slow_participants().await;
     }
 }
@@ -335,6 +335,24 @@ pub(crate) static FAILED_SEND_ENCRYPTED_LATENCY: Lazy<HistogramVec> = Lazy::new(
     .unwrap()
 });
 
+pub(crate) static NUM_LIGHT_CLIENT_VERIFICATION_FAILURES: Lazy<IntGaugeVec> = Lazy::new(|| {
+    try_create_int_gauge_vec(
+        "multichain_light_client_verification_failures",
+        "number of indexed blocks and sign calls rejected by light client verification",
+        &["node_account_id", "kind"],
+    )
+    .unwrap()
+});
+
+pub(crate) static LIGHT_CLIENT_HEAD_HEIGHT: Lazy<IntGaugeVec> = Lazy::new(|| {
+    try_create_int_gauge_vec(
+        "multichain_light_client_head_height",
+        "Height of the head verified by the indexer light client",
+        &["node_account_id"],
+    )
+    .unwrap()
+});
+
 pub fn try_create_int_gauge_vec(name: &str, help: &str, labels: &[&str]) -> Result<IntGaugeVec> {
     check_metric_multichain_prefix(name)?;
     let opts = Opts::new(name, help);
@@ -343,6 +361,14 @@ pub fn try_create_int_gauge_vec(name: &str, help: &str, labels: &[&str]) -> Resu
     Ok(gauge)
 }
 
+pub fn try_create_gauge_vec(name: &str, help: &str, labels: &[&str]) -> Result<GaugeVec> {
+    check_metric_multichain_prefix(name)?;
+    let opts = Opts::new(name, help);
+    let gauge = GaugeVec::new(opts, labels)?;
+    prometheus::register(Box::new(gauge.clone()))?;
+    Ok(gauge)
+}
+
 /// Attempts to create a `HistogramVector`, returning `Err` if the registry does not accept the counter
 /// (potentially due to naming conflict).
 pub fn try_create_histogram_vec(
@@ -371,3 +397,104 @@ fn check_metric_multichain_prefix(name: &str) -> Result<()> {
         )))
     }
 }
+
+pub(crate) static COMPUTE_POOL_THREADS: Lazy<IntGaugeVec> = Lazy::new(|| {
+    try_create_int_gauge_vec(
+        "multichain_compute_pool_threads",
+        "number of threads the triple and presignature protocols can be poked on",
+        &["node_account_id"],
+    )
+    .unwrap()
+});
+
+pub(crate) static COMPUTE_POOL_BUSY: Lazy<IntGaugeVec> = Lazy::new(|| {
+    try_create_int_gauge_vec(
+        "multichain_compute_pool_busy",
+        "number of compute pool threads currently poking a protocol",
+        &["node_account_id"],
+    )
+    .unwrap()
+});
+
+pub(crate) static COMPUTE_POOL_QUEUED: Lazy<IntGaugeVec> = Lazy::new(|| {
+    try_create_int_gauge_vec(
+        "multichain_compute_pool_queued",
+        "number of protocol pokes waiting for a free compute pool thread",
+        &["node_account_id"],
+    )
+    .unwrap()
+});
+
+pub(crate) static COMPUTE_POOL_JOB_LATENCY: Lazy<HistogramVec> = Lazy::new(|| {
+    try_create_histogram_vec(
+        "multichain_compute_pool_job_latency_sec",
+        "Latency of a single protocol poke on the compute pool, start from getting a thread, end when the protocol is blocked or done.",
+        &["node_account_id"],
+        Some(exponential_buckets(0.001, 2.0, 20).unwrap()),
+    )
+    .unwrap()
+});
+
+pub(crate) static NUM_SIGN_REQUESTS_PRUNED: Lazy<IntGaugeVec> = Lazy::new(|| {
+    try_create_int_gauge_vec(
+        "multichain_sign_requests_pruned",
+        "number of sign requests dropped without being signed, marked by the reason they were dropped",
+        &["node_account_id", "reason"],
+    )
+    .unwrap()
+});
+
+pub(crate) static NUM_MESSAGE_STREAM_CONNECTS: Lazy<IntGaugeVec> = Lazy::new(|| {
+    try_create_int_gauge_vec(
+        "multichain_message_stream_connects",
+        "number of times the message stream to a participant was established, marked by the participant",
+        &["node_account_id"],
+    )
+    .unwrap()
+});
+
+pub(crate) static MESSAGE_STREAM_UNACKED: Lazy<IntGaugeVec> = Lazy::new(|| {
+    try_create_int_gauge_vec(
+        "multichain_message_stream_unacked",
+        "number of message batches sent to a participant that it has not acknowledged yet",
+        &["node_account_id"],
+    )
+    .unwrap()
+});
+
+pub(crate) static NUM_MESSAGES_REJECTED: Lazy<IntGaugeVec> = Lazy::new(|| {
+    try_create_int_gauge_vec(
+        "multichain_messages_rejected",
+        "number of messages from a participant rejected as replayed, marked by the participant and the reason",
+        &["node_account_id", "reason"],
+    )
+    .unwrap()
+});
+
+pub(crate) static PEER_SUSPICION: Lazy<GaugeVec> = Lazy::new(|| {
+    try_create_gauge_vec(
+        "multichain_peer_suspicion",
+        "phi-accrual suspicion level that a participant is down, marked by the participant",
+        &["node_account_id"],
+    )
+    .unwrap()
+});
+
+pub(crate) static PEER_ALIVE: Lazy<IntGaugeVec> = Lazy::new(|| {
+    try_create_int_gauge_vec(
+        "multichain_peer_alive",
+        "whether a participant is considered alive by the failure detector, marked by the participant",
+        &["node_account_id"],
+    )
+    .unwrap()
+});
+
+pub(crate) static PEER_HEARTBEAT_LATENCY: Lazy<HistogramVec> = Lazy::new(|| {
+    try_create_histogram_vec(
+        "multichain_peer_heartbeat_latency_sec",
+        "Round trip time of the heartbeats sent to a participant, marked by the participant",
+        &["node_account_id"],
+        Some(exponential_buckets(0.001, 2.0, 14).unwrap()),
+    )
+    .unwrap()
+});
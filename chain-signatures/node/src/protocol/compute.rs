@@ -0,0 +1,276 @@
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use cait_sith::protocol::{Action, MessageData, Participant, Protocol, ProtocolError};
+use near_account_id::AccountId;
+use tokio::sync::mpsc::{self, error::TryRecvError};
+use tokio::sync::{Notify, Semaphore};
+use tokio::task::JoinHandle;
+
+/// Bounded pool of blocking threads that the CPU heavy protocol pokes run on, so that a node
+/// with multiple cores can make progress on multiple generators at the same time instead of
+/// doing all of the work on the protocol task.
+#[derive(Clone, Debug)]
+pub struct ComputePool {
+    permits: Arc<Semaphore>,
+    /// Notified whenever a protocol task took actions for the protocol loop to act upon.
+    progress: Arc<Notify>,
+    my_account_id: AccountId,
+}
+
+impl ComputePool {
+    pub fn new(threads: usize, my_account_id: &AccountId) -> Self {
+        let threads = threads.max(1);
+        crate::metrics::COMPUTE_POOL_THREADS
+            .with_label_values(&[my_account_id.as_str()])
+            .set(threads as i64);
+        Self {
+            permits: Arc::new(Semaphore::new(threads)),
+            progress: Arc::new(Notify::new()),
+            my_account_id: my_account_id.clone(),
+        }
+    }
+
+    /// Handle to wait on for protocol tasks to take actions.
+    pub fn notifier(&self) -> Arc<Notify> {
+        self.progress.clone()
+    }
+
+    async fn run<F, T>(&self, job: F) -> T
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        let my_account_id = self.my_account_id.as_str();
+        let queued = crate::metrics::COMPUTE_POOL_QUEUED.with_label_values(&[my_account_id]);
+        let busy = crate::metrics::COMPUTE_POOL_BUSY.with_label_values(&[my_account_id]);
+
+        queued.inc();
+        let permit = self
+            .permits
+            .clone()
+            .acquire_owned()
+            .await
+            .expect("compute pool semaphore is never closed");
+        queued.dec();
+
+        busy.inc();
+        let job_time = Instant::now();
+        let output = tokio::task::spawn_blocking(move || {
+            let _permit = permit;
+            job()
+        })
+        .await;
+        busy.dec();
+        crate::metrics::COMPUTE_POOL_JOB_LATENCY
+            .with_label_values(&[my_account_id])
+            .observe(job_time.elapsed().as_secs_f64());
+
+        match output {
+            Ok(output) => output,
+            Err(err) => std::panic::resume_unwind(err.into_panic()),
+        }
+    }
+}
+
+/// Pokes a protocol until it has to wait on other participants or is done, collecting the
+/// actions along the way. An error stops the poking but keeps the actions collected before it.
+pub fn poke_until_blocked<T>(
+    mut poke: impl FnMut() -> Result<Action<T>, ProtocolError>,
+) -> (Vec<Action<T>>, Option<ProtocolError>) {
+    let mut actions = Vec::new();
+    loop {
+        match poke() {
+            Ok(action @ (Action::Wait | Action::Return(_))) => {
+                actions.push(action);
+                return (actions, None);
+            }
+            Ok(action) => actions.push(action),
+            Err(err) => return (actions, Some(err)),
+        }
+    }
+}
+
+/// A protocol running on a task of its own, which pokes it on the compute pool as soon as a
+/// message for it arrives instead of waiting for the protocol loop to come around to it. This way
+/// a protocol that waits on a slow participant does not hold up any of the others. The task is
+/// aborted once its handle is dropped.
+pub struct ProtocolTask<T> {
+    inbox: mpsc::UnboundedSender<(Participant, MessageData)>,
+    outbox: mpsc::UnboundedReceiver<Result<Action<T>, ProtocolError>>,
+    task: JoinHandle<()>,
+}
+
+impl<T: Send + 'static> ProtocolTask<T> {
+    /// Spawns the task running `protocol`, which fails the protocol once it takes longer than
+    /// `timeout`.
+    pub fn spawn(
+        mut protocol: Box<dyn Protocol<Output = T> + Send + Sync>,
+        pool: &ComputePool,
+        timeout: Duration,
+    ) -> Self {
+        let (inbox, mut messages) = mpsc::unbounded_channel();
+        let (actions, outbox) = mpsc::unbounded_channel();
+        let pool = pool.clone();
+        let task = tokio::spawn(async move {
+            let deadline = tokio::time::Instant::now() + timeout;
+            loop {
+                let (poked, taken, error) = pool
+                    .run(move || {
+                        let (taken, error) = poke_until_blocked(|| protocol.poke());
+                        (protocol, taken, error)
+                    })
+                    .await;
+                protocol = poked;
+                let done = error.is_some() || matches!(taken.last(), Some(Action::Return(_)));
+                for action in taken {
+                    if !matches!(action, Action::Wait) {
+                        let _ = actions.send(Ok(action));
+                    }
+                }
+                if let Some(err) = error {
+                    let _ = actions.send(Err(err));
+                }
+                pool.progress.notify_one();
+                if done {
+                    return;
+                }
+
+                match tokio::time::timeout_at(deadline, messages.recv()).await {
+                    Ok(Some((from, data))) => {
+                        protocol.message(from, data);
+                        while let Ok((from, data)) = messages.try_recv() {
+                            protocol.message(from, data);
+                        }
+                    }
+                    // Nobody is waiting on the protocol anymore.
+                    Ok(None) => return,
+                    Err(_) => {
+                        let _ = actions.send(Err(ProtocolError::Other(
+                            anyhow::anyhow!("protocol timed out").into(),
+                        )));
+                        pool.progress.notify_one();
+                        return;
+                    }
+                }
+            }
+        });
+        Self {
+            inbox,
+            outbox,
+            task,
+        }
+    }
+
+    /// Hands a message from another participant over to the protocol.
+    pub fn message(&self, from: Participant, data: MessageData) {
+        // Messages arriving after the protocol is over have nothing left to do.
+        let _ = self.inbox.send((from, data));
+    }
+
+    /// Takes the actions of the protocol since the last call without waiting for any. The
+    /// protocol is over once it returned its output or failed, either of which comes last.
+    pub fn actions(&mut self) -> (Vec<Action<T>>, Option<ProtocolError>) {
+        let mut taken = Vec::new();
+        loop {
+            match self.outbox.try_recv() {
+                Ok(Ok(action @ Action::Return(_))) => {
+                    taken.push(action);
+                    return (taken, None);
+                }
+                Ok(Ok(action)) => taken.push(action),
+                Ok(Err(err)) => return (taken, Some(err)),
+                Err(TryRecvError::Empty) => return (taken, None),
+                Err(TryRecvError::Disconnected) => {
+                    return (
+                        taken,
+                        Some(ProtocolError::Other(
+                            anyhow::anyhow!("protocol task stopped").into(),
+                        )),
+                    )
+                }
+            }
+        }
+    }
+}
+
+impl<T> Drop for ProtocolTask<T> {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ComputePool, ProtocolTask};
+    use cait_sith::protocol::{Action, MessageData, Participant, Protocol, ProtocolError};
+    use std::time::Duration;
+
+    /// Protocol that broadcasts once and returns the first message it receives.
+    struct Echo {
+        sent: bool,
+        received: Option<MessageData>,
+    }
+
+    impl Protocol for Echo {
+        type Output = MessageData;
+
+        fn poke(&mut self) -> Result<Action<Self::Output>, ProtocolError> {
+            if let Some(data) = self.received.take() {
+                return Ok(Action::Return(data));
+            }
+            if !std::mem::replace(&mut self.sent, true) {
+                return Ok(Action::SendMany(vec![1]));
+            }
+            Ok(Action::Wait)
+        }
+
+        fn message(&mut self, _from: Participant, data: MessageData) {
+            self.received = Some(data);
+        }
+    }
+
+    fn echo() -> Box<dyn Protocol<Output = MessageData> + Send + Sync> {
+        Box::new(Echo {
+            sent: false,
+            received: None,
+        })
+    }
+
+    #[tokio::test]
+    async fn test_protocol_task_progresses_on_messages() {
+        let pool = ComputePool::new(1, &"test.testnet".parse().unwrap());
+        let progress = pool.notifier();
+        let mut task = ProtocolTask::spawn(echo(), &pool, Duration::from_secs(10));
+
+        progress.notified().await;
+        let (actions, error) = task.actions();
+        assert!(matches!(actions[..], [Action::SendMany(_)]));
+        assert!(error.is_none());
+
+        task.message(Participant::from(1), vec![2]);
+        progress.notified().await;
+        let (actions, error) = task.actions();
+        assert!(matches!(&actions[..], [Action::Return(data)] if data == &vec![2]));
+        assert!(error.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_protocol_task_times_out() {
+        let pool = ComputePool::new(1, &"test.testnet".parse().unwrap());
+        let progress = pool.notifier();
+        let mut task = ProtocolTask::spawn(echo(), &pool, Duration::from_millis(10));
+
+        let mut actions = Vec::new();
+        let error = loop {
+            progress.notified().await;
+            let (taken, error) = task.actions();
+            actions.extend(taken);
+            if let Some(error) = error {
+                break error;
+            }
+        };
+        assert!(matches!(actions[..], [Action::SendMany(_)]));
+        assert!(matches!(error, ProtocolError::Other(_)));
+    }
+}
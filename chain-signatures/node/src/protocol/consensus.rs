@@ -12,6 +12,7 @@ use crate::protocol::signature::SignatureManager;
 use crate::protocol::state::{GeneratingState, ResharingState};
 use crate::protocol::triple::TripleManager;
 use crate::rpc_client;
+use crate::storage::presignature_storage::{LockPresignatureNodeStorageBox, PresignatureData};
 use crate::storage::secret_storage::SecretNodeStorageBox;
 use crate::storage::triple_storage::LockTripleNodeStorageBox;
 use crate::storage::triple_storage::TripleData;
@@ -38,8 +39,9 @@ pub trait ConsensusCtx {
     fn mpc_contract_id(&self) -> &AccountId;
     fn my_address(&self) -> &Url;
     fn sign_queue(&self) -> Arc<RwLock<SignQueue>>;
-    fn secret_storage(&self) -> &SecretNodeStorageBox;
+    fn secret_storage(&mut self) -> &mut SecretNodeStorageBox;
     fn triple_storage(&self) -> LockTripleNodeStorageBox;
+    fn presignature_storage(&self) -> LockPresignatureNodeStorageBox;
     fn cfg(&self) -> &Config;
 }
 
@@ -97,11 +99,21 @@ impl ConsensusProtocol for StartedState {
         ctx: C,
         contract_state: ProtocolState,
     ) -> Result<NodeState, ConsensusError> {
-        match self.persistent_node_data {
+        let key_share = match &contract_state {
+            ProtocolState::Initializing(_) => self.key_shares.last(),
+            ProtocolState::Running(contract_state) => {
+                self.key_share_for(contract_state.epoch, &contract_state.public_key)
+            }
+            ProtocolState::Resharing(contract_state) => {
+                self.key_share_for(contract_state.old_epoch, &contract_state.public_key)
+            }
+        };
+        match key_share.cloned() {
             Some(PersistentNodeData {
                 epoch,
                 private_share,
                 public_key,
+                ..
             }) => match contract_state {
                 ProtocolState::Initializing(_) => Err(ConsensusError::ContractStateRollback),
                 ProtocolState::Running(contract_state) => {
@@ -131,19 +143,23 @@ impl ConsensusProtocol for StartedState {
                                     tracing::info!(
                                         "started: contract state is running and we are already a participant"
                                     );
+                                    let triple_data = load_triples(&ctx, epoch).await?;
+                                    let presignature_data = load_presignatures(&ctx, epoch).await?;
                                     let presignature_manager = PresignatureManager::new(
                                         me,
                                         contract_state.threshold,
                                         epoch,
                                         ctx.my_account_id(),
                                         &ctx.cfg().presig_cfg,
+                                        presignature_data,
+                                        ctx.presignature_storage(),
                                     );
                                     let triple_manager = TripleManager::new(
                                         me,
                                         contract_state.threshold,
                                         epoch,
                                         &ctx.cfg().triple_cfg,
-                                        self.triple_data,
+                                        triple_data,
                                         ctx.triple_storage(),
                                         ctx.my_account_id(),
                                     );
@@ -166,6 +182,7 @@ impl ConsensusProtocol for StartedState {
                                             ),
                                         )),
                                         messages: Default::default(),
+                                        pruned_key_shares: false,
                                     }))
                                 }
                                 None => Ok(NodeState::Joining(JoiningState {
@@ -363,6 +380,10 @@ impl ConsensusProtocol for WaitingForConsensusState {
                     if let Err(err) = ctx.triple_storage().write().await.clear().await {
                         tracing::warn!(?err, "failed to clear triples from storage");
                     }
+                    // Same goes for presignatures, which are bound to the key shares of their epoch.
+                    if let Err(err) = ctx.presignature_storage().write().await.clear().await {
+                        tracing::warn!(?err, "failed to clear presignatures from storage");
+                    }
 
                     let triple_manager = TripleManager::new(
                         me,
@@ -388,6 +409,8 @@ impl ConsensusProtocol for WaitingForConsensusState {
                             self.epoch,
                             ctx.my_account_id(),
                             &ctx.cfg().presig_cfg,
+                            vec![],
+                            ctx.presignature_storage(),
                         ))),
                         signature_manager: Arc::new(RwLock::new(SignatureManager::new(
                             me,
@@ -395,6 +418,7 @@ impl ConsensusProtocol for WaitingForConsensusState {
                             self.epoch,
                         ))),
                         messages: self.messages,
+                        pruned_key_shares: false,
                     }))
                 }
             },
@@ -478,8 +502,8 @@ impl ConsensusProtocol for WaitingForConsensusState {
 #[async_trait]
 impl ConsensusProtocol for RunningState {
     async fn advance<C: ConsensusCtx + Send + Sync>(
-        self,
-        ctx: C,
+        mut self,
+        mut ctx: C,
         contract_state: ProtocolState,
     ) -> Result<NodeState, ConsensusError> {
         match contract_state {
@@ -509,6 +533,36 @@ impl ConsensusProtocol for RunningState {
                     if contract_state.public_key != self.public_key {
                         return Err(ConsensusError::MismatchedPublicKey);
                     }
+                    // Measured from the contract's view of the epoch, so that restarts do not
+                    // push it back.
+                    if !self.pruned_key_shares
+                        && ctx.cfg().prune_due(contract_state.epoch_started_at)
+                    {
+                        match ctx.secret_storage().prune(self.epoch).await {
+                            Ok(()) => self.pruned_key_shares = true,
+                            Err(err) => tracing::warn!(
+                                ?err,
+                                "failed to remove the key shares of previous epochs"
+                            ),
+                        }
+                    }
+                    let refresh_due = ctx.cfg().refresh_due(contract_state.epoch_started_at);
+                    if refresh_due && !contract_state.refresh_votes.contains(ctx.my_account_id()) {
+                        tracing::info!(
+                            epoch = self.epoch,
+                            "running(running): voting to refresh the key shares"
+                        );
+                        if let Err(err) = rpc_client::vote_refresh(
+                            ctx.rpc_client(),
+                            ctx.signer(),
+                            ctx.mpc_contract_id(),
+                            self.epoch,
+                        )
+                        .await
+                        {
+                            tracing::warn!(?err, "failed to vote for refreshing the key shares");
+                        }
+                    }
                     Ok(NodeState::Running(self))
                 }
             },
@@ -528,7 +582,13 @@ impl ConsensusProtocol for RunningState {
                     }
                     Ordering::Less => Err(ConsensusError::EpochRollback),
                     Ordering::Equal => {
-                        tracing::info!("running(resharing): contract is resharing");
+                        if contract_state.old_participants == contract_state.new_participants {
+                            tracing::info!(
+                                "running(resharing): contract is refreshing the key shares"
+                            );
+                        } else {
+                            tracing::info!("running(resharing): contract is resharing");
+                        }
                         let is_in_old_participant_set = contract_state
                             .old_participants
                             .contains_account_id(ctx.my_account_id());
@@ -702,17 +762,13 @@ impl ConsensusProtocol for JoiningState {
 impl ConsensusProtocol for NodeState {
     async fn advance<C: ConsensusCtx + Send + Sync>(
         self,
-        ctx: C,
+        mut ctx: C,
         contract_state: ProtocolState,
     ) -> Result<NodeState, ConsensusError> {
         match self {
             NodeState::Starting => {
-                let persistent_node_data = ctx.secret_storage().load().await?;
-                let triple_data = load_triples(ctx).await?;
-                Ok(NodeState::Started(StartedState {
-                    persistent_node_data,
-                    triple_data,
-                }))
+                let key_shares = ctx.secret_storage().load().await?;
+                Ok(NodeState::Started(StartedState { key_shares }))
             }
             NodeState::Started(state) => state.advance(ctx, contract_state).await,
             NodeState::Generating(state) => state.advance(ctx, contract_state).await,
@@ -725,13 +781,14 @@ impl ConsensusProtocol for NodeState {
 }
 
 async fn load_triples<C: ConsensusCtx + Send + Sync>(
-    ctx: C,
+    ctx: &C,
+    epoch: u64,
 ) -> Result<Vec<TripleData>, ConsensusError> {
     let triple_storage = ctx.triple_storage();
     let mut retries = 3;
     let mut error = None;
     while retries > 0 {
-        match triple_storage.read().await.load().await {
+        match triple_storage.read().await.load(epoch).await {
             Err(DatastoreStorageError::FetchEntitiesError(_)) => {
                 tracing::info!("There are no triples persisted.");
                 return Ok(vec![]);
@@ -748,6 +805,31 @@ async fn load_triples<C: ConsensusCtx + Send + Sync>(
     Err(ConsensusError::DatastoreStorageError(error.unwrap()))
 }
 
+async fn load_presignatures<C: ConsensusCtx + Send + Sync>(
+    ctx: &C,
+    epoch: u64,
+) -> Result<Vec<PresignatureData>, ConsensusError> {
+    let presignature_storage = ctx.presignature_storage();
+    let mut retries = 3;
+    let mut error = None;
+    while retries > 0 {
+        match presignature_storage.read().await.load(epoch).await {
+            Err(DatastoreStorageError::FetchEntitiesError(_)) => {
+                tracing::info!("There are no presignatures persisted.");
+                return Ok(vec![]);
+            }
+            Err(e) => {
+                retries -= 1;
+                tracing::warn!(?e, "presignature load failed.");
+                error = Some(e);
+                tokio::time::sleep(std::time::Duration::from_millis(500)).await;
+            }
+            Ok(loaded_presignatures) => return Ok(loaded_presignatures),
+        }
+    }
+    Err(ConsensusError::DatastoreStorageError(error.unwrap()))
+}
+
 async fn start_resharing<C: ConsensusCtx>(
     private_share: Option<SecretKeyShare>,
     ctx: C,
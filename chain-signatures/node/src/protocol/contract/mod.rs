@@ -9,7 +9,7 @@ use std::{collections::HashSet, str::FromStr};
 
 use self::primitives::{Candidates, Participants, PkVotes, Votes};
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct InitializingContractState {
     pub candidates: Candidates,
     pub threshold: usize,
@@ -26,7 +26,7 @@ impl From<mpc_contract::InitializingContractState> for InitializingContractState
     }
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct RunningContractState {
     pub epoch: u64,
     pub participants: Participants,
@@ -35,6 +35,12 @@ pub struct RunningContractState {
     pub candidates: Candidates,
     pub join_votes: Votes,
     pub leave_votes: Votes,
+    #[serde(default)]
+    pub refresh_votes: HashSet<AccountId>,
+    /// Block timestamp in nanoseconds at which the contract started running in this epoch, or 0
+    /// if the contract does not track it.
+    #[serde(default)]
+    pub epoch_started_at: u64,
 }
 
 impl From<mpc_contract::RunningContractState> for RunningContractState {
@@ -47,11 +53,17 @@ impl From<mpc_contract::RunningContractState> for RunningContractState {
             candidates: value.candidates.into(),
             join_votes: value.join_votes.into(),
             leave_votes: value.leave_votes.into(),
+            refresh_votes: value
+                .refresh_votes
+                .into_iter()
+                .map(|acc_id| AccountId::from_str(acc_id.as_ref()).unwrap())
+                .collect(),
+            epoch_started_at: value.epoch_started_at,
         }
     }
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct ResharingContractState {
     pub old_epoch: u64,
     pub old_participants: Participants,
@@ -78,7 +90,7 @@ impl From<mpc_contract::ResharingContractState> for ResharingContractState {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub enum ProtocolState {
     Initializing(InitializingContractState),
     Running(RunningContractState),
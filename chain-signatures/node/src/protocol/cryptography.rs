@@ -1,14 +1,19 @@
 use std::sync::PoisonError;
 
+use super::compute::ComputePool;
+use super::encoding::EncodingError;
+use super::signature::resolved_on_chain;
 use super::state::{GeneratingState, NodeState, ResharingState, RunningState};
 use super::Config;
 use crate::gcp::error::SecretStorageError;
 use crate::http_client::SendError;
 use crate::mesh::Mesh;
-use crate::protocol::message::{GeneratingMessage, ResharingMessage};
+use crate::protocol::message::{GeneratingMessage, ReplayRejection, ResharingMessage};
 use crate::protocol::state::{PersistentNodeData, WaitingForConsensusState};
 use crate::protocol::MpcMessage;
 use crate::storage::secret_storage::SecretNodeStorageBox;
+use crate::storage::sign_request_storage::LockSignRequestNodeStorageBox;
+use crate::transport::Transport;
 use async_trait::async_trait;
 use cait_sith::protocol::{Action, InitializationError, Participant, ProtocolError};
 use k256::elliptic_curve::group::GroupEncoding;
@@ -18,15 +23,17 @@ use near_crypto::InMemorySigner;
 #[async_trait::async_trait]
 pub trait CryptographicCtx {
     async fn me(&self) -> Participant;
-    fn http_client(&self) -> &reqwest::Client;
+    fn transport(&self) -> &Transport;
     fn rpc_client(&self) -> &near_fetch::Client;
     fn signer(&self) -> &InMemorySigner;
     fn mpc_contract_id(&self) -> &AccountId;
     fn secret_storage(&mut self) -> &mut SecretNodeStorageBox;
+    fn sign_request_storage(&self) -> &LockSignRequestNodeStorageBox;
     fn cfg(&self) -> &Config;
 
     /// Active participants is the active participants at the beginning of each protocol loop.
     fn mesh(&self) -> &Mesh;
+    fn compute_pool(&self) -> &ComputePool;
 }
 
 #[derive(thiserror::Error, Debug)]
@@ -51,6 +58,10 @@ pub enum CryptographicError {
     InvalidStateHandle(String),
     #[error("secret storage error: {0}")]
     SecretStorageError(#[from] SecretStorageError),
+    #[error("encoding error: {0}")]
+    Encoding(#[from] EncodingError),
+    #[error("rejected replayed message: {0}")]
+    Replayed(ReplayRejection),
 }
 
 impl<T> From<PoisonError<T>> for CryptographicError {
@@ -98,7 +109,8 @@ impl CryptographicProtocol for GeneratingState {
                         .send_encrypted(
                             ctx.me().await,
                             &ctx.cfg().network_cfg.sign_sk,
-                            ctx.http_client(),
+                            0,
+                            ctx.transport(),
                             ctx.mesh().active_participants(),
                         )
                         .await;
@@ -147,6 +159,7 @@ impl CryptographicProtocol for GeneratingState {
                     ctx.secret_storage()
                         .store(&PersistentNodeData {
                             epoch: 0,
+                            key_version: PersistentNodeData::KEY_VERSION,
                             private_share: r.private_share,
                             public_key: r.public_key,
                         })
@@ -159,7 +172,8 @@ impl CryptographicProtocol for GeneratingState {
                         .send_encrypted(
                             ctx.me().await,
                             &ctx.cfg().network_cfg.sign_sk,
-                            ctx.http_client(),
+                            0,
+                            ctx.transport(),
                             ctx.mesh().active_participants(),
                         )
                         .await;
@@ -196,7 +210,8 @@ impl CryptographicProtocol for WaitingForConsensusState {
             .send_encrypted(
                 ctx.me().await,
                 &ctx.cfg().network_cfg.sign_sk,
-                ctx.http_client(),
+                self.epoch,
+                ctx.transport(),
                 ctx.mesh().active_participants(),
             )
             .await;
@@ -249,7 +264,8 @@ impl CryptographicProtocol for ResharingState {
                         .send_encrypted(
                             ctx.me().await,
                             &ctx.cfg().network_cfg.sign_sk,
-                            ctx.http_client(),
+                            self.old_epoch,
+                            ctx.transport(),
                             &active,
                         )
                         .await;
@@ -303,6 +319,7 @@ impl CryptographicProtocol for ResharingState {
                     ctx.secret_storage()
                         .store(&PersistentNodeData {
                             epoch: self.old_epoch + 1,
+                            key_version: PersistentNodeData::KEY_VERSION,
                             private_share,
                             public_key: self.public_key,
                         })
@@ -316,7 +333,8 @@ impl CryptographicProtocol for ResharingState {
                         .send_encrypted(
                             ctx.me().await,
                             &ctx.cfg().network_cfg.sign_sk,
-                            ctx.http_client(),
+                            self.old_epoch,
+                            ctx.transport(),
                             &active,
                         )
                         .await;
@@ -367,7 +385,7 @@ impl CryptographicProtocol for RunningState {
         if let Err(err) = triple_manager.stockpile(active) {
             tracing::warn!(?err, "running: failed to stockpile triples");
         }
-        for (p, msg) in triple_manager.poke().await {
+        for (p, msg) in triple_manager.poke(ctx.compute_pool()).await {
             let info = self.fetch_participant(&p)?;
             messages.push(info.clone(), MpcMessage::Triple(msg));
         }
@@ -392,13 +410,14 @@ impl CryptographicProtocol for RunningState {
                 &self.public_key,
                 &self.private_share,
                 &mut triple_manager,
+                ctx.compute_pool(),
             )
             .await
         {
             tracing::warn!(?err, "running: failed to stockpile presignatures");
         }
         drop(triple_manager);
-        for (p, msg) in presignature_manager.poke() {
+        for (p, msg) in presignature_manager.poke().await {
             let info = self.fetch_participant(&p)?;
             messages.push(info.clone(), MpcMessage::Presignature(msg));
         }
@@ -417,19 +436,41 @@ impl CryptographicProtocol for RunningState {
         crate::metrics::SIGN_QUEUE_SIZE
             .with_label_values(&[my_account_id.as_str()])
             .set(sign_queue.len() as i64);
-        sign_queue.organize(self.threshold, active, ctx.me().await, &my_account_id);
-        let my_requests = sign_queue.my_requests(ctx.me().await);
+        sign_queue.organize(
+            self.threshold,
+            &self.participants,
+            ctx.me().await,
+            &my_account_id,
+        );
+        let expired = sign_queue.prune_expired();
+        let block_height = sign_queue.block_height();
+
+        let me = ctx.me().await;
+        let mut signature_manager = self.signature_manager.write().await;
+        for receipt_id in signature_manager.my_generations() {
+            sign_queue.observe_progress(receipt_id, me);
+        }
+        let handed_off = sign_queue.rotate_proposers();
+        let mut resolved = sign_queue.take_resolved();
+        let my_requests = sign_queue.my_requests(me);
         crate::metrics::SIGN_QUEUE_MINE_SIZE
             .with_label_values(&[my_account_id.as_str()])
             .set(my_requests.len() as i64);
 
-        let mut signature_manager = self.signature_manager.write().await;
-        signature_manager.handle_requests(
-            self.threshold,
-            active,
-            my_requests,
-            &mut presignature_manager,
-        );
+        signature_manager.prune_expired(block_height, &expired, &my_account_id);
+        signature_manager.hand_off(&handed_off);
+        signature_manager
+            .handle_requests(
+                self.threshold,
+                &self.participants,
+                active,
+                ctx.mesh().slow_participants(),
+                my_requests,
+                &mut presignature_manager,
+                block_height,
+                ctx.compute_pool(),
+            )
+            .await;
         drop(sign_queue);
         drop(presignature_manager);
 
@@ -448,12 +489,43 @@ impl CryptographicProtocol for RunningState {
         {
             tracing::warn!(?err, "running: failed to publish signatures");
         }
+        let resolution_checks = signature_manager.resolution_checks();
+        resolved.extend(signature_manager.take_resolved());
+        let protocol_outcomes = signature_manager.take_protocol_outcomes();
         drop(signature_manager);
+        // The contract is queried without holding the lock, so that protocols keep progressing.
+        if !resolution_checks.is_empty() {
+            let answered =
+                resolved_on_chain(ctx.rpc_client(), ctx.mpc_contract_id(), resolution_checks).await;
+            resolved.extend(
+                self.signature_manager
+                    .write()
+                    .await
+                    .prune_resolved(&answered, &my_account_id),
+            );
+        }
+        ctx.mesh()
+            .connections
+            .record_protocol_outcomes(&protocol_outcomes)
+            .await;
+        let mut sign_queue = self.sign_queue.write().await;
+        for receipt_id in &resolved {
+            sign_queue.remove(receipt_id);
+        }
+        drop(sign_queue);
+        let mut sign_request_storage = ctx.sign_request_storage().write().await;
+        for receipt_id in resolved {
+            if let Err(err) = sign_request_storage.delete(receipt_id).await {
+                tracing::warn!(%receipt_id, ?err, "running: failed to delete resolved sign request");
+            }
+        }
+        drop(sign_request_storage);
         let failures = messages
             .send_encrypted(
                 ctx.me().await,
                 &ctx.cfg().network_cfg.sign_sk,
-                ctx.http_client(),
+                self.epoch,
+                ctx.transport(),
                 active,
             )
             .await;
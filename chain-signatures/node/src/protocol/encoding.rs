@@ -0,0 +1,94 @@
+//! Encodings of protocol messages before they get encrypted.
+//!
+//! JSON is what every node understands and is recognized by its leading `{`. The binary formats
+//! start with a tag byte naming the format, so a receiver can decode any format it knows without
+//! being told which one was used. Senders only use a binary format with peers that announced
+//! support for it when connecting their message stream.
+
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+
+/// Tag of CBOR encoded messages.
+const TAG_CBOR: u8 = 0x01;
+
+#[derive(thiserror::Error, Debug)]
+pub enum EncodingError {
+    #[error("json error: {0}")]
+    Json(#[from] serde_json::Error),
+    #[error("cbor error: {0}")]
+    Cbor(String),
+    #[error("unknown message format tag: {0:#04x}")]
+    UnknownFormat(u8),
+    #[error("message is empty")]
+    Empty,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub enum MessageFormat {
+    #[default]
+    Json,
+    Cbor,
+}
+
+impl MessageFormat {
+    /// Formats this node can decode, from least to most preferred.
+    pub const SUPPORTED: &'static [MessageFormat] = &[MessageFormat::Json, MessageFormat::Cbor];
+
+    /// Most preferred of the formats the peer offered that this node supports too.
+    pub fn negotiate(offered: &[MessageFormat]) -> MessageFormat {
+        offered
+            .iter()
+            .filter(|format| Self::SUPPORTED.contains(format))
+            .max()
+            .copied()
+            .unwrap_or_default()
+    }
+
+    /// Encodes `value`. Nothing gets compressed, since messages are encrypted afterwards and the
+    /// compressed length would leak information about their contents.
+    pub fn encode<T: Serialize>(&self, value: &T) -> Result<Vec<u8>, EncodingError> {
+        match self {
+            MessageFormat::Json => Ok(serde_json::to_vec(value)?),
+            MessageFormat::Cbor => {
+                let mut encoded = vec![TAG_CBOR];
+                ciborium::into_writer(value, &mut encoded)
+                    .map_err(|err| EncodingError::Cbor(err.to_string()))?;
+                Ok(encoded)
+            }
+        }
+    }
+
+    /// Decodes a value encoded in any of the supported formats.
+    pub fn decode<T: DeserializeOwned>(bytes: &[u8]) -> Result<T, EncodingError> {
+        let (&tag, body) = bytes.split_first().ok_or(EncodingError::Empty)?;
+        match tag {
+            b'{' => Ok(serde_json::from_slice(bytes)?),
+            TAG_CBOR => {
+                ciborium::from_reader(body).map_err(|err| EncodingError::Cbor(err.to_string()))
+            }
+            tag => Err(EncodingError::UnknownFormat(tag)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::MessageFormat;
+    use crate::protocol::message::{GeneratingMessage, MpcMessage};
+
+    #[test]
+    fn test_message_formats_roundtrip() {
+        let message = MpcMessage::Generating(GeneratingMessage {
+            from: cait_sith::protocol::Participant::from(0),
+            data: vec![7; 64 * 1024],
+        });
+        let json = MessageFormat::Json.encode(&message).unwrap();
+        let cbor = MessageFormat::Cbor.encode(&message).unwrap();
+        assert!(cbor.len() < json.len());
+
+        for encoded in [json, cbor] {
+            let decoded: MpcMessage = MessageFormat::decode(&encoded).unwrap();
+            assert_eq!(decoded, message);
+        }
+    }
+}
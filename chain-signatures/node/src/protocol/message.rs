@@ -1,7 +1,10 @@
+use super::compute::ComputePool;
 use super::cryptography::CryptographicError;
+use super::encoding::MessageFormat;
 use super::presignature::{self, PresignatureId};
+use super::signature::{select_signers, signers_seed, ProposalCheck};
 use super::state::{GeneratingState, NodeState, ResharingState, RunningState};
-use super::triple::TripleId;
+use super::triple::{batch_triple_ids, TripleBatchId, TripleId, SUPPORTED_TRIPLE_BATCH_SIZES};
 use crate::gcp::error::SecretStorageError;
 use crate::http_client::SendError;
 use crate::indexer::ContractSignRequest;
@@ -10,13 +13,16 @@ use crate::util;
 
 use async_trait::async_trait;
 use cait_sith::protocol::{InitializationError, MessageData, Participant, ProtocolError};
+use chrono::Utc;
 use k256::Scalar;
 use mpc_keys::hpke::{self, Ciphered};
 use near_crypto::Signature;
 use near_primitives::hash::CryptoHash;
 use serde::{Deserialize, Serialize};
-use std::collections::{HashMap, VecDeque};
-use std::sync::Arc;
+use serde_bytes::ByteBuf;
+use std::collections::{BTreeSet, HashMap, HashSet, VecDeque};
+use std::fmt;
+use std::sync::{Arc, Mutex};
 use std::time::Instant;
 use tokio::sync::RwLock;
 
@@ -24,11 +30,14 @@ use tokio::sync::RwLock;
 pub trait MessageCtx {
     async fn me(&self) -> Participant;
     fn mesh(&self) -> &Mesh;
+    fn compute_pool(&self) -> &ComputePool;
 }
 
 #[derive(Serialize, Deserialize, Debug, PartialEq, Eq)]
 pub struct GeneratingMessage {
     pub from: Participant,
+    /// Encoded as bytes by the binary formats, while JSON keeps it an array of numbers.
+    #[serde(with = "serde_bytes")]
     pub data: MessageData,
 }
 
@@ -36,19 +45,31 @@ pub struct GeneratingMessage {
 pub struct ResharingMessage {
     pub epoch: u64,
     pub from: Participant,
+    #[serde(with = "serde_bytes")]
     pub data: MessageData,
 }
 
 #[derive(Serialize, Deserialize, Debug, PartialEq, Eq)]
 pub struct TripleMessage {
-    pub id: u64,
+    /// Sent as `id`, which nodes that predate batching read as the id of the triple, since a
+    /// batch of one generates the triple with the id of the batch.
+    #[serde(rename = "id", alias = "batch_id")]
+    pub batch_id: TripleBatchId,
+    /// Amount of triples generated by the batch, which is one for nodes that predate batching.
+    #[serde(default = "single_triple")]
+    pub batch_size: usize,
     pub epoch: u64,
     pub from: Participant,
+    #[serde(with = "serde_bytes")]
     pub data: MessageData,
     // UNIX timestamp as seconds since the epoch
     pub timestamp: u64,
 }
 
+const fn single_triple() -> usize {
+    1
+}
+
 #[derive(Serialize, Deserialize, Debug, PartialEq, Eq)]
 pub struct PresignatureMessage {
     pub id: u64,
@@ -56,6 +77,7 @@ pub struct PresignatureMessage {
     pub triple1: TripleId,
     pub epoch: u64,
     pub from: Participant,
+    #[serde(with = "serde_bytes")]
     pub data: MessageData,
     // UNIX timestamp as seconds since the epoch
     pub timestamp: u64,
@@ -69,8 +91,20 @@ pub struct SignatureMessage {
     pub request: ContractSignRequest,
     pub epsilon: Scalar,
     pub delta: Scalar,
+    /// Height of the block the `sign` receipt got executed in, which nodes that predate it do
+    /// not send.
+    #[serde(default)]
+    pub block_height: u64,
+    /// Participants the proposer picked to sign with, which nodes that predate it do not send.
+    #[serde(default)]
+    pub participants: Vec<Participant>,
+    /// Participants the proposer avoided when picking `participants`, so that the pick can be
+    /// recomputed by every other node.
+    #[serde(default)]
+    pub slow: Vec<Participant>,
     pub epoch: u64,
     pub from: Participant,
+    #[serde(with = "serde_bytes")]
     pub data: MessageData,
     // UNIX timestamp as seconds since the epoch
     pub timestamp: u64,
@@ -101,9 +135,11 @@ impl MpcMessage {
 pub struct MpcMessageQueue {
     generating: VecDeque<GeneratingMessage>,
     resharing_bins: HashMap<u64, VecDeque<ResharingMessage>>,
-    triple_bins: HashMap<u64, HashMap<TripleId, VecDeque<TripleMessage>>>,
+    triple_bins: HashMap<u64, HashMap<TripleBatchId, VecDeque<TripleMessage>>>,
     presignature_bins: HashMap<u64, HashMap<PresignatureId, VecDeque<PresignatureMessage>>>,
     signature_bins: HashMap<u64, HashMap<CryptoHash, VecDeque<SignatureMessage>>>,
+    /// When signature messages were first buffered for requests not indexed by this node yet.
+    unindexed_signatures: HashMap<CryptoHash, Instant>,
 }
 
 impl MpcMessageQueue {
@@ -119,7 +155,7 @@ impl MpcMessageQueue {
                 .triple_bins
                 .entry(message.epoch)
                 .or_default()
-                .entry(message.id)
+                .entry(message.batch_id)
                 .or_default()
                 .push_back(message),
             MpcMessage::Presignature(message) => self
@@ -234,49 +270,60 @@ impl MessageHandler for RunningState {
         let participants = ctx.mesh().active_participants();
         let mut triple_manager = self.triple_manager.write().await;
 
-        // remove the triple_id that has already failed or taken from the triple_bins
+        // remove the triple batches that have already failed or been taken from the triple_bins
         // and refresh the timestamp of failed and taken
         queue
             .triple_bins
             .entry(self.epoch)
             .or_default()
             .retain(|id, queue| {
-                if let Some(first_msg) = queue.front() {
-                    // Skip this triple if its message already timed out
-                    if util::is_elapsed_longer_than_timeout(
-                        first_msg.timestamp,
-                        crate::types::PROTOCOL_TRIPLE_TIMEOUT,
-                    ) {
-                        return false;
-                    }
-                } else {
+                let Some(first_msg) = queue.front() else {
+                    return false;
+                };
+                // Skip this triple if its message already timed out
+                if util::is_elapsed_longer_than_timeout(
+                    first_msg.timestamp,
+                    crate::types::PROTOCOL_TRIPLE_TIMEOUT,
+                ) {
+                    return false;
+                }
+                // Drop batches that no protocol can be started with
+                if !SUPPORTED_TRIPLE_BATCH_SIZES.contains(&first_msg.batch_size) {
+                    tracing::warn!(
+                        id,
+                        batch_size = first_msg.batch_size,
+                        "received triple message with unsupported batch size"
+                    );
                     return false;
                 }
                 let has_failed = triple_manager.failed_triples.contains_key(id);
                 if has_failed {
                     triple_manager.failed_triples.insert(*id, Instant::now());
                 }
-                let is_taken = triple_manager.taken.contains_key(id);
-                if is_taken {
-                    triple_manager.taken.insert(*id, Instant::now());
+                let mut is_taken = false;
+                for triple_id in batch_triple_ids(*id, first_msg.batch_size) {
+                    if triple_manager.taken.contains_key(&triple_id) {
+                        triple_manager.taken.insert(triple_id, Instant::now());
+                        is_taken = true;
+                    }
                 }
                 !has_failed && !is_taken
             });
 
         for (id, queue) in queue.triple_bins.entry(self.epoch).or_default() {
-            if let Some(first_msg) = queue.front() {
-                // Skip this triple if its message already timed out
-                if util::is_elapsed_longer_than_timeout(
-                    first_msg.timestamp,
-                    crate::types::PROTOCOL_TRIPLE_TIMEOUT,
-                ) {
-                    continue;
-                }
-            } else {
+            let Some(first_msg) = queue.front() else {
+                continue;
+            };
+            // Skip this triple if its message already timed out
+            if util::is_elapsed_longer_than_timeout(
+                first_msg.timestamp,
+                crate::types::PROTOCOL_TRIPLE_TIMEOUT,
+            ) {
                 continue;
             }
-            let protocol = match triple_manager.get_or_generate(*id, participants) {
-                Ok(protocol) => protocol,
+            let batch_size = first_msg.batch_size;
+            let generator = match triple_manager.get_or_generate(*id, batch_size, participants) {
+                Ok(generator) => generator,
                 Err(err) => {
                     // ignore the message since the generation had bad parameters. Also have the other node who
                     // initiated the protocol resend the message or have it timeout on their side.
@@ -285,9 +332,9 @@ impl MessageHandler for RunningState {
                 }
             };
 
-            if let Some(protocol) = protocol {
+            if let Some(generator) = generator {
                 while let Some(message) = queue.pop_front() {
-                    protocol.message(message.from, message.data);
+                    generator.message(message.from, message.data);
                 }
             }
         }
@@ -313,10 +360,11 @@ impl MessageHandler for RunningState {
                         &mut triple_manager,
                         &self.public_key,
                         &self.private_share,
+                        ctx.compute_pool(),
                     )
                     .await
                 {
-                    Ok(protocol) => protocol.message(message.from, message.data),
+                    Ok(generator) => generator.message(message.from, message.data),
                     Err(presignature::GenerationError::AlreadyGenerated) => {
                         tracing::debug!(id, "presignature already generated, nothing left to do")
                     }
@@ -355,10 +403,15 @@ impl MessageHandler for RunningState {
             }
         }
 
+        // Proposals are only joined once this node indexed the same request by itself, so that a
+        // compromised proposer cannot get arbitrary payloads signed.
+        let sign_queue = self.sign_queue.read().await;
         let mut signature_manager = self.signature_manager.write().await;
+        let mut progressing = Vec::new();
+        let unindexed_signatures = &mut queue.unindexed_signatures;
         for (receipt_id, queue) in queue.signature_bins.entry(self.epoch).or_default() {
             let mut leftover_messages = Vec::new();
-            while let Some(message) = queue.pop_front() {
+            while let Some(mut message) = queue.pop_front() {
                 // Skip message if it already timed out
                 if util::is_elapsed_longer_than_timeout(
                     message.timestamp,
@@ -366,6 +419,11 @@ impl MessageHandler for RunningState {
                 ) {
                     continue;
                 }
+                if message.block_height == 0 {
+                    if let Some(block_height) = sign_queue.indexed_block_height(receipt_id) {
+                        message.block_height = block_height;
+                    }
+                }
 
                 // TODO: make consistent with presignature manager AlreadyGenerated.
                 if signature_manager.has_completed(&message.presignature_id) {
@@ -375,27 +433,126 @@ impl MessageHandler for RunningState {
                     );
                     continue;
                 }
-                // if !self
-                //     .sign_queue
-                //     .read()
-                //     .await
-                //     .contains(message.proposer, receipt_id.clone())
-                // {
-                //     leftover_messages.push(message);
-                //     continue;
-                // };
-                // TODO: Validate that the message matches our sign_queue
-                match signature_manager.get_or_generate(
-                    participants,
-                    *receipt_id,
+                match sign_queue.check_proposal(
+                    receipt_id,
                     message.proposer,
-                    message.presignature_id,
-                    message.request.clone(),
-                    message.epsilon,
-                    message.delta,
-                    &mut presignature_manager,
-                )? {
-                    Some(protocol) => protocol.message(message.from, message.data),
+                    &message.request,
+                    &message.epsilon,
+                    &message.delta,
+                    message.block_height,
+                ) {
+                    ProposalCheck::Verified => {
+                        unindexed_signatures.remove(receipt_id);
+                    }
+                    ProposalCheck::Unknown => {
+                        let since = unindexed_signatures
+                            .entry(*receipt_id)
+                            .or_insert_with(Instant::now);
+                        if since.elapsed() < crate::types::PROTOCOL_SIGNATURE_UNINDEXED_TIMEOUT {
+                            // Wait for our own indexer to catch up with the request.
+                            leftover_messages.push(message);
+                        } else {
+                            tracing::warn!(
+                                %receipt_id,
+                                proposer = ?message.proposer,
+                                "dropping signature message: request was never indexed"
+                            );
+                        }
+                        continue;
+                    }
+                    ProposalCheck::Mismatch(field) => {
+                        tracing::warn!(
+                            %receipt_id,
+                            proposer = ?message.proposer,
+                            from = ?message.from,
+                            field,
+                            "dropping signature message: proposal does not match the indexed request"
+                        );
+                        continue;
+                    }
+                }
+                // Of two proposers that both think it is their turn, the later one takes over.
+                if let Some(ongoing) = signature_manager.proposer_of(receipt_id) {
+                    if ongoing != message.proposer {
+                        if sign_queue.turn_of(receipt_id, message.proposer)
+                            > sign_queue.turn_of(receipt_id, ongoing)
+                        {
+                            signature_manager.hand_off(&[(*receipt_id, message.proposer)]);
+                        } else {
+                            tracing::debug!(
+                                %receipt_id,
+                                ?ongoing,
+                                proposer = ?message.proposer,
+                                "dropping signature message: a later proposer took over"
+                            );
+                            continue;
+                        }
+                    }
+                }
+                let signers = if message.participants.is_empty() {
+                    // Proposers that predate picking the signers sign with everyone active.
+                    participants.keys_vec()
+                } else if !message.participants.contains(&signature_manager.me()) {
+                    tracing::warn!(
+                        %receipt_id,
+                        proposer = ?message.proposer,
+                        participants = ?message.participants,
+                        "dropping signature message: we were not picked to sign"
+                    );
+                    continue;
+                } else {
+                    // Only the pick every node computes from the request and the presignature is
+                    // joined. Once the presignature is taken, the pick was checked already.
+                    if let Some(holders) = presignature_manager.holders(message.presignature_id) {
+                        let expected = select_signers(
+                            signers_seed(receipt_id, &message.delta, message.presignature_id),
+                            message.proposer,
+                            &self.participants,
+                            holders,
+                            &message.slow,
+                            self.threshold,
+                        );
+                        if expected.as_ref() != Some(&message.participants) {
+                            tracing::warn!(
+                                %receipt_id,
+                                proposer = ?message.proposer,
+                                participants = ?message.participants,
+                                slow = ?message.slow,
+                                ?expected,
+                                "dropping signature message: proposer picked invalid signers"
+                            );
+                            continue;
+                        }
+                    }
+                    message.participants.clone()
+                };
+                let generator = match signature_manager
+                    .get_or_generate(
+                        &signers,
+                        &message.slow,
+                        *receipt_id,
+                        message.proposer,
+                        message.presignature_id,
+                        message.request.clone(),
+                        message.epsilon,
+                        message.delta,
+                        message.block_height,
+                        &mut presignature_manager,
+                        ctx.compute_pool(),
+                    )
+                    .await
+                {
+                    Ok(generator) => generator,
+                    Err(err) => {
+                        tracing::warn!(%receipt_id, ?err, "unable to initialize incoming signature protocol");
+                        continue;
+                    }
+                };
+                match generator {
+                    Some(generator) => {
+                        progressing.push((*receipt_id, message.proposer));
+                        generator.message(message.from, message.data)
+                    }
                     None => {
                         // Store the message until we are ready to process it
                         leftover_messages.push(message)
@@ -410,9 +567,28 @@ impl MessageHandler for RunningState {
                 queue.extend(leftover_messages);
             }
         }
+        // Forgotten once the messages of the proposal would have timed out, after which messages
+        // for a request that is still not indexed get to wait once more.
+        unindexed_signatures
+            .retain(|_, since| since.elapsed() < crate::types::PROTOCOL_SIGNATURE_TIMEOUT);
         triple_manager.clear_failed_triples();
         triple_manager.clear_taken();
         presignature_manager.clear_taken();
+        drop(signature_manager);
+        drop(sign_queue);
+        drop(presignature_manager);
+        drop(triple_manager);
+
+        // Keeps the current proposers of these requests from being taken over, and has the
+        // previous ones stop once a later proposer took over.
+        let mut sign_queue = self.sign_queue.write().await;
+        let handed_off = progressing
+            .into_iter()
+            .filter_map(|(receipt_id, proposer)| sign_queue.observe_progress(&receipt_id, proposer))
+            .collect::<Vec<_>>();
+        if !handed_off.is_empty() {
+            self.signature_manager.write().await.hand_off(&handed_off);
+        }
         Ok(())
     }
 }
@@ -442,21 +618,116 @@ impl MessageHandler for NodeState {
 pub struct SignedMessage<T> {
     /// The message with all it's related info.
     pub msg: T,
-    /// The signature used to verify the authenticity of the encrypted message.
+    /// The signature used to verify the authenticity of the encrypted message, which also covers
+    /// the [`MessageHeader`] it was sent with.
     pub sig: Signature,
     /// From which particpant the message was sent.
     pub from: Participant,
 }
 
-impl<T> SignedMessage<T> {
-    pub const ASSOCIATED_DATA: &'static [u8] = b"";
+/// Sent in the clear along with an encrypted message and bound to it as associated data, so that
+/// the receiver can reject replayed messages before handling them.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MessageHeader {
+    pub from: Participant,
+    /// Epoch of the sender when the message was sent.
+    pub epoch: u64,
+    /// Picked at random whenever the sender starts, so that its sequence numbers do not have to
+    /// survive restarts.
+    pub session: u64,
+    /// Counts the messages sent to the same participant within the session, starting from one.
+    pub seq: u64,
+    /// Unix time in seconds at which the message was encrypted. Messages are encrypted again
+    /// whenever they are resent, so this bounds how long a captured message can be replayed.
+    pub sent_at: u64,
+}
+
+impl MessageHeader {
+    /// Associated data of a message with this header, which also binds it to the recipient.
+    fn associated_data(&self, to: &hpke::PublicKey) -> Vec<u8> {
+        let mut aad = b"multichain signed message".to_vec();
+        aad.extend_from_slice(&u32::from(self.from).to_be_bytes());
+        aad.extend_from_slice(&to.to_bytes());
+        aad.extend_from_slice(&self.epoch.to_be_bytes());
+        aad.extend_from_slice(&self.session.to_be_bytes());
+        aad.extend_from_slice(&self.seq.to_be_bytes());
+        aad.extend_from_slice(&self.sent_at.to_be_bytes());
+        aad
+    }
+}
+
+/// An encrypted [`SignedMessage`] as sent over the wire.
+#[derive(Serialize, Deserialize)]
+pub struct SealedMessage {
+    pub header: MessageHeader,
+    pub ciphered: Ciphered,
+}
+
+/// Body of the `/msg` endpoint, which also takes the bare ciphertexts sent by nodes that predate
+/// [`SealedMessage`], so that old and new nodes keep talking to each other during an upgrade.
+#[derive(Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum WireMessage {
+    Sealed(SealedMessage),
+    Legacy(Ciphered),
+}
+
+impl WireMessage {
+    pub fn ciphered(&self) -> &Ciphered {
+        match self {
+            WireMessage::Sealed(sealed) => &sealed.ciphered,
+            WireMessage::Legacy(ciphered) => ciphered,
+        }
+    }
+}
+
+impl fmt::Debug for WireMessage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            WireMessage::Sealed(sealed) => sealed.fmt(f),
+            WireMessage::Legacy(_) => f.debug_struct("Legacy").finish_non_exhaustive(),
+        }
+    }
+}
+
+impl fmt::Debug for SealedMessage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SealedMessage")
+            .field("header", &self.header)
+            .finish_non_exhaustive()
+    }
 }
 
 impl<T> SignedMessage<T>
 where
     T: Serialize,
 {
+    /// Encrypts `msg` in `format`, which the recipient has to be able to decode.
     pub fn encrypt(
+        msg: &T,
+        header: MessageHeader,
+        format: MessageFormat,
+        sign_sk: &near_crypto::SecretKey,
+        cipher_pk: &hpke::PublicKey,
+    ) -> Result<SealedMessage, CryptographicError> {
+        let aad = header.associated_data(cipher_pk);
+        let msg = format.encode(msg)?;
+        let sig = sign_sk.sign(&[aad.as_slice(), &msg].concat());
+        let msg = SignedMessage {
+            msg: ByteBuf::from(msg),
+            sig,
+            from: header.from,
+        };
+        let msg = format.encode(&msg)?;
+        let ciphered = cipher_pk
+            .encrypt(&msg, &aad)
+            .map_err(|e| CryptographicError::Encryption(e.to_string()))?;
+        Ok(SealedMessage { header, ciphered })
+    }
+
+    /// Encrypts `msg` the way nodes that predate [`SealedMessage`] expect it: JSON without any
+    /// associated data, signing only the message itself.
+    pub fn encrypt_legacy(
         msg: &T,
         from: Participant,
         sign_sk: &near_crypto::SecretKey,
@@ -466,10 +737,9 @@ where
         let sig = sign_sk.sign(&msg);
         let msg = SignedMessage { msg, sig, from };
         let msg = serde_json::to_vec(&msg)?;
-        let ciphered = cipher_pk
-            .encrypt(&msg, SignedMessage::<T>::ASSOCIATED_DATA)
-            .map_err(|e| CryptographicError::Encryption(e.to_string()))?;
-        Ok(ciphered)
+        cipher_pk
+            .encrypt(&msg, b"")
+            .map_err(|e| CryptographicError::Encryption(e.to_string()))
     }
 }
 
@@ -477,30 +747,344 @@ impl<T> SignedMessage<T>
 where
     T: for<'a> Deserialize<'a>,
 {
+    /// Decrypts and verifies a message, rejecting it if it was seen before or was sent for an
+    /// epoch too far from ours.
     pub async fn decrypt(
         cipher_sk: &hpke::SecretKey,
         protocol_state: &Arc<RwLock<NodeState>>,
-        encrypted: Ciphered,
+        replay_guard: &ReplayGuard,
+        sealed: SealedMessage,
     ) -> Result<T, CryptographicError> {
+        let SealedMessage { header, ciphered } = sealed;
+        let aad = header.associated_data(&cipher_sk.public_key());
         let message = cipher_sk
-            .decrypt(&encrypted, SignedMessage::<T>::ASSOCIATED_DATA)
+            .decrypt(&ciphered, &aad)
+            .map_err(|err| CryptographicError::Encryption(err.to_string()))?;
+        let SignedMessage::<ByteBuf> { msg, sig, from } = MessageFormat::decode(&message)?;
+        if from != header.from {
+            return Err(CryptographicError::Encryption(
+                "sender of the encrypted protocol message does not match its header".to_string(),
+            ));
+        }
+
+        let state = protocol_state.read().await;
+        let sender = state.fetch_participant(&from)?;
+        if !sig.verify(&[aad.as_slice(), msg.as_slice()].concat(), &sender.sign_pk) {
+            tracing::error!(from = ?from, "signed message erred out with invalid signature");
+            return Err(CryptographicError::Encryption(
+                "invalid signature while verifying authenticity of encrypted protocol message"
+                    .to_string(),
+            ));
+        }
+        if let Err(reason) = replay_guard.check(&header, state.epoch()) {
+            crate::metrics::NUM_MESSAGES_REJECTED
+                .with_label_values(&[sender.account_id.as_str(), reason.as_str()])
+                .inc();
+            return Err(CryptographicError::Replayed(reason));
+        }
+        drop(state);
+
+        Ok(MessageFormat::decode(&msg)?)
+    }
+
+    /// Decrypts and verifies a message from a node that predates [`SealedMessage`]. These carry
+    /// no sequence number to check, so they are only taken from participants that have not sent
+    /// a sealed message yet.
+    pub async fn decrypt_legacy(
+        cipher_sk: &hpke::SecretKey,
+        protocol_state: &Arc<RwLock<NodeState>>,
+        replay_guard: &ReplayGuard,
+        ciphered: Ciphered,
+    ) -> Result<T, CryptographicError> {
+        let message = cipher_sk
+            .decrypt(&ciphered, b"")
             .map_err(|err| CryptographicError::Encryption(err.to_string()))?;
         let SignedMessage::<Vec<u8>> { msg, sig, from } = serde_json::from_slice(&message)?;
-        if !sig.verify(
-            &msg,
-            &protocol_state
-                .read()
-                .await
-                .fetch_participant(&from)?
-                .sign_pk,
-        ) {
+
+        let state = protocol_state.read().await;
+        let sender = state.fetch_participant(&from)?;
+        if !sig.verify(&msg, &sender.sign_pk) {
             tracing::error!(from = ?from, "signed message erred out with invalid signature");
             return Err(CryptographicError::Encryption(
                 "invalid signature while verifying authenticity of encrypted protocol message"
                     .to_string(),
             ));
         }
+        if replay_guard.is_upgraded(&from) {
+            let reason = ReplayRejection::Legacy;
+            crate::metrics::NUM_MESSAGES_REJECTED
+                .with_label_values(&[sender.account_id.as_str(), reason.as_str()])
+                .inc();
+            return Err(CryptographicError::Replayed(reason));
+        }
+        drop(state);
 
         Ok(serde_json::from_slice(&msg)?)
     }
 }
+
+/// Messages from a participant are accepted with a sequence number at most this far behind the
+/// highest one seen from it, so that slightly reordered messages are not rejected.
+const REPLAY_WINDOW: u64 = 4096;
+
+/// Messages sent longer ago than this are rejected as replays. They only get that old by being
+/// captured, as the sender encrypts them again on every attempt.
+const MESSAGE_MAX_AGE_SECS: u64 = 60;
+
+/// Clock skew tolerated between participants.
+const MAX_CLOCK_SKEW_SECS: u64 = 10;
+
+fn unix_now() -> u64 {
+    Utc::now().timestamp() as u64
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReplayRejection {
+    Duplicate,
+    OutOfWindow,
+    Epoch,
+    /// Legacy message from a participant that already sent sealed ones.
+    Legacy,
+    /// Sent too long ago, or before this node started and lost track of the sequence numbers.
+    Stale,
+}
+
+impl ReplayRejection {
+    pub const fn as_str(&self) -> &'static str {
+        match self {
+            ReplayRejection::Duplicate => "duplicate",
+            ReplayRejection::OutOfWindow => "out_of_window",
+            ReplayRejection::Epoch => "epoch",
+            ReplayRejection::Legacy => "legacy",
+            ReplayRejection::Stale => "stale",
+        }
+    }
+}
+
+impl fmt::Display for ReplayRejection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+#[derive(Default)]
+struct SeqWindow {
+    highest: u64,
+    /// Sequence numbers seen within the window below `highest`.
+    seen: BTreeSet<u64>,
+}
+
+/// Sequence numbers recently received from each participant, used to reject replayed messages.
+///
+/// Every session of a sender gets a window of its own, which is kept for as long as the epoch it
+/// was used in is accepted. Replays of a session that ended are rejected by its window as well.
+/// The windows are lost on restart, so messages sent before the guard started are rejected by
+/// their signed send time instead.
+pub struct ReplayGuard {
+    windows: Mutex<HashMap<(Participant, u64, u64), SeqWindow>>,
+    /// Participants that sent sealed messages or serve the message stream, whose legacy
+    /// messages are no longer accepted.
+    upgraded: Mutex<HashSet<Participant>>,
+    /// Unix time in seconds at which the guard started tracking sequence numbers.
+    started_at: u64,
+}
+
+impl Default for ReplayGuard {
+    fn default() -> Self {
+        Self {
+            windows: Mutex::default(),
+            upgraded: Mutex::default(),
+            started_at: unix_now(),
+        }
+    }
+}
+
+impl ReplayGuard {
+    /// Records the message with `header`, unless it was seen before, fell out of the window, was
+    /// not sent recently, or was sent for an epoch other than `epoch` or its neighbours, which
+    /// are in use while the participants transition between epochs.
+    pub fn check(&self, header: &MessageHeader, epoch: Option<u64>) -> Result<(), ReplayRejection> {
+        if epoch.is_some_and(|epoch| header.epoch.abs_diff(epoch) > 1) {
+            return Err(ReplayRejection::Epoch);
+        }
+        let now = unix_now();
+        if header.sent_at + MESSAGE_MAX_AGE_SECS < now
+            || header.sent_at > now + MAX_CLOCK_SKEW_SECS
+            || header.sent_at + MAX_CLOCK_SKEW_SECS < self.started_at
+        {
+            return Err(ReplayRejection::Stale);
+        }
+        self.upgraded.lock().unwrap().insert(header.from);
+
+        let mut windows = self.windows.lock().unwrap();
+        if let Some(epoch) = epoch {
+            windows.retain(|(_, window_epoch, _), _| window_epoch.abs_diff(epoch) <= 1);
+        }
+        let window = windows
+            .entry((header.from, header.epoch, header.session))
+            .or_default();
+        if header.seq + REPLAY_WINDOW <= window.highest {
+            return Err(ReplayRejection::OutOfWindow);
+        }
+        if !window.seen.insert(header.seq) {
+            return Err(ReplayRejection::Duplicate);
+        }
+        if header.seq > window.highest {
+            window.highest = header.seq;
+            let floor = window.highest.saturating_sub(REPLAY_WINDOW);
+            window.seen = window.seen.split_off(&floor);
+        }
+        Ok(())
+    }
+
+    pub fn is_upgraded(&self, from: &Participant) -> bool {
+        self.upgraded.lock().unwrap().contains(from)
+    }
+
+    /// Records that `participant` runs a node that sends sealed messages, having found it to
+    /// serve the message stream, so that legacy messages claiming to be from it are rejected
+    /// even before it sent a sealed one.
+    pub fn mark_upgraded(&self, participant: Participant) {
+        self.upgraded.lock().unwrap().insert(participant);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        unix_now, GeneratingMessage, MessageHeader, ReplayGuard, ReplayRejection, SignedMessage,
+        TripleMessage, WireMessage, MAX_CLOCK_SKEW_SECS, MESSAGE_MAX_AGE_SECS, REPLAY_WINDOW,
+    };
+    use crate::protocol::encoding::MessageFormat;
+    use crate::protocol::MpcMessage;
+    use cait_sith::protocol::Participant;
+
+    #[test]
+    fn test_triple_message_from_before_batching() {
+        let legacy = serde_json::json!({
+            "id": 5,
+            "epoch": 1,
+            "from": Participant::from(2),
+            "data": [1, 2, 3],
+            "timestamp": 0,
+        });
+        let msg: TripleMessage = serde_json::from_value(legacy.clone()).unwrap();
+        assert_eq!(msg.batch_id, 5);
+        assert_eq!(msg.batch_size, 1);
+
+        // Nodes that predate batching find the triple id where they expect it.
+        let sent = serde_json::to_value(&msg).unwrap();
+        assert_eq!(sent["id"], legacy["id"]);
+        assert!(sent.get("batch_id").is_none());
+    }
+
+    #[test]
+    fn test_wire_message_accepts_legacy_and_sealed() {
+        let (_, cipher_pk) = mpc_keys::hpke::generate();
+        let sign_sk = near_crypto::SecretKey::from_random(near_crypto::KeyType::ED25519);
+        let from = Participant::from(0);
+        let msg = MpcMessage::Generating(GeneratingMessage {
+            from,
+            data: vec![1, 2, 3],
+        });
+
+        let legacy = SignedMessage::encrypt_legacy(&msg, from, &sign_sk, &cipher_pk).unwrap();
+        let legacy = serde_json::to_vec(&vec![legacy]).unwrap();
+        let legacy: Vec<WireMessage> = serde_json::from_slice(&legacy).unwrap();
+        assert!(matches!(legacy[..], [WireMessage::Legacy(_)]));
+
+        let header = MessageHeader {
+            from,
+            epoch: 0,
+            session: 1,
+            seq: 1,
+            sent_at: unix_now(),
+        };
+        let sealed =
+            SignedMessage::encrypt(&msg, header, MessageFormat::default(), &sign_sk, &cipher_pk)
+                .unwrap();
+        let sealed = serde_json::to_vec(&vec![WireMessage::Sealed(sealed)]).unwrap();
+        let sealed: Vec<WireMessage> = serde_json::from_slice(&sealed).unwrap();
+        assert!(matches!(&sealed[..], [WireMessage::Sealed(sealed)] if sealed.header == header));
+    }
+
+    #[test]
+    fn test_replay_guard() {
+        let guard = ReplayGuard::default();
+        let header = |epoch, seq| MessageHeader {
+            from: Participant::from(1),
+            epoch,
+            session: 7,
+            seq,
+            sent_at: unix_now(),
+        };
+
+        assert_eq!(guard.check(&header(3, 10), Some(3)), Ok(()));
+        assert_eq!(guard.check(&header(3, 8), Some(3)), Ok(()));
+        assert_eq!(
+            guard.check(&header(3, 10), Some(3)),
+            Err(ReplayRejection::Duplicate)
+        );
+        assert_eq!(
+            guard.check(&header(1, 11), Some(3)),
+            Err(ReplayRejection::Epoch)
+        );
+        assert_eq!(guard.check(&header(4, 10 + REPLAY_WINDOW), Some(3)), Ok(()));
+        assert_eq!(
+            guard.check(&header(4, 9), Some(3)),
+            Err(ReplayRejection::OutOfWindow)
+        );
+
+        // A restarted sender counts from one again, which only its old session has seen.
+        let restarted = MessageHeader {
+            session: 8,
+            ..header(4, 1)
+        };
+        assert_eq!(guard.check(&restarted, Some(4)), Ok(()));
+        assert_eq!(
+            guard.check(&restarted, Some(4)),
+            Err(ReplayRejection::Duplicate)
+        );
+        assert_eq!(
+            guard.check(&header(4, 9), Some(4)),
+            Err(ReplayRejection::OutOfWindow)
+        );
+        assert!(guard.is_upgraded(&Participant::from(1)));
+        assert!(!guard.is_upgraded(&Participant::from(2)));
+        guard.mark_upgraded(Participant::from(2));
+        assert!(guard.is_upgraded(&Participant::from(2)));
+    }
+
+    #[test]
+    fn test_replay_guard_rejects_stale_messages() {
+        let guard = ReplayGuard::default();
+        let header = |seq, sent_at| MessageHeader {
+            from: Participant::from(1),
+            epoch: 0,
+            session: 7,
+            seq,
+            sent_at,
+        };
+        let now = unix_now();
+
+        assert_eq!(guard.check(&header(1, now), Some(0)), Ok(()));
+        assert_eq!(
+            guard.check(&header(2, now - MESSAGE_MAX_AGE_SECS - 1), Some(0)),
+            Err(ReplayRejection::Stale)
+        );
+        assert_eq!(
+            guard.check(&header(3, now + MAX_CLOCK_SKEW_SECS + 5), Some(0)),
+            Err(ReplayRejection::Stale)
+        );
+
+        // After a restart, messages sent before it are rejected even though no window saw them.
+        let restarted = ReplayGuard {
+            started_at: now + MAX_CLOCK_SKEW_SECS + 1,
+            ..ReplayGuard::default()
+        };
+        assert_eq!(
+            restarted.check(&header(1, now), Some(0)),
+            Err(ReplayRejection::Stale)
+        );
+    }
+}
@@ -1,5 +1,7 @@
+pub mod compute;
 pub mod contract;
 mod cryptography;
+pub mod encoding;
 pub mod presignature;
 mod signature;
 pub mod triple;
@@ -17,34 +19,84 @@ pub use signature::SignQueue;
 pub use signature::SignRequest;
 pub use state::NodeState;
 
+use self::compute::ComputePool;
 use self::consensus::ConsensusCtx;
 use self::cryptography::CryptographicCtx;
 use self::message::MessageCtx;
 use self::presignature::PresignatureConfig;
 use self::triple::TripleConfig;
+use crate::mesh::connection::{LivenessView, Pool, SlowView};
 use crate::mesh::{Mesh, NetworkConfig};
 use crate::protocol::consensus::ConsensusProtocol;
 use crate::protocol::cryptography::CryptographicProtocol;
-use crate::protocol::message::{MessageHandler, MpcMessageQueue};
+use crate::protocol::message::{MessageHandler, MpcMessageQueue, ReplayGuard};
 use crate::rpc_client;
+use crate::storage::presignature_storage::LockPresignatureNodeStorageBox;
 use crate::storage::secret_storage::SecretNodeStorageBox;
+use crate::storage::sign_request_storage::LockSignRequestNodeStorageBox;
 use crate::storage::triple_storage::LockTripleNodeStorageBox;
+use crate::tls::NodeTls;
+use crate::transport::Transport;
 
 use cait_sith::protocol::Participant;
 use near_account_id::AccountId;
 use near_crypto::InMemorySigner;
 use reqwest::IntoUrl;
-use std::time::Instant;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
 use std::{sync::Arc, time::Duration};
 use tokio::sync::mpsc::{self, error::TryRecvError};
-use tokio::sync::RwLock;
+use tokio::sync::{watch, RwLock};
 use url::Url;
 
+/// How often the contract state is fetched.
+const CONTRACT_STATE_REFRESH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How often participants are pinged to find out who is alive.
+const PING_INTERVAL: Duration = Duration::from_millis(300);
+
 #[derive(Clone, Debug)]
 pub struct Config {
     pub triple_cfg: TripleConfig,
     pub presig_cfg: PresignatureConfig,
     pub network_cfg: NetworkConfig,
+    /// Number of threads to run the triple and presignature protocols on.
+    pub compute_threads: usize,
+    /// How long the contract has to be running an epoch before the key shares of earlier epochs
+    /// are removed.
+    pub key_share_retention: Duration,
+    /// How often to vote for refreshing the key shares among the same participants, if at all.
+    pub refresh_interval: Option<Duration>,
+}
+
+impl Config {
+    /// Whether it is time to vote for refreshing the key shares of an epoch that started running
+    /// at the block timestamp `epoch_started_at`, in nanoseconds. Contracts that do not track
+    /// when the epoch started are never voted to refresh.
+    pub fn refresh_due(&self, epoch_started_at: u64) -> bool {
+        let Some(interval) = self.refresh_interval else {
+            return false;
+        };
+        epoch_age(epoch_started_at).is_some_and(|age| age >= interval)
+    }
+
+    /// Whether the key shares of earlier epochs can be removed, the epoch having started running
+    /// at the block timestamp `epoch_started_at`. With contracts that do not track when the epoch
+    /// started they are kept.
+    pub fn prune_due(&self, epoch_started_at: u64) -> bool {
+        epoch_age(epoch_started_at).is_some_and(|age| age >= self.key_share_retention)
+    }
+}
+
+/// Time since the block timestamp `epoch_started_at`, in nanoseconds, or `None` if the contract
+/// does not track it.
+fn epoch_age(epoch_started_at: u64) -> Option<Duration> {
+    if epoch_started_at == 0 {
+        return None;
+    }
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    Some(now.saturating_sub(Duration::from_nanos(epoch_started_at)))
 }
 
 struct Ctx {
@@ -54,11 +106,15 @@ struct Ctx {
     signer: InMemorySigner,
     rpc_client: near_fetch::Client,
     http_client: reqwest::Client,
+    transport: Transport,
     sign_queue: Arc<RwLock<SignQueue>>,
     secret_storage: SecretNodeStorageBox,
     triple_storage: LockTripleNodeStorageBox,
+    presignature_storage: LockPresignatureNodeStorageBox,
+    sign_request_storage: LockSignRequestNodeStorageBox,
     cfg: Config,
     mesh: Mesh,
+    compute_pool: ComputePool,
 }
 
 impl ConsensusCtx for &mut MpcSignProtocol {
@@ -90,8 +146,8 @@ impl ConsensusCtx for &mut MpcSignProtocol {
         self.ctx.sign_queue.clone()
     }
 
-    fn secret_storage(&self) -> &SecretNodeStorageBox {
-        &self.ctx.secret_storage
+    fn secret_storage(&mut self) -> &mut SecretNodeStorageBox {
+        &mut self.ctx.secret_storage
     }
 
     fn cfg(&self) -> &Config {
@@ -101,6 +157,10 @@ impl ConsensusCtx for &mut MpcSignProtocol {
     fn triple_storage(&self) -> LockTripleNodeStorageBox {
         self.ctx.triple_storage.clone()
     }
+
+    fn presignature_storage(&self) -> LockPresignatureNodeStorageBox {
+        self.ctx.presignature_storage.clone()
+    }
 }
 
 #[async_trait::async_trait]
@@ -109,8 +169,8 @@ impl CryptographicCtx for &mut MpcSignProtocol {
         get_my_participant(self).await
     }
 
-    fn http_client(&self) -> &reqwest::Client {
-        &self.ctx.http_client
+    fn transport(&self) -> &Transport {
+        &self.ctx.transport
     }
 
     fn rpc_client(&self) -> &near_fetch::Client {
@@ -129,6 +189,10 @@ impl CryptographicCtx for &mut MpcSignProtocol {
         &mut self.ctx.secret_storage
     }
 
+    fn sign_request_storage(&self) -> &LockSignRequestNodeStorageBox {
+        &self.ctx.sign_request_storage
+    }
+
     fn cfg(&self) -> &Config {
         &self.ctx.cfg
     }
@@ -136,6 +200,10 @@ impl CryptographicCtx for &mut MpcSignProtocol {
     fn mesh(&self) -> &Mesh {
         &self.ctx.mesh
     }
+
+    fn compute_pool(&self) -> &ComputePool {
+        &self.ctx.compute_pool
+    }
 }
 
 #[async_trait::async_trait]
@@ -147,6 +215,10 @@ impl MessageCtx for &MpcSignProtocol {
     fn mesh(&self) -> &Mesh {
         &self.ctx.mesh
     }
+
+    fn compute_pool(&self) -> &ComputePool {
+        &self.ctx.compute_pool
+    }
 }
 
 pub struct MpcSignProtocol {
@@ -167,21 +239,39 @@ impl MpcSignProtocol {
         sign_queue: Arc<RwLock<SignQueue>>,
         secret_storage: SecretNodeStorageBox,
         triple_storage: LockTripleNodeStorageBox,
+        presignature_storage: LockPresignatureNodeStorageBox,
+        sign_request_storage: LockSignRequestNodeStorageBox,
         cfg: Config,
     ) -> (Self, Arc<RwLock<NodeState>>) {
         let state = Arc::new(RwLock::new(NodeState::Starting));
+        let compute_pool = ComputePool::new(cfg.compute_threads, &account_id);
+        let tls = cfg.network_cfg.tls.clone();
+        let http_client = tls
+            .as_ref()
+            .map_or_else(reqwest::Client::new, NodeTls::http_client);
+        let trusted_peers = tls.as_ref().map(NodeTls::trusted_peers).unwrap_or_default();
         let ctx = Ctx {
             my_address: my_address.into_url().unwrap(),
             account_id,
             mpc_contract_id,
             rpc_client,
-            http_client: reqwest::Client::new(),
+            transport: Transport::new(
+                http_client.clone(),
+                tls.as_ref().map(NodeTls::client_config),
+            ),
+            http_client,
             sign_queue,
             signer,
             secret_storage,
             triple_storage,
+            presignature_storage,
+            sign_request_storage,
             cfg,
-            mesh: Mesh::default(),
+            mesh: Mesh {
+                connections: Pool::new(http_client.clone(), trusted_peers),
+                ..Default::default()
+            },
+            compute_pool,
         };
         let protocol = MpcSignProtocol {
             ctx,
@@ -191,6 +281,21 @@ impl MpcSignProtocol {
         (protocol, state)
     }
 
+    /// Participants this node measures as slow, to be served to the other participants.
+    pub fn slow_view(&self) -> SlowView {
+        self.ctx.mesh.connections.slow_view()
+    }
+
+    /// Liveness of the participants as measured by this node, to be served on `/state`.
+    pub fn liveness_view(&self) -> LivenessView {
+        self.ctx.mesh.connections.liveness_view()
+    }
+
+    /// Guard against replayed messages, to check the messages received from the participants.
+    pub fn replay_guard(&self) -> Arc<ReplayGuard> {
+        self.ctx.transport.replay_guard()
+    }
+
     pub async fn run(mut self) -> anyhow::Result<()> {
         let my_account_id = self.ctx.account_id.to_string();
         let _span = tracing::info_span!("running", my_account_id);
@@ -201,8 +306,14 @@ impl MpcSignProtocol {
             .with_label_values(&[my_account_id.as_str()])
             .set(node_version());
         let mut queue = MpcMessageQueue::default();
-        let mut last_state_update = Instant::now();
         let mut last_pinged = Instant::now();
+        let mut contract_states = watch_contract_state(
+            near_fetch::Client::new(&self.ctx.rpc_client.rpc_addr()),
+            self.ctx.mpc_contract_id.clone(),
+        );
+        let mut next_contract_state = None;
+        let new_sign_requests = self.ctx.sign_queue.read().await.notifier();
+        let protocol_progress = self.ctx.compute_pool.notifier();
         loop {
             let protocol_time = Instant::now();
             tracing::debug!("trying to advance mpc recovery protocol");
@@ -224,34 +335,23 @@ impl MpcSignProtocol {
                 }
             }
 
-            let contract_state = if last_state_update.elapsed() > Duration::from_secs(1) {
-                let contract_state = match rpc_client::fetch_mpc_contract_state(
-                    &self.ctx.rpc_client,
-                    &self.ctx.mpc_contract_id,
-                )
-                .await
-                {
-                    Ok(contract_state) => contract_state,
-                    Err(e) => {
-                        tracing::error!("could not fetch contract's state: {e}");
-                        tokio::time::sleep(Duration::from_secs(1)).await;
-                        continue;
-                    }
-                };
+            let contract_state = next_contract_state.take().or_else(|| {
+                if contract_states.has_changed().unwrap_or(false) {
+                    contract_states.borrow_and_update().clone()
+                } else {
+                    None
+                }
+            });
+            if let Some(contract_state) = &contract_state {
                 tracing::debug!(?contract_state);
 
                 // Establish the participants for this current iteration of the protocol loop. This will
                 // set which participants are currently active in the protocol and determines who will be
                 // receiving messages.
-                self.ctx.mesh.establish_participants(&contract_state).await;
-
-                last_state_update = Instant::now();
-                Some(contract_state)
-            } else {
-                None
-            };
+                self.ctx.mesh.establish_participants(contract_state).await;
+            }
 
-            if last_pinged.elapsed() > Duration::from_millis(300) {
+            if last_pinged.elapsed() > PING_INTERVAL {
                 self.ctx.mesh.ping().await;
                 last_pinged = Instant::now();
             }
@@ -299,7 +399,9 @@ impl MpcSignProtocol {
                 .with_label_values(&[my_account_id.as_str()])
                 .observe(message_time.elapsed().as_secs_f64());
 
-            let sleep_ms = match state {
+            // Upper bound on how long to wait for an event before progressing again, for the
+            // protocols that advance on their own such as starting new generators.
+            let idle_ms = match state {
                 NodeState::Generating(_) => 500,
                 NodeState::Resharing(_) => 500,
                 NodeState::Running(_) => 100,
@@ -317,11 +419,65 @@ impl MpcSignProtocol {
             crate::metrics::PROTOCOL_LATENCY_ITER_TOTAL
                 .with_label_values(&[my_account_id.as_str()])
                 .observe(protocol_time.elapsed().as_secs_f64());
-            tokio::time::sleep(Duration::from_millis(sleep_ms)).await;
+
+            // Wake up as soon as there is something to act upon instead of sleeping it out.
+            tokio::select! {
+                msg = self.receiver.recv() => match msg {
+                    Some(msg) => {
+                        tracing::debug!("woken up by a new message");
+                        queue.push(msg);
+                    }
+                    None => {
+                        tracing::debug!("communication was disconnected, no more messages will be received, spinning down");
+                        return Ok(());
+                    }
+                },
+                Ok(()) = contract_states.changed() => {
+                    tracing::debug!("woken up by a new contract state");
+                    next_contract_state = contract_states.borrow_and_update().clone();
+                }
+                _ = new_sign_requests.notified() => {
+                    tracing::debug!("woken up by a new sign request");
+                }
+                _ = protocol_progress.notified() => {
+                    tracing::debug!("woken up by a protocol task");
+                }
+                _ = tokio::time::sleep(Duration::from_millis(idle_ms)) => {}
+            }
         }
     }
 }
 
+/// Fetches the contract state in the background every `CONTRACT_STATE_REFRESH_INTERVAL`, so that
+/// a slow RPC does not hold up the protocol. Only the latest state is kept, so states that are not
+/// picked up in time are replaced by the newer ones.
+fn watch_contract_state(
+    rpc_client: near_fetch::Client,
+    mpc_contract_id: AccountId,
+) -> watch::Receiver<Option<ProtocolState>> {
+    let (sender, receiver) = watch::channel(None);
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(CONTRACT_STATE_REFRESH_INTERVAL);
+        interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        loop {
+            interval.tick().await;
+            let contract_state =
+                match rpc_client::fetch_mpc_contract_state(&rpc_client, &mpc_contract_id).await {
+                    Ok(contract_state) => contract_state,
+                    Err(e) => {
+                        tracing::error!("could not fetch contract's state: {e}");
+                        continue;
+                    }
+                };
+            if sender.send(Some(contract_state)).is_err() {
+                // The protocol stopped.
+                break;
+            }
+        }
+    });
+    receiver
+}
+
 async fn get_my_participant(protocol: &MpcSignProtocol) -> Participant {
     let my_near_acc_id = &protocol.ctx.account_id;
     let state = protocol.state.read().await;
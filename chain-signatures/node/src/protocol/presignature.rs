@@ -1,17 +1,21 @@
+use super::compute::{ComputePool, ProtocolTask};
 use super::message::PresignatureMessage;
 use super::triple::{Triple, TripleConfig, TripleId, TripleManager};
+use crate::gcp::error;
 use crate::protocol::contract::primitives::Participants;
+use crate::storage::presignature_storage::{LockPresignatureNodeStorageBox, PresignatureData};
 use crate::types::{PresignatureProtocol, SecretKeyShare};
 use crate::util::AffinePointExt;
 
-use cait_sith::protocol::{Action, InitializationError, Participant, ProtocolError};
+use cait_sith::protocol::{Action, InitializationError, MessageData, Participant};
 use cait_sith::{KeygenOutput, PresignArguments, PresignOutput};
 use chrono::Utc;
 use crypto_shared::PublicKey;
 use k256::Secp256k1;
 use std::collections::hash_map::Entry;
 use std::collections::{HashMap, HashSet, VecDeque};
-use std::time::Instant;
+use std::fmt;
+use std::time::{Duration, Instant};
 
 use near_account_id::AccountId;
 
@@ -21,22 +25,32 @@ use near_account_id::AccountId;
 pub type PresignatureId = u64;
 
 /// A completed presignature.
+#[derive(Clone)]
 pub struct Presignature {
     pub id: PresignatureId,
     pub output: PresignOutput<Secp256k1>,
     pub participants: Vec<Participant>,
 }
 
+impl fmt::Debug for Presignature {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Presignature")
+            .field("id", &self.id)
+            .field("participants", &self.participants)
+            .finish()
+    }
+}
+
 #[derive(Copy, Clone, Debug)]
 pub struct PresignatureConfig {
     pub min_presignatures: usize,
     pub max_presignatures: usize,
 }
 
-/// An ongoing presignature generator.
+/// An ongoing presignature generator, running on a task of its own.
 pub struct PresignatureGenerator {
     pub participants: Vec<Participant>,
-    pub protocol: PresignatureProtocol,
+    pub task: ProtocolTask<PresignOutput<Secp256k1>>,
     pub triple0: TripleId,
     pub triple1: TripleId,
     pub mine: bool,
@@ -45,14 +59,14 @@ pub struct PresignatureGenerator {
 
 impl PresignatureGenerator {
     pub fn new(
-        protocol: PresignatureProtocol,
+        task: ProtocolTask<PresignOutput<Secp256k1>>,
         participants: Vec<Participant>,
         triple0: TripleId,
         triple1: TripleId,
         mine: bool,
     ) -> Self {
         Self {
-            protocol,
+            task,
             participants,
             triple0,
             triple1,
@@ -61,20 +75,8 @@ impl PresignatureGenerator {
         }
     }
 
-    pub fn poke(&mut self) -> Result<Action<PresignOutput<Secp256k1>>, ProtocolError> {
-        if self.timestamp.elapsed() > crate::types::PROTOCOL_PRESIG_TIMEOUT {
-            tracing::info!(
-                self.triple0,
-                self.triple1,
-                self.mine,
-                "presignature protocol timed out"
-            );
-            return Err(ProtocolError::Other(
-                anyhow::anyhow!("presignature protocol timed out").into(),
-            ));
-        }
-
-        self.protocol.poke()
+    pub fn message(&self, from: Participant, data: MessageData) {
+        self.task.message(from, data);
     }
 }
 
@@ -109,6 +111,7 @@ pub struct PresignatureManager {
     epoch: u64,
     my_account_id: AccountId,
     presig_cfg: PresignatureConfig,
+    presignature_storage: LockPresignatureNodeStorageBox,
 }
 
 impl PresignatureManager {
@@ -118,11 +121,25 @@ impl PresignatureManager {
         epoch: u64,
         my_account_id: &AccountId,
         presig_cfg: &PresignatureConfig,
+        presignature_data: Vec<PresignatureData>,
+        presignature_storage: LockPresignatureNodeStorageBox,
     ) -> Self {
+        let mut mine = VecDeque::new();
+        let mut presignatures = HashMap::new();
+        for entry in presignature_data {
+            if entry.epoch != epoch {
+                continue;
+            }
+            tracing::debug!(id = entry.presignature.id, "loaded presignature");
+            if entry.mine {
+                mine.push_back(entry.presignature.id);
+            }
+            presignatures.insert(entry.presignature.id, entry.presignature);
+        }
         Self {
-            presignatures: HashMap::new(),
+            presignatures,
             generators: HashMap::new(),
-            mine: VecDeque::new(),
+            mine,
             introduced: HashSet::new(),
             taken: HashMap::new(),
             me,
@@ -130,6 +147,7 @@ impl PresignatureManager {
             epoch,
             my_account_id: my_account_id.clone(),
             presig_cfg: *presig_cfg,
+            presignature_storage,
         }
     }
 
@@ -161,6 +179,7 @@ impl PresignatureManager {
 
     #[allow(clippy::too_many_arguments)]
     fn generate_internal(
+        pool: &ComputePool,
         participants: &Participants,
         me: Participant,
         threshold: usize,
@@ -171,7 +190,7 @@ impl PresignatureManager {
         mine: bool,
     ) -> Result<PresignatureGenerator, InitializationError> {
         let participants: Vec<_> = participants.keys().cloned().collect();
-        let protocol = Box::new(cait_sith::presign(
+        let protocol: PresignatureProtocol = Box::new(cait_sith::presign(
             &participants,
             me,
             // These paramaters appear to be to make it easier to use different indexing schemes for triples
@@ -189,7 +208,7 @@ impl PresignatureManager {
             },
         )?);
         Ok(PresignatureGenerator::new(
-            protocol,
+            ProtocolTask::spawn(protocol, pool, crate::types::PROTOCOL_PRESIG_TIMEOUT),
             participants,
             triple0.id,
             triple1.id,
@@ -205,6 +224,7 @@ impl PresignatureManager {
         triple1: Triple,
         public_key: &PublicKey,
         private_share: &SecretKeyShare,
+        pool: &ComputePool,
     ) -> Result<(), InitializationError> {
         let id = rand::random();
 
@@ -220,6 +240,7 @@ impl PresignatureManager {
 
         tracing::debug!(id, "starting protocol to generate a new presignature");
         let generator = Self::generate_internal(
+            pool,
             participants,
             self.me,
             self.threshold,
@@ -246,6 +267,7 @@ impl PresignatureManager {
         pk: &PublicKey,
         sk_share: &SecretKeyShare,
         triple_manager: &mut TripleManager,
+        pool: &ComputePool,
     ) -> Result<(), InitializationError> {
         let PresignatureConfig {
             min_presignatures,
@@ -288,7 +310,7 @@ impl PresignatureManager {
                     triple_manager.insert_mine(triple0).await;
                     triple_manager.insert_mine(triple1).await;
                 } else {
-                    self.generate(&presig_participants, triple0, triple1, pk, sk_share)?;
+                    self.generate(&presig_participants, triple0, triple1, pk, sk_share, pool)?;
                 }
             } else {
                 tracing::debug!("running: we don't have enough triples to generate a presignature");
@@ -314,7 +336,8 @@ impl PresignatureManager {
         triple_manager: &mut TripleManager,
         public_key: &PublicKey,
         private_share: &SecretKeyShare,
-    ) -> Result<&mut PresignatureProtocol, GenerationError> {
+        pool: &ComputePool,
+    ) -> Result<&mut PresignatureGenerator, GenerationError> {
         if self.presignatures.contains_key(&id) || self.taken.contains_key(&id) {
             Err(GenerationError::AlreadyGenerated)
         } else {
@@ -350,6 +373,7 @@ impl PresignatureManager {
                         },
                     };
                     let generator = Self::generate_internal(
+                        pool,
                         participants,
                         self.me,
                         self.threshold,
@@ -363,66 +387,130 @@ impl PresignatureManager {
                     crate::metrics::NUM_TOTAL_HISTORICAL_PRESIGNATURE_GENERATORS
                         .with_label_values(&[self.my_account_id.as_str()])
                         .inc();
-                    Ok(&mut generator.protocol)
+                    Ok(generator)
                 }
-                Entry::Occupied(entry) => Ok(&mut entry.into_mut().protocol),
+                Entry::Occupied(entry) => Ok(entry.into_mut()),
             }
         }
     }
 
-    pub fn take_mine(&mut self) -> Option<Presignature> {
+    pub async fn take_mine(&mut self) -> Option<Presignature> {
         tracing::info!(mine = ?self.mine, "my presignatures");
         let my_presignature_id = self.mine.pop_front()?;
-        self.take(my_presignature_id)
+        let presignature = self.take(my_presignature_id).await;
+        if presignature.is_none() && self.presignatures.contains_key(&my_presignature_id) {
+            // It could not be deleted from storage, so it stays ours to be taken later on.
+            self.mine.push_back(my_presignature_id);
+        }
+        presignature
     }
 
-    pub fn take(&mut self, id: PresignatureId) -> Option<Presignature> {
+    /// Participants holding a share of the unspent presignature with the given id.
+    pub fn holders(&self, id: PresignatureId) -> Option<&[Participant]> {
+        self.presignatures
+            .get(&id)
+            .map(|presignature| presignature.participants.as_slice())
+    }
+
+    /// Take an unspent presignature by its id with no way to return it.
+    /// It is very important to NOT reuse the same presignature twice for two different
+    /// signatures, so it is removed from storage before being handed out. A presignature left in
+    /// storage would be loaded again after a restart, so it is not handed out if that fails.
+    pub async fn take(&mut self, id: PresignatureId) -> Option<Presignature> {
+        if !self.presignatures.contains_key(&id) {
+            self.taken.insert(id, Instant::now());
+            return None;
+        }
+        if let Err(err) = self.delete_presignature_from_storage(id).await {
+            tracing::error!(
+                id,
+                ?err,
+                "unable to delete presignature from storage; not taking it"
+            );
+            return None;
+        }
         self.taken.insert(id, Instant::now());
         self.presignatures.remove(&id)
     }
 
-    pub fn insert_mine(&mut self, presig: Presignature) {
+    pub async fn insert_mine(&mut self, presig: Presignature) {
         // Remove from taken list if it was there
         self.taken.remove(&presig.id);
         self.mine.push_back(presig.id);
-        self.presignatures.insert(presig.id, presig);
+        self.presignatures.insert(presig.id, presig.clone());
+        self.insert_presignatures_to_storage(vec![(presig, true)])
+            .await;
+    }
+
+    async fn delete_presignature_from_storage(
+        &mut self,
+        id: PresignatureId,
+    ) -> Result<(), error::DatastoreStorageError> {
+        let epoch = self.epoch;
+        let action = || async {
+            let mut presignature_storage = self.presignature_storage.write().await;
+            if let Err(err) = presignature_storage.delete(epoch, id).await {
+                tracing::warn!(?err, id, "presignature deletion failed.");
+                return Err(err);
+            }
+            Ok(())
+        };
+
+        // Retry the action 3x with 500ms delay between each retry
+        let retry_strategy = std::iter::repeat_with(|| Duration::from_millis(500)).take(3);
+        tokio_retry::Retry::spawn(retry_strategy, action).await
     }
 
-    /// Pokes all of the ongoing generation protocols and returns a vector of
-    /// messages to be sent to the respective participant.
+    async fn insert_presignatures_to_storage(&mut self, presignatures: Vec<(Presignature, bool)>) {
+        let epoch = self.epoch;
+        for (presignature, mine) in presignatures {
+            let action = || async {
+                let mut presignature_storage = self.presignature_storage.write().await;
+                if let Err(err) = presignature_storage
+                    .insert(epoch, presignature.clone(), mine)
+                    .await
+                {
+                    tracing::warn!(?err, id = presignature.id, "presignature insertion failed.");
+                    return Err(err);
+                }
+                Ok(())
+            };
+
+            // Retry the action 3x with 500ms delay between each retry
+            let retry_strategy = std::iter::repeat_with(|| Duration::from_millis(500)).take(3);
+            let _ = tokio_retry::Retry::spawn(retry_strategy, action).await;
+        }
+    }
+
+    /// Collects the actions the generation protocols took on their tasks since the last call and
+    /// returns a vector of messages to be sent to the respective participant.
     ///
-    /// An empty vector means we cannot progress until we receive a new message.
-    pub fn poke(&mut self) -> Vec<(Participant, PresignatureMessage)> {
+    /// An empty vector means no protocol progressed since, which happens once they receive a new
+    /// message.
+    pub async fn poke(&mut self) -> Vec<(Participant, PresignatureMessage)> {
         let mut messages = Vec::new();
+        let mut presignatures_to_insert = Vec::new();
         let mut errors = Vec::new();
-        self.generators.retain(|id, generator| {
-            loop {
-                let action = match generator.poke() {
-                    Ok(action) => action,
-                    Err(e) => {
-                        self.introduced.remove(id);
-                        errors.push(e);
-                        break false;
-                    }
-                };
+        for (id, mut generator) in std::mem::take(&mut self.generators) {
+            let (actions, error) = generator.task.actions();
+            let mut retain = error.is_none();
+            for action in actions {
                 match action {
                     Action::Wait => {
                         tracing::debug!("waiting");
-                        // Retain protocol until we are finished
-                        return true;
                     }
                     Action::SendMany(data) => {
                         for p in generator.participants.iter() {
                             messages.push((
                                 *p,
                                 PresignatureMessage {
-                                    id: *id,
+                                    id,
                                     triple0: generator.triple0,
                                     triple1: generator.triple1,
                                     epoch: self.epoch,
                                     from: self.me,
                                     data: data.clone(),
-                                    timestamp: Utc::now().timestamp() as u64
+                                    timestamp: Utc::now().timestamp() as u64,
                                 },
                             ))
                         }
@@ -430,13 +518,13 @@ impl PresignatureManager {
                     Action::SendPrivate(p, data) => messages.push((
                         p,
                         PresignatureMessage {
-                            id: *id,
+                            id,
                             triple0: generator.triple0,
                             triple1: generator.triple1,
                             epoch: self.epoch,
                             from: self.me,
                             data,
-                            timestamp: Utc::now().timestamp() as u64
+                            timestamp: Utc::now().timestamp() as u64,
                         },
                     )),
                     Action::Return(output) => {
@@ -446,22 +534,21 @@ impl PresignatureManager {
                             big_r = ?output.big_r.to_base58(),
                             "completed presignature generation"
                         );
-                        self.presignatures.insert(
-                            *id,
-                            Presignature {
-                                id: *id,
-                                output,
-                                participants: generator.participants.clone(),
-                            },
-                        );
+                        let presignature = Presignature {
+                            id,
+                            output,
+                            participants: generator.participants.clone(),
+                        };
+                        self.presignatures.insert(id, presignature.clone());
+                        presignatures_to_insert.push((presignature, generator.mine));
                         if generator.mine {
                             tracing::info!(id, "assigning presignature to myself");
-                            self.mine.push_back(*id);
+                            self.mine.push_back(id);
                             crate::metrics::NUM_TOTAL_HISTORICAL_PRESIGNATURE_GENERATORS_MINE_SUCCESS
                                 .with_label_values(&[self.my_account_id.as_str()])
                                 .inc();
                         }
-                        self.introduced.remove(id);
+                        self.introduced.remove(&id);
 
                         crate::metrics::PRESIGNATURE_LATENCY
                             .with_label_values(&[self.my_account_id.as_str()])
@@ -470,11 +557,31 @@ impl PresignatureManager {
                             .with_label_values(&[self.my_account_id.as_str()])
                             .inc();
                         // Do not retain the protocol
-                        return false;
+                        retain = false;
                     }
                 }
             }
-        });
+
+            if let Some(e) = error {
+                tracing::info!(
+                    id,
+                    generator.triple0,
+                    generator.triple1,
+                    generator.mine,
+                    "presignature protocol failed"
+                );
+                self.introduced.remove(&id);
+                errors.push(e);
+            }
+
+            if retain {
+                // Retain protocol until we are finished
+                self.generators.insert(id, generator);
+            }
+        }
+
+        self.insert_presignatures_to_storage(presignatures_to_insert)
+            .await;
 
         if !errors.is_empty() {
             tracing::warn!(?errors, "faled to generate some presignatures");
@@ -483,3 +590,94 @@ impl PresignatureManager {
         messages
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::{Presignature, PresignatureConfig, PresignatureManager};
+    use crate::gcp::error::DatastoreStorageError;
+    use crate::storage::presignature_storage::{
+        PresignatureData, PresignatureNodeStorage, PresignatureNodeStorageBox,
+    };
+
+    use cait_sith::protocol::Participant;
+    use cait_sith::PresignOutput;
+    use k256::{AffinePoint, Scalar};
+    use near_account_id::AccountId;
+    use std::sync::Arc;
+    use tokio::sync::RwLock;
+
+    /// Storage that fails to delete anything.
+    struct UndeletableStorage {
+        account_id: AccountId,
+    }
+
+    #[async_trait::async_trait]
+    impl PresignatureNodeStorage for UndeletableStorage {
+        async fn insert(
+            &mut self,
+            _epoch: u64,
+            _presignature: Presignature,
+            _mine: bool,
+        ) -> Result<(), DatastoreStorageError> {
+            Ok(())
+        }
+
+        async fn delete(&mut self, _epoch: u64, _id: u64) -> Result<(), DatastoreStorageError> {
+            Err(DatastoreStorageError::FetchEntitiesError(
+                "storage is unavailable".to_string(),
+            ))
+        }
+
+        async fn clear(&mut self) -> Result<Vec<PresignatureData>, DatastoreStorageError> {
+            Ok(Vec::new())
+        }
+
+        async fn load(&self, _epoch: u64) -> Result<Vec<PresignatureData>, DatastoreStorageError> {
+            Ok(Vec::new())
+        }
+
+        fn account_id(&self) -> &AccountId {
+            &self.account_id
+        }
+    }
+
+    #[tokio::test]
+    async fn test_presignature_is_not_taken_unless_deleted_from_storage() {
+        let account_id: AccountId = "node.testnet".parse().unwrap();
+        let presignature = Presignature {
+            id: 1,
+            output: PresignOutput {
+                big_r: AffinePoint::GENERATOR,
+                k: Scalar::ONE,
+                sigma: Scalar::ONE,
+            },
+            participants: vec![Participant::from(0), Participant::from(1)],
+        };
+        let storage: PresignatureNodeStorageBox = Box::new(UndeletableStorage {
+            account_id: account_id.clone(),
+        });
+        let mut manager = PresignatureManager::new(
+            Participant::from(0),
+            2,
+            0,
+            &account_id,
+            &PresignatureConfig {
+                min_presignatures: 1,
+                max_presignatures: 1,
+            },
+            vec![PresignatureData {
+                account_id: account_id.clone(),
+                epoch: 0,
+                presignature,
+                mine: true,
+            }],
+            Arc::new(RwLock::new(storage)),
+        );
+
+        assert!(manager.take_mine().await.is_none());
+        assert!(manager.take(1).await.is_none());
+        // Still there to be taken once storage recovers.
+        assert_eq!(manager.len(), 1);
+        assert_eq!(manager.my_len(), 1);
+    }
+}
@@ -1,3 +1,4 @@
+use super::compute::{ComputePool, ProtocolTask};
 use super::contract::primitives::Participants;
 use super::message::SignatureMessage;
 use super::presignature::{Presignature, PresignatureId, PresignatureManager};
@@ -6,19 +7,24 @@ use crate::kdf::into_eth_sig;
 use crate::types::SignatureProtocol;
 use crate::util::AffinePointExt;
 
-use cait_sith::protocol::{Action, InitializationError, Participant, ProtocolError};
+use cait_sith::protocol::{Action, InitializationError, MessageData, Participant};
 use cait_sith::{FullSignature, PresignOutput};
 use chrono::Utc;
-use crypto_shared::{derive_key, PublicKey};
+use crypto_shared::{derive_child_tweak, derive_key, PublicKey};
 use crypto_shared::{ScalarExt, SerializableScalar};
+use futures_util::future::join_all;
 use k256::{Scalar, Secp256k1};
-use mpc_contract::SignatureRequest;
+use mpc_contract::{SignatureRequest, MAX_SIGN_HELPER_DEPTH};
 use rand::rngs::StdRng;
 use rand::seq::{IteratorRandom, SliceRandom};
 use rand::SeedableRng;
+use sha2::{Digest, Sha256};
+use std::cmp::Reverse;
 use std::collections::hash_map::Entry;
-use std::collections::{HashMap, VecDeque};
+use std::collections::{BTreeSet, HashMap, HashSet, VecDeque};
+use std::sync::Arc;
 use std::time::{Duration, Instant};
+use tokio::sync::Notify;
 
 use near_account_id::AccountId;
 use near_fetch::signer::SignerExt;
@@ -27,6 +33,74 @@ use near_primitives::hash::CryptoHash;
 /// Duration for which completed signatures are retained.
 pub const COMPLETION_EXISTENCE_TIMEOUT: Duration = Duration::from_secs(120 * 60);
 
+/// Amount of blocks after the `sign` receipt past which the contract no longer accepts a
+/// signature for it. Each `sign_helper` yield takes at least a block, so the window is doubled
+/// to account for yields getting delayed under congestion.
+pub const SIGN_REQUEST_TIMEOUT_BLOCKS: u64 = 2 * (MAX_SIGN_HELPER_DEPTH as u64 + 2);
+
+/// How often failed requests are checked against the contract for having been resolved.
+pub const SIGN_REQUEST_RESOLUTION_CHECK_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Amount of blocks a request can wait before it gets served ahead of higher deposits.
+pub const SIGN_REQUEST_STARVATION_BLOCKS: u64 = SIGN_REQUEST_TIMEOUT_BLOCKS / 4;
+
+/// Amount of blocks a proposer has to get a request signed before the next member of the proposer
+/// rotation takes over. The turn is extended for as long as the proposer is seen making progress.
+pub const PROPOSER_TURN_BLOCKS: u64 = 10;
+
+/// The requests among `checks` that the contract no longer has pending. The contract is queried
+/// for all of them at once, and requests it could not be queried for are taken as pending.
+pub async fn resolved_on_chain(
+    rpc_client: &near_fetch::Client,
+    mpc_contract_id: &AccountId,
+    checks: Vec<(CryptoHash, SignatureRequest)>,
+) -> Vec<CryptoHash> {
+    let pending = join_all(checks.iter().map(|(_, request)| {
+        crate::rpc_client::is_request_pending(rpc_client, mpc_contract_id, request)
+    }))
+    .await;
+    checks
+        .into_iter()
+        .zip(pending)
+        .filter_map(|((receipt_id, _), pending)| match pending {
+            Ok(pending) => (!pending).then_some(receipt_id),
+            Err(err) => {
+                tracing::warn!(%receipt_id, ?err, "failed to check whether sign request is pending");
+                None
+            }
+        })
+        .collect()
+}
+
+/// Whether a request made at `request_height` has timed out on-chain by `block_height`.
+fn is_expired(request_height: u64, block_height: u64) -> bool {
+    block_height > request_height.saturating_add(SIGN_REQUEST_TIMEOUT_BLOCKS)
+}
+
+/// Order in which requests get signed when presignatures are scarce, highest first.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct SignPriority {
+    /// Age of requests waiting for longer than `SIGN_REQUEST_STARVATION_BLOCKS`. These go first,
+    /// oldest first, so that a stream of higher deposits cannot starve the others.
+    starving_age: Option<u64>,
+    deposit: u128,
+    retries: Reverse<u32>,
+    age: u64,
+}
+
+impl SignPriority {
+    pub fn new(deposit: u128, retries: u32, request_height: u64, block_height: u64) -> Self {
+        let age = block_height.saturating_sub(request_height);
+        Self {
+            starving_age: (age > SIGN_REQUEST_STARVATION_BLOCKS).then_some(age),
+            deposit,
+            retries: Reverse(retries),
+            age,
+        }
+    }
+}
+
+#[derive(Clone)]
 pub struct SignRequest {
     pub receipt_id: CryptoHash,
     pub request: ContractSignRequest,
@@ -34,12 +108,148 @@ pub struct SignRequest {
     pub delta: Scalar,
     pub entropy: [u8; 32],
     pub time_added: Instant,
+    /// Height of the block the `sign` receipt got executed in.
+    pub block_height: u64,
+    /// Deposit attached to the `sign` call, in yoctoNEAR.
+    pub deposit: u128,
+}
+
+impl SignRequest {
+    pub fn priority(&self, block_height: u64) -> SignPriority {
+        SignPriority::new(self.deposit, 0, self.block_height, block_height)
+    }
+}
+
+/// Picks the `threshold` participants that take turns proposing a request, in the order of their
+/// turns, using `rng` seeded with the entropy of the request. Nothing but the contract
+/// `participants` and the entropy goes into this, so that every node computes the same rotation
+/// no matter how it sees the network.
+fn proposer_rotation(
+    rng: &mut StdRng,
+    participants: &Participants,
+    threshold: usize,
+) -> Vec<Participant> {
+    // The sample comes out in an unspecified order, which the shuffle makes random as well.
+    let mut proposers = participants.keys().copied().choose_multiple(rng, threshold);
+    proposers.shuffle(rng);
+    proposers
+}
+
+/// Seed of the signers picked for the request `receipt_id` with the presignature `presignature_id`.
+/// `delta` is derived from the entropy of the request, and checked by every node against the
+/// request it indexed before it checks the pick.
+pub fn signers_seed(
+    receipt_id: &CryptoHash,
+    delta: &Scalar,
+    presignature_id: PresignatureId,
+) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    hasher.update(receipt_id.0);
+    hasher.update(delta.to_bytes());
+    hasher.update(presignature_id.to_le_bytes());
+    hasher.finalize().into()
+}
+
+/// Picks the `threshold` participants, `proposer` included, to sign with a presignature held by
+/// `holders`, out of the contract `participants`, preferring the ones not in `slow`. The pick only
+/// depends on `seed` and these sets, and the proposer sends its `slow` set along with the pick,
+/// so that every other node recomputes the pick and checks it. Returns `None` when too few
+/// participants hold the presignature.
+pub fn select_signers(
+    seed: [u8; 32],
+    proposer: Participant,
+    participants: &Participants,
+    holders: &[Participant],
+    slow: &[Participant],
+    threshold: usize,
+) -> Option<Vec<Participant>> {
+    if !holders.contains(&proposer) || !participants.contains_key(&proposer) {
+        return None;
+    }
+    let (slow, responsive): (Vec<_>, Vec<_>) = participants
+        .keys()
+        .copied()
+        .filter(|p| *p != proposer && holders.contains(p))
+        .partition(|p| slow.contains(p));
+    if responsive.len() + slow.len() + 1 < threshold {
+        return None;
+    }
+    let mut rng = StdRng::from_seed(seed);
+    let mut signers = vec![proposer];
+    signers.extend(
+        responsive
+            .into_iter()
+            .choose_multiple(&mut rng, threshold.saturating_sub(1)),
+    );
+    if signers.len() < threshold {
+        let missing = threshold - signers.len();
+        signers.extend(slow.into_iter().choose_multiple(&mut rng, missing));
+    }
+    signers.sort();
+    Some(signers)
+}
+
+/// Moves a request from the queue of its `previous` proposer to the one of its next `proposer`.
+fn hand_over(
+    requests: &mut HashMap<Participant, HashMap<CryptoHash, SignRequest>>,
+    receipt_id: &CryptoHash,
+    previous: Participant,
+    proposer: Participant,
+) {
+    if let Some(request) = requests
+        .get_mut(&previous)
+        .and_then(|requests| requests.remove(receipt_id))
+    {
+        requests
+            .entry(proposer)
+            .or_default()
+            .insert(*receipt_id, request);
+    }
+}
+
+/// Outcome of checking a signature proposal against the requests indexed by this node.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProposalCheck {
+    /// The request was indexed and the proposal matches it.
+    Verified,
+    /// The request has not been indexed by this node, at least not yet.
+    Unknown,
+    /// The proposal contradicts the indexed request.
+    Mismatch(&'static str),
+}
+
+/// Order in which the participants picked for a request take turns proposing it.
+struct ProposerRotation {
+    proposers: Vec<Participant>,
+    turn: usize,
+    /// Block height at which the current turn started, or last saw progress.
+    turn_started: u64,
+    /// Height of the block the `sign` receipt got executed in.
+    block_height: u64,
+    /// The request as indexed by this node, kept until it is resolved so that proposals can be
+    /// checked against it after the request left the queue of its proposer.
+    request: ContractSignRequest,
+    epsilon: Scalar,
+    delta: Scalar,
+}
+
+impl ProposerRotation {
+    fn proposer(&self) -> Participant {
+        self.proposers[self.turn]
+    }
 }
 
 #[derive(Default)]
 pub struct SignQueue {
+    /// Notified whenever a new request is added.
+    notify: Arc<Notify>,
     unorganized_requests: Vec<SignRequest>,
     requests: HashMap<Participant, HashMap<CryptoHash, SignRequest>>,
+    rotations: HashMap<CryptoHash, ProposerRotation>,
+    /// Requests that expired on-chain, yet to be removed from storage.
+    resolved: Vec<CryptoHash>,
+    /// Latest block height reached by the indexer.
+    block_height: u64,
 }
 
 impl SignQueue {
@@ -63,29 +273,35 @@ impl SignQueue {
             "new sign request"
         );
         self.unorganized_requests.push(request);
+        self.notify.notify_one();
     }
 
+    /// Handle to wait on for new requests to be added.
+    pub fn notifier(&self) -> Arc<Notify> {
+        self.notify.clone()
+    }
+
+    /// Sets up the proposer rotation of the newly added requests. Every node keeps the rotation,
+    /// including the nodes that are not part of it, so that it can check the proposals it gets
+    /// asked to sign against the request it indexed.
     pub fn organize(
         &mut self,
         threshold: usize,
-        active: &Participants,
+        participants: &Participants,
         me: Participant,
         my_account_id: &AccountId,
     ) {
         for request in self.unorganized_requests.drain(..) {
             let mut rng = StdRng::from_seed(request.entropy);
-            let subset = active.keys().choose_multiple(&mut rng, threshold);
-            let proposer = **subset.choose(&mut rng).unwrap();
-            if subset.contains(&&me) {
+            let proposers = proposer_rotation(&mut rng, participants, threshold);
+            let proposer = proposers[0];
+            if proposers.contains(&me) {
                 tracing::info!(
                     receipt_id = %request.receipt_id,
                     ?me,
-                    ?subset,
-                    ?proposer,
-                    "saving sign request: node is in the signer subset"
+                    ?proposers,
+                    "saving sign request: node is in the proposer rotation"
                 );
-                let proposer_requests = self.requests.entry(proposer).or_default();
-                proposer_requests.insert(request.receipt_id, request);
                 crate::metrics::NUM_SIGN_REQUESTS_MINE
                     .with_label_values(&[my_account_id.as_str()])
                     .inc();
@@ -93,11 +309,24 @@ impl SignQueue {
                 tracing::info!(
                     receipt_id = %request.receipt_id,
                     ?me,
-                    ?subset,
-                    ?proposer,
-                    "skipping sign request: node is NOT in the signer subset"
+                    ?proposers,
+                    "saving sign request: node is NOT in the proposer rotation, only joining"
                 );
             }
+            self.rotations.insert(
+                request.receipt_id,
+                ProposerRotation {
+                    proposers,
+                    turn: 0,
+                    turn_started: request.block_height,
+                    block_height: request.block_height,
+                    request: request.request.clone(),
+                    epsilon: request.epsilon,
+                    delta: request.delta,
+                },
+            );
+            let proposer_requests = self.requests.entry(proposer).or_default();
+            proposer_requests.insert(request.receipt_id, request);
         }
     }
 
@@ -108,58 +337,255 @@ impl SignQueue {
         participant_requests.contains_key(&receipt_id)
     }
 
+    /// Checks that `proposer` may propose the request and that the proposed request, `epsilon`
+    /// and `delta` are the ones this node computed when indexing the receipt itself.
+    pub fn check_proposal(
+        &self,
+        receipt_id: &CryptoHash,
+        proposer: Participant,
+        request: &ContractSignRequest,
+        epsilon: &Scalar,
+        delta: &Scalar,
+        block_height: u64,
+    ) -> ProposalCheck {
+        let Some(rotation) = self.rotations.get(receipt_id) else {
+            return ProposalCheck::Unknown;
+        };
+        if !rotation.proposers.contains(&proposer) {
+            return ProposalCheck::Mismatch("proposer is not in the proposer rotation");
+        }
+        if rotation.request != *request {
+            ProposalCheck::Mismatch("request")
+        } else if rotation.epsilon != *epsilon {
+            ProposalCheck::Mismatch("epsilon")
+        } else if rotation.delta != *delta {
+            ProposalCheck::Mismatch("delta")
+        } else if rotation.block_height != block_height {
+            ProposalCheck::Mismatch("block height")
+        } else {
+            ProposalCheck::Verified
+        }
+    }
+
+    /// Height of the block this node indexed the request in, for proposals from nodes that do not
+    /// send it.
+    pub fn indexed_block_height(&self, receipt_id: &CryptoHash) -> Option<u64> {
+        self.rotations
+            .get(receipt_id)
+            .map(|rotation| rotation.block_height)
+    }
+
     pub fn my_requests(&mut self, me: Participant) -> &mut HashMap<CryptoHash, SignRequest> {
         self.requests.entry(me).or_default()
     }
+
+    /// Position of `proposer` in the rotation of the request, where later turns take precedence.
+    pub fn turn_of(&self, receipt_id: &CryptoHash, proposer: Participant) -> Option<usize> {
+        self.rotations
+            .get(receipt_id)?
+            .proposers
+            .iter()
+            .position(|p| *p == proposer)
+    }
+
+    /// Records that `proposer` is making progress on the request, which extends its turn. Nodes
+    /// see blocks at different times, so they can disagree on whose turn it is for a while. To
+    /// settle on a single proposer, one whose turn comes later takes the request over right away.
+    /// Returns the request along with its new proposer if it got handed over.
+    pub fn observe_progress(
+        &mut self,
+        receipt_id: &CryptoHash,
+        proposer: Participant,
+    ) -> Option<(CryptoHash, Participant)> {
+        let block_height = self.block_height;
+        let rotation = self.rotations.get_mut(receipt_id)?;
+        let turn = rotation.proposers.iter().position(|p| *p == proposer)?;
+        if turn < rotation.turn {
+            return None;
+        }
+        rotation.turn_started = rotation.turn_started.max(block_height);
+        if turn == rotation.turn {
+            return None;
+        }
+        let previous = rotation.proposer();
+        rotation.turn = turn;
+        tracing::info!(
+            %receipt_id,
+            ?previous,
+            ?proposer,
+            block_height,
+            "proposer with a later turn is making progress: handing sign request over to it"
+        );
+        hand_over(&mut self.requests, receipt_id, previous, proposer);
+        Some((*receipt_id, proposer))
+    }
+
+    /// Hands the requests whose proposer's turn is over to the next proposer of their rotation.
+    /// The last proposer keeps the request until it expires. Returns the requests that got
+    /// handed over along with their new proposer.
+    pub fn rotate_proposers(&mut self) -> Vec<(CryptoHash, Participant)> {
+        let block_height = self.block_height;
+        let mut handed_off = Vec::new();
+        for (receipt_id, rotation) in self.rotations.iter_mut() {
+            let previous = rotation.proposer();
+            while rotation.turn + 1 < rotation.proposers.len()
+                && block_height >= rotation.turn_started + PROPOSER_TURN_BLOCKS
+            {
+                rotation.turn += 1;
+                rotation.turn_started += PROPOSER_TURN_BLOCKS;
+            }
+            let proposer = rotation.proposer();
+            if proposer == previous {
+                continue;
+            }
+            tracing::info!(
+                %receipt_id,
+                ?previous,
+                ?proposer,
+                block_height,
+                "proposer turn is over: handing sign request to the next proposer"
+            );
+            hand_over(&mut self.requests, receipt_id, previous, proposer);
+            handed_off.push((*receipt_id, proposer));
+        }
+        handed_off
+    }
+
+    /// Forgets about a request that got resolved.
+    pub fn remove(&mut self, receipt_id: &CryptoHash) {
+        if let Some(rotation) = self.rotations.remove(receipt_id) {
+            for proposer in rotation.proposers {
+                if let Some(requests) = self.requests.get_mut(&proposer) {
+                    requests.remove(receipt_id);
+                }
+            }
+        }
+    }
+
+    /// Id of the request in `requests` to be signed next.
+    pub fn next_request(
+        requests: &HashMap<CryptoHash, SignRequest>,
+        block_height: u64,
+    ) -> Option<(CryptoHash, SignPriority)> {
+        requests
+            .values()
+            .map(|request| (request.receipt_id, request.priority(block_height)))
+            .max_by_key(|(_, priority)| *priority)
+    }
+
+    /// Takes the requests that no longer need to be persisted.
+    pub fn take_resolved(&mut self) -> Vec<CryptoHash> {
+        std::mem::take(&mut self.resolved)
+    }
+
+    pub fn block_height(&self) -> u64 {
+        self.block_height
+    }
+
+    pub fn set_block_height(&mut self, block_height: u64) {
+        self.block_height = self.block_height.max(block_height);
+    }
+
+    /// Drops the requests the contract has given up on by now and returns their ids.
+    pub fn prune_expired(&mut self) -> Vec<CryptoHash> {
+        let block_height = self.block_height;
+        let mut expired = Vec::new();
+        let mut is_live = |request: &SignRequest| {
+            if is_expired(request.block_height, block_height) {
+                expired.push(request.receipt_id);
+                false
+            } else {
+                true
+            }
+        };
+        self.unorganized_requests.retain(&mut is_live);
+        for requests in self.requests.values_mut() {
+            requests.retain(|_, request| is_live(request));
+        }
+        self.rotations
+            .retain(|_, rotation| !is_expired(rotation.block_height, block_height));
+        if !expired.is_empty() {
+            tracing::info!(
+                block_height,
+                ?expired,
+                "dropping sign requests that expired on-chain"
+            );
+        }
+        self.resolved.extend(&expired);
+        expired
+    }
 }
 
-/// An ongoing signature generator.
+/// An ongoing signature generator, running on a task of its own.
 pub struct SignatureGenerator {
-    pub protocol: SignatureProtocol,
+    pub task: ProtocolTask<FullSignature<Secp256k1>>,
     pub participants: Vec<Participant>,
+    /// Participants the proposer avoided when picking `participants`, sent along with them so
+    /// that the other nodes can recompute the pick.
+    pub slow: Vec<Participant>,
     pub proposer: Participant,
     pub presignature_id: PresignatureId,
     pub request: ContractSignRequest,
     pub epsilon: Scalar,
+    /// Tweak of the child key at `request.child_path`, added on top of `epsilon`.
+    pub child_tweak: Scalar,
     pub delta: Scalar,
     pub sign_request_timestamp: Instant,
-    pub generator_timestamp: Instant,
+    /// Height of the block the `sign` receipt got executed in.
+    pub block_height: u64,
+    pub deposit: u128,
+    /// Amount of times generating this signature was attempted before.
+    pub retries: u32,
+    /// Participants that sent their share so far.
+    heard_from: HashSet<Participant>,
 }
 
 impl SignatureGenerator {
     #[allow(clippy::too_many_arguments)]
     pub fn new(
-        protocol: SignatureProtocol,
+        task: ProtocolTask<FullSignature<Secp256k1>>,
         participants: Vec<Participant>,
+        slow: Vec<Participant>,
         proposer: Participant,
         presignature_id: PresignatureId,
         request: ContractSignRequest,
         epsilon: Scalar,
+        child_tweak: Scalar,
         delta: Scalar,
         sign_request_timestamp: Instant,
+        block_height: u64,
+        deposit: u128,
+        retries: u32,
     ) -> Self {
         Self {
-            protocol,
+            task,
             participants,
+            slow,
             proposer,
             presignature_id,
             request,
             epsilon,
+            child_tweak,
             delta,
             sign_request_timestamp,
-            generator_timestamp: Instant::now(),
+            block_height,
+            deposit,
+            retries,
+            heard_from: HashSet::new(),
         }
     }
 
-    pub fn poke(&mut self) -> Result<Action<FullSignature<Secp256k1>>, ProtocolError> {
-        if self.generator_timestamp.elapsed() > crate::types::PROTOCOL_SIGNATURE_TIMEOUT {
-            tracing::info!(self.presignature_id, "signature protocol timed out");
-            return Err(ProtocolError::Other(
-                anyhow::anyhow!("signature protocol timed out").into(),
-            ));
-        }
+    pub fn message(&mut self, from: Participant, data: MessageData) {
+        self.heard_from.insert(from);
+        self.task.message(from, data);
+    }
 
-        self.protocol.poke()
+    /// Whether each of the other participants sent its share, once the protocol is over.
+    fn outcomes(&self, me: Participant) -> impl Iterator<Item = (Participant, bool)> + '_ {
+        self.participants
+            .iter()
+            .filter(move |p| **p != me)
+            .map(|p| (*p, self.heard_from.contains(p)))
     }
 }
 
@@ -171,6 +597,15 @@ pub struct GenerationRequest {
     pub epsilon: Scalar,
     pub delta: Scalar,
     pub sign_request_timestamp: Instant,
+    pub block_height: u64,
+    pub deposit: u128,
+    pub retries: u32,
+}
+
+impl GenerationRequest {
+    pub fn priority(&self, block_height: u64) -> SignPriority {
+        SignPriority::new(self.deposit, self.retries, self.block_height, block_height)
+    }
 }
 
 pub struct SignatureManager {
@@ -188,6 +623,17 @@ pub struct SignatureManager {
         Instant,
         FullSignature<Secp256k1>,
     )>,
+    /// Requests that got resolved, yet to be removed from storage.
+    resolved: Vec<CryptoHash>,
+    /// Requests this node helped sign for another proposer, along with the height of their
+    /// block. They stay in the sign queue until the contract no longer has them pending, so that
+    /// the next proposer can take over if the response never lands.
+    awaiting_response: HashMap<CryptoHash, (SignatureRequest, u64)>,
+    /// Whether the participants of finished protocols sent their share, yet to be recorded by the
+    /// mesh.
+    protocol_outcomes: Vec<(Participant, bool)>,
+    /// Last time failed requests were checked against the contract.
+    last_resolution_check: Instant,
     me: Participant,
     public_key: PublicKey,
     epoch: u64,
@@ -200,6 +646,10 @@ impl SignatureManager {
             failed: VecDeque::new(),
             completed: HashMap::new(),
             signatures: Vec::new(),
+            resolved: Vec::new(),
+            awaiting_response: HashMap::new(),
+            protocol_outcomes: Vec::new(),
+            last_resolution_check: Instant::now(),
             me,
             public_key,
             epoch,
@@ -216,43 +666,64 @@ impl SignatureManager {
 
     #[allow(clippy::too_many_arguments)]
     fn generate_internal(
-        participants: &Participants,
+        pool: &ComputePool,
+        participants: &[Participant],
+        slow: &[Participant],
         me: Participant,
         public_key: PublicKey,
         presignature: Presignature,
         req: GenerationRequest,
     ) -> Result<SignatureGenerator, InitializationError> {
-        let participants = participants.keys_vec();
+        if let Some(p) = participants
+            .iter()
+            .find(|p| !presignature.participants.contains(p))
+        {
+            return Err(InitializationError::BadParameters(format!(
+                "participant {p:?} does not hold a share of the presignature"
+            )));
+        }
+        let participants = participants.to_vec();
         let GenerationRequest {
             proposer,
             request,
             epsilon,
             delta,
             sign_request_timestamp,
+            block_height,
+            deposit,
+            retries,
         } = req;
+        let child_tweak = derive_child_tweak(public_key, epsilon, &request.child_path)
+            .map_err(|err| InitializationError::BadParameters(err.to_string()))?;
+        let tweak = epsilon + child_tweak;
         let PresignOutput { big_r, k, sigma } = presignature.output;
         // TODO: Check whether it is okay to use invert_vartime instead
         let output: PresignOutput<Secp256k1> = PresignOutput {
             big_r: (big_r * delta).to_affine(),
             k: k * delta.invert().unwrap(),
-            sigma: (sigma + epsilon * k) * delta.invert().unwrap(),
+            sigma: (sigma + tweak * k) * delta.invert().unwrap(),
         };
-        let protocol = Box::new(cait_sith::sign(
+        let protocol: SignatureProtocol = Box::new(cait_sith::sign(
             &participants,
             me,
-            derive_key(public_key, epsilon),
+            derive_key(public_key, tweak),
             output,
             Scalar::from_bytes(&request.payload),
         )?);
         Ok(SignatureGenerator::new(
-            protocol,
+            ProtocolTask::spawn(protocol, pool, crate::types::PROTOCOL_SIGNATURE_TIMEOUT),
             participants,
+            slow.to_vec(),
             proposer,
             presignature.id,
             request,
             epsilon,
+            child_tweak,
             delta,
             sign_request_timestamp,
+            block_height,
+            deposit,
+            retries,
         ))
     }
 
@@ -261,11 +732,20 @@ impl SignatureManager {
         receipt_id: CryptoHash,
         req: GenerationRequest,
         presignature: Presignature,
-        participants: &Participants,
+        participants: &[Participant],
+        slow: &[Participant],
+        pool: &ComputePool,
     ) -> Result<(), InitializationError> {
-        tracing::info!(receipt_id = %receipt_id, participants = ?participants.keys_vec(), "restarting failed protocol to generate signature");
-        let generator =
-            Self::generate_internal(participants, self.me, self.public_key, presignature, req)?;
+        tracing::info!(receipt_id = %receipt_id, ?participants, ?slow, "restarting failed protocol to generate signature");
+        let generator = Self::generate_internal(
+            pool,
+            participants,
+            slow,
+            self.me,
+            self.public_key,
+            presignature,
+            req,
+        )?;
         self.generators.insert(receipt_id, generator);
         Ok(())
     }
@@ -274,23 +754,30 @@ impl SignatureManager {
     #[allow(clippy::too_many_arguments)]
     pub fn generate(
         &mut self,
-        participants: &Participants,
+        participants: &[Participant],
+        slow: &[Participant],
         receipt_id: CryptoHash,
         presignature: Presignature,
         request: ContractSignRequest,
         epsilon: Scalar,
         delta: Scalar,
         sign_request_timestamp: Instant,
+        block_height: u64,
+        deposit: u128,
+        pool: &ComputePool,
     ) -> Result<(), InitializationError> {
         tracing::info!(
             %receipt_id,
             me = ?self.me,
             presignature_id = presignature.id,
-            participants = ?participants.keys_vec(),
+            ?participants,
+            ?slow,
             "starting protocol to generate a new signature",
         );
         let generator = Self::generate_internal(
+            pool,
             participants,
+            slow,
             self.me,
             self.public_key,
             presignature,
@@ -300,6 +787,9 @@ impl SignatureManager {
                 epsilon,
                 delta,
                 sign_request_timestamp,
+                block_height,
+                deposit,
+                retries: 0,
             },
         )?;
         self.generators.insert(receipt_id, generator);
@@ -313,27 +803,32 @@ impl SignatureManager {
     /// 4) Depends on triples (`triple0`/`triple1`) that are unknown to the node
     // TODO: What if the presignature completed generation and is already spent?
     #[allow(clippy::too_many_arguments)]
-    pub fn get_or_generate(
+    pub async fn get_or_generate(
         &mut self,
-        participants: &Participants,
+        participants: &[Participant],
+        slow: &[Participant],
         receipt_id: CryptoHash,
         proposer: Participant,
         presignature_id: PresignatureId,
         request: ContractSignRequest,
         epsilon: Scalar,
         delta: Scalar,
+        block_height: u64,
         presignature_manager: &mut PresignatureManager,
-    ) -> Result<Option<&mut SignatureProtocol>, InitializationError> {
+        pool: &ComputePool,
+    ) -> Result<Option<&mut SignatureGenerator>, InitializationError> {
         match self.generators.entry(receipt_id) {
             Entry::Vacant(entry) => {
                 tracing::info!(%receipt_id, me = ?self.me, presignature_id, "joining protocol to generate a new signature");
-                let Some(presignature) = presignature_manager.take(presignature_id) else {
+                let Some(presignature) = presignature_manager.take(presignature_id).await else {
                     tracing::warn!(me = ?self.me, presignature_id, "presignature is missing, can't join signature generation protocol");
                     return Ok(None);
                 };
                 tracing::info!(me = ?self.me, presignature_id, "found presignature: ready to start signature generation");
                 let generator = Self::generate_internal(
+                    pool,
                     participants,
+                    slow,
                     self.me,
                     self.public_key,
                     presignature,
@@ -343,50 +838,39 @@ impl SignatureManager {
                         epsilon,
                         delta,
                         sign_request_timestamp: Instant::now(),
+                        block_height,
+                        // Only the proposer retries failed generations, so the priority of the
+                        // request does not matter here.
+                        deposit: 0,
+                        retries: 0,
                     },
                 )?;
-                let generator = entry.insert(generator);
-                Ok(Some(&mut generator.protocol))
+                Ok(Some(entry.insert(generator)))
+            }
+            Entry::Occupied(entry) => {
+                let generator = entry.into_mut();
+                // A proposer that took over must not join the signature of the previous one.
+                if generator.proposer != proposer {
+                    tracing::warn!(%receipt_id, ongoing_proposer = ?generator.proposer, ?proposer, "signature is already being generated for another proposer");
+                    return Ok(None);
+                }
+                Ok(Some(generator))
             }
-            Entry::Occupied(entry) => Ok(Some(&mut entry.into_mut().protocol)),
         }
     }
 
-    /// Pokes all of the ongoing generation protocols and returns a vector of
-    /// messages to be sent to the respective participant.
+    /// Collects the actions the generation protocols took on their tasks since the last call and
+    /// returns a vector of messages to be sent to the respective participant.
     ///
-    /// An empty vector means we cannot progress until we receive a new message.
+    /// An empty vector means no protocol progressed since, which happens once they receive a new
+    /// message.
     pub fn poke(&mut self) -> Vec<(Participant, SignatureMessage)> {
         let mut messages = Vec::new();
         self.generators.retain(|receipt_id, generator| {
-            loop {
-                let action = match generator.poke() {
-                    Ok(action) => action,
-                    Err(err) => {
-                        tracing::warn!(?err, "signature failed to be produced; pushing request back into failed queue");
-                        if generator.proposer == self.me {
-                            // only retry the signature generation if it was initially proposed by us. We do not
-                            // want any nodes to be proposing the same signature multiple times.
-                            self.failed.push_back((
-                                *receipt_id,
-                                GenerationRequest {
-                                    proposer: generator.proposer,
-                                    request: generator.request.clone(),
-                                    epsilon: generator.epsilon,
-                                    delta: generator.delta,
-                                    sign_request_timestamp: generator.sign_request_timestamp
-                                },
-                            ));
-                        }
-                        break false;
-                    }
-                };
+            let (actions, error) = generator.task.actions();
+            for action in actions {
                 match action {
-                    Action::Wait => {
-                        tracing::debug!("waiting");
-                        // Retain protocol until we are finished
-                        return true;
-                    }
+                    Action::Wait => {}
                     Action::SendMany(data) => {
                         for p in generator.participants.iter() {
                             messages.push((
@@ -398,10 +882,13 @@ impl SignatureManager {
                                     request: generator.request.clone(),
                                     epsilon: generator.epsilon,
                                     delta: generator.delta,
+                                    block_height: generator.block_height,
+                                    participants: generator.participants.clone(),
+                                    slow: generator.slow.clone(),
                                     epoch: self.epoch,
                                     from: self.me,
                                     data: data.clone(),
-                                    timestamp: Utc::now().timestamp() as u64
+                                    timestamp: Utc::now().timestamp() as u64,
                                 },
                             ))
                         }
@@ -415,10 +902,13 @@ impl SignatureManager {
                             request: generator.request.clone(),
                             epsilon: generator.epsilon,
                             delta: generator.delta,
+                            block_height: generator.block_height,
+                            participants: generator.participants.clone(),
+                            slow: generator.slow.clone(),
                             epoch: self.epoch,
                             from: self.me,
                             data,
-                            timestamp: Utc::now().timestamp() as u64
+                            timestamp: Utc::now().timestamp() as u64,
                         },
                     )),
                     Action::Return(output) => {
@@ -430,43 +920,90 @@ impl SignatureManager {
                             s = ?output.s,
                             "completed signature generation"
                         );
-                        self.completed.insert(generator.presignature_id, Instant::now());
+                        self.completed
+                            .insert(generator.presignature_id, Instant::now());
+                        self.protocol_outcomes.extend(generator.outcomes(self.me));
+                        // The contract folds the child tweak into the epsilon it stores for the request.
                         let request = SignatureRequest {
-                            epsilon: SerializableScalar {scalar: generator.epsilon},
+                            epsilon: SerializableScalar {
+                                scalar: generator.epsilon + generator.child_tweak,
+                            },
                             payload_hash: generator.request.payload,
                         };
                         if generator.proposer == self.me {
-                            self.signatures
-                                .push((*receipt_id, request, generator.sign_request_timestamp, output));
+                            self.signatures.push((
+                                *receipt_id,
+                                request,
+                                generator.sign_request_timestamp,
+                                output,
+                            ));
+                        } else {
+                            // Only the proposer publishes. The request is kept until the
+                            // contract resolves it, in case the proposer fails to.
+                            self.awaiting_response
+                                .insert(*receipt_id, (request, generator.block_height));
                         }
                         // Do not retain the protocol
                         return false;
                     }
                 }
             }
+
+            let Some(err) = error else {
+                // Retain protocol until we are finished
+                return true;
+            };
+            tracing::warn!(
+                ?err,
+                presignature_id = generator.presignature_id,
+                "signature failed to be produced; pushing request back into failed queue"
+            );
+            self.protocol_outcomes.extend(generator.outcomes(self.me));
+            if generator.proposer == self.me {
+                // only retry the signature generation if it was initially proposed by us. We do not
+                // want any nodes to be proposing the same signature multiple times.
+                self.failed.push_back((
+                    *receipt_id,
+                    GenerationRequest {
+                        proposer: generator.proposer,
+                        request: generator.request.clone(),
+                        epsilon: generator.epsilon,
+                        delta: generator.delta,
+                        sign_request_timestamp: generator.sign_request_timestamp,
+                        block_height: generator.block_height,
+                        deposit: generator.deposit,
+                        retries: generator.retries + 1,
+                    },
+                ));
+            }
+            false
         });
         messages
     }
 
-    pub fn handle_requests(
+    pub async fn handle_requests(
         &mut self,
         threshold: usize,
+        participants: &Participants,
         active: &Participants,
+        slow: &BTreeSet<Participant>,
         my_requests: &mut HashMap<CryptoHash, SignRequest>,
         presignature_manager: &mut PresignatureManager,
+        block_height: u64,
+        pool: &ComputePool,
     ) {
         let mut failed_presigs = Vec::new();
-        while let Some(mut presignature) = {
+        while let Some(presignature) = {
             if self.failed.is_empty() && my_requests.is_empty() {
                 None
             } else {
-                presignature_manager.take_mine()
+                presignature_manager.take_mine().await
             }
         } {
-            let sig_participants = active.intersection(&[&presignature.participants]);
-            if sig_participants.len() < threshold {
+            let candidates = active.intersection(&[&presignature.participants]);
+            if candidates.len() < threshold {
                 tracing::debug!(
-                    participants = ?sig_participants.keys_vec(),
+                    participants = ?candidates.keys_vec(),
                     "we do not have enough participants to generate a failed signature"
                 );
                 failed_presigs.push(presignature);
@@ -474,29 +1011,85 @@ impl SignatureManager {
             }
             let presig_id = presignature.id;
 
-            // NOTE: this prioritizes old requests first then tries to do new ones if there's enough presignatures.
-            // TODO: we need to decide how to prioritize certain requests over others such as with gas or time of
-            // when the request made it into the NEAR network.
-            // issue: https://github.com/near/mpc-recovery/issues/596
-            if let Some((receipt_id, failed_req)) = self.failed.pop_front() {
+            // Each presignature goes to the highest priority request, whether it is a failed one
+            // to be retried or a new one.
+            let next_failed = self
+                .failed
+                .iter()
+                .enumerate()
+                .map(|(index, (_, req))| (index, req.priority(block_height)))
+                .max_by_key(|(_, priority)| *priority);
+            let next_mine = SignQueue::next_request(my_requests, block_height);
+            let retry = match (&next_failed, &next_mine) {
+                (Some((_, failed)), Some((_, mine))) => failed >= mine,
+                (Some(_), None) => true,
+                (None, _) => false,
+            };
+            let next = if retry {
+                next_failed.and_then(|(index, _)| {
+                    self.failed
+                        .get(index)
+                        .map(|(receipt_id, req)| (*receipt_id, req.delta))
+                })
+            } else {
+                next_mine.and_then(|(receipt_id, _)| {
+                    my_requests
+                        .get(&receipt_id)
+                        .map(|request| (receipt_id, request.delta))
+                })
+            };
+            let Some((receipt_id, delta)) = next else {
+                failed_presigs.push(presignature);
+                continue;
+            };
+
+            // The participants we cannot reach are avoided just like the slow ones, so they only
+            // get picked when the presignature leaves no other choice.
+            let avoided = presignature
+                .participants
+                .iter()
+                .filter(|p| slow.contains(p) || !active.contains_key(p))
+                .copied()
+                .collect::<Vec<_>>();
+            let Some(sig_participants) = select_signers(
+                signers_seed(&receipt_id, &delta, presig_id),
+                self.me,
+                participants,
+                &presignature.participants,
+                &avoided,
+                threshold,
+            )
+            .filter(|signers| signers.iter().all(|p| active.contains_key(p))) else {
+                tracing::debug!(
+                    %receipt_id,
+                    presig_id,
+                    "not enough active participants picked for the presignature"
+                );
+                failed_presigs.push(presignature);
+                continue;
+            };
+
+            if retry {
+                let Some((receipt_id, failed_req)) =
+                    next_failed.and_then(|(index, _)| self.failed.remove(index))
+                else {
+                    failed_presigs.push(presignature);
+                    continue;
+                };
                 if let Err(err) = self.retry_failed_generation(
                     receipt_id,
                     failed_req,
                     presignature,
                     &sig_participants,
+                    &avoided,
+                    pool,
                 ) {
                     tracing::warn!(%receipt_id, presig_id, ?err, "failed to retry signature generation: trashing presignature");
-                    continue;
-                }
-
-                if let Some(another_presignature) = presignature_manager.take_mine() {
-                    presignature = another_presignature;
-                } else {
-                    break;
                 }
+                continue;
             }
 
-            let Some(receipt_id) = my_requests.keys().next().cloned() else {
+            let Some((receipt_id, priority)) = next_mine else {
                 failed_presigs.push(presignature);
                 continue;
             };
@@ -504,14 +1097,19 @@ impl SignatureManager {
                 failed_presigs.push(presignature);
                 continue;
             };
+            tracing::debug!(%receipt_id, ?priority, "picked sign request to generate");
             if let Err(err) = self.generate(
                 &sig_participants,
+                &avoided,
                 receipt_id,
                 presignature,
                 my_request.request,
                 my_request.epsilon,
                 my_request.delta,
                 my_request.time_added,
+                my_request.block_height,
+                my_request.deposit,
+                pool,
             ) {
                 tracing::warn!(%receipt_id, presig_id, ?err, "failed to start signature generation: trashing presignature");
                 continue;
@@ -521,7 +1119,7 @@ impl SignatureManager {
         // add back the failed presignatures that were incompatible to be made into
         // signatures due to failures or lack of participants.
         for presignature in failed_presigs {
-            presignature_manager.insert_mine(presignature);
+            presignature_manager.insert_mine(presignature).await;
         }
     }
 
@@ -552,6 +1150,9 @@ impl SignatureManager {
                 .retry_exponential(10, 5)
                 .transact()
                 .await?;
+            // The request is resolved once `respond` lands, even when the contract rejects it
+            // for having been answered already.
+            self.resolved.push(receipt_id);
             crate::metrics::NUM_SIGN_SUCCESS
                 .with_label_values(&[my_account_id.as_str()])
                 .inc();
@@ -568,6 +1169,161 @@ impl SignatureManager {
         Ok(())
     }
 
+    /// Takes the requests that no longer need to be persisted.
+    pub fn take_resolved(&mut self) -> Vec<CryptoHash> {
+        std::mem::take(&mut self.resolved)
+    }
+
+    /// Takes whether the participants of the protocols that finished since sent their share.
+    pub fn take_protocol_outcomes(&mut self) -> Vec<(Participant, bool)> {
+        std::mem::take(&mut self.protocol_outcomes)
+    }
+
+    /// Requests this node is generating a signature for as their proposer.
+    pub fn my_generations(&self) -> impl Iterator<Item = &CryptoHash> {
+        self.generators
+            .iter()
+            .filter(|(_, generator)| generator.proposer == self.me)
+            .map(|(receipt_id, _)| receipt_id)
+    }
+
+    /// Proposer of the signature being generated for the request, if any.
+    pub fn proposer_of(&self, receipt_id: &CryptoHash) -> Option<Participant> {
+        self.generators
+            .get(receipt_id)
+            .map(|generator| generator.proposer)
+    }
+
+    /// Stops generating the signatures of the requests that got handed over to another proposer,
+    /// be it as their previous proposer or by joining it, and stops retrying the failed ones. This
+    /// way no presignature is spent on a request but by its current proposer.
+    pub fn hand_off(&mut self, handed_off: &[(CryptoHash, Participant)]) {
+        let proposers = handed_off.iter().copied().collect::<HashMap<_, _>>();
+        let me = self.me;
+        self.failed.retain(|(receipt_id, _)| {
+            proposers
+                .get(receipt_id)
+                .map_or(true, |proposer| *proposer == me)
+        });
+        self.generators.retain(|receipt_id, generator| {
+            let Some(proposer) = proposers.get(receipt_id) else {
+                return true;
+            };
+            if *proposer == generator.proposer {
+                return true;
+            }
+            tracing::info!(
+                %receipt_id,
+                ongoing_proposer = ?generator.proposer,
+                ?proposer,
+                "cancelling signature generation: another proposer took over"
+            );
+            false
+        });
+    }
+
+    /// Drops the generators and failed requests that expired on-chain by `block_height`, along
+    /// with those of the `expired` requests.
+    pub fn prune_expired(
+        &mut self,
+        block_height: u64,
+        expired: &[CryptoHash],
+        my_account_id: &AccountId,
+    ) {
+        let expired = expired.iter().collect::<HashSet<_>>();
+        let mut pruned = 0;
+        self.generators.retain(|receipt_id, generator| {
+            let is_live = !expired.contains(receipt_id)
+                && !is_expired(generator.block_height, block_height);
+            if !is_live {
+                tracing::info!(%receipt_id, block_height, "dropping signature generator of expired sign request");
+                // Requests still in the sign queue were already counted along with `expired`.
+                pruned += !expired.contains(receipt_id) as i64;
+            }
+            is_live
+        });
+        // The sign queue resolves these along with the rest of its expired requests.
+        self.awaiting_response
+            .retain(|receipt_id, (_, request_height)| {
+                !expired.contains(receipt_id) && !is_expired(*request_height, block_height)
+            });
+        self.failed.retain(|(receipt_id, req)| {
+            let is_live =
+                !expired.contains(receipt_id) && !is_expired(req.block_height, block_height);
+            if !is_live {
+                tracing::info!(%receipt_id, block_height, "dropping failed expired sign request");
+                self.resolved.push(*receipt_id);
+                pruned += !expired.contains(receipt_id) as i64;
+            }
+            is_live
+        });
+        crate::metrics::NUM_SIGN_REQUESTS_PRUNED
+            .with_label_values(&[my_account_id.as_str(), "expired"])
+            .add(pruned + expired.len() as i64);
+    }
+
+    /// Requests to check against the contract for having been resolved: the failed ones and
+    /// those awaiting another proposer's response. Empty unless
+    /// `SIGN_REQUEST_RESOLUTION_CHECK_INTERVAL` passed since the last check.
+    pub fn resolution_checks(&mut self) -> Vec<(CryptoHash, SignatureRequest)> {
+        if (self.failed.is_empty() && self.awaiting_response.is_empty())
+            || self.last_resolution_check.elapsed() < SIGN_REQUEST_RESOLUTION_CHECK_INTERVAL
+        {
+            return Vec::new();
+        }
+        self.last_resolution_check = Instant::now();
+
+        let failed = self.failed.iter().filter_map(|(receipt_id, req)| {
+            // Requests with an invalid child path never reached the contract in this shape.
+            let child_tweak =
+                derive_child_tweak(self.public_key, req.epsilon, &req.request.child_path).ok()?;
+            let request = SignatureRequest {
+                epsilon: SerializableScalar {
+                    scalar: req.epsilon + child_tweak,
+                },
+                payload_hash: req.request.payload,
+            };
+            Some((*receipt_id, request))
+        });
+        let awaiting = self
+            .awaiting_response
+            .iter()
+            .map(|(receipt_id, (request, _))| (*receipt_id, request.clone()));
+        failed.chain(awaiting).collect()
+    }
+
+    /// Drops the failed requests and those awaiting another proposer's response that the
+    /// contract no longer has pending, since they were either answered or timed out. Returns the
+    /// ones that were still known, to be removed from the sign queue and storage.
+    pub fn prune_resolved(
+        &mut self,
+        resolved: &[CryptoHash],
+        my_account_id: &AccountId,
+    ) -> Vec<CryptoHash> {
+        let resolved = resolved.iter().collect::<HashSet<_>>();
+        let mut pruned = Vec::new();
+        self.failed.retain(|(receipt_id, _)| {
+            if !resolved.contains(receipt_id) {
+                return true;
+            }
+            tracing::info!(%receipt_id, "dropping failed sign request resolved on-chain");
+            crate::metrics::NUM_SIGN_REQUESTS_PRUNED
+                .with_label_values(&[my_account_id.as_str(), "resolved"])
+                .inc();
+            pruned.push(*receipt_id);
+            false
+        });
+        self.awaiting_response.retain(|receipt_id, _| {
+            if !resolved.contains(receipt_id) {
+                return true;
+            }
+            tracing::info!(%receipt_id, "sign request got answered on-chain");
+            pruned.push(*receipt_id);
+            false
+        });
+        pruned
+    }
+
     /// Check whether or not the signature has been completed with this presignature_id.
     pub fn has_completed(&mut self, presignature_id: &PresignatureId) -> bool {
         self.completed
@@ -576,3 +1332,372 @@ impl SignatureManager {
         self.completed.contains_key(presignature_id)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        select_signers, signers_seed, GenerationRequest, ProposalCheck, SignQueue, SignRequest,
+        SignatureGenerator, SignatureManager, PROPOSER_TURN_BLOCKS,
+        SIGN_REQUEST_RESOLUTION_CHECK_INTERVAL, SIGN_REQUEST_TIMEOUT_BLOCKS,
+    };
+    use crate::indexer::ContractSignRequest;
+    use crate::protocol::compute::{ComputePool, ProtocolTask};
+    use crate::protocol::contract::primitives::{ParticipantInfo, Participants};
+    use cait_sith::protocol::{Action, MessageData, Participant, Protocol, ProtocolError};
+    use cait_sith::FullSignature;
+    use crypto_shared::SerializableScalar;
+    use k256::{AffinePoint, Scalar, Secp256k1};
+    use mpc_contract::SignatureRequest;
+    use near_primitives::hash::CryptoHash;
+    use std::collections::HashSet;
+    use std::time::{Duration, Instant};
+
+    fn participants(count: u32) -> Participants {
+        let mut participants = Participants::default();
+        for id in 0..count {
+            participants.insert(&Participant::from(id), ParticipantInfo::new(id));
+        }
+        participants
+    }
+
+    fn sign_request(seed: u8) -> SignRequest {
+        SignRequest {
+            receipt_id: CryptoHash([seed; 32]),
+            request: ContractSignRequest {
+                payload: [seed; 32],
+                path: "test".to_string(),
+                key_version: 0,
+                child_path: Vec::new(),
+            },
+            epsilon: Scalar::from(seed as u64),
+            delta: Scalar::from(seed as u64 + 1),
+            entropy: [seed; 32],
+            time_added: Instant::now(),
+            block_height: 100,
+            deposit: 1,
+        }
+    }
+
+    /// Organizes a request from the point of view of each participant and returns the queue of
+    /// its proposer, along with the proposer.
+    fn proposer_queue(
+        participants: &Participants,
+        request: &SignRequest,
+    ) -> (SignQueue, Participant) {
+        for me in participants.keys() {
+            let mut queue = SignQueue::new();
+            queue.add(request.clone());
+            queue.organize(2, participants, *me, &"me.near".parse().unwrap());
+            if queue.contains(*me, request.receipt_id) {
+                return (queue, *me);
+            }
+        }
+        panic!("the request has a proposer among the participants");
+    }
+
+    #[test]
+    fn test_proposer_verifies_messages_of_its_own_generator() {
+        let participants = participants(3);
+        let request = sign_request(7);
+        let (mut queue, me) = proposer_queue(&participants, &request);
+        let check = |queue: &SignQueue, request: &SignRequest| {
+            queue.check_proposal(
+                &request.receipt_id,
+                me,
+                &request.request,
+                &request.epsilon,
+                &request.delta,
+                request.block_height,
+            )
+        };
+
+        // Starting the generator takes the request out of the proposer's queue, after which the
+        // messages of that generator still have to be accepted by the proposer itself.
+        queue.my_requests(me).remove(&request.receipt_id);
+        assert_eq!(check(&queue, &request), ProposalCheck::Verified);
+
+        let mut tampered = request.clone();
+        tampered.request.payload = [8; 32];
+        assert_eq!(check(&queue, &tampered), ProposalCheck::Mismatch("request"));
+
+        queue.remove(&request.receipt_id);
+        assert_eq!(check(&queue, &request), ProposalCheck::Unknown);
+    }
+
+    #[test]
+    fn test_every_node_checks_proposals_of_the_rotation() {
+        let participants = participants(4);
+        let request = sign_request(3);
+        let (_, proposer) = proposer_queue(&participants, &request);
+
+        // Nodes outside of the rotation still get to sign, so they need to check proposals too.
+        for me in participants.keys() {
+            let mut queue = SignQueue::new();
+            queue.add(request.clone());
+            queue.organize(2, &participants, *me, &"me.near".parse().unwrap());
+            assert_eq!(queue.contains(*me, request.receipt_id), *me == proposer);
+            let check = queue.check_proposal(
+                &request.receipt_id,
+                proposer,
+                &request.request,
+                &request.epsilon,
+                &request.delta,
+                request.block_height,
+            );
+            assert_eq!(check, ProposalCheck::Verified);
+        }
+    }
+
+    #[test]
+    fn test_select_signers_prefers_responsive_participants() {
+        let participants = participants(5);
+        let holders = participants.keys_vec();
+        let me = Participant::from(0);
+        let slow = vec![Participant::from(1), Participant::from(2)];
+        let request = sign_request(1);
+        let seed = signers_seed(&request.receipt_id, &request.delta, 7);
+
+        let signers = select_signers(seed, me, &participants, &holders, &slow, 3);
+        assert_eq!(
+            signers,
+            Some(vec![me, Participant::from(3), Participant::from(4)])
+        );
+
+        // Slow participants make up for the missing responsive ones.
+        let signers = select_signers(seed, me, &participants, &holders, &slow, 4).unwrap();
+        assert_eq!(signers.len(), 4);
+        assert!(signers.contains(&me));
+        assert!(signers.contains(&Participant::from(3)));
+        assert!(signers.contains(&Participant::from(4)));
+
+        // Only the participants holding a share of the presignature can be picked.
+        let signers = select_signers(seed, me, &participants, &holders[..3], &[], 3);
+        assert_eq!(signers, Some(holders[..3].to_vec()));
+        assert_eq!(
+            select_signers(seed, me, &participants, &holders[..2], &[], 3),
+            None
+        );
+    }
+
+    #[test]
+    fn test_select_signers_is_deterministic() {
+        let participants = participants(7);
+        let holders = participants.keys_vec();
+        let me = Participant::from(3);
+        let request = sign_request(1);
+
+        // Every node recomputes the same pick from the request and the presignature.
+        let seed = signers_seed(&request.receipt_id, &request.delta, 7);
+        let signers = select_signers(seed, me, &participants, &holders, &[], 4).unwrap();
+        for _ in 0..8 {
+            assert_eq!(
+                select_signers(seed, me, &participants, &holders, &[], 4),
+                Some(signers.clone())
+            );
+        }
+
+        // Another presignature leads to other picks.
+        let picks = (0..16)
+            .map(|id| {
+                let seed = signers_seed(&request.receipt_id, &request.delta, id);
+                select_signers(seed, me, &participants, &holders, &[], 4).unwrap()
+            })
+            .collect::<HashSet<_>>();
+        assert!(picks.len() > 1);
+    }
+
+    /// Organizes a request the way every participant does when all of them are in its rotation,
+    /// and returns the queue of one of them along with the rotation.
+    fn rotation_queue(request: &SignRequest) -> (SignQueue, Vec<Participant>) {
+        let participants = participants(3);
+        let mut queue = SignQueue::new();
+        queue.add(request.clone());
+        queue.organize(
+            3,
+            &participants,
+            Participant::from(0),
+            &"me.near".parse().unwrap(),
+        );
+        queue.set_block_height(request.block_height);
+        let proposers = queue.rotations[&request.receipt_id].proposers.clone();
+        (queue, proposers)
+    }
+
+    #[test]
+    fn test_rotation_hands_request_to_the_next_proposer() {
+        let request = sign_request(5);
+        let (mut queue, proposers) = rotation_queue(&request);
+        assert!(queue.contains(proposers[0], request.receipt_id));
+
+        // Making progress extends the turn of the proposer.
+        queue.set_block_height(request.block_height + PROPOSER_TURN_BLOCKS - 1);
+        queue.observe_progress(&request.receipt_id, proposers[0]);
+        queue.set_block_height(request.block_height + PROPOSER_TURN_BLOCKS);
+        assert!(queue.rotate_proposers().is_empty());
+
+        queue.set_block_height(request.block_height + 2 * PROPOSER_TURN_BLOCKS);
+        assert_eq!(
+            queue.rotate_proposers(),
+            vec![(request.receipt_id, proposers[1])]
+        );
+        assert!(!queue.contains(proposers[0], request.receipt_id));
+        assert!(queue.contains(proposers[1], request.receipt_id));
+
+        // The last proposer keeps the request for good.
+        queue.set_block_height(request.block_height + 10 * PROPOSER_TURN_BLOCKS);
+        assert_eq!(
+            queue.rotate_proposers(),
+            vec![(request.receipt_id, proposers[2])]
+        );
+        assert!(queue.rotate_proposers().is_empty());
+        assert!(queue.contains(proposers[2], request.receipt_id));
+    }
+
+    #[test]
+    fn test_later_proposer_takes_over() {
+        let request = sign_request(6);
+        let (mut queue, proposers) = rotation_queue(&request);
+
+        // A node that has yet to see the turn of the first proposer end learns about it from the
+        // progress of a later one.
+        assert_eq!(
+            queue.observe_progress(&request.receipt_id, proposers[2]),
+            Some((request.receipt_id, proposers[2]))
+        );
+        assert!(queue.contains(proposers[2], request.receipt_id));
+
+        // The earlier proposers do not get the request back.
+        assert_eq!(
+            queue.observe_progress(&request.receipt_id, proposers[0]),
+            None
+        );
+        assert_eq!(
+            queue.turn_of(&request.receipt_id, proposers[2]),
+            Some(queue.rotations[&request.receipt_id].turn)
+        );
+        assert!(queue.contains(proposers[2], request.receipt_id));
+    }
+
+    /// Protocol that never completes.
+    struct Stalled;
+
+    impl Protocol for Stalled {
+        type Output = FullSignature<Secp256k1>;
+
+        fn poke(&mut self) -> Result<Action<Self::Output>, ProtocolError> {
+            Ok(Action::Wait)
+        }
+
+        fn message(&mut self, _from: Participant, _data: MessageData) {}
+    }
+
+    #[tokio::test]
+    async fn test_hand_off_cancels_generation_of_previous_proposer() {
+        let me = Participant::from(0);
+        let next = Participant::from(1);
+        let pool = ComputePool::new(1, &"test.testnet".parse().unwrap());
+        let mut manager = SignatureManager::new(me, AffinePoint::GENERATOR, 0);
+        let generating = sign_request(1);
+        let failed = sign_request(2);
+        manager.generators.insert(
+            generating.receipt_id,
+            SignatureGenerator::new(
+                ProtocolTask::spawn(Box::new(Stalled), &pool, Duration::from_secs(60)),
+                vec![me, next],
+                Vec::new(),
+                me,
+                1,
+                generating.request.clone(),
+                generating.epsilon,
+                Scalar::ZERO,
+                generating.delta,
+                Instant::now(),
+                generating.block_height,
+                generating.deposit,
+                0,
+            ),
+        );
+        manager.failed.push_back((
+            failed.receipt_id,
+            GenerationRequest {
+                proposer: me,
+                request: failed.request.clone(),
+                epsilon: failed.epsilon,
+                delta: failed.delta,
+                sign_request_timestamp: Instant::now(),
+                block_height: failed.block_height,
+                deposit: failed.deposit,
+                retries: 1,
+            },
+        ));
+
+        // Handing a request back to the same proposer changes nothing.
+        manager.hand_off(&[(generating.receipt_id, me)]);
+        assert_eq!(manager.proposer_of(&generating.receipt_id), Some(me));
+
+        manager.hand_off(&[(generating.receipt_id, next), (failed.receipt_id, next)]);
+        assert_eq!(manager.proposer_of(&generating.receipt_id), None);
+        assert_eq!(manager.failed_len(), 0);
+    }
+
+    #[test]
+    fn test_requests_signed_for_another_proposer_wait_for_the_contract() {
+        let me = Participant::from(0);
+        let account_id = "me.near".parse().unwrap();
+        let mut manager = SignatureManager::new(me, AffinePoint::GENERATOR, 0);
+        let failed = sign_request(1);
+        let awaiting = sign_request(2);
+        manager.failed.push_back((
+            failed.receipt_id,
+            GenerationRequest {
+                proposer: me,
+                request: failed.request.clone(),
+                epsilon: failed.epsilon,
+                delta: failed.delta,
+                sign_request_timestamp: Instant::now(),
+                block_height: failed.block_height,
+                deposit: failed.deposit,
+                retries: 1,
+            },
+        ));
+        let request = SignatureRequest {
+            epsilon: SerializableScalar {
+                scalar: awaiting.epsilon,
+            },
+            payload_hash: awaiting.request.payload,
+        };
+        manager
+            .awaiting_response
+            .insert(awaiting.receipt_id, (request, awaiting.block_height));
+
+        // The contract is only asked once the check interval passed.
+        assert!(manager.resolution_checks().is_empty());
+        manager.last_resolution_check -= SIGN_REQUEST_RESOLUTION_CHECK_INTERVAL;
+        let checks = manager.resolution_checks();
+        assert_eq!(checks.len(), 2);
+        assert!(manager.resolution_checks().is_empty());
+
+        // Only the requests the contract resolved are dropped, and only known ones are returned.
+        let unknown = CryptoHash([9; 32]);
+        assert_eq!(
+            manager.prune_resolved(&[awaiting.receipt_id, unknown], &account_id),
+            vec![awaiting.receipt_id]
+        );
+        assert!(manager.awaiting_response.is_empty());
+        assert_eq!(manager.failed_len(), 1);
+
+        // Requests awaiting a response are dropped once they expire.
+        manager.awaiting_response.insert(
+            awaiting.receipt_id,
+            (checks[1].1.clone(), awaiting.block_height),
+        );
+        manager.prune_expired(awaiting.block_height + 1, &[], &account_id);
+        assert_eq!(manager.awaiting_response.len(), 1);
+        manager.prune_expired(
+            awaiting.block_height + SIGN_REQUEST_TIMEOUT_BLOCKS + 1,
+            &[],
+            &account_id,
+        );
+        assert!(manager.awaiting_response.is_empty());
+    }
+}
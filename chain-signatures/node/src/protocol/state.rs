@@ -5,7 +5,6 @@ use super::signature::SignatureManager;
 use super::triple::TripleManager;
 use super::SignQueue;
 use crate::http_client::MessageQueue;
-use crate::storage::triple_storage::TripleData;
 use crate::types::{KeygenProtocol, ReshareProtocol, SecretKeyShare};
 use cait_sith::protocol::Participant;
 use crypto_shared::PublicKey;
@@ -18,14 +17,25 @@ use tokio::sync::RwLock;
 #[derive(Clone, Serialize, Deserialize)]
 pub struct PersistentNodeData {
     pub epoch: u64,
+    /// Version of the root key the share is of. Shares stored before versions were recorded are
+    /// of the first one.
+    #[serde(default)]
+    pub key_version: u32,
     pub private_share: SecretKeyShare,
     pub public_key: PublicKey,
 }
 
+impl PersistentNodeData {
+    /// Version of the root key that key generation produces and resharing carries over, which is
+    /// the contract's `latest_key_version`.
+    pub const KEY_VERSION: u32 = 0;
+}
+
 impl fmt::Debug for PersistentNodeData {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         f.debug_struct("PersistentNodeData")
             .field("epoch", &self.epoch)
+            .field("key_version", &self.key_version)
             .field("public_key", &self.public_key)
             .finish()
     }
@@ -33,8 +43,20 @@ impl fmt::Debug for PersistentNodeData {
 
 #[derive(Debug, Clone)]
 pub struct StartedState {
-    pub persistent_node_data: Option<PersistentNodeData>,
-    pub triple_data: Vec<TripleData>,
+    /// Key shares of all the epochs kept in secret storage, oldest first.
+    pub key_shares: Vec<PersistentNodeData>,
+}
+
+impl StartedState {
+    /// Picks the key share to continue with: the one of the epoch the contract is at if we still
+    /// have it, so that a node that outlived an abandoned resharing goes back to its old share.
+    /// Otherwise the latest one, from which the node rejoins or finds out about a rollback.
+    pub fn key_share_for(&self, epoch: u64, public_key: &PublicKey) -> Option<&PersistentNodeData> {
+        self.key_shares
+            .iter()
+            .find(|data| data.epoch == epoch && &data.public_key == public_key)
+            .or_else(|| self.key_shares.last())
+    }
 }
 
 #[derive(Clone)]
@@ -96,6 +118,9 @@ pub struct RunningState {
     pub presignature_manager: Arc<RwLock<PresignatureManager>>,
     pub signature_manager: Arc<RwLock<SignatureManager>>,
     pub messages: Arc<RwLock<MessageQueue>>,
+    /// Whether the key shares of earlier epochs were removed from secret storage, which happens
+    /// once the contract has been running this epoch for `key_share_retention`.
+    pub pruned_key_shares: bool,
 }
 
 impl RunningState {
@@ -171,6 +196,17 @@ impl NodeState {
         }
     }
 
+    /// Epoch this node is in, if it has any participants to exchange messages with.
+    pub fn epoch(&self) -> Option<u64> {
+        match self {
+            NodeState::Generating(_) => Some(0),
+            NodeState::WaitingForConsensus(state) => Some(state.epoch),
+            NodeState::Running(state) => Some(state.epoch),
+            NodeState::Resharing(state) => Some(state.old_epoch),
+            _ => None,
+        }
+    }
+
     pub fn find_participant_info(&self, account_id: &AccountId) -> Option<&ParticipantInfo> {
         match self {
             NodeState::Starting => None,
@@ -1,3 +1,4 @@
+use super::compute::{ComputePool, ProtocolTask};
 use super::contract::primitives::Participants;
 use super::cryptography::CryptographicError;
 use super::message::TripleMessage;
@@ -7,8 +8,12 @@ use crate::storage::triple_storage::{LockTripleNodeStorageBox, TripleData};
 use crate::types::TripleProtocol;
 use crate::util::AffinePointExt;
 
-use cait_sith::protocol::{Action, InitializationError, Participant, ProtocolError};
-use cait_sith::triples::{TripleGenerationOutput, TriplePub, TripleShare};
+use cait_sith::protocol::{
+    Action, InitializationError, MessageData, Participant, Protocol, ProtocolError,
+};
+use cait_sith::triples::{
+    TripleGenerationOutput, TripleGenerationOutputMany, TriplePub, TripleShare,
+};
 use chrono::Utc;
 use highway::{HighwayHash, HighwayHasher};
 use k256::elliptic_curve::group::GroupEncoding;
@@ -25,6 +30,99 @@ use near_account_id::AccountId;
 /// messages.
 pub type TripleId = u64;
 
+/// Unique number used to identify a specific ongoing batched triple generation protocol. The
+/// triples coming out of the batch get their ids derived from it, see [`batch_triple_ids`].
+pub type TripleBatchId = u64;
+
+/// Batch sizes a triple generation protocol can be started with. cait-sith needs to know the
+/// batch size at compile time, so only this fixed set of sizes is available.
+pub const SUPPORTED_TRIPLE_BATCH_SIZES: [usize; 5] = [1, 2, 4, 8, 16];
+
+/// Ids of the triples generated by the batch `batch_id` of size `batch_size`. The first triple
+/// reuses the batch id, so that a batch of one is the same as the unbatched protocol.
+pub fn batch_triple_ids(batch_id: TripleBatchId, batch_size: usize) -> Vec<TripleId> {
+    (0..batch_size as u64)
+        .map(|index| {
+            if index == 0 {
+                batch_id
+            } else {
+                let mut bytes = batch_id.to_le_bytes().to_vec();
+                bytes.extend(index.to_le_bytes());
+                HighwayHasher::default().hash64(&bytes)
+            }
+        })
+        .collect()
+}
+
+/// Runs the unbatched protocol for batches of one, which is what nodes that predate batching run
+/// for every triple.
+struct SingleTriple<P>(P);
+
+impl<P> Protocol for SingleTriple<P>
+where
+    P: Protocol<Output = TripleGenerationOutput<Secp256k1>>,
+{
+    type Output = TripleGenerationOutputMany<Secp256k1>;
+
+    fn poke(&mut self) -> Result<Action<Self::Output>, ProtocolError> {
+        Ok(match self.0.poke()? {
+            Action::Wait => Action::Wait,
+            Action::SendMany(data) => Action::SendMany(data),
+            Action::SendPrivate(to, data) => Action::SendPrivate(to, data),
+            Action::Return(output) => Action::Return(vec![output]),
+        })
+    }
+
+    fn message(&mut self, from: Participant, data: MessageData) {
+        self.0.message(from, data)
+    }
+}
+
+/// Starts a triple generation protocol generating `batch_size` triples at once, which all share
+/// the same communication rounds.
+fn generate_triple_batch(
+    participants: &[Participant],
+    me: Participant,
+    threshold: usize,
+    batch_size: usize,
+) -> Result<TripleProtocol, InitializationError> {
+    use cait_sith::triples::{generate_triple, generate_triple_many};
+
+    let protocol: TripleProtocol = match batch_size {
+        1 => Box::new(SingleTriple(generate_triple::<Secp256k1>(
+            participants,
+            me,
+            threshold,
+        )?)),
+        2 => Box::new(generate_triple_many::<Secp256k1, 2>(
+            participants,
+            me,
+            threshold,
+        )?),
+        4 => Box::new(generate_triple_many::<Secp256k1, 4>(
+            participants,
+            me,
+            threshold,
+        )?),
+        8 => Box::new(generate_triple_many::<Secp256k1, 8>(
+            participants,
+            me,
+            threshold,
+        )?),
+        16 => Box::new(generate_triple_many::<Secp256k1, 16>(
+            participants,
+            me,
+            threshold,
+        )?),
+        _ => {
+            return Err(InitializationError::BadParameters(format!(
+                "unsupported triple batch size: {batch_size}"
+            )))
+        }
+    };
+    Ok(protocol)
+}
+
 /// A completed triple.
 #[derive(Clone, Serialize, Deserialize, Debug)]
 pub struct Triple {
@@ -33,37 +131,78 @@ pub struct Triple {
     pub public: TriplePub<Secp256k1>,
 }
 
+/// A triple generation protocol, which only gets a task of its own once it leaves the queue.
+enum TripleTask {
+    /// Waiting for a slot in the ongoing pool. The protocol keeps the messages it gets until then.
+    Queued(TripleProtocol),
+    Running(ProtocolTask<TripleGenerationOutputMany<Secp256k1>>),
+}
+
 pub struct TripleGenerator {
-    pub id: TripleId,
+    pub id: TripleBatchId,
+    pub batch_size: usize,
     pub participants: Vec<Participant>,
-    pub protocol: TripleProtocol,
+    task: TripleTask,
     pub timestamp: Option<Instant>,
 }
 
 impl TripleGenerator {
-    pub fn new(id: TripleId, participants: Vec<Participant>, protocol: TripleProtocol) -> Self {
+    pub fn new(
+        id: TripleBatchId,
+        batch_size: usize,
+        participants: Vec<Participant>,
+        protocol: TripleProtocol,
+    ) -> Self {
         Self {
             id,
+            batch_size,
             participants,
-            protocol,
+            task: TripleTask::Queued(protocol),
             timestamp: None,
         }
     }
 
-    pub fn poke(&mut self) -> Result<Action<TripleGenerationOutput<Secp256k1>>, ProtocolError> {
-        let timestamp = self.timestamp.get_or_insert_with(Instant::now);
-        if timestamp.elapsed() > crate::util::get_triple_timeout() {
-            tracing::info!(
-                id = self.id,
-                elapsed = ?timestamp.elapsed(),
-                "triple protocol timed out"
-            );
-            return Err(ProtocolError::Other(
-                anyhow::anyhow!("triple protocol timed out").into(),
-            ));
+    /// Ids of the triples this generator will produce.
+    pub fn triple_ids(&self) -> Vec<TripleId> {
+        batch_triple_ids(self.id, self.batch_size)
+    }
+
+    pub fn message(&mut self, from: Participant, data: MessageData) {
+        match &mut self.task {
+            TripleTask::Queued(protocol) => protocol.message(from, data),
+            TripleTask::Running(task) => task.message(from, data),
         }
+    }
 
-        self.protocol.poke()
+    /// Starts the protocol on a task of its own, which fails it once it runs for longer than the
+    /// triple timeout.
+    fn start(self, pool: &ComputePool) -> Self {
+        let task = match self.task {
+            TripleTask::Queued(protocol) => TripleTask::Running(ProtocolTask::spawn(
+                protocol,
+                pool,
+                crate::util::get_triple_timeout(),
+            )),
+            running @ TripleTask::Running(_) => running,
+        };
+        Self {
+            task,
+            timestamp: Some(self.timestamp.unwrap_or_else(Instant::now)),
+            ..self
+        }
+    }
+
+    /// Takes the actions of the protocol since the last call, none until it started.
+    fn actions(
+        &mut self,
+    ) -> (
+        Vec<Action<TripleGenerationOutputMany<Secp256k1>>>,
+        Option<ProtocolError>,
+    ) {
+        match &mut self.task {
+            TripleTask::Queued(_) => (Vec::new(), None),
+            TripleTask::Running(task) => task.actions(),
+        }
     }
 }
 
@@ -78,6 +217,8 @@ pub struct TripleConfig {
     pub max_concurrent_introduction: usize,
     /// Maximum amount of concurrent triple generation that can be done per node.
     pub max_concurrent_generation: usize,
+    /// Maximum amount of triples generated at once by a single triple generation protocol.
+    pub max_batch_size: usize,
 }
 
 /// Abstracts how triples are generated by providing a way to request a new triple that will be
@@ -87,18 +228,18 @@ pub struct TripleManager {
     pub triples: HashMap<TripleId, Triple>,
 
     /// The pool of triple protocols that have yet to be completed.
-    pub generators: HashMap<TripleId, TripleGenerator>,
+    pub generators: HashMap<TripleBatchId, TripleGenerator>,
 
     /// Triples that are queued to be poked. If these generators sit for too long in
     /// the queue, they will be removed due to triple generation timeout.
-    pub queued: VecDeque<TripleId>,
+    pub queued: VecDeque<TripleBatchId>,
 
     /// Ongoing triple generation protocols. Once added here, they will not be removed until
     /// they are completed or timed out.
-    pub ongoing: HashSet<TripleId>,
+    pub ongoing: HashSet<TripleBatchId>,
 
-    /// The set of triples that were introduced to the system by the current node.
-    pub introduced: HashSet<TripleId>,
+    /// The set of triple batches that were introduced to the system by the current node.
+    pub introduced: HashSet<TripleBatchId>,
 
     /// List of triple ids generation of which was initiated by the current node.
     pub mine: VecDeque<TripleId>,
@@ -113,7 +254,7 @@ pub struct TripleManager {
     pub triple_cfg: TripleConfig,
     pub triple_storage: LockTripleNodeStorageBox,
     /// triple generation protocols that failed.
-    pub failed_triples: HashMap<TripleId, Instant>,
+    pub failed_triples: HashMap<TripleBatchId, Instant>,
     pub my_account_id: AccountId,
 }
 
@@ -173,7 +314,19 @@ impl TripleManager {
     /// Returns the number of unspent triples we will have in the manager once
     /// all ongoing generation protocols complete.
     pub fn potential_len(&self) -> usize {
-        self.len() + self.generators.len()
+        self.len()
+            + self
+                .generators
+                .values()
+                .map(|generator| generator.batch_size)
+                .sum::<usize>()
+    }
+
+    /// Returns if the triple with the given id is currently being generated.
+    fn is_generating(&self, id: TripleId) -> bool {
+        self.generators
+            .values()
+            .any(|generator| generator.triple_ids().contains(&id))
     }
 
     /// Clears an entry from failed triples if that triple protocol was created more than 2 hrs ago
@@ -187,29 +340,34 @@ impl TripleManager {
             .retain(|_, timestamp| timestamp.elapsed() < crate::types::TAKEN_TIMEOUT)
     }
 
-    /// Starts a new Beaver triple generation protocol.
-    pub fn generate(&mut self, participants: &Participants) -> Result<(), InitializationError> {
+    /// Starts a new Beaver triple generation protocol generating `batch_size` triples.
+    pub fn generate(
+        &mut self,
+        participants: &Participants,
+        batch_size: usize,
+    ) -> Result<(), InitializationError> {
         let id = rand::random();
 
         // Check if the `id` is already in the system. Error out and have the next cycle try again.
         if self.generators.contains_key(&id)
-            || self.triples.contains_key(&id)
-            || self.taken.contains_key(&id)
+            || batch_triple_ids(id, batch_size).iter().any(|triple_id| {
+                self.triples.contains_key(triple_id)
+                    || self.taken.contains_key(triple_id)
+                    || self.is_generating(*triple_id)
+            })
         {
             return Err(InitializationError::BadParameters(format!(
-                "id collision: triple_id={id}"
+                "id collision: triple_batch_id={id}"
             )));
         }
 
-        tracing::debug!(id, "starting protocol to generate a new triple");
+        tracing::debug!(id, batch_size, "starting protocol to generate new triples");
         let participants: Vec<_> = participants.keys().cloned().collect();
-        let protocol: TripleProtocol = Box::new(cait_sith::triples::generate_triple::<Secp256k1>(
-            &participants,
-            self.me,
-            self.threshold,
-        )?);
-        self.generators
-            .insert(id, TripleGenerator::new(id, participants, protocol));
+        let protocol = generate_triple_batch(&participants, self.me, self.threshold, batch_size)?;
+        self.generators.insert(
+            id,
+            TripleGenerator::new(id, batch_size, participants, protocol),
+        );
         self.queued.push_back(id);
         self.introduced.insert(id);
         crate::metrics::NUM_TOTAL_HISTORICAL_TRIPLE_GENERATORS
@@ -226,6 +384,7 @@ impl TripleManager {
             max_triples,
             max_concurrent_introduction,
             max_concurrent_generation,
+            max_batch_size,
         } = self.triple_cfg;
 
         let not_enough_triples = {
@@ -243,7 +402,17 @@ impl TripleManager {
         };
 
         if not_enough_triples {
-            self.generate(participants)?;
+            // Generate as many triples at once as we are missing, within the configured and
+            // supported batch sizes and without going over the maximum amount of triples.
+            let wanted = (min_triples - self.my_len())
+                .min(max_triples - self.potential_len())
+                .min(max_batch_size);
+            let batch_size = SUPPORTED_TRIPLE_BATCH_SIZES
+                .into_iter()
+                .filter(|batch_size| *batch_size <= wanted)
+                .max()
+                .unwrap_or(1);
+            self.generate(participants, batch_size)?;
         }
         Ok(())
     }
@@ -258,13 +427,13 @@ impl TripleManager {
         id1: TripleId,
     ) -> Result<(Triple, Triple), GenerationError> {
         if !self.triples.contains_key(&id0) {
-            if self.generators.contains_key(&id0) {
+            if self.is_generating(id0) {
                 Err(GenerationError::TripleIsGenerating(id0))
             } else {
                 Err(GenerationError::TripleIsMissing(id0))
             }
         } else if !self.triples.contains_key(&id1) {
-            if self.generators.contains_key(&id1) {
+            if self.is_generating(id1) {
                 Err(GenerationError::TripleIsGenerating(id1))
             } else {
                 Err(GenerationError::TripleIsMissing(id1))
@@ -294,9 +463,10 @@ impl TripleManager {
         &mut self,
         id: TripleId,
     ) -> Result<(), error::DatastoreStorageError> {
+        let epoch = self.epoch;
         let action = || async {
             let mut triple_storage = self.triple_storage.write().await;
-            if let Err(err) = triple_storage.delete(id).await {
+            if let Err(err) = triple_storage.delete(epoch, id).await {
                 tracing::warn!(?err, id, "triple deletion failed.");
                 return Err(err);
             }
@@ -357,98 +527,99 @@ impl TripleManager {
         self.insert_triples_to_storage(vec![triple]).await;
     }
 
-    /// Ensures that the triple with the given id is either:
+    /// Ensures that the triple batch with the given id is either:
     /// 1) Already generated in which case returns `None`, or
-    /// 2) Is currently being generated by `protocol` in which case returns `Some(protocol)`, or
-    /// 3) Has never been seen by the manager in which case start a new protocol and returns `Some(protocol)`
+    /// 2) Is currently being generated by `generator` in which case returns `Some(generator)`, or
+    /// 3) Has never been seen by the manager in which case start a new protocol and returns `Some(generator)`
     // TODO: What if the triple completed generation and is already spent?
     pub fn get_or_generate(
         &mut self,
-        id: TripleId,
+        id: TripleBatchId,
+        batch_size: usize,
         participants: &Participants,
-    ) -> Result<Option<&mut TripleProtocol>, CryptographicError> {
-        if self.triples.contains_key(&id) || self.taken.contains_key(&id) {
+    ) -> Result<Option<&mut TripleGenerator>, CryptographicError> {
+        if batch_triple_ids(id, batch_size).iter().any(|triple_id| {
+            self.triples.contains_key(triple_id) || self.taken.contains_key(triple_id)
+        }) {
             Ok(None)
         } else {
             let potential_len = self.potential_len();
             match self.generators.entry(id) {
                 Entry::Vacant(e) => {
-                    if potential_len >= self.triple_cfg.max_triples {
+                    if potential_len + batch_size > self.triple_cfg.max_triples {
                         // We are at the maximum amount of triples, we cannot generate more. So just in case a node
                         // sends more triple generation requests, reject them and have them tiemout.
                         return Ok(None);
                     }
 
-                    tracing::debug!(id, "joining protocol to generate a new triple");
+                    tracing::debug!(id, batch_size, "joining protocol to generate new triples");
                     let participants = participants.keys_vec();
-                    let protocol = Box::new(cait_sith::triples::generate_triple::<Secp256k1>(
-                        &participants,
-                        self.me,
-                        self.threshold,
-                    )?);
-                    let generator = e.insert(TripleGenerator::new(id, participants, protocol));
+                    let protocol =
+                        generate_triple_batch(&participants, self.me, self.threshold, batch_size)?;
+                    let generator =
+                        e.insert(TripleGenerator::new(id, batch_size, participants, protocol));
                     self.queued.push_back(id);
                     crate::metrics::NUM_TOTAL_HISTORICAL_TRIPLE_GENERATORS
                         .with_label_values(&[self.my_account_id.as_str()])
                         .inc();
-                    Ok(Some(&mut generator.protocol))
+                    Ok(Some(generator))
                 }
-                Entry::Occupied(e) => Ok(Some(&mut e.into_mut().protocol)),
+                Entry::Occupied(e) => Ok(Some(e.into_mut())),
             }
         }
     }
 
-    /// Pokes all of the ongoing generation protocols and returns a vector of
-    /// messages to be sent to the respective participant.
+    /// Starts the queued generation protocols there is room for on tasks of their own, which run
+    /// them on `pool`, and collects the actions the ongoing ones took since the last call. Returns
+    /// a vector of messages to be sent to the respective participant.
     ///
-    /// An empty vector means we cannot progress until we receive a new message.
-    pub async fn poke(&mut self) -> Vec<(Participant, TripleMessage)> {
-        // Add more protocols to the ongoing pool if there is space.
+    /// An empty vector means no protocol progressed since, which happens once they receive a new
+    /// message.
+    pub async fn poke(&mut self, pool: &ComputePool) -> Vec<(Participant, TripleMessage)> {
+        // Add more protocols to the ongoing pool if there is space, each on a task of its own.
         let to_generate_len = self.triple_cfg.max_concurrent_generation - self.ongoing.len();
         if !self.queued.is_empty() && to_generate_len > 0 {
             for _ in 0..to_generate_len {
-                self.queued.pop_front().map(|id| self.ongoing.insert(id));
+                let Some(id) = self.queued.pop_front() else {
+                    break;
+                };
+                if let Some(generator) = self.generators.remove(&id) {
+                    self.generators.insert(id, generator.start(pool));
+                    self.ongoing.insert(id);
+                }
             }
         }
 
+        // Only the ongoing protocols make progress, the rest are retained for the next time
+        // they are in the ongoing pool.
+        let poked = self
+            .ongoing
+            .iter()
+            .filter_map(|id| self.generators.remove(id))
+            .map(|mut generator| {
+                let (actions, error) = generator.actions();
+                (generator, actions, error)
+            })
+            .collect::<Vec<_>>();
+
         let mut messages = Vec::new();
         let mut triples_to_insert = Vec::new();
         let mut errors = Vec::new();
-        self.generators.retain(|id, generator| {
-            if !self.ongoing.contains(id) {
-                // If the protocol is not ongoing, we should retain it for the next time
-                // it is in the ongoing pool.
-                return true;
-            }
-
-            loop {
-                let action = match generator.poke() {
-                    Ok(action) => action,
-                    Err(e) => {
-                        errors.push(e);
-                        self.failed_triples.insert(*id, Instant::now());
-                        self.ongoing.remove(id);
-                        self.introduced.remove(id);
-                        tracing::info!(
-                            elapsed = ?generator.timestamp.unwrap().elapsed(),
-                            "added {id} to failed triples"
-                        );
-                        break false;
-                    }
-                };
-
+        for (generator, actions, error) in poked {
+            let id = generator.id;
+            let mut retain = error.is_none();
+            for action in actions {
                 match action {
                     Action::Wait => {
                         tracing::debug!("waiting");
-                        // Retain protocol until we are finished
-                        break true;
                     }
                     Action::SendMany(data) => {
                         for p in &generator.participants {
                             messages.push((
                                 *p,
                                 TripleMessage {
-                                    id: *id,
+                                    batch_id: id,
+                                    batch_size: generator.batch_size,
                                     epoch: self.epoch,
                                     from: self.me,
                                     data: data.clone(),
@@ -460,21 +631,20 @@ impl TripleManager {
                     Action::SendPrivate(p, data) => messages.push((
                         p,
                         TripleMessage {
-                            id: *id,
+                            batch_id: id,
+                            batch_size: generator.batch_size,
                             epoch: self.epoch,
                             from: self.me,
                             data,
                             timestamp: Utc::now().timestamp() as u64,
                         },
                     )),
-                    Action::Return(output) => {
+                    Action::Return(outputs) => {
                         tracing::info!(
                             id,
                             me = ?self.me,
+                            batch_size = outputs.len(),
                             elapsed = ?generator.timestamp.unwrap().elapsed(),
-                            big_a = ?output.1.big_a.to_base58(),
-                            big_b = ?output.1.big_b.to_base58(),
-                            big_c = ?output.1.big_c.to_base58(),
                             "completed triple generation"
                         );
 
@@ -488,50 +658,79 @@ impl TripleManager {
                             .with_label_values(&[self.my_account_id.as_str()])
                             .inc();
 
-                        let triple = Triple {
-                            id: *id,
-                            share: output.0,
-                            public: output.1,
-                        };
-
-                        // After creation the triple is assigned to a random node, which is NOT necessarily the one that initiated it's creation
-                        let triple_is_mine = {
-                            // This is an entirely unpredictable value to all participants because it's a combination of big_c_i
-                            // It is the same value across all participants
-                            let big_c = triple.public.big_c;
-
-                            // We turn this into a u64 in a way not biased to the structure of the byte serialisation so we hash it
-                            // We use Highway Hash because the DefaultHasher doesn't guarantee a consistent output across versions
-                            let entropy =
-                                HighwayHasher::default().hash64(&big_c.to_bytes()) as usize;
-
-                            let num_participants = generator.participants.len();
-                            // This has a *tiny* bias towards lower indexed participants, they're up to (1 + num_participants / u64::MAX)^2 times more likely to be selected
-                            // This is acceptably small that it will likely never result in a biased selection happening
-                            let triple_owner = generator.participants[entropy % num_participants];
-
-                            triple_owner == self.me
-                        };
-
-                        if triple_is_mine {
-                            self.mine.push_back(*id);
-                            crate::metrics::NUM_TOTAL_HISTORICAL_TRIPLE_GENERATIONS_MINE_SUCCESS
-                                .with_label_values(&[self.my_account_id.as_str()])
-                                .inc();
+                        for (triple_id, (share, public)) in
+                            generator.triple_ids().into_iter().zip(outputs)
+                        {
+                            tracing::debug!(
+                                id = triple_id,
+                                batch_id = id,
+                                big_a = ?public.big_a.to_base58(),
+                                big_b = ?public.big_b.to_base58(),
+                                big_c = ?public.big_c.to_base58(),
+                                "completed triple"
+                            );
+                            let triple = Triple {
+                                id: triple_id,
+                                share,
+                                public,
+                            };
+
+                            // After creation the triple is assigned to a random node, which is NOT necessarily the one that initiated it's creation
+                            let triple_is_mine = {
+                                // This is an entirely unpredictable value to all participants because it's a combination of big_c_i
+                                // It is the same value across all participants
+                                let big_c = triple.public.big_c;
+
+                                // We turn this into a u64 in a way not biased to the structure of the byte serialisation so we hash it
+                                // We use Highway Hash because the DefaultHasher doesn't guarantee a consistent output across versions
+                                let entropy =
+                                    HighwayHasher::default().hash64(&big_c.to_bytes()) as usize;
+
+                                let num_participants = generator.participants.len();
+                                // This has a *tiny* bias towards lower indexed participants, they're up to (1 + num_participants / u64::MAX)^2 times more likely to be selected
+                                // This is acceptably small that it will likely never result in a biased selection happening
+                                let triple_owner =
+                                    generator.participants[entropy % num_participants];
+
+                                triple_owner == self.me
+                            };
+
+                            if triple_is_mine {
+                                self.mine.push_back(triple_id);
+                                crate::metrics::NUM_TOTAL_HISTORICAL_TRIPLE_GENERATIONS_MINE_SUCCESS
+                                    .with_label_values(&[self.my_account_id.as_str()])
+                                    .inc();
+                            }
+
+                            self.triples.insert(triple_id, triple.clone());
+                            triples_to_insert.push(triple);
                         }
 
-                        self.triples.insert(*id, triple.clone());
-                        triples_to_insert.push(triple);
-
                         // Protocol done, remove it from the ongoing pool.
-                        self.ongoing.remove(id);
-                        self.introduced.remove(id);
+                        self.ongoing.remove(&id);
+                        self.introduced.remove(&id);
                         // Do not retain the protocol
-                        break false;
+                        retain = false;
                     }
                 }
             }
-        });
+
+            if let Some(e) = error {
+                errors.push(e);
+                self.failed_triples.insert(id, Instant::now());
+                self.ongoing.remove(&id);
+                self.introduced.remove(&id);
+                tracing::info!(
+                    elapsed = ?generator.timestamp.unwrap().elapsed(),
+                    "added {id} to failed triples"
+                );
+            }
+
+            if retain {
+                // Retain protocol until we are finished
+                self.generators.insert(id, generator);
+            }
+        }
         self.insert_triples_to_storage(triples_to_insert).await;
 
         if !errors.is_empty() {
@@ -546,7 +745,10 @@ impl TripleManager {
             let mine = self.mine.contains(&triple.id);
             let action = || async {
                 let mut triple_storage = self.triple_storage.write().await;
-                if let Err(e) = triple_storage.insert(triple.clone(), mine).await {
+                if let Err(e) = triple_storage
+                    .insert(self.epoch, triple.clone(), mine)
+                    .await
+                {
                     tracing::warn!(?e, id = triple.id, "triple insertion failed.");
                     return Err(e);
                 }
@@ -569,6 +771,11 @@ mod test {
         crate::test_utils::test_triple_generation(None).await
     }
 
+    #[tokio::test]
+    async fn test_batched_triple_generation_locally() {
+        crate::test_utils::test_batched_triple_generation(None).await
+    }
+
     #[tokio::test]
     async fn test_triple_deletion_locally() {
         crate::test_utils::test_triple_deletion(None).await
@@ -1,5 +1,6 @@
 use crate::protocol::ProtocolState;
 
+use mpc_contract::SignatureRequest;
 use near_account_id::AccountId;
 use near_crypto::InMemorySigner;
 
@@ -56,3 +57,40 @@ pub async fn vote_reshared(
 
     Ok(result)
 }
+
+pub async fn vote_refresh(
+    rpc_client: &near_fetch::Client,
+    signer: &InMemorySigner,
+    mpc_contract_id: &AccountId,
+    epoch: u64,
+) -> anyhow::Result<bool> {
+    let result = rpc_client
+        .call(signer, mpc_contract_id, "vote_refresh")
+        .args_json(json!({
+            "epoch": epoch
+        }))
+        .max_gas()
+        .retry_exponential(10, 5)
+        .transact()
+        .await?
+        .json()?;
+
+    Ok(result)
+}
+
+/// Whether the contract is still waiting on a signature for `request`. Requests that were
+/// answered or that timed out are no longer pending.
+pub async fn is_request_pending(
+    rpc_client: &near_fetch::Client,
+    mpc_contract_id: &AccountId,
+    request: &SignatureRequest,
+) -> anyhow::Result<bool> {
+    let pending = rpc_client
+        .view(mpc_contract_id, "is_request_pending")
+        .args_json(json!({
+            "request": request
+        }))
+        .await?
+        .json()?;
+    Ok(pending)
+}
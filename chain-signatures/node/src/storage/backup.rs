@@ -0,0 +1,322 @@
+use std::collections::BTreeMap;
+
+use crypto_shared::PublicKey;
+use k256::elliptic_curve::Field;
+use k256::Scalar;
+use mpc_keys::hpke;
+use near_account_id::AccountId;
+use serde::{Deserialize, Serialize};
+
+use crate::protocol::state::PersistentNodeData;
+use crate::protocol::ProtocolState;
+use crate::storage::cipher::StorageCipher;
+
+#[derive(thiserror::Error, Debug)]
+pub enum BackupError {
+    #[error("invalid backup: {0}")]
+    Invalid(String),
+    #[error("failed to encrypt the backup for a custodian: {0}")]
+    EncryptionError(String),
+    #[error("only {found} of the {threshold} custodian shares required could be decrypted")]
+    NotEnoughShares { found: usize, threshold: usize },
+    #[error("backup does not match the contract: {0}")]
+    ContractMismatch(String),
+    #[error("serde error: {0}")]
+    SerdeError(#[from] serde_json::Error),
+}
+
+/// Key share of a node encrypted for recovery by offline custodians. The share is encrypted with
+/// a random key, which is split with Shamir's secret sharing so that any `threshold` of the
+/// custodians together can restore it. With a threshold of one every custodian holds the whole
+/// key.
+#[derive(Serialize, Deserialize)]
+pub struct KeyShareBackup {
+    pub account_id: AccountId,
+    pub epoch: u64,
+    pub public_key: PublicKey,
+    pub threshold: usize,
+    pub custodians: Vec<CustodianShare>,
+    /// Hex encoded [`PersistentNodeData`], encrypted with the key split among the custodians.
+    pub ciphertext: String,
+}
+
+/// Piece of the backup key, encrypted to the recovery public key of a custodian.
+#[derive(Serialize, Deserialize)]
+pub struct CustodianShare {
+    pub recipient: hpke::PublicKey,
+    pub piece: hpke::Ciphered,
+}
+
+#[derive(Serialize, Deserialize)]
+struct KeyPiece {
+    index: u64,
+    value: Scalar,
+}
+
+/// Binds every encrypted part of a backup to the node, epoch and key it was exported for.
+fn backup_aad(account_id: &AccountId, epoch: u64, public_key: &PublicKey) -> Vec<u8> {
+    let mut aad = b"multichain key share backup".to_vec();
+    aad.extend_from_slice(account_id.as_bytes());
+    aad.extend_from_slice(&epoch.to_be_bytes());
+    aad.extend(serde_json::to_vec(public_key).expect("a public key serializes"));
+    aad
+}
+
+/// Splits `secret` so that any `threshold` of the `count` pieces recombine into it.
+fn split(secret: Scalar, threshold: usize, count: usize) -> Vec<KeyPiece> {
+    let mut rng = rand::thread_rng();
+    let coefficients = std::iter::once(secret)
+        .chain((1..threshold).map(|_| Scalar::random(&mut rng)))
+        .collect::<Vec<_>>();
+    (1..=count as u64)
+        .map(|index| {
+            let x = Scalar::from(index);
+            let value = coefficients
+                .iter()
+                .rev()
+                .fold(Scalar::ZERO, |acc, coefficient| acc * x + coefficient);
+            KeyPiece { index, value }
+        })
+        .collect()
+}
+
+/// Recombines the secret from pieces with distinct indices by interpolating at zero.
+fn combine(pieces: &BTreeMap<u64, Scalar>) -> Scalar {
+    pieces.iter().fold(Scalar::ZERO, |acc, (&i, value)| {
+        let xi = Scalar::from(i);
+        let basis = pieces
+            .keys()
+            .filter(|&&j| j != i)
+            .fold(Scalar::ONE, |basis, &j| {
+                let xj = Scalar::from(j);
+                basis * xj * (xj - xi).invert().unwrap()
+            });
+        acc + *value * basis
+    })
+}
+
+fn backup_cipher(key: &Scalar) -> StorageCipher {
+    StorageCipher::derive(&key.to_bytes())
+}
+
+impl KeyShareBackup {
+    /// Encrypts `data` so that any `threshold` of the holders of `recipients` can restore it.
+    pub fn seal(
+        account_id: &AccountId,
+        data: &PersistentNodeData,
+        recipients: &[hpke::PublicKey],
+        threshold: usize,
+    ) -> Result<Self, BackupError> {
+        if threshold == 0 || threshold > recipients.len() {
+            return Err(BackupError::Invalid(format!(
+                "threshold {threshold} must be between 1 and the {} custodians",
+                recipients.len()
+            )));
+        }
+        // A custodian listed twice would hold more than one piece of the key.
+        for (i, recipient) in recipients.iter().enumerate() {
+            if recipients[..i].contains(recipient) {
+                return Err(BackupError::Invalid(format!(
+                    "custodian {} is listed more than once",
+                    hex::encode(recipient.to_bytes())
+                )));
+            }
+        }
+        let aad = backup_aad(account_id, data.epoch, &data.public_key);
+        let key = Scalar::random(&mut rand::thread_rng());
+        let ciphertext = backup_cipher(&key).encrypt(&aad, &serde_json::to_vec(data)?);
+
+        let custodians = split(key, threshold, recipients.len())
+            .into_iter()
+            .zip(recipients)
+            .map(|(piece, recipient)| {
+                let piece = recipient
+                    .encrypt(&serde_json::to_vec(&piece)?, &aad)
+                    .map_err(|err| BackupError::EncryptionError(format!("{err:?}")))?;
+                Ok(CustodianShare {
+                    recipient: recipient.clone(),
+                    piece,
+                })
+            })
+            .collect::<Result<Vec<_>, BackupError>>()?;
+
+        Ok(Self {
+            account_id: account_id.clone(),
+            epoch: data.epoch,
+            public_key: data.public_key,
+            threshold,
+            custodians,
+            ciphertext: hex::encode(ciphertext),
+        })
+    }
+
+    /// Restores the key share with the secret keys of at least `threshold` custodians.
+    pub fn open(&self, secret_keys: &[hpke::SecretKey]) -> Result<PersistentNodeData, BackupError> {
+        let aad = backup_aad(&self.account_id, self.epoch, &self.public_key);
+        let mut pieces = BTreeMap::new();
+        for sk in secret_keys {
+            let recipient = sk.public_key();
+            for custodian in self.custodians.iter().filter(|c| c.recipient == recipient) {
+                let Ok(plaintext) = sk.decrypt(&custodian.piece, &aad) else {
+                    tracing::warn!("failed to decrypt a custodian share of the backup");
+                    continue;
+                };
+                let piece: KeyPiece = serde_json::from_slice(&plaintext)?;
+                pieces.insert(piece.index, piece.value);
+            }
+        }
+        if pieces.len() < self.threshold {
+            return Err(BackupError::NotEnoughShares {
+                found: pieces.len(),
+                threshold: self.threshold,
+            });
+        }
+
+        let key = combine(&pieces);
+        let ciphertext = hex::decode(&self.ciphertext)
+            .map_err(|err| BackupError::Invalid(format!("ciphertext is not hex: {err}")))?;
+        let plaintext = backup_cipher(&key)
+            .decrypt(&aad, &ciphertext)
+            .map_err(|_| BackupError::Invalid("failed to decrypt the key share".to_string()))?;
+        let data: PersistentNodeData = serde_json::from_slice(&plaintext)?;
+        if data.epoch != self.epoch || data.public_key != self.public_key {
+            return Err(BackupError::Invalid(
+                "key share does not match the epoch and public key of the backup".to_string(),
+            ));
+        }
+        Ok(data)
+    }
+}
+
+/// Checks that `data` is the key share the contract currently runs with, so that restoring it
+/// does not roll the node back to a stale epoch.
+pub fn verify_against_contract(
+    data: &PersistentNodeData,
+    contract_state: &ProtocolState,
+) -> Result<(), BackupError> {
+    let (epoch, public_key) = match contract_state {
+        ProtocolState::Initializing(_) => {
+            return Err(BackupError::ContractMismatch(
+                "contract has not generated a key yet".to_string(),
+            ))
+        }
+        ProtocolState::Running(state) => (state.epoch, &state.public_key),
+        ProtocolState::Resharing(state) => (state.old_epoch, &state.public_key),
+    };
+    if data.public_key != *public_key {
+        return Err(BackupError::ContractMismatch(
+            "public key differs from the contract's".to_string(),
+        ));
+    }
+    if data.epoch != epoch {
+        return Err(BackupError::ContractMismatch(format!(
+            "backup is of epoch {} while the contract is at epoch {epoch}",
+            data.epoch
+        )));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::{verify_against_contract, BackupError, KeyShareBackup};
+    use crate::protocol::contract::primitives::{Candidates, Participants, Votes};
+    use crate::protocol::contract::{ResharingContractState, RunningContractState};
+    use crate::protocol::state::PersistentNodeData;
+    use crate::protocol::ProtocolState;
+
+    use k256::{AffinePoint, ProjectivePoint, Scalar};
+    use mpc_keys::hpke;
+    use std::collections::{BTreeMap, HashSet};
+
+    #[test]
+    fn test_backup_threshold_restore() {
+        let account_id = "node.testnet".parse().unwrap();
+        let data = PersistentNodeData {
+            epoch: 3,
+            key_version: 0,
+            private_share: Scalar::from(42u64),
+            public_key: AffinePoint::GENERATOR,
+        };
+        let (secret_keys, public_keys): (Vec<_>, Vec<_>) = (0..3).map(|_| hpke::generate()).unzip();
+        let backup = KeyShareBackup::seal(&account_id, &data, &public_keys, 2).unwrap();
+
+        let restored = backup.open(&secret_keys[1..]).unwrap();
+        assert_eq!(restored.epoch, data.epoch);
+        assert_eq!(restored.private_share, data.private_share);
+        assert!(backup.open(&secret_keys[..1]).is_err());
+    }
+
+    #[test]
+    fn test_backup_rejects_duplicate_custodians() {
+        let account_id = "node.testnet".parse().unwrap();
+        let data = PersistentNodeData {
+            epoch: 3,
+            key_version: 0,
+            private_share: Scalar::from(42u64),
+            public_key: AffinePoint::GENERATOR,
+        };
+        let (_, pk) = hpke::generate();
+        let (_, other) = hpke::generate();
+        let recipients = [pk.clone(), other, pk];
+        assert!(matches!(
+            KeyShareBackup::seal(&account_id, &data, &recipients, 2),
+            Err(BackupError::Invalid(_))
+        ));
+    }
+
+    #[test]
+    fn test_verify_against_contract() {
+        let data = PersistentNodeData {
+            epoch: 3,
+            key_version: 0,
+            private_share: Scalar::from(42u64),
+            public_key: AffinePoint::GENERATOR,
+        };
+        let running = |epoch, public_key| {
+            ProtocolState::Running(RunningContractState {
+                epoch,
+                participants: Participants::default(),
+                threshold: 2,
+                public_key,
+                candidates: Candidates {
+                    candidates: BTreeMap::new(),
+                },
+                join_votes: Votes {
+                    votes: BTreeMap::new(),
+                },
+                leave_votes: Votes {
+                    votes: BTreeMap::new(),
+                },
+                refresh_votes: HashSet::new(),
+                epoch_started_at: 0,
+            })
+        };
+        let other_key = (ProjectivePoint::GENERATOR * Scalar::from(2u64)).to_affine();
+
+        assert!(verify_against_contract(&data, &running(3, AffinePoint::GENERATOR)).is_ok());
+        // A backup of an earlier epoch would roll the node back.
+        assert!(matches!(
+            verify_against_contract(&data, &running(4, AffinePoint::GENERATOR)),
+            Err(BackupError::ContractMismatch(_))
+        ));
+        assert!(matches!(
+            verify_against_contract(&data, &running(3, other_key)),
+            Err(BackupError::ContractMismatch(_))
+        ));
+
+        // While resharing the share of the epoch being reshared is the one to restore.
+        let resharing = |old_epoch| {
+            ProtocolState::Resharing(ResharingContractState {
+                old_epoch,
+                old_participants: Participants::default(),
+                new_participants: Participants::default(),
+                threshold: 2,
+                public_key: AffinePoint::GENERATOR,
+                finished_votes: HashSet::new(),
+            })
+        };
+        assert!(verify_against_contract(&data, &resharing(3)).is_ok());
+        assert!(verify_against_contract(&data, &resharing(2)).is_err());
+    }
+}
@@ -0,0 +1,211 @@
+use std::path::{Path, PathBuf};
+
+use argon2::{Algorithm, Argon2, Params, Version};
+use chacha20poly1305::aead::{Aead, KeyInit, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use rand::RngCore;
+use tokio::fs::OpenOptions;
+use tokio::io::AsyncWriteExt;
+
+use crate::gcp::error::SecretStorageError;
+use crate::gcp::SecretResult;
+
+/// Marks a file as an encrypted key share, as opposed to the plain JSON written by older nodes.
+const MAGIC: &[u8; 8] = b"MPCSHARE";
+const VERSION: u8 = 1;
+const SALT_LEN: usize = 16;
+const NONCE_LEN: usize = 12;
+/// Magic, version, the three Argon2 cost parameters, salt and nonce.
+const HEADER_LEN: usize = MAGIC.len() + 1 + 3 * 4 + SALT_LEN + NONCE_LEN;
+
+/// Secret the key share file is encrypted with.
+#[derive(Clone)]
+pub enum KeyFileSecret {
+    Passphrase(String),
+    /// Path to a file holding the secret, only readable by its owner.
+    Keyfile(PathBuf),
+}
+
+impl KeyFileSecret {
+    async fn bytes(&self) -> SecretResult<Vec<u8>> {
+        match self {
+            KeyFileSecret::Passphrase(passphrase) => Ok(passphrase.as_bytes().to_vec()),
+            KeyFileSecret::Keyfile(path) => {
+                check_permissions(path).await?;
+                Ok(tokio::fs::read(path).await?)
+            }
+        }
+    }
+}
+
+fn key_file_error(msg: impl Into<String>) -> SecretStorageError {
+    SecretStorageError::KeyFileError(msg.into())
+}
+
+fn derive_key(secret: &[u8], salt: &[u8], params: Params) -> SecretResult<Key> {
+    let mut key = Key::default();
+    Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+        .hash_password_into(secret, salt, &mut key)
+        .map_err(|err| key_file_error(format!("failed to derive key: {err}")))?;
+    Ok(key)
+}
+
+/// Encrypts `plaintext` with a key derived from `secret`. The header, holding everything needed
+/// to derive the key again, is authenticated along with the ciphertext.
+pub async fn seal(secret: &KeyFileSecret, plaintext: &[u8]) -> SecretResult<Vec<u8>> {
+    let params = Params::default();
+    let mut salt = [0u8; SALT_LEN];
+    let mut nonce = [0u8; NONCE_LEN];
+    rand::thread_rng().fill_bytes(&mut salt);
+    rand::thread_rng().fill_bytes(&mut nonce);
+
+    let mut sealed = Vec::with_capacity(HEADER_LEN + plaintext.len() + 16);
+    sealed.extend_from_slice(MAGIC);
+    sealed.push(VERSION);
+    sealed.extend_from_slice(&params.m_cost().to_le_bytes());
+    sealed.extend_from_slice(&params.t_cost().to_le_bytes());
+    sealed.extend_from_slice(&params.p_cost().to_le_bytes());
+    sealed.extend_from_slice(&salt);
+    sealed.extend_from_slice(&nonce);
+
+    let key = derive_key(&secret.bytes().await?, &salt, params)?;
+    let ciphertext = ChaCha20Poly1305::new(&key)
+        .encrypt(
+            Nonce::from_slice(&nonce),
+            Payload {
+                msg: plaintext,
+                aad: &sealed,
+            },
+        )
+        .map_err(|_| key_file_error("failed to encrypt key share"))?;
+    sealed.extend(ciphertext);
+    Ok(sealed)
+}
+
+/// Whether `contents` are in the encrypted format, rather than legacy plain JSON.
+pub fn is_sealed(contents: &[u8]) -> bool {
+    contents.starts_with(MAGIC)
+}
+
+/// Decrypts the output of [`seal`], failing if the file was tampered with or `secret` is wrong.
+pub async fn open(secret: &KeyFileSecret, sealed: &[u8]) -> SecretResult<Vec<u8>> {
+    if sealed.len() < HEADER_LEN || !is_sealed(sealed) {
+        return Err(key_file_error("not an encrypted key share file"));
+    }
+    let (header, ciphertext) = sealed.split_at(HEADER_LEN);
+    let version = header[MAGIC.len()];
+    if version != VERSION {
+        return Err(key_file_error(format!(
+            "unsupported key share file version {version}"
+        )));
+    }
+    let u32_at = |offset: usize| {
+        let start = MAGIC.len() + 1 + offset * 4;
+        u32::from_le_bytes(header[start..start + 4].try_into().unwrap())
+    };
+    let params = Params::new(u32_at(0), u32_at(1), u32_at(2), None)
+        .map_err(|err| key_file_error(format!("invalid key derivation parameters: {err}")))?;
+    let salt_start = MAGIC.len() + 1 + 3 * 4;
+    let salt = &header[salt_start..salt_start + SALT_LEN];
+    let nonce = &header[salt_start + SALT_LEN..];
+
+    let key = derive_key(&secret.bytes().await?, salt, params)?;
+    ChaCha20Poly1305::new(&key)
+        .decrypt(
+            Nonce::from_slice(nonce),
+            Payload {
+                msg: ciphertext,
+                aad: header,
+            },
+        )
+        .map_err(|_| key_file_error("wrong passphrase or corrupted key share file"))
+}
+
+/// Fails if `path` is accessible to anyone but its owner.
+pub async fn check_permissions(path: &Path) -> SecretResult<()> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+
+        let mode = tokio::fs::metadata(path).await?.permissions().mode();
+        if mode & 0o077 != 0 {
+            return Err(key_file_error(format!(
+                "{} has permissions {:o}, it must only be accessible to its owner",
+                path.display(),
+                mode & 0o777
+            )));
+        }
+    }
+    Ok(())
+}
+
+/// Replaces the file at `path` with `contents`, so that a crash leaves either the old or the new
+/// contents on disk. The file is only accessible to its owner.
+pub async fn write_atomic(path: &Path, contents: &[u8]) -> SecretResult<()> {
+    let tmp_path = with_suffix(path, ".tmp");
+    // A leftover from an interrupted write would keep its permissions when truncated.
+    let _ = tokio::fs::remove_file(&tmp_path).await;
+    let mut options = OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    options.mode(0o600);
+    let mut file = options.open(&tmp_path).await?;
+    file.write_all(contents).await?;
+    file.sync_all().await?;
+    drop(file);
+
+    tokio::fs::rename(&tmp_path, path).await?;
+    sync_parent(path).await
+}
+
+/// Flushes the directory entry of `path`, making a rename into it durable.
+async fn sync_parent(path: &Path) -> SecretResult<()> {
+    #[cfg(unix)]
+    {
+        let parent = match path.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => parent,
+            _ => Path::new("."),
+        };
+        tokio::fs::File::open(parent).await?.sync_all().await?;
+    }
+    Ok(())
+}
+
+pub fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut path = path.as_os_str().to_owned();
+    path.push(suffix);
+    PathBuf::from(path)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{open, seal, write_atomic, KeyFileSecret};
+
+    #[tokio::test]
+    async fn test_key_file_roundtrip() {
+        let secret = KeyFileSecret::Passphrase("correct horse battery staple".to_string());
+        let mut sealed = seal(&secret, b"key share").await.unwrap();
+        assert_eq!(open(&secret, &sealed).await.unwrap(), b"key share");
+
+        let wrong = KeyFileSecret::Passphrase("wrong".to_string());
+        assert!(open(&wrong, &sealed).await.is_err());
+
+        // Tampering with the header is detected as well as with the ciphertext.
+        sealed[9] ^= 1;
+        assert!(open(&secret, &sealed).await.is_err());
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+
+            let path = std::env::temp_dir().join(format!(
+                "multichain-key-file-test-{}",
+                rand::random::<u64>()
+            ));
+            write_atomic(&path, b"contents").await.unwrap();
+            let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+            std::fs::remove_file(&path).unwrap();
+        }
+    }
+}
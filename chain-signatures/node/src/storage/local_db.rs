@@ -0,0 +1,280 @@
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use rusqlite::Connection;
+
+use crate::storage::key_file::with_suffix;
+
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS secrets (
+        account_id TEXT PRIMARY KEY,
+        data BLOB NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS triples (
+        account_id TEXT NOT NULL,
+        triple_id INTEGER NOT NULL,
+        epoch INTEGER NOT NULL,
+        triple_public TEXT NOT NULL,
+        triple_share BLOB NOT NULL,
+        mine INTEGER NOT NULL,
+        PRIMARY KEY (account_id, epoch, triple_id)
+    );
+    CREATE TABLE IF NOT EXISTS presignatures (
+        account_id TEXT NOT NULL,
+        epoch INTEGER NOT NULL,
+        presignature_id INTEGER NOT NULL,
+        participants TEXT NOT NULL,
+        output BLOB NOT NULL,
+        mine INTEGER NOT NULL,
+        PRIMARY KEY (account_id, epoch, presignature_id)
+    );
+    CREATE TABLE IF NOT EXISTS sign_requests (
+        account_id TEXT NOT NULL,
+        receipt_id TEXT NOT NULL,
+        request TEXT NOT NULL,
+        epsilon TEXT NOT NULL,
+        delta TEXT NOT NULL,
+        entropy TEXT NOT NULL,
+        block_height INTEGER NOT NULL,
+        deposit TEXT NOT NULL,
+        PRIMARY KEY (account_id, receipt_id)
+    );
+    CREATE TABLE IF NOT EXISTS latest_block_height (
+        account_id TEXT PRIMARY KEY,
+        block_height INTEGER NOT NULL
+    );
+";
+
+/// Embedded SQLite database for nodes running outside of GCP. Holds the key share, triples,
+/// presignatures, pending sign requests and the progress of the indexer in a single file.
+///
+/// Every write is a transaction committed to the write-ahead log with `synchronous = FULL`, so a
+/// crash leaves either the old or the new state on disk, never a partial write.
+#[derive(Clone)]
+pub struct LocalDb {
+    conn: Arc<Mutex<Connection>>,
+}
+
+impl LocalDb {
+    /// Opens the database at `path`, creating it if needed. The database holds the key share, so
+    /// it is only made accessible to its owner.
+    pub fn open(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let path = path.as_ref();
+        restrict_permissions(path)?;
+        let conn = Connection::open(path)?;
+        conn.pragma_update(None, "journal_mode", "WAL")?;
+        conn.pragma_update(None, "synchronous", "FULL")?;
+        conn.execute_batch(SCHEMA)?;
+        Ok(Self {
+            conn: Arc::new(Mutex::new(conn)),
+        })
+    }
+
+    /// Runs `f` against the database on the blocking thread pool.
+    pub(crate) async fn call<T, E, F>(&self, f: F) -> Result<T, E>
+    where
+        F: FnOnce(&mut Connection) -> Result<T, E> + Send + 'static,
+        T: Send + 'static,
+        E: From<std::io::Error> + Send + 'static,
+    {
+        let conn = self.conn.clone();
+        tokio::task::spawn_blocking(move || {
+            let mut conn = conn.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+            f(&mut conn)
+        })
+        .await
+        .map_err(std::io::Error::other)?
+    }
+}
+
+/// Creates the database file only accessible to its owner, or restricts the existing one along
+/// with its write-ahead log and shared memory files. SQLite creates those two with the
+/// permissions of the database file.
+#[cfg_attr(not(unix), allow(unused_variables))]
+fn restrict_permissions(path: &Path) -> std::io::Result<()> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+
+        std::fs::OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .mode(0o600)
+            .open(path)?;
+        for suffix in ["", "-wal", "-shm"] {
+            let path = with_suffix(path, suffix);
+            match std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o600)) {
+                Err(err) if err.kind() != std::io::ErrorKind::NotFound => return Err(err),
+                _ => {}
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::LocalDb;
+    use crate::indexer::ContractSignRequest;
+    use crate::protocol::state::PersistentNodeData;
+    use crate::protocol::triple::Triple;
+    use crate::protocol::SignRequest;
+    use crate::storage::cipher::StorageCipher;
+    use crate::storage::{
+        presignature_storage, secret_storage, sign_request_storage, triple_storage, Options,
+    };
+    use crate::types::{BlockHeightStorage, LatestBlockHeight};
+
+    use cait_sith::protocol::Participant;
+    use k256::{AffinePoint, Scalar, Secp256k1};
+    use near_primitives::hash::CryptoHash;
+    use std::time::Instant;
+
+    #[tokio::test]
+    async fn test_local_db_survives_reopen() {
+        let path = std::env::temp_dir().join(format!(
+            "multichain-local-db-test-{}.sqlite",
+            rand::random::<u64>()
+        ));
+        let account_id = "node.testnet".parse().unwrap();
+        let cipher = StorageCipher::derive(b"local-db-test");
+        let participants = [Participant::from(0), Participant::from(1)];
+        let (public, shares) =
+            cait_sith::triples::deal::<Secp256k1>(&mut rand::thread_rng(), &participants, 2);
+
+        {
+            let db = LocalDb::open(&path).unwrap();
+            let mut triples = triple_storage::init(None, Some(&db), &cipher, &account_id);
+            for (id, share) in shares.into_iter().enumerate() {
+                let triple = Triple {
+                    id: id as u64,
+                    share,
+                    public: public.clone(),
+                };
+                triples.insert(1, triple, id == 0).await.unwrap();
+            }
+            triples.delete(1, 1).await.unwrap();
+        }
+
+        let db = LocalDb::open(&path).unwrap();
+        let triples = triple_storage::init(None, Some(&db), &cipher, &account_id);
+        let loaded = triples.load(1).await.unwrap();
+        assert_eq!(loaded.len(), 1);
+        assert_eq!(loaded[0].triple.id, 0);
+        assert!(loaded[0].mine);
+        assert!(triples.load(2).await.unwrap().is_empty());
+
+        // Shares encrypted under another key cannot be read back, and are skipped but kept.
+        let other = StorageCipher::derive(b"another-key");
+        let other_triples = triple_storage::init(None, Some(&db), &other, &account_id);
+        assert!(other_triples.load(1).await.unwrap().is_empty());
+        assert_eq!(triples.load(1).await.unwrap().len(), 1);
+
+        let presignatures = presignature_storage::init(None, Some(&db), &cipher, &account_id);
+        assert!(presignatures.load(1).await.unwrap().is_empty());
+
+        let opts = Options {
+            env: "local-db-test".to_string(),
+            gcp_project_id: "local-db-test".to_string(),
+            sk_share_secret_id: None,
+            gcp_datastore_url: None,
+            sk_share_local_path: None,
+            sk_share_passphrase: None,
+            sk_share_keyfile: None,
+            local_db_path: None,
+        };
+        // The key share is encrypted, so the database refuses it without a secret.
+        assert!(secret_storage::init(None, Some(&db), &opts, &account_id).is_err());
+        let opts = Options {
+            sk_share_passphrase: Some("local-db-test".to_string()),
+            ..opts
+        };
+        let mut secrets = secret_storage::init(None, Some(&db), &opts, &account_id).unwrap();
+        assert!(secrets.load().await.unwrap().is_empty());
+
+        // Key shares of earlier epochs are kept until pruned, one per key version.
+        for (epoch, key_version) in [(0, 0), (1, 0), (1, 1)] {
+            let data = PersistentNodeData {
+                epoch,
+                key_version,
+                private_share: Scalar::from(epoch + 1),
+                public_key: AffinePoint::GENERATOR,
+            };
+            secrets.store(&data).await.unwrap();
+        }
+        let versions = |shares: Vec<PersistentNodeData>| -> Vec<(u64, u32)> {
+            shares
+                .into_iter()
+                .map(|data| (data.epoch, data.key_version))
+                .collect()
+        };
+        assert_eq!(
+            versions(secrets.load().await.unwrap()),
+            [(0, 0), (1, 0), (1, 1)]
+        );
+        secrets.prune(1).await.unwrap();
+        assert_eq!(versions(secrets.load().await.unwrap()), [(1, 0), (1, 1)]);
+        let stored: Vec<u8> = db
+            .call(|conn| -> anyhow::Result<_> {
+                Ok(conn.query_row("SELECT data FROM secrets", [], |row| row.get(0))?)
+            })
+            .await
+            .unwrap();
+        assert!(crate::storage::key_file::is_sealed(&stored));
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+
+            let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+
+        let mut sign_requests = sign_request_storage::init(None, Some(&db), &account_id);
+        let request = SignRequest {
+            receipt_id: CryptoHash::hash_bytes(b"local-db-test"),
+            request: ContractSignRequest {
+                payload: [1; 32],
+                path: "test".to_string(),
+                key_version: 0,
+                child_path: vec![1, 2],
+            },
+            epsilon: Scalar::from(3u64),
+            delta: Scalar::from(4u64),
+            entropy: [5; 32],
+            time_added: Instant::now(),
+            block_height: 100,
+            deposit: 1,
+        };
+        sign_requests.insert(&request).await.unwrap();
+        let loaded = sign_requests.load().await.unwrap();
+        assert_eq!(loaded.len(), 1);
+        assert_eq!(loaded[0].request, request.request);
+        assert_eq!(loaded[0].block_height, 100);
+        sign_requests.delete(request.receipt_id).await.unwrap();
+        assert!(sign_requests.load().await.unwrap().is_empty());
+
+        let block_heights = BlockHeightStorage::init(None, Some(&db));
+        assert!(LatestBlockHeight::fetch(&block_heights, &account_id)
+            .await
+            .is_err());
+        LatestBlockHeight {
+            account_id: account_id.clone(),
+            block_height: 42,
+        }
+        .store(&block_heights)
+        .await
+        .unwrap();
+        let latest = LatestBlockHeight::fetch(&block_heights, &account_id)
+            .await
+            .unwrap();
+        assert_eq!(latest.block_height, 42);
+
+        drop(db);
+        for suffix in ["", "-wal", "-shm"] {
+            let mut file = path.clone().into_os_string();
+            file.push(suffix);
+            let _ = std::fs::remove_file(file);
+        }
+    }
+}
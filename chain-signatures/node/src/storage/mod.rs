@@ -1,6 +1,14 @@
+pub mod backup;
+pub mod cipher;
+pub mod key_file;
+pub mod local_db;
+pub mod presignature_storage;
 pub mod secret_storage;
+pub mod sign_request_storage;
 pub mod triple_storage;
 
+const SK_SHARE_PASSPHRASE_ENV: &str = "MPC_RECOVERY_SK_SHARE_PASSPHRASE";
+
 /// Configures storage.
 #[derive(Debug, Clone, clap::Parser)]
 #[group(id = "storage_options")]
@@ -20,6 +28,30 @@ pub struct Options {
     pub gcp_datastore_url: Option<String>,
     #[arg(long, env("MPC_RECOVERY_SK_SHARE_LOCAL_PATH"))]
     pub sk_share_local_path: Option<String>,
+    /// Passphrase the key share stored at `sk_share_local_path` or in the local database is
+    /// encrypted with. Better passed through the environment, since arguments are visible to
+    /// every user of the machine.
+    #[arg(
+        long,
+        env(SK_SHARE_PASSPHRASE_ENV),
+        hide_env_values = true,
+        conflicts_with = "sk_share_keyfile"
+    )]
+    pub sk_share_passphrase: Option<String>,
+    /// File holding the secret the key share stored at `sk_share_local_path` or in the local
+    /// database is encrypted with.
+    #[arg(long, env("MPC_RECOVERY_SK_SHARE_KEYFILE"))]
+    pub sk_share_keyfile: Option<String>,
+    /// Path to an embedded database that will be used to load/store the node's secret key share,
+    /// triples, presignatures, sign requests and indexer progress instead of GCP, which is then
+    /// not used at all. Meant for nodes hosted outside of GCP. The key share is encrypted with
+    /// `sk_share_passphrase` or `sk_share_keyfile`.
+    #[arg(
+        long,
+        env("MPC_RECOVERY_LOCAL_DB_PATH"),
+        conflicts_with = "sk_share_local_path"
+    )]
+    pub local_db_path: Option<String>,
 }
 
 impl Options {
@@ -42,7 +74,30 @@ impl Options {
                 sk_share_local_path,
             ]);
         }
+        if let Some(sk_share_keyfile) = self.sk_share_keyfile {
+            opts.extend(vec!["--sk-share-keyfile".to_string(), sk_share_keyfile]);
+        }
+        if let Some(local_db_path) = self.local_db_path {
+            opts.extend(vec!["--local-db-path".to_string(), local_db_path]);
+        }
 
         opts
     }
+
+    /// Environment variables to run a node with along with [`Self::into_str_args`], for the
+    /// options that must not show up in its arguments.
+    pub fn str_envs(&self) -> Vec<(String, String)> {
+        self.sk_share_passphrase
+            .iter()
+            .map(|passphrase| (SK_SHARE_PASSPHRASE_ENV.to_string(), passphrase.clone()))
+            .collect()
+    }
+
+    /// Opens the embedded database if one was configured.
+    pub fn open_local_db(&self) -> anyhow::Result<Option<local_db::LocalDb>> {
+        self.local_db_path
+            .as_ref()
+            .map(local_db::LocalDb::open)
+            .transpose()
+    }
 }
@@ -0,0 +1,619 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use crate::gcp::{error, Keyable};
+use crate::gcp::{
+    error::ConvertError,
+    value::{FromValue, IntoValue, Value},
+    KeyKind,
+};
+use crate::gcp::{DatastoreService, GcpService};
+use crate::protocol::presignature::{Presignature, PresignatureId};
+use crate::storage::cipher::{share_aad, StorageCipher};
+use crate::storage::local_db::LocalDb;
+
+use async_trait::async_trait;
+use cait_sith::protocol::Participant;
+use google_datastore1::api::{
+    Filter, Key, PathElement, PropertyFilter, PropertyReference, Value as DatastoreValue,
+};
+use tokio::sync::RwLock;
+
+use near_account_id::AccountId;
+
+pub struct PresignatureKey<'a> {
+    pub account_id: &'a str,
+    pub epoch: u64,
+    pub presignature_id: PresignatureId,
+}
+
+impl KeyKind for PresignatureKey<'_> {
+    fn kind() -> String {
+        "presignatures".to_string()
+    }
+}
+
+impl Keyable for PresignatureKey<'_> {
+    fn key(&self) -> Key {
+        Key {
+            path: Some(vec![PathElement {
+                kind: None,
+                name: Some(format!(
+                    "{}/{}/{}",
+                    self.account_id, self.epoch, self.presignature_id
+                )),
+                id: None,
+            }]),
+            partition_id: None,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct PresignatureData {
+    pub account_id: AccountId,
+    /// Epoch of the key shares the presignature was generated with.
+    pub epoch: u64,
+    pub presignature: Presignature,
+    pub mine: bool,
+}
+
+impl KeyKind for PresignatureData {
+    fn kind() -> String {
+        "presignatures".to_string()
+    }
+}
+
+impl Keyable for PresignatureData {
+    fn key(&self) -> Key {
+        PresignatureKey {
+            account_id: self.account_id.as_str(),
+            epoch: self.epoch,
+            presignature_id: self.presignature.id,
+        }
+        .key()
+    }
+}
+
+/// A presignature as it is kept in the datastore, with its output encrypted by a
+/// [`StorageCipher`].
+struct StoredPresignatureData {
+    account_id: AccountId,
+    epoch: u64,
+    presignature_id: PresignatureId,
+    participants: Vec<Participant>,
+    sealed_output: Vec<u8>,
+    mine: bool,
+}
+
+impl StoredPresignatureData {
+    fn seal(data: PresignatureData, cipher: &StorageCipher) -> Self {
+        let aad = share_aad(
+            &PresignatureData::kind(),
+            data.account_id.as_str(),
+            data.epoch,
+            data.presignature.id,
+        );
+        let output = serde_json::to_vec(&data.presignature.output).unwrap();
+        Self {
+            sealed_output: cipher.encrypt(&aad, &output),
+            account_id: data.account_id,
+            epoch: data.epoch,
+            presignature_id: data.presignature.id,
+            participants: data.presignature.participants,
+            mine: data.mine,
+        }
+    }
+
+    fn open(self, cipher: &StorageCipher) -> PresignatureResult<PresignatureData> {
+        let aad = share_aad(
+            &PresignatureData::kind(),
+            self.account_id.as_str(),
+            self.epoch,
+            self.presignature_id,
+        );
+        let output = serde_json::from_slice(&cipher.decrypt(&aad, &self.sealed_output)?)?;
+        Ok(PresignatureData {
+            account_id: self.account_id,
+            epoch: self.epoch,
+            presignature: Presignature {
+                id: self.presignature_id,
+                output,
+                participants: self.participants,
+            },
+            mine: self.mine,
+        })
+    }
+}
+
+impl KeyKind for StoredPresignatureData {
+    fn kind() -> String {
+        "presignatures".to_string()
+    }
+}
+
+impl Keyable for StoredPresignatureData {
+    fn key(&self) -> Key {
+        PresignatureKey {
+            account_id: self.account_id.as_str(),
+            epoch: self.epoch,
+            presignature_id: self.presignature_id,
+        }
+        .key()
+    }
+}
+
+impl IntoValue for StoredPresignatureData {
+    fn into_value(self) -> Value {
+        let mut properties = HashMap::new();
+        properties.insert(
+            "account_id".to_string(),
+            Value::StringValue(self.account_id.to_string()),
+        );
+        properties.insert("epoch".to_string(), Value::IntegerValue(self.epoch as i64));
+        properties.insert(
+            "presignature_id".to_string(),
+            Value::IntegerValue(self.presignature_id as i64),
+        );
+        properties.insert(
+            "presignature_output_encrypted".to_string(),
+            Value::StringValue(hex::encode(&self.sealed_output)),
+        );
+        properties.insert(
+            "presignature_participants".to_string(),
+            Value::StringValue(serde_json::to_string(&self.participants).unwrap()),
+        );
+        properties.insert("mine".to_string(), Value::BooleanValue(self.mine));
+        Value::EntityValue {
+            key: self.key(),
+            properties,
+        }
+    }
+}
+
+impl FromValue for StoredPresignatureData {
+    fn from_value(value: Value) -> Result<Self, ConvertError> {
+        match value {
+            Value::EntityValue { mut properties, .. } => {
+                let mut property = |name: &str| {
+                    properties
+                        .remove(name)
+                        .ok_or_else(|| ConvertError::MissingProperty(name.to_string()))
+                };
+                let malformed = |name: &str| ConvertError::MalformedProperty(name.to_string());
+
+                let account_id = String::from_value(property("account_id")?)?
+                    .parse()
+                    .map_err(|err| {
+                        ConvertError::MalformedProperty(format!(
+                            "PresignatureData failed to parse account_id: {err:?}"
+                        ))
+                    })?;
+                let epoch = i64::from_value(property("epoch")?)? as u64;
+                let presignature_id = i64::from_value(property("presignature_id")?)? as u64;
+                let sealed_output = hex::decode(String::from_value(property(
+                    "presignature_output_encrypted",
+                )?)?)
+                .map_err(|_| malformed("presignature_output_encrypted"))?;
+                let participants = serde_json::from_str(&String::from_value(property(
+                    "presignature_participants",
+                )?)?)
+                .map_err(|_| malformed("presignature_participants"))?;
+                let mine = bool::from_value(property("mine")?)?;
+
+                Ok(Self {
+                    account_id,
+                    epoch,
+                    presignature_id,
+                    participants,
+                    sealed_output,
+                    mine,
+                })
+            }
+            value => Err(ConvertError::UnexpectedPropertyType {
+                expected: "entity".to_string(),
+                got: format!("{:?}", value),
+            }),
+        }
+    }
+}
+
+type PresignatureResult<T> = std::result::Result<T, error::DatastoreStorageError>;
+
+/// Persists completed presignatures until they are taken, so that neither the presignatures nor
+/// the triples consumed to build them are lost on a restart.
+#[async_trait]
+pub trait PresignatureNodeStorage {
+    async fn insert(
+        &mut self,
+        epoch: u64,
+        presignature: Presignature,
+        mine: bool,
+    ) -> PresignatureResult<()>;
+    async fn delete(&mut self, epoch: u64, id: PresignatureId) -> PresignatureResult<()>;
+    async fn clear(&mut self) -> PresignatureResult<Vec<PresignatureData>>;
+    /// Loads the presignatures generated during `epoch`.
+    async fn load(&self, epoch: u64) -> PresignatureResult<Vec<PresignatureData>>;
+    fn account_id(&self) -> &AccountId;
+}
+
+struct MemoryPresignatureNodeStorage {
+    presignatures: HashMap<(u64, PresignatureId), (Presignature, bool)>,
+    account_id: AccountId,
+}
+
+#[async_trait]
+impl PresignatureNodeStorage for MemoryPresignatureNodeStorage {
+    async fn insert(
+        &mut self,
+        epoch: u64,
+        presignature: Presignature,
+        mine: bool,
+    ) -> PresignatureResult<()> {
+        self.presignatures
+            .insert((epoch, presignature.id), (presignature, mine));
+        Ok(())
+    }
+
+    async fn delete(&mut self, epoch: u64, id: PresignatureId) -> PresignatureResult<()> {
+        self.presignatures.remove(&(epoch, id));
+        Ok(())
+    }
+
+    async fn clear(&mut self) -> PresignatureResult<Vec<PresignatureData>> {
+        let res = self
+            .presignatures
+            .drain()
+            .map(|((epoch, _), (presignature, mine))| PresignatureData {
+                account_id: self.account_id.clone(),
+                epoch,
+                presignature,
+                mine,
+            })
+            .collect();
+        Ok(res)
+    }
+
+    async fn load(&self, epoch: u64) -> PresignatureResult<Vec<PresignatureData>> {
+        let res = self
+            .presignatures
+            .iter()
+            .filter(|((presignature_epoch, _), _)| *presignature_epoch == epoch)
+            .map(|(_, (presignature, mine))| PresignatureData {
+                account_id: self.account_id.clone(),
+                epoch,
+                presignature: presignature.clone(),
+                mine: *mine,
+            })
+            .collect();
+        Ok(res)
+    }
+
+    fn account_id(&self) -> &AccountId {
+        &self.account_id
+    }
+}
+
+struct DataStorePresignatureNodeStorage {
+    datastore: DatastoreService,
+    cipher: StorageCipher,
+    account_id: AccountId,
+}
+
+impl DataStorePresignatureNodeStorage {
+    fn new(datastore: DatastoreService, cipher: StorageCipher, account_id: &AccountId) -> Self {
+        Self {
+            datastore,
+            cipher,
+            account_id: account_id.clone(),
+        }
+    }
+
+    async fn load_stored(&self) -> PresignatureResult<Vec<StoredPresignatureData>> {
+        let filter = if self.datastore.is_emulator() {
+            None
+        } else {
+            Some(Filter {
+                composite_filter: None,
+                property_filter: Some(PropertyFilter {
+                    op: Some("Equal".to_string()),
+                    property: Some(PropertyReference {
+                        name: Some("account_id".to_string()),
+                    }),
+                    value: Some(DatastoreValue::from_value(
+                        self.account_id.as_str().into_value(),
+                    )?),
+                }),
+            })
+        };
+        let response = self
+            .datastore
+            .fetch_entities::<StoredPresignatureData>(filter)
+            .await?;
+        let mut res = vec![];
+        for entity_result in response {
+            let entity = entity_result.entity.ok_or_else(|| {
+                error::DatastoreStorageError::FetchEntitiesError(
+                    "entity was not able to unwrapped".to_string(),
+                )
+            })?;
+            let data = StoredPresignatureData::from_value(entity.into_value())?;
+            if data.account_id == self.account_id {
+                res.push(data);
+            }
+        }
+        Ok(res)
+    }
+}
+
+#[async_trait]
+impl PresignatureNodeStorage for DataStorePresignatureNodeStorage {
+    async fn insert(
+        &mut self,
+        epoch: u64,
+        presignature: Presignature,
+        mine: bool,
+    ) -> PresignatureResult<()> {
+        tracing::debug!(
+            id = presignature.id,
+            "inserting presignature using datastore"
+        );
+        let data = PresignatureData {
+            account_id: self.account_id.clone(),
+            epoch,
+            presignature,
+            mine,
+        };
+        self.datastore
+            .upsert(StoredPresignatureData::seal(data, &self.cipher))
+            .await?;
+        Ok(())
+    }
+
+    async fn delete(&mut self, epoch: u64, id: PresignatureId) -> PresignatureResult<()> {
+        tracing::debug!(id, "deleting presignature using datastore");
+        self.datastore
+            .delete(PresignatureKey {
+                account_id: self.account_id.as_str(),
+                epoch,
+                presignature_id: id,
+            })
+            .await?;
+        Ok(())
+    }
+
+    async fn clear(&mut self) -> PresignatureResult<Vec<PresignatureData>> {
+        let stored = self.load_stored().await?;
+        self.datastore.delete_many(&stored).await?;
+        let presignatures = stored
+            .into_iter()
+            .filter_map(|presignature| presignature.open(&self.cipher).ok())
+            .collect();
+        Ok(presignatures)
+    }
+
+    async fn load(&self, epoch: u64) -> PresignatureResult<Vec<PresignatureData>> {
+        tracing::debug!(epoch, "loading presignatures using datastore");
+        let mut res = vec![];
+        let mut skipped = 0;
+        for stored in self.load_stored().await? {
+            if stored.epoch != epoch {
+                continue;
+            }
+            let id = stored.presignature_id;
+            match stored.open(&self.cipher) {
+                Ok(presignature) => res.push(presignature),
+                // Left in place, so that it can be looked into.
+                Err(err) => {
+                    tracing::error!(
+                        id,
+                        ?err,
+                        "skipping a stored presignature that failed to decrypt"
+                    );
+                    skipped += 1;
+                }
+            }
+        }
+        tracing::debug!(count = res.len(), skipped, "loading presignatures success");
+        Ok(res)
+    }
+
+    fn account_id(&self) -> &AccountId {
+        &self.account_id
+    }
+}
+
+/// Columns of a row in the `presignatures` table: epoch, presignature_id, participants, output, mine.
+type PresignatureRow = (i64, i64, String, Vec<u8>, bool);
+
+struct LocalDbPresignatureNodeStorage {
+    db: LocalDb,
+    cipher: StorageCipher,
+    account_id: AccountId,
+}
+
+impl LocalDbPresignatureNodeStorage {
+    fn row(row: &rusqlite::Row) -> rusqlite::Result<PresignatureRow> {
+        Ok((
+            row.get(0)?,
+            row.get(1)?,
+            row.get(2)?,
+            row.get(3)?,
+            row.get(4)?,
+        ))
+    }
+
+    fn stored(
+        &self,
+        (epoch, presignature_id, participants, sealed_output, mine): PresignatureRow,
+    ) -> PresignatureResult<StoredPresignatureData> {
+        let participants = serde_json::from_str(&participants).map_err(|_| {
+            ConvertError::MalformedProperty("presignature_participants".to_string())
+        })?;
+        Ok(StoredPresignatureData {
+            account_id: self.account_id.clone(),
+            epoch: epoch as u64,
+            presignature_id: presignature_id as u64,
+            participants,
+            sealed_output,
+            mine,
+        })
+    }
+}
+
+#[async_trait]
+impl PresignatureNodeStorage for LocalDbPresignatureNodeStorage {
+    async fn insert(
+        &mut self,
+        epoch: u64,
+        presignature: Presignature,
+        mine: bool,
+    ) -> PresignatureResult<()> {
+        tracing::debug!(
+            id = presignature.id,
+            "inserting presignature using local db"
+        );
+        let data = PresignatureData {
+            account_id: self.account_id.clone(),
+            epoch,
+            presignature,
+            mine,
+        };
+        let stored = StoredPresignatureData::seal(data, &self.cipher);
+        let participants = serde_json::to_string(&stored.participants)?;
+        let account_id = self.account_id.to_string();
+        self.db
+            .call(move |conn| -> PresignatureResult<()> {
+                conn.execute(
+                    "INSERT OR REPLACE INTO presignatures \
+                    (account_id, epoch, presignature_id, participants, output, mine) \
+                    VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+                    (
+                        account_id,
+                        epoch as i64,
+                        stored.presignature_id as i64,
+                        participants,
+                        stored.sealed_output,
+                        stored.mine,
+                    ),
+                )?;
+                Ok(())
+            })
+            .await
+    }
+
+    async fn delete(&mut self, epoch: u64, id: PresignatureId) -> PresignatureResult<()> {
+        tracing::debug!(id, "deleting presignature using local db");
+        let account_id = self.account_id.to_string();
+        self.db
+            .call(move |conn| -> PresignatureResult<()> {
+                conn.execute(
+                    "DELETE FROM presignatures \
+                    WHERE account_id = ?1 AND epoch = ?2 AND presignature_id = ?3",
+                    (account_id, epoch as i64, id as i64),
+                )?;
+                Ok(())
+            })
+            .await
+    }
+
+    async fn clear(&mut self) -> PresignatureResult<Vec<PresignatureData>> {
+        let account_id = self.account_id.to_string();
+        let rows = self
+            .db
+            .call(move |conn| -> PresignatureResult<_> {
+                let tx = conn.transaction()?;
+                let rows = tx
+                    .prepare(
+                        "SELECT epoch, presignature_id, participants, output, mine \
+                        FROM presignatures WHERE account_id = ?1",
+                    )?
+                    .query_map([&account_id], Self::row)?
+                    .collect::<Result<Vec<_>, _>>()?;
+                tx.execute(
+                    "DELETE FROM presignatures WHERE account_id = ?1",
+                    [&account_id],
+                )?;
+                tx.commit()?;
+                Ok(rows)
+            })
+            .await?;
+        let presignatures = rows
+            .into_iter()
+            .filter_map(|row| self.stored(row).ok()?.open(&self.cipher).ok())
+            .collect();
+        Ok(presignatures)
+    }
+
+    async fn load(&self, epoch: u64) -> PresignatureResult<Vec<PresignatureData>> {
+        tracing::debug!(epoch, "loading presignatures using local db");
+        let account_id = self.account_id.to_string();
+        let rows = self
+            .db
+            .call(move |conn| -> PresignatureResult<_> {
+                let rows = conn
+                    .prepare(
+                        "SELECT epoch, presignature_id, participants, output, mine \
+                        FROM presignatures WHERE account_id = ?1 AND epoch = ?2",
+                    )?
+                    .query_map((account_id, epoch as i64), Self::row)?
+                    .collect::<Result<Vec<_>, _>>()?;
+                Ok(rows)
+            })
+            .await?;
+        let mut res = vec![];
+        let mut skipped = 0;
+        for row in rows {
+            let id = row.1;
+            match self
+                .stored(row)
+                .and_then(|stored| stored.open(&self.cipher))
+            {
+                Ok(presignature) => res.push(presignature),
+                // Left in place, so that it can be looked into.
+                Err(err) => {
+                    tracing::error!(
+                        id,
+                        ?err,
+                        "skipping a stored presignature that failed to decrypt"
+                    );
+                    skipped += 1;
+                }
+            }
+        }
+        tracing::debug!(count = res.len(), skipped, "loading presignatures success");
+        Ok(res)
+    }
+
+    fn account_id(&self) -> &AccountId {
+        &self.account_id
+    }
+}
+
+pub type PresignatureNodeStorageBox = Box<dyn PresignatureNodeStorage + Send + Sync>;
+
+pub type LockPresignatureNodeStorageBox = Arc<RwLock<PresignatureNodeStorageBox>>;
+
+pub fn init(
+    gcp_service: Option<&GcpService>,
+    local_db: Option<&LocalDb>,
+    cipher: &StorageCipher,
+    account_id: &AccountId,
+) -> PresignatureNodeStorageBox {
+    match (local_db, gcp_service) {
+        (Some(db), _) => Box::new(LocalDbPresignatureNodeStorage {
+            db: db.clone(),
+            cipher: cipher.clone(),
+            account_id: account_id.clone(),
+        }) as PresignatureNodeStorageBox,
+        (None, Some(gcp)) => Box::new(DataStorePresignatureNodeStorage::new(
+            gcp.datastore.clone(),
+            cipher.clone(),
+            account_id,
+        )) as PresignatureNodeStorageBox,
+        _ => Box::new(MemoryPresignatureNodeStorage {
+            presignatures: HashMap::new(),
+            account_id: account_id.clone(),
+        }) as PresignatureNodeStorageBox,
+    }
+}
@@ -1,37 +1,113 @@
-use std::path::PathBuf;
-use tokio::fs::File;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
 
+use crate::gcp::error::SecretStorageError;
 use crate::gcp::{GcpService, SecretResult};
+use crate::storage::key_file::{self, KeyFileSecret};
+use crate::storage::local_db::LocalDb;
 use crate::storage::Options;
 use crate::{gcp::SecretManagerService, protocol::state::PersistentNodeData};
 use async_trait::async_trait;
 
 use near_account_id::AccountId;
+use rusqlite::OptionalExtension;
 
+/// Keeps the key shares of this node, one per epoch and key version. A share is only pruned once
+/// the contract has been running with a newer one for a while, so that an abandoned resharing
+/// does not leave the node without a valid share.
 #[async_trait]
 pub trait SecretNodeStorage {
+    /// Stores the key share of `data.epoch`, replacing an earlier share of the same epoch and key
+    /// version.
     async fn store(&mut self, data: &PersistentNodeData) -> SecretResult<()>;
-    async fn load(&self) -> SecretResult<Option<PersistentNodeData>>;
+    /// Loads the key shares of all the epochs kept, oldest first.
+    async fn load(&self) -> SecretResult<Vec<PersistentNodeData>>;
+    /// Removes the key shares of all the epochs before `epoch`.
+    async fn prune(&mut self, epoch: u64) -> SecretResult<()>;
 }
 
-#[derive(Default)]
-struct MemoryNodeStorage {
-    node_data: Option<PersistentNodeData>,
+type KeyShares = BTreeMap<(u64, u32), PersistentNodeData>;
+
+/// Key shares are stored together as a JSON list. Older nodes stored a single share as a JSON
+/// object, which is read as the only share kept.
+fn decode_shares(bytes: &[u8]) -> SecretResult<KeyShares> {
+    let shares = match serde_json::from_slice::<Vec<PersistentNodeData>>(bytes) {
+        Ok(shares) => shares,
+        Err(_) => vec![serde_json::from_slice::<PersistentNodeData>(bytes)?],
+    };
+    Ok(shares
+        .into_iter()
+        .map(|data| ((data.epoch, data.key_version), data))
+        .collect())
+}
+
+fn encode_shares(shares: &KeyShares) -> SecretResult<Vec<u8>> {
+    Ok(serde_json::to_vec(&shares.values().collect::<Vec<_>>())?)
+}
+
+/// Backend holding the encoded key shares as a single blob.
+#[async_trait]
+trait KeyShareBlob {
+    async fn read(&self) -> SecretResult<Option<Vec<u8>>>;
+    async fn write(&mut self, bytes: Vec<u8>) -> SecretResult<()>;
+}
+
+/// Keeps the key shares of every epoch in a single [`KeyShareBlob`].
+struct KeyShareHistory<B>(B);
+
+impl<B: KeyShareBlob + Send + Sync> KeyShareHistory<B> {
+    async fn read_shares(&self) -> SecretResult<KeyShares> {
+        match self.0.read().await? {
+            Some(bytes) => decode_shares(&bytes),
+            None => Ok(KeyShares::new()),
+        }
+    }
 }
 
 #[async_trait]
-impl SecretNodeStorage for MemoryNodeStorage {
+impl<B: KeyShareBlob + Send + Sync> SecretNodeStorage for KeyShareHistory<B> {
     async fn store(&mut self, data: &PersistentNodeData) -> SecretResult<()> {
-        tracing::info!("storing PersistentNodeData using memory");
-        self.node_data = Some(data.clone());
-        Ok(())
+        let mut shares = self.read_shares().await?;
+        shares.insert((data.epoch, data.key_version), data.clone());
+        self.0.write(encode_shares(&shares)?).await
+    }
+
+    async fn load(&self) -> SecretResult<Vec<PersistentNodeData>> {
+        Ok(self.read_shares().await?.into_values().collect())
+    }
+
+    async fn prune(&mut self, epoch: u64) -> SecretResult<()> {
+        let mut shares = self.read_shares().await?;
+        let kept = shares.split_off(&(epoch, 0));
+        if shares.is_empty() {
+            return Ok(());
+        }
+        tracing::info!(
+            pruned = ?shares.keys().collect::<Vec<_>>(),
+            epoch,
+            "removing the key shares of previous epochs"
+        );
+        self.0.write(encode_shares(&kept)?).await
     }
+}
 
-    async fn load(&self) -> SecretResult<Option<PersistentNodeData>> {
+#[derive(Default)]
+struct MemoryNodeStorage {
+    node_data: Option<Vec<u8>>,
+}
+
+#[async_trait]
+impl KeyShareBlob for MemoryNodeStorage {
+    async fn read(&self) -> SecretResult<Option<Vec<u8>>> {
         tracing::info!("loading PersistentNodeData using memory");
         Ok(self.node_data.clone())
     }
+
+    async fn write(&mut self, bytes: Vec<u8>) -> SecretResult<()> {
+        tracing::info!("storing PersistentNodeData using memory");
+        self.node_data = Some(bytes);
+        Ok(())
+    }
 }
 
 struct SecretManagerNodeStorage {
@@ -49,98 +125,182 @@ impl SecretManagerNodeStorage {
 }
 
 #[async_trait]
-impl SecretNodeStorage for SecretManagerNodeStorage {
-    async fn store(&mut self, data: &PersistentNodeData) -> SecretResult<()> {
-        tracing::debug!("storing PersistentNodeData using SecretNodeStorage");
-        self.secret_manager
-            .store_secret(&serde_json::to_vec(data)?, &self.sk_share_secret_id)
-            .await?;
-        Ok(())
-    }
-
-    async fn load(&self) -> SecretResult<Option<PersistentNodeData>> {
+impl KeyShareBlob for SecretManagerNodeStorage {
+    async fn read(&self) -> SecretResult<Option<Vec<u8>>> {
         tracing::debug!("loading PersistentNodeData using SecretNodeStorage");
         let raw_data = self
             .secret_manager
             .load_secret(&self.sk_share_secret_id)
             .await?;
         match raw_data {
-            Some(data) if data.len() > 1 => Ok(Some(serde_json::from_slice(&data)?)),
+            Some(data) if data.len() > 1 => Ok(Some(data)),
             _ => {
                 tracing::info!("failed to load existing key share, presuming it is missing");
                 Ok(None)
             }
         }
     }
+
+    async fn write(&mut self, bytes: Vec<u8>) -> SecretResult<()> {
+        tracing::debug!("storing PersistentNodeData using SecretNodeStorage");
+        self.secret_manager
+            .store_secret(&bytes, &self.sk_share_secret_id)
+            .await?;
+        Ok(())
+    }
 }
 
+/// Keeps the key shares in a file encrypted with a passphrase or keyfile. A file stored in plain
+/// JSON by an older node is encrypted in place the first time it is read.
 struct DiskNodeStorage {
     path: PathBuf,
+    secret: KeyFileSecret,
 }
 
 impl DiskNodeStorage {
-    pub fn new(path: &str) -> Self {
+    pub fn new(path: &str, secret: KeyFileSecret) -> Self {
         Self {
             path: PathBuf::from(path),
+            secret,
         }
     }
+
+    async fn seal(&self, path: &Path, bytes: &[u8]) -> SecretResult<()> {
+        let sealed = key_file::seal(&self.secret, bytes).await?;
+        key_file::write_atomic(path, &sealed).await
+    }
 }
 
 #[async_trait]
-impl SecretNodeStorage for DiskNodeStorage {
-    async fn store(&mut self, data: &PersistentNodeData) -> SecretResult<()> {
-        tracing::debug!("storing PersistentNodeData using DiskNodeStorage");
-        let mut file = File::create(self.path.as_os_str()).await?;
-        // Serialize the person object to JSON and convert directly to bytes
-        let json_bytes = serde_json::to_vec(data)?;
-        // Write the serialized JSON bytes to the file
-        file.write_all(&json_bytes).await?;
-
-        Ok(())
+impl KeyShareBlob for DiskNodeStorage {
+    async fn read(&self) -> SecretResult<Option<Vec<u8>>> {
+        tracing::info!("loading PersistentNodeData using DiskNodeStorage");
+        let contents = match tokio::fs::read(&self.path).await {
+            Ok(contents) => contents,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(err.into()),
+        };
+        if !key_file::is_sealed(&contents) {
+            tracing::warn!(path = %self.path.display(), "found unencrypted key share: encrypting it");
+            self.seal(&self.path, &contents).await?;
+            return Ok(Some(contents));
+        }
+        key_file::check_permissions(&self.path).await?;
+        Ok(Some(key_file::open(&self.secret, &contents).await?))
     }
 
-    async fn load(&self) -> SecretResult<Option<PersistentNodeData>> {
-        tracing::info!("loading PersistentNodeData using DiskNodeStorage");
-        // Open the file asynchronously
-        let file_res = File::open(self.path.as_os_str()).await;
+    async fn write(&mut self, bytes: Vec<u8>) -> SecretResult<()> {
+        tracing::debug!("storing PersistentNodeData using DiskNodeStorage");
+        self.seal(&self.path, &bytes).await
+    }
+}
 
-        match file_res {
-            Ok(mut file) => {
-                let mut contents = Vec::new();
-                // Read the contents of the file into the vector
-                tracing::debug!("loading PersistentNodeData using DiskNodeStorage: reading");
-                file.read_to_end(&mut contents).await?;
+/// Keeps the key shares in the local database, encrypted like [`DiskNodeStorage`] does. Shares
+/// stored in plain JSON by an older node are encrypted in place the first time they are read.
+struct LocalDbNodeStorage {
+    db: LocalDb,
+    account_id: AccountId,
+    secret: KeyFileSecret,
+}
 
-                tracing::debug!("loading PersistentNodeData using DiskNodeStorage: read done");
-                // Deserialize the JSON content to a PersistentNodeData object
-                let data: PersistentNodeData = serde_json::from_slice(&contents)?;
+impl LocalDbNodeStorage {
+    async fn write_sealed(&self, bytes: &[u8]) -> SecretResult<()> {
+        let sealed = key_file::seal(&self.secret, bytes).await?;
+        let account_id = self.account_id.to_string();
+        self.db
+            .call(move |conn| {
+                conn.execute(
+                    "INSERT OR REPLACE INTO secrets (account_id, data) VALUES (?1, ?2)",
+                    (account_id, sealed),
+                )?;
+                Ok(())
+            })
+            .await
+    }
+}
 
-                Ok(Some(data))
-            }
-            _ => Ok(None),
+#[async_trait]
+impl KeyShareBlob for LocalDbNodeStorage {
+    async fn read(&self) -> SecretResult<Option<Vec<u8>>> {
+        tracing::info!("loading PersistentNodeData using LocalDbNodeStorage");
+        let account_id = self.account_id.to_string();
+        let contents = self
+            .db
+            .call(move |conn| -> SecretResult<Option<Vec<u8>>> {
+                Ok(conn
+                    .query_row(
+                        "SELECT data FROM secrets WHERE account_id = ?1",
+                        [account_id],
+                        |row| row.get(0),
+                    )
+                    .optional()?)
+            })
+            .await?;
+        let Some(contents) = contents else {
+            return Ok(None);
+        };
+        if !key_file::is_sealed(&contents) {
+            tracing::warn!("found unencrypted key share in the local database: encrypting it");
+            self.write_sealed(&contents).await?;
+            return Ok(Some(contents));
         }
+        Ok(Some(key_file::open(&self.secret, &contents).await?))
     }
-}
 
+    async fn write(&mut self, bytes: Vec<u8>) -> SecretResult<()> {
+        tracing::debug!("storing PersistentNodeData using LocalDbNodeStorage");
+        self.write_sealed(&bytes).await
+    }
+}
 pub type SecretNodeStorageBox = Box<dyn SecretNodeStorage + Send + Sync>;
 
 pub fn init(
     gcp_service: Option<&GcpService>,
+    local_db: Option<&LocalDb>,
     opts: &Options,
     account_id: &AccountId,
-) -> SecretNodeStorageBox {
-    match gcp_service {
-        Some(gcp) if opts.sk_share_secret_id.is_some() => Box::new(SecretManagerNodeStorage::new(
-            &gcp.secret_manager.clone(),
-            opts.clone().sk_share_secret_id.unwrap().clone(),
-        )) as SecretNodeStorageBox,
-        _ => {
-            if let Some(sk_share_local_path) = &opts.sk_share_local_path {
-                let path = format!("{sk_share_local_path}-{account_id}");
-                Box::new(DiskNodeStorage::new(&path)) as SecretNodeStorageBox
-            } else {
-                Box::<MemoryNodeStorage>::default() as SecretNodeStorageBox
-            }
+) -> SecretResult<SecretNodeStorageBox> {
+    // The local database holds everything when there is one, like it does for triples and
+    // presignatures.
+    if let Some(db) = local_db {
+        if opts.sk_share_local_path.is_some() {
+            return Err(SecretStorageError::KeyFileError(
+                "the key share is kept in the local database, drop --sk-share-local-path"
+                    .to_string(),
+            ));
         }
+        if opts.sk_share_secret_id.is_some() {
+            tracing::warn!("ignoring the secret manager in favor of the local database");
+        }
+        return Ok(Box::new(KeyShareHistory(LocalDbNodeStorage {
+            db: db.clone(),
+            account_id: account_id.clone(),
+            secret: key_file_secret(opts)?,
+        })));
+    }
+    if let (Some(gcp), Some(sk_share_secret_id)) = (gcp_service, &opts.sk_share_secret_id) {
+        return Ok(Box::new(KeyShareHistory(SecretManagerNodeStorage::new(
+            &gcp.secret_manager.clone(),
+            sk_share_secret_id.clone(),
+        ))));
+    }
+    let Some(sk_share_local_path) = &opts.sk_share_local_path else {
+        return Ok(Box::new(KeyShareHistory(MemoryNodeStorage::default())));
+    };
+    let path = format!("{sk_share_local_path}-{account_id}");
+    Ok(Box::new(KeyShareHistory(DiskNodeStorage::new(
+        &path,
+        key_file_secret(opts)?,
+    ))))
+}
+
+/// Secret the key share is encrypted with when stored by the node itself.
+fn key_file_secret(opts: &Options) -> SecretResult<KeyFileSecret> {
+    match (&opts.sk_share_passphrase, &opts.sk_share_keyfile) {
+        (Some(passphrase), _) => Ok(KeyFileSecret::Passphrase(passphrase.clone())),
+        (None, Some(keyfile)) => Ok(KeyFileSecret::Keyfile(PathBuf::from(keyfile))),
+        (None, None) => Err(SecretStorageError::KeyFileError(
+            "a passphrase or keyfile is required to store the key share locally".to_string(),
+        )),
     }
 }
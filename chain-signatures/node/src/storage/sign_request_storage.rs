@@ -0,0 +1,439 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Instant;
+
+use crate::gcp::{error, Keyable};
+use crate::gcp::{
+    error::ConvertError,
+    value::{FromValue, IntoValue, Value},
+    KeyKind,
+};
+use crate::gcp::{DatastoreService, GcpService};
+use crate::protocol::SignRequest;
+use crate::storage::local_db::LocalDb;
+
+use async_trait::async_trait;
+use google_datastore1::api::{
+    Filter, Key, PathElement, PropertyFilter, PropertyReference, Value as DatastoreValue,
+};
+use tokio::sync::RwLock;
+
+use near_account_id::AccountId;
+use near_primitives::hash::CryptoHash;
+
+pub struct SignRequestKey<'a> {
+    pub account_id: &'a str,
+    pub receipt_id: CryptoHash,
+}
+
+impl KeyKind for SignRequestKey<'_> {
+    fn kind() -> String {
+        "sign_requests".to_string()
+    }
+}
+
+impl Keyable for SignRequestKey<'_> {
+    fn key(&self) -> Key {
+        Key {
+            path: Some(vec![PathElement {
+                kind: None,
+                name: Some(format!("{}/{}", self.account_id, self.receipt_id)),
+                id: None,
+            }]),
+            partition_id: None,
+        }
+    }
+}
+
+/// A sign request indexed by this node which has not been resolved yet.
+pub struct SignRequestData {
+    pub account_id: AccountId,
+    pub request: SignRequest,
+}
+
+impl KeyKind for SignRequestData {
+    fn kind() -> String {
+        "sign_requests".to_string()
+    }
+}
+
+impl Keyable for SignRequestData {
+    fn key(&self) -> Key {
+        SignRequestKey {
+            account_id: self.account_id.as_str(),
+            receipt_id: self.request.receipt_id,
+        }
+        .key()
+    }
+}
+
+impl IntoValue for SignRequestData {
+    fn into_value(self) -> Value {
+        let mut properties = HashMap::new();
+        properties.insert(
+            "account_id".to_string(),
+            Value::StringValue(self.account_id.to_string()),
+        );
+        properties.insert(
+            "receipt_id".to_string(),
+            Value::StringValue(self.request.receipt_id.to_string()),
+        );
+        properties.insert(
+            "request".to_string(),
+            Value::StringValue(serde_json::to_string(&self.request.request).unwrap()),
+        );
+        properties.insert(
+            "epsilon".to_string(),
+            Value::StringValue(serde_json::to_string(&self.request.epsilon).unwrap()),
+        );
+        properties.insert(
+            "delta".to_string(),
+            Value::StringValue(serde_json::to_string(&self.request.delta).unwrap()),
+        );
+        properties.insert(
+            "entropy".to_string(),
+            Value::StringValue(hex::encode(self.request.entropy)),
+        );
+        properties.insert(
+            "block_height".to_string(),
+            Value::IntegerValue(self.request.block_height as i64),
+        );
+        properties.insert(
+            "deposit".to_string(),
+            Value::StringValue(self.request.deposit.to_string()),
+        );
+        Value::EntityValue {
+            key: self.key(),
+            properties,
+        }
+    }
+}
+
+impl FromValue for SignRequestData {
+    fn from_value(value: Value) -> Result<Self, ConvertError> {
+        match value {
+            Value::EntityValue { mut properties, .. } => {
+                let mut property = |name: &str| {
+                    properties
+                        .remove(name)
+                        .ok_or_else(|| ConvertError::MissingProperty(name.to_string()))
+                        .and_then(String::from_value)
+                };
+                let malformed = |name: &str| ConvertError::MalformedProperty(name.to_string());
+
+                let account_id = property("account_id")?.parse().map_err(|err| {
+                    ConvertError::MalformedProperty(format!(
+                        "SignRequestData failed to parse account_id: {err:?}"
+                    ))
+                })?;
+                let receipt_id = property("receipt_id")?
+                    .parse()
+                    .map_err(|_| malformed("receipt_id"))?;
+                let request = serde_json::from_str(&property("request")?)
+                    .map_err(|_| malformed("request"))?;
+                let epsilon = serde_json::from_str(&property("epsilon")?)
+                    .map_err(|_| malformed("epsilon"))?;
+                let delta =
+                    serde_json::from_str(&property("delta")?).map_err(|_| malformed("delta"))?;
+                let entropy = hex::decode(property("entropy")?)
+                    .ok()
+                    .and_then(|entropy| entropy.try_into().ok())
+                    .ok_or_else(|| malformed("entropy"))?;
+                let deposit = property("deposit")?
+                    .parse()
+                    .map_err(|_| malformed("deposit"))?;
+                // Requests stored before block heights were persisted are taken as the oldest,
+                // which gets them dropped once the queue learns the current height.
+                let block_height = match properties.remove("block_height") {
+                    Some(value) => i64::from_value(value)? as u64,
+                    None => 0,
+                };
+
+                Ok(Self {
+                    account_id,
+                    request: SignRequest {
+                        receipt_id,
+                        request,
+                        epsilon,
+                        delta,
+                        entropy,
+                        // The original indexing time is lost across restarts.
+                        time_added: Instant::now(),
+                        block_height,
+                        deposit,
+                    },
+                })
+            }
+            value => Err(ConvertError::UnexpectedPropertyType {
+                expected: "entity".to_string(),
+                got: format!("{:?}", value),
+            }),
+        }
+    }
+}
+
+type SignRequestResult<T> = std::result::Result<T, error::DatastoreStorageError>;
+
+/// Persists sign requests from the moment they are indexed until they are resolved, so that
+/// they can be picked back up after a restart.
+#[async_trait]
+pub trait SignRequestNodeStorage {
+    async fn insert(&mut self, request: &SignRequest) -> SignRequestResult<()>;
+    async fn delete(&mut self, receipt_id: CryptoHash) -> SignRequestResult<()>;
+    async fn load(&self) -> SignRequestResult<Vec<SignRequest>>;
+    fn account_id(&self) -> &AccountId;
+}
+
+struct MemorySignRequestNodeStorage {
+    requests: HashMap<CryptoHash, SignRequest>,
+    account_id: AccountId,
+}
+
+#[async_trait]
+impl SignRequestNodeStorage for MemorySignRequestNodeStorage {
+    async fn insert(&mut self, request: &SignRequest) -> SignRequestResult<()> {
+        self.requests.insert(request.receipt_id, request.clone());
+        Ok(())
+    }
+
+    async fn delete(&mut self, receipt_id: CryptoHash) -> SignRequestResult<()> {
+        self.requests.remove(&receipt_id);
+        Ok(())
+    }
+
+    async fn load(&self) -> SignRequestResult<Vec<SignRequest>> {
+        Ok(self.requests.values().cloned().collect())
+    }
+
+    fn account_id(&self) -> &AccountId {
+        &self.account_id
+    }
+}
+
+struct DataStoreSignRequestNodeStorage {
+    datastore: DatastoreService,
+    account_id: AccountId,
+}
+
+impl DataStoreSignRequestNodeStorage {
+    fn new(datastore: DatastoreService, account_id: &AccountId) -> Self {
+        Self {
+            datastore,
+            account_id: account_id.clone(),
+        }
+    }
+}
+
+#[async_trait]
+impl SignRequestNodeStorage for DataStoreSignRequestNodeStorage {
+    async fn insert(&mut self, request: &SignRequest) -> SignRequestResult<()> {
+        tracing::debug!(receipt_id = %request.receipt_id, "inserting sign request using datastore");
+        self.datastore
+            .upsert(SignRequestData {
+                account_id: self.account_id.clone(),
+                request: request.clone(),
+            })
+            .await?;
+        Ok(())
+    }
+
+    async fn delete(&mut self, receipt_id: CryptoHash) -> SignRequestResult<()> {
+        tracing::debug!(%receipt_id, "deleting sign request using datastore");
+        self.datastore
+            .delete(SignRequestKey {
+                account_id: self.account_id.as_str(),
+                receipt_id,
+            })
+            .await?;
+        Ok(())
+    }
+
+    async fn load(&self) -> SignRequestResult<Vec<SignRequest>> {
+        tracing::debug!("loading sign requests using datastore");
+        let filter = if self.datastore.is_emulator() {
+            None
+        } else {
+            Some(Filter {
+                composite_filter: None,
+                property_filter: Some(PropertyFilter {
+                    op: Some("Equal".to_string()),
+                    property: Some(PropertyReference {
+                        name: Some("account_id".to_string()),
+                    }),
+                    value: Some(DatastoreValue::from_value(
+                        self.account_id.as_str().into_value(),
+                    )?),
+                }),
+            })
+        };
+        let response = self
+            .datastore
+            .fetch_entities::<SignRequestData>(filter)
+            .await?;
+        let mut res = vec![];
+        for entity_result in response {
+            let entity = entity_result.entity.ok_or_else(|| {
+                error::DatastoreStorageError::FetchEntitiesError(
+                    "entity was not able to unwrapped".to_string(),
+                )
+            })?;
+            // A request that cannot be read is left to time out on-chain rather than keeping
+            // the node from starting.
+            let data = match SignRequestData::from_value(entity.into_value()) {
+                Ok(data) => data,
+                Err(err) => {
+                    tracing::warn!(?err, "skipping malformed stored sign request");
+                    continue;
+                }
+            };
+            if data.account_id == self.account_id {
+                res.push(data.request);
+            }
+        }
+        tracing::debug!(count = res.len(), "loading sign requests success");
+        Ok(res)
+    }
+
+    fn account_id(&self) -> &AccountId {
+        &self.account_id
+    }
+}
+
+/// Columns of a row in the `sign_requests` table: receipt_id, request, epsilon, delta, entropy,
+/// block_height, deposit.
+type SignRequestRow = (String, String, String, String, String, i64, String);
+
+struct LocalDbSignRequestNodeStorage {
+    db: LocalDb,
+    account_id: AccountId,
+}
+
+impl LocalDbSignRequestNodeStorage {
+    fn request(
+        (receipt_id, request, epsilon, delta, entropy, block_height, deposit): SignRequestRow,
+    ) -> Result<SignRequest, ConvertError> {
+        let malformed = |name: &str| ConvertError::MalformedProperty(name.to_string());
+        Ok(SignRequest {
+            receipt_id: receipt_id.parse().map_err(|_| malformed("receipt_id"))?,
+            request: serde_json::from_str(&request).map_err(|_| malformed("request"))?,
+            epsilon: serde_json::from_str(&epsilon).map_err(|_| malformed("epsilon"))?,
+            delta: serde_json::from_str(&delta).map_err(|_| malformed("delta"))?,
+            entropy: hex::decode(entropy)
+                .ok()
+                .and_then(|entropy| entropy.try_into().ok())
+                .ok_or_else(|| malformed("entropy"))?,
+            // The original indexing time is lost across restarts.
+            time_added: Instant::now(),
+            block_height: block_height as u64,
+            deposit: deposit.parse().map_err(|_| malformed("deposit"))?,
+        })
+    }
+}
+
+#[async_trait]
+impl SignRequestNodeStorage for LocalDbSignRequestNodeStorage {
+    async fn insert(&mut self, request: &SignRequest) -> SignRequestResult<()> {
+        tracing::debug!(receipt_id = %request.receipt_id, "inserting sign request using local db");
+        let row = (
+            self.account_id.to_string(),
+            request.receipt_id.to_string(),
+            serde_json::to_string(&request.request)?,
+            serde_json::to_string(&request.epsilon)?,
+            serde_json::to_string(&request.delta)?,
+            hex::encode(request.entropy),
+            request.block_height as i64,
+            request.deposit.to_string(),
+        );
+        self.db
+            .call(move |conn| -> SignRequestResult<()> {
+                conn.execute(
+                    "INSERT OR REPLACE INTO sign_requests \
+                    (account_id, receipt_id, request, epsilon, delta, entropy, block_height, \
+                    deposit) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+                    row,
+                )?;
+                Ok(())
+            })
+            .await
+    }
+
+    async fn delete(&mut self, receipt_id: CryptoHash) -> SignRequestResult<()> {
+        tracing::debug!(%receipt_id, "deleting sign request using local db");
+        let account_id = self.account_id.to_string();
+        self.db
+            .call(move |conn| -> SignRequestResult<()> {
+                conn.execute(
+                    "DELETE FROM sign_requests WHERE account_id = ?1 AND receipt_id = ?2",
+                    (account_id, receipt_id.to_string()),
+                )?;
+                Ok(())
+            })
+            .await
+    }
+
+    async fn load(&self) -> SignRequestResult<Vec<SignRequest>> {
+        tracing::debug!("loading sign requests using local db");
+        let account_id = self.account_id.to_string();
+        let rows = self
+            .db
+            .call(move |conn| -> SignRequestResult<_> {
+                let rows = conn
+                    .prepare(
+                        "SELECT receipt_id, request, epsilon, delta, entropy, block_height, \
+                        deposit FROM sign_requests WHERE account_id = ?1",
+                    )?
+                    .query_map([account_id], |row| {
+                        Ok((
+                            row.get(0)?,
+                            row.get(1)?,
+                            row.get(2)?,
+                            row.get(3)?,
+                            row.get(4)?,
+                            row.get(5)?,
+                            row.get(6)?,
+                        ))
+                    })?
+                    .collect::<Result<Vec<_>, _>>()?;
+                Ok(rows)
+            })
+            .await?;
+        let mut res = vec![];
+        for row in rows {
+            match Self::request(row) {
+                Ok(request) => res.push(request),
+                Err(err) => tracing::warn!(?err, "skipping malformed stored sign request"),
+            }
+        }
+        tracing::debug!(count = res.len(), "loading sign requests success");
+        Ok(res)
+    }
+
+    fn account_id(&self) -> &AccountId {
+        &self.account_id
+    }
+}
+
+pub type SignRequestNodeStorageBox = Box<dyn SignRequestNodeStorage + Send + Sync>;
+
+pub type LockSignRequestNodeStorageBox = Arc<RwLock<SignRequestNodeStorageBox>>;
+
+pub fn init(
+    gcp_service: Option<&GcpService>,
+    local_db: Option<&LocalDb>,
+    account_id: &AccountId,
+) -> SignRequestNodeStorageBox {
+    match (local_db, gcp_service) {
+        (Some(db), _) => Box::new(LocalDbSignRequestNodeStorage {
+            db: db.clone(),
+            account_id: account_id.clone(),
+        }) as SignRequestNodeStorageBox,
+        (None, Some(gcp)) => Box::new(DataStoreSignRequestNodeStorage::new(
+            gcp.datastore.clone(),
+            account_id,
+        )) as SignRequestNodeStorageBox,
+        (None, None) => Box::new(MemorySignRequestNodeStorage {
+            requests: HashMap::new(),
+            account_id: account_id.clone(),
+        }) as SignRequestNodeStorageBox,
+    }
+}
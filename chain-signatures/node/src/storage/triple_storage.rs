@@ -9,11 +9,15 @@ use crate::gcp::{
 };
 use crate::gcp::{DatastoreService, GcpService};
 use crate::protocol::triple::{Triple, TripleId};
+use crate::storage::cipher::{share_aad, StorageCipher};
+use crate::storage::local_db::LocalDb;
 
 use async_trait::async_trait;
+use cait_sith::triples::TriplePub;
 use google_datastore1::api::{
     Filter, Key, PathElement, PropertyFilter, PropertyReference, Value as DatastoreValue,
 };
+use k256::Secp256k1;
 use tokio::sync::RwLock;
 
 use near_account_id::AccountId;
@@ -45,6 +49,8 @@ impl Keyable for TripleKey<'_> {
 #[derive(Clone, Debug)]
 pub struct TripleData {
     pub account_id: AccountId,
+    /// Epoch of the participant set the triple was generated with.
+    pub epoch: u64,
     pub triple: Triple,
     pub mine: bool,
 }
@@ -57,49 +63,133 @@ impl KeyKind for TripleData {
 
 impl Keyable for TripleData {
     fn key(&self) -> Key {
-        Key {
-            path: Some(vec![PathElement {
-                kind: None,
-                name: Some(format!("{}/{}", self.account_id, self.triple.id)),
-                id: None,
-            }]),
-            partition_id: None,
+        TripleKey {
+            account_id: self.account_id.as_str(),
+            triple_id: self.triple.id,
         }
+        .key()
     }
 }
 
-impl IntoValue for TripleData {
-    fn into_value(self) -> Value {
-        let triple_key = TripleKey {
-            account_id: self.account_id.as_str(),
-            triple_id: self.triple.id,
+/// How the secret share of a triple is kept in the datastore.
+enum StoredShare {
+    /// Written before triple shares were encrypted, along with legacy triples. Never read back.
+    Plaintext(String),
+    Encrypted(Vec<u8>),
+}
+
+/// A triple as it is kept in the datastore, with its share encrypted by a [`StorageCipher`].
+struct StoredTripleData {
+    account_id: AccountId,
+    /// Missing for legacy triples, written before epochs were recorded. The participants those
+    /// were generated with are unknown, so they are deleted on load.
+    epoch: Option<u64>,
+    triple_id: TripleId,
+    triple_public: TriplePub<Secp256k1>,
+    triple_share: StoredShare,
+    mine: bool,
+}
+
+impl StoredTripleData {
+    fn seal(data: TripleData, cipher: &StorageCipher) -> Self {
+        let aad = share_aad(
+            &TripleData::kind(),
+            data.account_id.as_str(),
+            data.epoch,
+            data.triple.id,
+        );
+        let share = serde_json::to_vec(&data.triple.share).unwrap();
+        Self {
+            triple_share: StoredShare::Encrypted(cipher.encrypt(&aad, &share)),
+            account_id: data.account_id,
+            epoch: Some(data.epoch),
+            triple_id: data.triple.id,
+            triple_public: data.triple.public,
+            mine: data.mine,
+        }
+    }
+
+    /// Decrypts the triple, which fails for legacy triples.
+    fn open(self, cipher: &StorageCipher) -> TripleResult<TripleData> {
+        let (Some(epoch), StoredShare::Encrypted(sealed)) = (self.epoch, &self.triple_share) else {
+            return Err(error::DatastoreStorageError::DecryptionError(
+                "legacy triple has no epoch".to_string(),
+            ));
         };
+        let aad = share_aad(
+            &TripleData::kind(),
+            self.account_id.as_str(),
+            epoch,
+            self.triple_id,
+        );
+        let share = serde_json::from_slice(&cipher.decrypt(&aad, sealed)?)?;
+        Ok(TripleData {
+            account_id: self.account_id,
+            epoch,
+            triple: Triple {
+                id: self.triple_id,
+                share,
+                public: self.triple_public,
+            },
+            mine: self.mine,
+        })
+    }
+}
+
+impl KeyKind for StoredTripleData {
+    fn kind() -> String {
+        "triples".to_string()
+    }
+}
+
+impl Keyable for StoredTripleData {
+    fn key(&self) -> Key {
+        TripleKey {
+            account_id: self.account_id.as_str(),
+            triple_id: self.triple_id,
+        }
+        .key()
+    }
+}
+
+impl IntoValue for StoredTripleData {
+    fn into_value(self) -> Value {
         let mut properties = HashMap::new();
         properties.insert(
             "account_id".to_string(),
             Value::StringValue(self.account_id.to_string()),
         );
+        if let Some(epoch) = self.epoch {
+            properties.insert("epoch".to_string(), Value::IntegerValue(epoch as i64));
+        }
         properties.insert(
             "triple_id".to_string(),
-            Value::IntegerValue(self.triple.id as i64),
-        );
-        properties.insert(
-            "triple_share".to_string(),
-            Value::StringValue(serde_json::to_string(&self.triple.share).unwrap()),
+            Value::IntegerValue(self.triple_id as i64),
         );
+        match self.triple_share {
+            StoredShare::Plaintext(share) => {
+                properties.insert("triple_share".to_string(), Value::StringValue(share));
+            }
+            StoredShare::Encrypted(sealed) => {
+                properties.insert(
+                    "triple_share_encrypted".to_string(),
+                    Value::StringValue(hex::encode(sealed)),
+                );
+            }
+        }
         properties.insert(
             "triple_public".to_string(),
-            Value::StringValue(serde_json::to_string(&self.triple.public).unwrap()),
+            Value::StringValue(serde_json::to_string(&self.triple_public).unwrap()),
         );
         properties.insert("mine".to_string(), Value::BooleanValue(self.mine));
         Value::EntityValue {
-            key: triple_key.key(),
+            key: self.key(),
             properties,
         }
     }
 }
 
-impl FromValue for TripleData {
+impl FromValue for StoredTripleData {
     fn from_value(value: Value) -> Result<Self, ConvertError> {
         match value {
             Value::EntityValue { mut properties, .. } => {
@@ -117,12 +207,26 @@ impl FromValue for TripleData {
                     ))
                 })?;
 
-                let (_, triple_share) = properties
-                    .remove_entry("triple_share")
-                    .ok_or_else(|| ConvertError::MissingProperty("triple_share".to_string()))?;
-                let triple_share = String::from_value(triple_share)?;
-                let triple_share = serde_json::from_str(&triple_share)
-                    .map_err(|_| ConvertError::MalformedProperty("triple_share".to_string()))?;
+                let epoch = properties
+                    .remove("epoch")
+                    .map(i64::from_value)
+                    .transpose()?
+                    .map(|epoch| epoch as u64);
+
+                let triple_share = match properties.remove("triple_share_encrypted") {
+                    Some(sealed) => StoredShare::Encrypted(
+                        hex::decode(String::from_value(sealed)?).map_err(|_| {
+                            ConvertError::MalformedProperty("triple_share_encrypted".to_string())
+                        })?,
+                    ),
+                    None => {
+                        let (_, triple_share) =
+                            properties.remove_entry("triple_share").ok_or_else(|| {
+                                ConvertError::MissingProperty("triple_share".to_string())
+                            })?;
+                        StoredShare::Plaintext(String::from_value(triple_share)?)
+                    }
+                };
 
                 let (_, triple_public) = properties
                     .remove_entry("triple_public")
@@ -138,11 +242,10 @@ impl FromValue for TripleData {
 
                 Ok(Self {
                     account_id,
-                    triple: Triple {
-                        id: triple_id as u64,
-                        share: triple_share,
-                        public: triple_public,
-                    },
+                    epoch,
+                    triple_id: triple_id as u64,
+                    triple_public,
+                    triple_share,
                     mine,
                 })
             }
@@ -158,52 +261,70 @@ type TripleResult<T> = std::result::Result<T, error::DatastoreStorageError>;
 
 #[async_trait]
 pub trait TripleNodeStorage {
-    async fn insert(&mut self, triple: Triple, mine: bool) -> TripleResult<()>;
-    async fn delete(&mut self, id: TripleId) -> TripleResult<()>;
+    async fn insert(&mut self, epoch: u64, triple: Triple, mine: bool) -> TripleResult<()>;
+    async fn delete(&mut self, epoch: u64, id: TripleId) -> TripleResult<()>;
     async fn clear(&mut self) -> TripleResult<Vec<TripleData>>;
-    async fn load(&self) -> TripleResult<Vec<TripleData>>;
+    /// Loads the triples generated during `epoch`.
+    async fn load(&self, epoch: u64) -> TripleResult<Vec<TripleData>>;
     fn account_id(&self) -> &AccountId;
 }
 
 #[derive(Clone)]
 struct MemoryTripleNodeStorage {
-    triples: HashMap<TripleId, Triple>,
+    triples: HashMap<TripleId, (u64, Triple)>,
     mine: HashSet<TripleId>,
     account_id: AccountId,
 }
 
+impl MemoryTripleNodeStorage {
+    fn data(&self, epoch: u64, triple: Triple) -> TripleData {
+        TripleData {
+            account_id: self.account_id.clone(),
+            epoch,
+            mine: self.mine.contains(&triple.id),
+            triple,
+        }
+    }
+}
+
 #[async_trait]
 impl TripleNodeStorage for MemoryTripleNodeStorage {
-    async fn insert(&mut self, triple: Triple, mine: bool) -> TripleResult<()> {
+    async fn insert(&mut self, epoch: u64, triple: Triple, mine: bool) -> TripleResult<()> {
         if mine {
             self.mine.insert(triple.id);
         }
-        self.triples.insert(triple.id, triple);
+        self.triples.insert(triple.id, (epoch, triple));
         Ok(())
     }
 
-    async fn delete(&mut self, id: TripleId) -> TripleResult<()> {
-        self.triples.remove(&id);
-        self.mine.remove(&id);
+    async fn delete(&mut self, epoch: u64, id: TripleId) -> TripleResult<()> {
+        if self
+            .triples
+            .get(&id)
+            .is_some_and(|(triple_epoch, _)| *triple_epoch == epoch)
+        {
+            self.triples.remove(&id);
+            self.mine.remove(&id);
+        }
         Ok(())
     }
 
     async fn clear(&mut self) -> TripleResult<Vec<TripleData>> {
-        let res = self.load().await?;
-        self.triples.clear();
+        let triples = std::mem::take(&mut self.triples);
+        let res = triples
+            .into_values()
+            .map(|(epoch, triple)| self.data(epoch, triple))
+            .collect();
         self.mine.clear();
         Ok(res)
     }
 
-    async fn load(&self) -> TripleResult<Vec<TripleData>> {
+    async fn load(&self, epoch: u64) -> TripleResult<Vec<TripleData>> {
         let mut res: Vec<TripleData> = vec![];
-        for (triple_id, triple) in self.triples.clone() {
-            let mine = self.mine.contains(&triple_id);
-            res.push(TripleData {
-                account_id: self.account_id().clone(),
-                triple,
-                mine,
-            });
+        for (triple_epoch, triple) in self.triples.values() {
+            if *triple_epoch == epoch {
+                res.push(self.data(epoch, triple.clone()));
+            }
         }
         Ok(res)
     }
@@ -216,51 +337,20 @@ impl TripleNodeStorage for MemoryTripleNodeStorage {
 #[derive(Clone)]
 struct DataStoreTripleNodeStorage {
     datastore: DatastoreService,
+    cipher: StorageCipher,
     account_id: AccountId,
 }
 
 impl DataStoreTripleNodeStorage {
-    fn new(datastore: DatastoreService, account_id: &AccountId) -> Self {
+    fn new(datastore: DatastoreService, cipher: StorageCipher, account_id: &AccountId) -> Self {
         Self {
             datastore,
+            cipher,
             account_id: account_id.clone(),
         }
     }
-}
-
-#[async_trait]
-impl TripleNodeStorage for DataStoreTripleNodeStorage {
-    async fn insert(&mut self, triple: Triple, mine: bool) -> TripleResult<()> {
-        tracing::debug!(id = triple.id, "inserting triples using datastore");
-        self.datastore
-            .upsert(TripleData {
-                account_id: self.account_id().clone(),
-                triple,
-                mine,
-            })
-            .await?;
-        Ok(())
-    }
-
-    async fn delete(&mut self, id: TripleId) -> TripleResult<()> {
-        tracing::debug!(id, "deleting triples using datastore");
-        self.datastore
-            .delete(TripleKey {
-                account_id: self.account_id.as_str(),
-                triple_id: id,
-            })
-            .await?;
-        Ok(())
-    }
-
-    async fn clear(&mut self) -> TripleResult<Vec<TripleData>> {
-        let triples = self.load().await?;
-        self.datastore.delete_many(&triples).await?;
-        Ok(triples)
-    }
 
-    async fn load(&self) -> TripleResult<Vec<TripleData>> {
-        tracing::debug!("loading triples using datastore");
+    async fn load_stored(&self) -> TripleResult<Vec<StoredTripleData>> {
         let filter = if self.datastore.is_emulator() {
             None
         } else {
@@ -277,20 +367,256 @@ impl TripleNodeStorage for DataStoreTripleNodeStorage {
                 }),
             })
         };
-        let response = self.datastore.fetch_entities::<TripleData>(filter).await?;
-        let mut res: Vec<TripleData> = vec![];
+        let response = self
+            .datastore
+            .fetch_entities::<StoredTripleData>(filter)
+            .await?;
+        let mut res = vec![];
         for entity_result in response {
             let entity = entity_result.entity.ok_or_else(|| {
                 error::DatastoreStorageError::FetchEntitiesError(
                     "entity was not able to unwrapped".to_string(),
                 )
             })?;
-            let triple_data = TripleData::from_value(entity.into_value())?;
+            let triple_data = StoredTripleData::from_value(entity.into_value())?;
             if &triple_data.account_id == self.account_id() {
                 res.push(triple_data);
             }
         }
-        tracing::debug!(count = res.len(), "loading triples success");
+        Ok(res)
+    }
+}
+
+#[async_trait]
+impl TripleNodeStorage for DataStoreTripleNodeStorage {
+    async fn insert(&mut self, epoch: u64, triple: Triple, mine: bool) -> TripleResult<()> {
+        tracing::debug!(id = triple.id, "inserting triples using datastore");
+        let data = TripleData {
+            account_id: self.account_id().clone(),
+            epoch,
+            triple,
+            mine,
+        };
+        self.datastore
+            .upsert(StoredTripleData::seal(data, &self.cipher))
+            .await?;
+        Ok(())
+    }
+
+    /// Triples are keyed by id alone in the datastore, so `epoch` is not needed to find them.
+    async fn delete(&mut self, _epoch: u64, id: TripleId) -> TripleResult<()> {
+        tracing::debug!(id, "deleting triples using datastore");
+        self.datastore
+            .delete(TripleKey {
+                account_id: self.account_id.as_str(),
+                triple_id: id,
+            })
+            .await?;
+        Ok(())
+    }
+
+    /// Legacy triples are deleted along with the rest, but cannot be returned.
+    async fn clear(&mut self) -> TripleResult<Vec<TripleData>> {
+        let stored = self.load_stored().await?;
+        self.datastore.delete_many(&stored).await?;
+        let triples = stored
+            .into_iter()
+            .filter_map(|triple| triple.open(&self.cipher).ok())
+            .collect();
+        Ok(triples)
+    }
+
+    async fn load(&self, epoch: u64) -> TripleResult<Vec<TripleData>> {
+        tracing::debug!(epoch, "loading triples using datastore");
+        let mut res: Vec<TripleData> = vec![];
+        let mut legacy = vec![];
+        let mut skipped = 0;
+        for stored in self.load_stored().await? {
+            match stored.epoch {
+                Some(triple_epoch) if triple_epoch != epoch => continue,
+                Some(_) => {
+                    let id = stored.triple_id;
+                    match stored.open(&self.cipher) {
+                        Ok(triple) => res.push(triple),
+                        // Left in place, so that it can be looked into.
+                        Err(err) => {
+                            tracing::error!(
+                                id,
+                                ?err,
+                                "skipping a stored triple that failed to decrypt"
+                            );
+                            skipped += 1;
+                        }
+                    }
+                }
+                // Written before epochs were recorded. Which participants hold the other shares
+                // is unknown, so they can never be used and are deleted.
+                None => legacy.push(stored),
+            }
+        }
+        if !legacy.is_empty() {
+            tracing::warn!(
+                count = legacy.len(),
+                "deleting stored triples that predate epochs"
+            );
+            self.datastore.delete_many(&legacy).await?;
+        }
+        tracing::debug!(count = res.len(), skipped, "loading triples success");
+        Ok(res)
+    }
+
+    fn account_id(&self) -> &AccountId {
+        &self.account_id
+    }
+}
+
+/// Columns of a row in the `triples` table: triple_id, epoch, triple_public, triple_share, mine.
+type TripleRow = (i64, i64, String, Vec<u8>, bool);
+
+#[derive(Clone)]
+struct LocalDbTripleNodeStorage {
+    db: LocalDb,
+    cipher: StorageCipher,
+    account_id: AccountId,
+}
+
+impl LocalDbTripleNodeStorage {
+    fn row(row: &rusqlite::Row) -> rusqlite::Result<TripleRow> {
+        Ok((
+            row.get(0)?,
+            row.get(1)?,
+            row.get(2)?,
+            row.get(3)?,
+            row.get(4)?,
+        ))
+    }
+
+    fn stored(
+        &self,
+        (triple_id, epoch, triple_public, triple_share, mine): TripleRow,
+    ) -> TripleResult<StoredTripleData> {
+        let triple_public = serde_json::from_str(&triple_public)
+            .map_err(|_| ConvertError::MalformedProperty("triple_public".to_string()))?;
+        Ok(StoredTripleData {
+            account_id: self.account_id.clone(),
+            epoch: Some(epoch as u64),
+            triple_id: triple_id as u64,
+            triple_public,
+            triple_share: StoredShare::Encrypted(triple_share),
+            mine,
+        })
+    }
+}
+
+#[async_trait]
+impl TripleNodeStorage for LocalDbTripleNodeStorage {
+    async fn insert(&mut self, epoch: u64, triple: Triple, mine: bool) -> TripleResult<()> {
+        tracing::debug!(id = triple.id, "inserting triples using local db");
+        let data = TripleData {
+            account_id: self.account_id.clone(),
+            epoch,
+            triple,
+            mine,
+        };
+        let stored = StoredTripleData::seal(data, &self.cipher);
+        let StoredShare::Encrypted(triple_share) = stored.triple_share else {
+            unreachable!("sealed triple shares are always encrypted");
+        };
+        let triple_public = serde_json::to_string(&stored.triple_public)?;
+        let account_id = self.account_id.to_string();
+        self.db
+            .call(move |conn| -> TripleResult<()> {
+                conn.execute(
+                    "INSERT OR REPLACE INTO triples \
+                    (account_id, triple_id, epoch, triple_public, triple_share, mine) \
+                    VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+                    (
+                        account_id,
+                        stored.triple_id as i64,
+                        epoch as i64,
+                        triple_public,
+                        triple_share,
+                        stored.mine,
+                    ),
+                )?;
+                Ok(())
+            })
+            .await
+    }
+
+    async fn delete(&mut self, epoch: u64, id: TripleId) -> TripleResult<()> {
+        tracing::debug!(epoch, id, "deleting triples using local db");
+        let account_id = self.account_id.to_string();
+        self.db
+            .call(move |conn| -> TripleResult<()> {
+                conn.execute(
+                    "DELETE FROM triples \
+                    WHERE account_id = ?1 AND epoch = ?2 AND triple_id = ?3",
+                    (account_id, epoch as i64, id as i64),
+                )?;
+                Ok(())
+            })
+            .await
+    }
+
+    async fn clear(&mut self) -> TripleResult<Vec<TripleData>> {
+        let account_id = self.account_id.to_string();
+        let rows = self
+            .db
+            .call(move |conn| -> TripleResult<_> {
+                let tx = conn.transaction()?;
+                let rows = tx
+                    .prepare(
+                        "SELECT triple_id, epoch, triple_public, triple_share, mine \
+                        FROM triples WHERE account_id = ?1",
+                    )?
+                    .query_map([&account_id], Self::row)?
+                    .collect::<Result<Vec<_>, _>>()?;
+                tx.execute("DELETE FROM triples WHERE account_id = ?1", [&account_id])?;
+                tx.commit()?;
+                Ok(rows)
+            })
+            .await?;
+        let triples = rows
+            .into_iter()
+            .filter_map(|row| self.stored(row).ok()?.open(&self.cipher).ok())
+            .collect();
+        Ok(triples)
+    }
+
+    async fn load(&self, epoch: u64) -> TripleResult<Vec<TripleData>> {
+        tracing::debug!(epoch, "loading triples using local db");
+        let account_id = self.account_id.to_string();
+        let rows = self
+            .db
+            .call(move |conn| -> TripleResult<_> {
+                let rows = conn
+                    .prepare(
+                        "SELECT triple_id, epoch, triple_public, triple_share, mine \
+                        FROM triples WHERE account_id = ?1 AND epoch = ?2",
+                    )?
+                    .query_map((account_id, epoch as i64), Self::row)?
+                    .collect::<Result<Vec<_>, _>>()?;
+                Ok(rows)
+            })
+            .await?;
+        let mut res = vec![];
+        let mut skipped = 0;
+        for row in rows {
+            let id = row.0;
+            match self
+                .stored(row)
+                .and_then(|stored| stored.open(&self.cipher))
+            {
+                Ok(triple) => res.push(triple),
+                // Left in place, so that it can be looked into.
+                Err(err) => {
+                    tracing::error!(id, ?err, "skipping a stored triple that failed to decrypt");
+                    skipped += 1;
+                }
+            }
+        }
+        tracing::debug!(count = res.len(), skipped, "loading triples success");
         Ok(res)
     }
 
@@ -307,10 +633,21 @@ pub struct TripleStorage {
 
 pub type LockTripleNodeStorageBox = Arc<RwLock<TripleNodeStorageBox>>;
 
-pub fn init(gcp_service: Option<&GcpService>, account_id: &AccountId) -> TripleNodeStorageBox {
-    match gcp_service {
-        Some(gcp) => Box::new(DataStoreTripleNodeStorage::new(
+pub fn init(
+    gcp_service: Option<&GcpService>,
+    local_db: Option<&LocalDb>,
+    cipher: &StorageCipher,
+    account_id: &AccountId,
+) -> TripleNodeStorageBox {
+    match (local_db, gcp_service) {
+        (Some(db), _) => Box::new(LocalDbTripleNodeStorage {
+            db: db.clone(),
+            cipher: cipher.clone(),
+            account_id: account_id.clone(),
+        }) as TripleNodeStorageBox,
+        (None, Some(gcp)) => Box::new(DataStoreTripleNodeStorage::new(
             gcp.datastore.clone(),
+            cipher.clone(),
             account_id,
         )) as TripleNodeStorageBox,
         _ => Box::new(MemoryTripleNodeStorage {
@@ -320,3 +657,97 @@ pub fn init(gcp_service: Option<&GcpService>, account_id: &AccountId) -> TripleN
         }) as TripleNodeStorageBox,
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{StoredShare, StoredTripleData, TripleData, TripleKey};
+    use crate::gcp::value::{FromValue, IntoValue, Value};
+    use crate::gcp::Keyable;
+    use crate::protocol::triple::Triple;
+    use crate::storage::cipher::StorageCipher;
+
+    use cait_sith::protocol::Participant;
+    use k256::Secp256k1;
+
+    fn triple(id: u64) -> Triple {
+        let participants = [Participant::from(0), Participant::from(1)];
+        let (public, mut shares) =
+            cait_sith::triples::deal::<Secp256k1>(&mut rand::thread_rng(), &participants, 2);
+        Triple {
+            id,
+            share: shares.remove(0),
+            public,
+        }
+    }
+
+    /// Seals `triple` and reads it back the way the datastore hands it out.
+    fn stored(cipher: &StorageCipher, triple: &Triple) -> StoredTripleData {
+        let data = TripleData {
+            account_id: "node.testnet".parse().unwrap(),
+            epoch: 1,
+            triple: triple.clone(),
+            mine: true,
+        };
+        StoredTripleData::from_value(StoredTripleData::seal(data, cipher).into_value()).unwrap()
+    }
+
+    #[test]
+    fn test_stored_triple_roundtrip() {
+        let cipher = StorageCipher::derive(b"triple-storage-test");
+        let triple = triple(7);
+
+        let stored = stored(&cipher, &triple);
+        assert!(matches!(stored.triple_share, StoredShare::Encrypted(_)));
+        let opened = stored.open(&cipher).unwrap();
+        assert_eq!(opened.epoch, 1);
+        assert!(opened.mine);
+        assert_eq!(
+            serde_json::to_string(&opened.triple).unwrap(),
+            serde_json::to_string(&triple).unwrap()
+        );
+
+        // The share is bound to the epoch it was stored for.
+        let moved = StoredTripleData {
+            epoch: Some(2),
+            ..stored(&cipher, &triple)
+        };
+        assert!(moved.open(&cipher).is_err());
+    }
+
+    #[test]
+    fn test_legacy_triple_is_never_opened() {
+        let cipher = StorageCipher::derive(b"triple-storage-test");
+        let triple = triple(7);
+        let key = TripleKey {
+            account_id: "node.testnet",
+            triple_id: triple.id,
+        };
+        // As written before shares were encrypted and epochs were recorded.
+        let legacy = Value::EntityValue {
+            key: key.key(),
+            properties: [
+                ("account_id", "node.testnet".to_string().into_value()),
+                ("triple_id", Value::IntegerValue(triple.id as i64)),
+                (
+                    "triple_share",
+                    serde_json::to_string(&triple.share).unwrap().into_value(),
+                ),
+                (
+                    "triple_public",
+                    serde_json::to_string(&triple.public).unwrap().into_value(),
+                ),
+                ("mine", Value::BooleanValue(false)),
+            ]
+            .into_iter()
+            .map(|(name, value)| (name.to_string(), value))
+            .collect(),
+        };
+
+        let stored = StoredTripleData::from_value(legacy).unwrap();
+        assert_eq!(stored.epoch, None);
+        assert!(matches!(stored.triple_share, StoredShare::Plaintext(_)));
+        // Keyed like every other triple, so that loading it deletes the legacy entity.
+        assert_eq!(format!("{:?}", stored.key()), format!("{:?}", key.key()));
+        assert!(stored.open(&cipher).is_err());
+    }
+}
@@ -1,7 +1,9 @@
+use crate::protocol::compute::ComputePool;
 use crate::protocol::contract::primitives::Participants;
 use crate::protocol::presignature::GenerationError;
 use crate::protocol::triple::{Triple, TripleConfig, TripleId, TripleManager};
 use crate::protocol::ParticipantInfo;
+use crate::storage::cipher::StorageCipher;
 use crate::storage::triple_storage::LockTripleNodeStorageBox;
 use crate::{gcp::GcpService, protocol::message::TripleMessage, storage};
 
@@ -12,6 +14,7 @@ use std::{collections::HashMap, fs::OpenOptions, ops::Range};
 use itertools::multiunzip;
 use std::collections::VecDeque;
 use std::sync::Arc;
+use std::time::Duration;
 use tokio::sync::RwLock;
 
 // Constants to be used for testing.
@@ -21,11 +24,13 @@ const TRIPLE_CFG: TripleConfig = TripleConfig {
     max_triples: 10,
     max_concurrent_introduction: 4,
     max_concurrent_generation: 16,
+    max_batch_size: 4,
 };
 
 struct TestTripleManagers {
     managers: Vec<TripleManager>,
     participants: Participants,
+    pool: ComputePool,
 }
 
 impl TestTripleManagers {
@@ -45,6 +50,9 @@ impl TestTripleManagers {
                     gcp_datastore_url: Some(url.clone()),
                     env: "triple-test".to_string(),
                     sk_share_local_path: None,
+                    sk_share_passphrase: None,
+                    sk_share_keyfile: None,
+                    local_db_path: None,
                 };
                 Some(
                     GcpService::init(&account_id, &storage_options)
@@ -60,9 +68,13 @@ impl TestTripleManagers {
         let managers = (0..num_managers)
             .map(|num| {
                 let account_id = format!("account_{num}.testnet").parse().unwrap();
-                let triple_storage: LockTripleNodeStorageBox = Arc::new(RwLock::new(
-                    storage::triple_storage::init(services[num as usize].as_ref(), &account_id),
-                ));
+                let triple_storage: LockTripleNodeStorageBox =
+                    Arc::new(RwLock::new(storage::triple_storage::init(
+                        services[num as usize].as_ref(),
+                        None,
+                        &StorageCipher::derive(b"triple-test"),
+                        &account_id,
+                    )));
                 TripleManager::new(
                     Participant::from(num),
                     num_managers as usize,
@@ -77,20 +89,25 @@ impl TestTripleManagers {
         TestTripleManagers {
             managers,
             participants,
+            pool: ComputePool::new(4, &"test.testnet".parse().unwrap()),
         }
     }
 
-    fn generate(&mut self, index: usize) -> Result<(), InitializationError> {
-        self.managers[index].generate(&self.participants)
+    fn generate(&mut self, index: usize, batch_size: usize) -> Result<(), InitializationError> {
+        self.managers[index].generate(&self.participants, batch_size)
     }
 
     async fn poke(&mut self, index: usize) -> Result<bool, ProtocolError> {
         let mut quiet = true;
-        let messages = self.managers[index].poke().await;
+        let messages = self.managers[index].poke(&self.pool).await;
         for (
             participant,
             ref tm @ TripleMessage {
-                id, from, ref data, ..
+                batch_id,
+                batch_size,
+                from,
+                ref data,
+                ..
             },
         ) in messages
         {
@@ -98,8 +115,11 @@ impl TestTripleManagers {
             quiet = false;
             let participant_i: u32 = participant.into();
             let manager = &mut self.managers[participant_i as usize];
-            if let Some(protocol) = manager.get_or_generate(id, &self.participants).unwrap() {
-                protocol.message(from, data.to_vec());
+            if let Some(generator) = manager
+                .get_or_generate(batch_id, batch_size, &self.participants)
+                .unwrap()
+            {
+                generator.message(from, data.to_vec());
             } else {
                 println!("Tried to write to completed mailbox {:?}", tm);
             }
@@ -121,17 +141,26 @@ impl TestTripleManagers {
     }
     // This allows you to see what each node is recieving and when
     #[allow(unused)]
-    fn debug_mailbox(participant: u32, TripleMessage { id, from, data, .. }: &TripleMessage) {
+    fn debug_mailbox(
+        participant: u32,
+        TripleMessage {
+            batch_id,
+            from,
+            data,
+            ..
+        }: &TripleMessage,
+    ) {
         let mut file = OpenOptions::new()
             .write(true)
             .append(true)
             .open(format!("{}.csv", participant))
             .unwrap();
 
-        writeln!(file, "'{id}, {from:?}, {}", hex::encode(data)).unwrap();
+        writeln!(file, "'{batch_id}, {from:?}, {}", hex::encode(data)).unwrap();
     }
 
     async fn poke_until_quiet(&mut self) -> Result<(), ProtocolError> {
+        let progress = self.pool.notifier();
         loop {
             let mut quiet = true;
             for i in 0..self.managers.len() {
@@ -139,7 +168,11 @@ impl TestTripleManagers {
                 quiet = quiet && poke;
             }
             if quiet {
-                return Ok(());
+                // The protocols run on tasks of their own, so they are only done once none is left.
+                if self.managers.iter().all(|m| m.generators.is_empty()) {
+                    return Ok(());
+                }
+                let _ = tokio::time::timeout(Duration::from_millis(100), progress.notified()).await;
             }
         }
     }
@@ -172,13 +205,13 @@ pub async fn test_triple_generation(datastore_url: Option<String>) {
     // Generate 5 triples
     let mut tm = TestTripleManagers::new(5, datastore_url).await;
     for _ in 0..M {
-        tm.generate(0).unwrap();
+        tm.generate(0, 1).unwrap();
     }
     tm.poke_until_quiet().await.unwrap();
 
-    tm.generate(1).unwrap();
-    tm.generate(2).unwrap();
-    tm.generate(4).unwrap();
+    tm.generate(1, 1).unwrap();
+    tm.generate(2, 1).unwrap();
+    tm.generate(4, 1).unwrap();
 
     tm.poke_until_quiet().await.unwrap();
 
@@ -240,7 +273,7 @@ pub async fn test_triple_generation(datastore_url: Option<String>) {
         let datastore_loaded_triples = {
             let triple_store = triple_store.read().await;
             let datastore_loaded_triples = triple_store
-                .load()
+                .load(STARTING_EPOCH)
                 .await
                 .expect("the triple loading result should return Ok");
             assert_eq!(
@@ -295,11 +328,48 @@ pub async fn test_triple_generation(datastore_url: Option<String>) {
     )
 }
 
+pub async fn test_batched_triple_generation(datastore_url: Option<String>) {
+    // Generate 6 triples out of two batches
+    let mut tm = TestTripleManagers::new(3, datastore_url).await;
+    tm.generate(0, 4).unwrap();
+    tm.generate(1, 2).unwrap();
+    tm.poke_until_quiet().await.unwrap();
+
+    let my_lens: usize = tm.managers.iter().map(|m| m.my_len()).sum();
+    assert_eq!(
+        my_lens, 6,
+        "There should be 6 owned completed triples in total"
+    );
+
+    for m in &tm.managers {
+        assert_eq!(m.len(), 6, "All nodes should have 6 completed triples");
+        assert!(
+            m.generators.is_empty(),
+            "There are no triples still being generated"
+        );
+    }
+
+    let mut triples = (0..tm.managers.len())
+        .map(|i| {
+            tm.triples(i)
+                .into_iter()
+                .map(|(id, triple)| (id, triple.public))
+                .collect::<HashMap<_, _>>()
+        })
+        .collect::<Vec<_>>();
+    triples.dedup();
+    assert_eq!(
+        triples.len(),
+        1,
+        "All triple IDs and public parts are identical"
+    );
+}
+
 pub async fn test_triple_deletion(datastore_url: Option<String>) {
     // Generate 3 triples
     let mut tm = TestTripleManagers::new(2, datastore_url).await;
     for _ in 0..3 {
-        tm.generate(0).unwrap();
+        tm.generate(0, 1).unwrap();
     }
     tm.poke_until_quiet().await.unwrap();
 
@@ -322,7 +392,7 @@ pub async fn test_triple_deletion(datastore_url: Option<String>) {
         {
             let triple_storage = triple_storage.read().await;
             let loaded_triples = triple_storage
-                .load()
+                .load(STARTING_EPOCH)
                 .await
                 .expect("expected triples to load successfully");
             assert_eq!(
@@ -335,8 +405,8 @@ pub async fn test_triple_deletion(datastore_url: Option<String>) {
         //verify that if in take_two, one of the triples were accidentally deleted, double deletion will not cause issue
         {
             let mut triple_storage = triple_storage.write().await;
-            let del_res_mine_false = triple_storage.delete(triple0.id).await;
-            let del_res_mine_true = triple_storage.delete(triple0.id).await;
+            let del_res_mine_false = triple_storage.delete(STARTING_EPOCH, triple0.id).await;
+            let del_res_mine_true = triple_storage.delete(STARTING_EPOCH, triple0.id).await;
             assert!(
                 del_res_mine_false.is_ok() && del_res_mine_true.is_ok(),
                 "repeatedly deleting a triple won't err out"
@@ -346,7 +416,7 @@ pub async fn test_triple_deletion(datastore_url: Option<String>) {
         {
             let triple_storage = triple_storage.read().await;
             let loaded_triples = triple_storage
-                .load()
+                .load(STARTING_EPOCH)
                 .await
                 .expect("expected to be able to load recently added triple");
             assert_eq!(
@@ -360,11 +430,11 @@ pub async fn test_triple_deletion(datastore_url: Option<String>) {
         {
             let mut triple_storage = triple_storage.write().await;
             triple_storage
-                .insert(triple0.clone(), true)
+                .insert(STARTING_EPOCH, triple0.clone(), true)
                 .await
                 .expect("expected insert to succeed");
             triple_storage
-                .delete(triple0.id)
+                .delete(STARTING_EPOCH, triple0.id)
                 .await
                 .expect("expected delete to succeed");
         }
@@ -372,7 +442,7 @@ pub async fn test_triple_deletion(datastore_url: Option<String>) {
         {
             let triple_storage = triple_storage.read().await;
             let loaded = triple_storage
-                .load()
+                .load(STARTING_EPOCH)
                 .await
                 .expect("expected to be able to load at least one triple");
             assert_eq!(
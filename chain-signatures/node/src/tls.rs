@@ -0,0 +1,213 @@
+//! Optional mutual TLS between the nodes.
+//!
+//! Every node serves and connects with a self-signed certificate for its `sign_pk`, and only
+//! talks to peers whose certificate is for the `sign_pk` of a participant or candidate in the
+//! contract. The handshake proves that the peer holds the key, so no certificate authority is
+//! involved and host names are not checked.
+
+use std::collections::HashSet;
+use std::sync::{Arc, RwLock};
+use std::time::SystemTime;
+
+use near_crypto::{KeyType, PublicKey, SecretKey};
+use rustls::client::{ServerCertVerified, ServerCertVerifier};
+use rustls::server::{ClientCertVerified, ClientCertVerifier};
+use rustls::{Certificate, ClientConfig, DistinguishedName, PrivateKey, ServerConfig, ServerName};
+use x509_parser::oid_registry::OID_SIG_ED25519;
+
+use crate::protocol::ProtocolState;
+
+/// PKCS#8 prefix of an ed25519 private key, followed by its 32 byte seed.
+const ED25519_PKCS8_PREFIX: [u8; 16] = [
+    0x30, 0x2e, 0x02, 0x01, 0x00, 0x30, 0x05, 0x06, 0x03, 0x2b, 0x65, 0x70, 0x04, 0x22, 0x04, 0x20,
+];
+
+#[derive(thiserror::Error, Debug)]
+pub enum TlsError {
+    #[error("mutual TLS requires an ed25519 sign key, got a {0} key")]
+    UnsupportedKey(KeyType),
+    #[error("failed to generate the node certificate: {0}")]
+    Certificate(#[from] rcgen::RcgenError),
+    #[error("invalid TLS configuration: {0}")]
+    Config(#[from] rustls::Error),
+}
+
+/// The ed25519 `sign_pk`s of the participants and candidates in the contract, which are the only
+/// keys peers are accepted with. Kept up to date by the mesh as the contract state changes.
+#[derive(Clone, Default)]
+pub struct TrustedPeers(Arc<RwLock<HashSet<Vec<u8>>>>);
+
+impl TrustedPeers {
+    pub fn update(&self, contract_state: &ProtocolState) {
+        let keys: Vec<&PublicKey> = match contract_state {
+            ProtocolState::Initializing(state) => state
+                .candidates
+                .iter()
+                .map(|(_, info)| &info.sign_pk)
+                .collect(),
+            ProtocolState::Running(state) => state
+                .participants
+                .iter()
+                .map(|(_, info)| &info.sign_pk)
+                .chain(state.candidates.iter().map(|(_, info)| &info.sign_pk))
+                .collect(),
+            ProtocolState::Resharing(state) => state
+                .old_participants
+                .iter()
+                .chain(state.new_participants.iter())
+                .map(|(_, info)| &info.sign_pk)
+                .collect(),
+        };
+        *self.0.write().unwrap() = keys
+            .into_iter()
+            .filter(|key| key.key_type() == KeyType::ED25519)
+            .map(|key| key.key_data().to_vec())
+            .collect();
+    }
+
+    fn verify(&self, cert: &Certificate) -> Result<(), rustls::Error> {
+        let key = certificate_key(cert)?;
+        if self.0.read().unwrap().contains(key.as_slice()) {
+            Ok(())
+        } else {
+            Err(rustls::Error::InvalidCertificate(
+                rustls::CertificateError::ApplicationVerificationFailure,
+            ))
+        }
+    }
+}
+
+/// The ed25519 key a certificate is for.
+fn certificate_key(cert: &Certificate) -> Result<Vec<u8>, rustls::Error> {
+    let bad_encoding = || rustls::Error::InvalidCertificate(rustls::CertificateError::BadEncoding);
+    let (_, cert) = x509_parser::parse_x509_certificate(&cert.0).map_err(|_| bad_encoding())?;
+    let spki = cert.public_key();
+    if spki.algorithm.algorithm != OID_SIG_ED25519 {
+        return Err(bad_encoding());
+    }
+    Ok(spki.subject_public_key.data.to_vec())
+}
+
+/// Accepts the certificates of trusted peers, both as client and as server.
+struct PeerVerifier(TrustedPeers);
+
+impl ServerCertVerifier for PeerVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &Certificate,
+        _intermediates: &[Certificate],
+        _server_name: &ServerName,
+        _scts: &mut dyn Iterator<Item = &[u8]>,
+        _ocsp_response: &[u8],
+        _now: SystemTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        self.0.verify(end_entity)?;
+        Ok(ServerCertVerified::assertion())
+    }
+}
+
+impl ClientCertVerifier for PeerVerifier {
+    fn client_auth_root_subjects(&self) -> &[DistinguishedName] {
+        &[]
+    }
+
+    fn verify_client_cert(
+        &self,
+        end_entity: &Certificate,
+        _intermediates: &[Certificate],
+        _now: SystemTime,
+    ) -> Result<ClientCertVerified, rustls::Error> {
+        self.0.verify(end_entity)?;
+        Ok(ClientCertVerified::assertion())
+    }
+}
+
+/// Certificate of this node along with the TLS configurations using it.
+#[derive(Clone)]
+pub struct NodeTls {
+    certificate: Certificate,
+    server_config: Arc<ServerConfig>,
+    client_config: Arc<ClientConfig>,
+    trusted_peers: TrustedPeers,
+}
+
+impl std::fmt::Debug for NodeTls {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("NodeTls").finish_non_exhaustive()
+    }
+}
+
+impl NodeTls {
+    /// Creates a self-signed certificate for the public key of `sign_sk`.
+    pub fn new(sign_sk: &SecretKey) -> Result<Self, TlsError> {
+        let SecretKey::ED25519(secret) = sign_sk else {
+            return Err(TlsError::UnsupportedKey(sign_sk.key_type()));
+        };
+        let pkcs8 = [&ED25519_PKCS8_PREFIX[..], &secret.0[..32]].concat();
+        let mut params = rcgen::CertificateParams::new(vec!["multichain-node".to_string()]);
+        params.alg = &rcgen::PKCS_ED25519;
+        params.key_pair = Some(rcgen::KeyPair::from_der(&pkcs8)?);
+        let cert = rcgen::Certificate::from_params(params)?;
+        let cert_chain = vec![Certificate(cert.serialize_der()?)];
+        let key = PrivateKey(cert.serialize_private_key_der());
+
+        let trusted_peers = TrustedPeers::default();
+        let verifier = Arc::new(PeerVerifier(trusted_peers.clone()));
+        let server_config = ServerConfig::builder()
+            .with_safe_default_cipher_suites()
+            .with_safe_default_kx_groups()
+            .with_protocol_versions(&[&rustls::version::TLS13])?
+            .with_client_cert_verifier(verifier.clone())
+            .with_single_cert(cert_chain.clone(), key.clone())?;
+        let client_config = ClientConfig::builder()
+            .with_safe_default_cipher_suites()
+            .with_safe_default_kx_groups()
+            .with_protocol_versions(&[&rustls::version::TLS13])?
+            .with_custom_certificate_verifier(verifier)
+            .with_client_auth_cert(cert_chain.clone(), key)?;
+
+        Ok(Self {
+            certificate: cert_chain[0].clone(),
+            server_config: Arc::new(server_config),
+            client_config: Arc::new(client_config),
+            trusted_peers,
+        })
+    }
+
+    pub fn server_config(&self) -> Arc<ServerConfig> {
+        self.server_config.clone()
+    }
+
+    pub fn client_config(&self) -> Arc<ClientConfig> {
+        self.client_config.clone()
+    }
+
+    pub fn trusted_peers(&self) -> TrustedPeers {
+        self.trusted_peers.clone()
+    }
+
+    /// Client for making requests to the other nodes over mutual TLS.
+    pub fn http_client(&self) -> reqwest::Client {
+        reqwest::Client::builder()
+            .use_preconfigured_tls((*self.client_config).clone())
+            .build()
+            .expect("a preconfigured rustls client config is accepted")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{certificate_key, NodeTls};
+    use near_crypto::{KeyType, SecretKey};
+
+    #[test]
+    fn test_certificate_is_bound_to_sign_pk() {
+        let sign_sk = SecretKey::from_random(KeyType::ED25519);
+        let tls = NodeTls::new(&sign_sk).unwrap();
+        assert_eq!(
+            certificate_key(&tls.certificate).unwrap(),
+            sign_sk.public_key().key_data()
+        );
+        assert!(NodeTls::new(&SecretKey::from_random(KeyType::SECP256K1)).is_err());
+    }
+}
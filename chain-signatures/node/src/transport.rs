@@ -0,0 +1,792 @@
+//! Long-lived message streams to the other participants.
+//!
+//! Every peer gets a WebSocket connection to its `/msg/stream` endpoint, carrying batches of
+//! encrypted messages that the peer acknowledges once they are handed to its protocol loop.
+//! Unacknowledged batches are resent after a reconnect, and the peer drops the ones it already
+//! delivered. Peers that do not serve the stream yet are sent to over the `/msg` endpoint, in the
+//! legacy format they expect, and are checked again for a stream every so often. Batches still
+//! on their way to a peer found not to serve the stream are handed back to be sent that way.
+//!
+//! When connecting, the sender offers the message formats it knows and the peer answers with the
+//! one to encrypt messages to it in. Peers that do not answer are sent JSON.
+//!
+//! Frames themselves are always CBOR, whatever format the messages they carry are encrypted in.
+
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use cait_sith::protocol::Participant;
+use futures_util::{SinkExt, StreamExt};
+use serde::{Deserialize, Serialize};
+use tokio::net::TcpStream;
+use tokio::sync::mpsc::{self, error::TrySendError};
+use tokio::task::JoinHandle;
+use tokio::time::Instant;
+use tokio_tungstenite::tungstenite::{self, http::StatusCode, Message};
+use tokio_tungstenite::{Connector, MaybeTlsStream, WebSocketStream};
+use url::Url;
+
+use crate::http_client::{self, QueuedMessage, SendError};
+use crate::protocol::contract::primitives::ParticipantInfo;
+use crate::protocol::encoding::{EncodingError, MessageFormat};
+use crate::protocol::message::{ReplayGuard, SealedMessage, WireMessage};
+
+/// Batches queued for a peer, after which sending to it fails until it catches up.
+const PEER_QUEUE_CAPACITY: usize = 64;
+
+/// Batches sent to a peer but not yet acknowledged, after which no more are sent to it.
+const MAX_IN_FLIGHT: usize = 16;
+
+/// How long to wait for an acknowledgement before the connection is considered broken.
+const ACK_TIMEOUT: Duration = Duration::from_secs(10);
+
+const RECONNECT_MIN_DELAY: Duration = Duration::from_millis(100);
+const RECONNECT_MAX_DELAY: Duration = Duration::from_secs(10);
+
+/// How long to keep sending legacy messages to a peer without a stream before checking again
+/// whether it serves one, doubling every time it still does not.
+const LEGACY_RECHECK_MIN_DELAY: Duration = Duration::from_secs(60);
+const LEGACY_RECHECK_MAX_DELAY: Duration = Duration::from_secs(30 * 60);
+
+/// How long a receiver remembers what it delivered from a sender that went away.
+const SESSION_TTL: Duration = Duration::from_secs(10 * 60);
+
+#[derive(Serialize, Deserialize, Debug)]
+pub enum Frame {
+    /// First frame of every connection, naming the sender's stream across reconnects.
+    Hello {
+        session: u64,
+        /// Message formats the sender can encode, which older senders did not send.
+        #[serde(default)]
+        formats: Vec<MessageFormat>,
+    },
+    /// Answers a `Hello` with the format the receiver wants messages to be encoded in.
+    Format { format: MessageFormat },
+    /// Encrypted messages, numbered in the order they were sent.
+    Batch {
+        seq: u64,
+        messages: Vec<SealedMessage>,
+    },
+    /// Acknowledges every batch up to and including `seq`.
+    Ack { seq: u64 },
+}
+
+impl Frame {
+    /// Encodes the frame as CBOR, so that the ciphertexts it carries go over the wire as raw
+    /// bytes.
+    pub fn encode(&self) -> Vec<u8> {
+        MessageFormat::Cbor
+            .encode(self)
+            .expect("frames always serialize")
+    }
+
+    /// Decodes a frame, which senders that predate binary frames encoded as JSON.
+    pub fn decode(bytes: &[u8]) -> Result<Self, EncodingError> {
+        MessageFormat::decode(bytes)
+    }
+}
+
+/// Latest batch delivered from each participant's stream, kept by the receiving end. Only
+/// participants whose messages were verified get an entry, and only for their latest session, so
+/// there are never more entries than participants.
+#[derive(Default)]
+pub struct StreamSessions {
+    delivered: Mutex<HashMap<Participant, StreamSession>>,
+}
+
+struct StreamSession {
+    session: u64,
+    seq: u64,
+    at: Instant,
+}
+
+impl StreamSessions {
+    /// Sequence number of the last batch delivered from the `session` of `from`, if it
+    /// connected before.
+    pub fn resume(&self, from: Participant, session: u64) -> u64 {
+        let mut delivered = self.delivered.lock().unwrap();
+        delivered.retain(|_, entry| entry.at.elapsed() < SESSION_TTL);
+        delivered
+            .get(&from)
+            .filter(|entry| entry.session == session)
+            .map_or(0, |entry| entry.seq)
+    }
+
+    /// Records that the batch `seq` of `session` was delivered, `from` being the participant its
+    /// messages were verified to come from.
+    pub fn delivered(&self, from: Participant, session: u64, seq: u64) {
+        self.delivered.lock().unwrap().insert(
+            from,
+            StreamSession {
+                session,
+                seq,
+                at: Instant::now(),
+            },
+        );
+    }
+}
+
+/// Receiving end of a single connection of a message stream.
+pub struct StreamReceiver<'a> {
+    sessions: &'a StreamSessions,
+    session: Option<u64>,
+    /// Last batch delivered, which is looked up once the sender names itself in a batch.
+    delivered: Option<u64>,
+}
+
+impl<'a> StreamReceiver<'a> {
+    pub fn new(sessions: &'a StreamSessions) -> Self {
+        Self {
+            sessions,
+            session: None,
+            delivered: None,
+        }
+    }
+
+    /// Picks up `session` where a previous connection of it left off.
+    pub fn hello(&mut self, session: u64) {
+        self.session = Some(session);
+        self.delivered = None;
+    }
+
+    /// Whether the batch `seq` sent by `from` still has to be delivered, rather than being resent
+    /// after a reconnect. `from` is not verified yet, but a sender claiming to be someone else
+    /// only gets its own batches skipped.
+    pub fn is_new(&mut self, from: Participant, seq: u64) -> bool {
+        let (sessions, session) = (self.sessions, self.session);
+        let delivered = *self
+            .delivered
+            .get_or_insert_with(|| session.map_or(0, |session| sessions.resume(from, session)));
+        seq > delivered
+    }
+
+    /// Records that the batch `seq` was delivered. It is only remembered past this connection
+    /// once one of its messages was verified to come from a participant, `from`.
+    pub fn delivered(&mut self, from: Option<Participant>, seq: u64) {
+        self.delivered = Some(seq);
+        if let (Some(from), Some(session)) = (from, self.session) {
+            self.sessions.delivered(from, session, seq);
+        }
+    }
+}
+
+/// How messages get to a peer.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum PeerMode {
+    /// Its message stream has not been connected yet.
+    Connecting,
+    Streaming,
+    /// It does not serve the message stream, until checked again after `recheck_at`.
+    Legacy {
+        recheck_at: Instant,
+        backoff: Duration,
+    },
+}
+
+/// Messages sent to a peer in one frame, along with the queued messages they were encrypted from.
+struct Batch {
+    messages: Vec<SealedMessage>,
+    queued: Vec<QueuedMessage>,
+}
+
+struct PeerStream {
+    url: String,
+    sender: mpsc::Sender<Batch>,
+    mode: Arc<Mutex<PeerMode>>,
+    /// Format the peer asked for on the current connection.
+    format: Arc<Mutex<MessageFormat>>,
+    task: JoinHandle<()>,
+}
+
+impl PeerStream {
+    fn mode(&self) -> PeerMode {
+        *self.mode.lock().unwrap()
+    }
+
+    /// Whether the stream has to be connected again to reach the peer at `url`. Peers without a
+    /// stream are only checked again once their backoff passed.
+    fn is_stale(&self, url: &str) -> bool {
+        if self.url != url {
+            return true;
+        }
+        match self.mode() {
+            PeerMode::Legacy { recheck_at, .. } => Instant::now() >= recheck_at,
+            PeerMode::Connecting | PeerMode::Streaming => self.sender.is_closed(),
+        }
+    }
+}
+
+impl Drop for PeerStream {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+/// Sends encrypted messages to the other participants over a stream per peer.
+#[derive(Default)]
+pub struct Transport {
+    http: reqwest::Client,
+    /// Client side of mutual TLS with the other nodes, if enabled.
+    tls: Option<Arc<rustls::ClientConfig>>,
+    peers: Mutex<HashMap<Participant, PeerStream>>,
+    /// Guard of the incoming messages, told about the peers found to serve the message stream.
+    replay_guard: Arc<ReplayGuard>,
+    /// Messages handed back by the streams to peers found not to serve one.
+    requeued: Arc<Mutex<Vec<QueuedMessage>>>,
+}
+
+impl Transport {
+    pub fn new(http: reqwest::Client, tls: Option<Arc<rustls::ClientConfig>>) -> Self {
+        Self {
+            http,
+            tls,
+            ..Default::default()
+        }
+    }
+
+    /// Guard to check the messages received from the peers with.
+    pub fn replay_guard(&self) -> Arc<ReplayGuard> {
+        self.replay_guard.clone()
+    }
+
+    /// Queues `messages` on the stream to `info`, connecting to it first if needed. Fails right
+    /// away if the stream is not connected yet or the peer is not keeping up, leaving it to the
+    /// caller to try again later. Legacy messages only go over http.
+    ///
+    /// `queued` are the messages that `messages` were encrypted from. They are taken along with
+    /// the batch once it is queued on the stream, and left to the caller otherwise.
+    pub async fn send(
+        &self,
+        from: Participant,
+        info: &ParticipantInfo,
+        messages: Vec<WireMessage>,
+        queued: &mut Vec<QueuedMessage>,
+    ) -> Result<(), SendError> {
+        let messages = {
+            let mut peers = self.peers.lock().unwrap();
+            let id = Participant::from(info.id);
+            if peers.get(&id).map_or(true, |peer| peer.is_stale(&info.url)) {
+                // Peers without a stream keep getting legacy messages while being checked again.
+                let mode = match peers.get(&id).filter(|peer| peer.url == info.url) {
+                    Some(peer) => match peer.mode() {
+                        PeerMode::Legacy { backoff, .. } => PeerMode::Legacy {
+                            recheck_at: Instant::now() + backoff,
+                            backoff,
+                        },
+                        _ => PeerMode::Connecting,
+                    },
+                    None => PeerMode::Connecting,
+                };
+                peers.insert(id, self.connect(info, mode));
+            }
+            let peer = &peers[&id];
+            let account_id = info.account_id.to_string();
+            match sealed(messages) {
+                Ok(messages) => {
+                    return match peer.mode() {
+                        PeerMode::Streaming => {
+                            let batch = Batch {
+                                messages,
+                                queued: std::mem::take(queued),
+                            };
+                            peer.sender.try_send(batch).map_err(|err| match err {
+                                TrySendError::Full(batch) => {
+                                    *queued = batch.queued;
+                                    SendError::Backpressure(account_id)
+                                }
+                                TrySendError::Closed(batch) => {
+                                    *queued = batch.queued;
+                                    SendError::StreamClosed(account_id)
+                                }
+                            })
+                        }
+                        PeerMode::Connecting => Err(SendError::NotConnected(account_id)),
+                        // Encrypted before we knew, so they have to be encrypted again as legacy
+                        // messages.
+                        PeerMode::Legacy { .. } => Err(SendError::StreamClosed(account_id)),
+                    };
+                }
+                Err(messages) => messages,
+            }
+        };
+        http_client::send_encrypted(from, &self.http, &info.url, messages).await
+    }
+
+    /// Takes the messages that were on their way to peers found not to serve the message stream,
+    /// to be encrypted again as legacy messages.
+    pub fn take_requeued(&self) -> Vec<QueuedMessage> {
+        std::mem::take(&mut *self.requeued.lock().unwrap())
+    }
+
+    /// Whether `info` does not serve the message stream, and so expects legacy messages.
+    pub fn is_legacy(&self, info: &ParticipantInfo) -> bool {
+        let peers = self.peers.lock().unwrap();
+        peers.get(&Participant::from(info.id)).is_some_and(|peer| {
+            peer.url == info.url && matches!(peer.mode(), PeerMode::Legacy { .. })
+        })
+    }
+
+    /// Format to encode the messages to `info` in, which is JSON until its stream negotiated
+    /// another one.
+    pub fn format(&self, info: &ParticipantInfo) -> MessageFormat {
+        let peers = self.peers.lock().unwrap();
+        match peers.get(&Participant::from(info.id)) {
+            Some(peer) if peer.url == info.url && peer.mode() == PeerMode::Streaming => {
+                *peer.format.lock().unwrap()
+            }
+            _ => MessageFormat::default(),
+        }
+    }
+
+    fn connect(&self, info: &ParticipantInfo, mode: PeerMode) -> PeerStream {
+        let (sender, receiver) = mpsc::channel(PEER_QUEUE_CAPACITY);
+        let mode = Arc::new(Mutex::new(mode));
+        let format = Arc::new(Mutex::new(MessageFormat::default()));
+        let peer = Peer {
+            participant: Participant::from(info.id),
+            replay_guard: self.replay_guard.clone(),
+            requeued: self.requeued.clone(),
+            account_id: info.account_id.to_string(),
+            url: info.url.clone(),
+            tls: self.tls.clone(),
+            mode: mode.clone(),
+            format: format.clone(),
+        };
+        PeerStream {
+            url: info.url.clone(),
+            sender,
+            mode,
+            format,
+            task: tokio::spawn(peer.run(receiver)),
+        }
+    }
+}
+
+struct Peer {
+    participant: Participant,
+    replay_guard: Arc<ReplayGuard>,
+    requeued: Arc<Mutex<Vec<QueuedMessage>>>,
+    account_id: String,
+    url: String,
+    tls: Option<Arc<rustls::ClientConfig>>,
+    mode: Arc<Mutex<PeerMode>>,
+    format: Arc<Mutex<MessageFormat>>,
+}
+
+impl Peer {
+    async fn run(self, mut outgoing: mpsc::Receiver<Batch>) {
+        let stream_url = match stream_url(&self.url) {
+            Ok(url) => url,
+            Err(err) => {
+                tracing::warn!(?err, url = self.url, "invalid participant url");
+                return;
+            }
+        };
+        let session = rand::random();
+        let mut next_seq = 1;
+        let mut unacked = VecDeque::new();
+        let mut delay = RECONNECT_MIN_DELAY;
+        loop {
+            let connector = self.tls.clone().map(Connector::Rustls);
+            match tokio_tungstenite::connect_async_tls_with_config(
+                stream_url.as_str(),
+                None,
+                false,
+                connector,
+            )
+            .await
+            {
+                Ok((ws, _)) => {
+                    delay = RECONNECT_MIN_DELAY;
+                    *self.mode.lock().unwrap() = PeerMode::Streaming;
+                    self.replay_guard.mark_upgraded(self.participant);
+                    crate::metrics::NUM_MESSAGE_STREAM_CONNECTS
+                        .with_label_values(&[&self.account_id])
+                        .inc();
+                    let result = self
+                        .stream(ws, session, &mut next_seq, &mut unacked, &mut outgoing)
+                        .await;
+                    crate::metrics::MESSAGE_STREAM_UNACKED
+                        .with_label_values(&[&self.account_id])
+                        .set(unacked.len() as i64);
+                    match result {
+                        Ok(()) => return,
+                        Err(reason) => tracing::warn!(
+                            to = self.account_id,
+                            reason,
+                            unacked = unacked.len(),
+                            "message stream broke, reconnecting"
+                        ),
+                    }
+                }
+                Err(tungstenite::Error::Http(response))
+                    if matches!(
+                        response.status(),
+                        StatusCode::NOT_FOUND | StatusCode::METHOD_NOT_ALLOWED
+                    ) =>
+                {
+                    {
+                        let mut mode = self.mode.lock().unwrap();
+                        let backoff = match *mode {
+                            PeerMode::Legacy { backoff, .. } => {
+                                (backoff * 2).min(LEGACY_RECHECK_MAX_DELAY)
+                            }
+                            PeerMode::Connecting | PeerMode::Streaming => LEGACY_RECHECK_MIN_DELAY,
+                        };
+                        tracing::info!(
+                            to = self.account_id,
+                            ?backoff,
+                            "participant does not serve the message stream, sending over http"
+                        );
+                        *mode = PeerMode::Legacy {
+                            recheck_at: Instant::now() + backoff,
+                            backoff,
+                        };
+                    }
+                    self.requeue(&mut unacked, &mut outgoing);
+                    return;
+                }
+                Err(err) => {
+                    tracing::debug!(
+                        ?err,
+                        to = self.account_id,
+                        "failed to connect message stream"
+                    );
+                }
+            }
+            tokio::time::sleep(delay).await;
+            delay = (delay * 2).min(RECONNECT_MAX_DELAY);
+        }
+    }
+
+    /// Hands the batches that did not make it to the peer back to be sent over http. No more
+    /// batches are queued once the peer is known not to serve the stream.
+    fn requeue(
+        &self,
+        unacked: &mut VecDeque<(u64, Vec<u8>, Vec<QueuedMessage>)>,
+        outgoing: &mut mpsc::Receiver<Batch>,
+    ) {
+        outgoing.close();
+        let mut requeued: Vec<_> = unacked
+            .drain(..)
+            .flat_map(|(_, _, queued)| queued)
+            .collect();
+        while let Ok(batch) = outgoing.try_recv() {
+            requeued.extend(batch.queued);
+        }
+        crate::metrics::MESSAGE_STREAM_UNACKED
+            .with_label_values(&[&self.account_id])
+            .set(0);
+        if !requeued.is_empty() {
+            tracing::info!(
+                to = self.account_id,
+                count = requeued.len(),
+                "requeueing messages to be sent over http"
+            );
+            self.requeued.lock().unwrap().extend(requeued);
+        }
+    }
+
+    /// Sends batches over `ws` until it breaks, or returns `Ok` once there is nothing left to
+    /// send for good.
+    async fn stream(
+        &self,
+        ws: WebSocketStream<MaybeTlsStream<TcpStream>>,
+        session: u64,
+        next_seq: &mut u64,
+        unacked: &mut VecDeque<(u64, Vec<u8>, Vec<QueuedMessage>)>,
+        outgoing: &mut mpsc::Receiver<Batch>,
+    ) -> Result<(), String> {
+        let (mut sink, mut stream) = ws.split();
+        // The peer behind the url may have changed, so its format gets negotiated again.
+        *self.format.lock().unwrap() = MessageFormat::default();
+        let hello = Frame::Hello {
+            session,
+            formats: MessageFormat::SUPPORTED.to_vec(),
+        };
+        sink.send(Message::Binary(hello.encode()))
+            .await
+            .map_err(|err| err.to_string())?;
+        for (_, frame, _) in unacked.iter() {
+            sink.send(Message::Binary(frame.clone()))
+                .await
+                .map_err(|err| err.to_string())?;
+        }
+
+        let mut last_progress = Instant::now();
+        loop {
+            tokio::select! {
+                batch = outgoing.recv(), if unacked.len() < MAX_IN_FLIGHT => {
+                    let Some(Batch { messages, queued }) = batch else {
+                        let _ = sink.close().await;
+                        return Ok(());
+                    };
+                    if unacked.is_empty() {
+                        last_progress = Instant::now();
+                    }
+                    let seq = *next_seq;
+                    *next_seq += 1;
+                    let frame = Frame::Batch { seq, messages }.encode();
+                    unacked.push_back((seq, frame.clone(), queued));
+                    sink.send(Message::Binary(frame))
+                        .await
+                        .map_err(|err| err.to_string())?;
+                }
+                incoming = stream.next() => match incoming {
+                    Some(Ok(Message::Binary(bytes))) => match Frame::decode(&bytes) {
+                        Ok(Frame::Ack { seq }) => {
+                            while unacked.front().is_some_and(|(sent, _, _)| *sent <= seq) {
+                                unacked.pop_front();
+                            }
+                            last_progress = Instant::now();
+                        }
+                        Ok(Frame::Format { format }) => {
+                            tracing::debug!(to = self.account_id, ?format, "negotiated message format");
+                            *self.format.lock().unwrap() = format;
+                        }
+                        Ok(frame) => tracing::debug!(?frame, "unexpected frame from the receiver"),
+                        Err(err) => return Err(format!("malformed frame: {err}")),
+                    },
+                    Some(Ok(Message::Close(_))) | None => return Err("closed by the participant".to_string()),
+                    Some(Ok(_)) => {}
+                    Some(Err(err)) => return Err(err.to_string()),
+                },
+                _ = tokio::time::sleep_until(last_progress + ACK_TIMEOUT), if !unacked.is_empty() => {
+                    return Err("timed out waiting for acknowledgements".to_string());
+                }
+            }
+            crate::metrics::MESSAGE_STREAM_UNACKED
+                .with_label_values(&[&self.account_id])
+                .set(unacked.len() as i64);
+        }
+    }
+}
+
+/// The sealed messages of `messages`, unless some of them are legacy ones.
+fn sealed(messages: Vec<WireMessage>) -> Result<Vec<SealedMessage>, Vec<WireMessage>> {
+    if messages
+        .iter()
+        .any(|message| matches!(message, WireMessage::Legacy(_)))
+    {
+        return Err(messages);
+    }
+    Ok(messages
+        .into_iter()
+        .filter_map(|message| match message {
+            WireMessage::Sealed(sealed) => Some(sealed),
+            WireMessage::Legacy(_) => None,
+        })
+        .collect())
+}
+
+/// WebSocket url of the message stream served at a participant's url.
+fn stream_url(url: &str) -> Result<Url, url::ParseError> {
+    let mut url = Url::parse(url)?;
+    let scheme = if url.scheme() == "https" { "wss" } else { "ws" };
+    // Only fails for urls that cannot have a scheme of their own, which are not http(s) urls.
+    let _ = url.set_scheme(scheme);
+    url.set_path("msg/stream");
+    Ok(url)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_frames_roundtrip() {
+        let frames = [
+            Frame::Hello {
+                session: 7,
+                formats: MessageFormat::SUPPORTED.to_vec(),
+            },
+            Frame::Format {
+                format: MessageFormat::Cbor,
+            },
+            Frame::Batch {
+                seq: 3,
+                messages: Vec::new(),
+            },
+            Frame::Ack { seq: 3 },
+        ];
+        for frame in frames {
+            let encoded = frame.encode();
+            assert_eq!(Frame::decode(&encoded).unwrap().encode(), encoded);
+        }
+
+        // Senders that predate format negotiation do not offer any.
+        let hello = Frame::decode(br#"{"Hello":{"session":7}}"#).unwrap();
+        assert!(matches!(hello, Frame::Hello { session: 7, formats } if formats.is_empty()));
+    }
+
+    #[test]
+    fn test_batch_frames_carry_ciphertexts_as_bytes() {
+        use crate::protocol::message::{GeneratingMessage, MessageHeader, SignedMessage};
+        use crate::protocol::MpcMessage;
+
+        let sign_sk = near_crypto::SecretKey::from_random(near_crypto::KeyType::ED25519);
+        let (_, cipher_pk) = mpc_keys::hpke::generate();
+        let message = MpcMessage::Generating(GeneratingMessage {
+            from: Participant::from(0),
+            data: vec![7; 64 * 1024],
+        });
+        let header = MessageHeader {
+            from: Participant::from(0),
+            epoch: 0,
+            session: 1,
+            seq: 1,
+            sent_at: 0,
+        };
+        let sealed =
+            SignedMessage::encrypt(&message, header, MessageFormat::Cbor, &sign_sk, &cipher_pk)
+                .unwrap();
+        let frame = Frame::Batch {
+            seq: 1,
+            messages: vec![sealed],
+        };
+        let encoded = frame.encode();
+        assert!(encoded.len() < 65 * 1024);
+
+        // Senders that predate binary frames sent the ciphertexts as arrays of numbers.
+        let json = serde_json::to_vec(&frame).unwrap();
+        assert!(json.len() > 2 * 64 * 1024);
+        let (Frame::Batch { messages: cbor, .. }, Frame::Batch { messages: json, .. }) = (
+            Frame::decode(&encoded).unwrap(),
+            Frame::decode(&json).unwrap(),
+        ) else {
+            panic!("expected batch frames");
+        };
+        assert_eq!(cbor[0].ciphered.text, json[0].ciphered.text);
+    }
+
+    #[test]
+    fn test_resumed_stream_skips_delivered_batches() {
+        let sessions = StreamSessions::default();
+        let from = Participant::from(1);
+
+        let mut first = StreamReceiver::new(&sessions);
+        first.hello(42);
+        for seq in 1..=2 {
+            assert!(first.is_new(from, seq));
+            first.delivered(Some(from), seq);
+        }
+        assert!(!first.is_new(from, 2));
+
+        // The batches resent after a reconnect are acknowledged without being delivered again.
+        let mut second = StreamReceiver::new(&sessions);
+        second.hello(42);
+        assert!(!second.is_new(from, 1));
+        assert!(!second.is_new(from, 2));
+        assert!(second.is_new(from, 3));
+
+        // Other participants reusing the session do not get to skip anything.
+        let mut other = StreamReceiver::new(&sessions);
+        other.hello(42);
+        assert!(other.is_new(Participant::from(2), 1));
+
+        // A restarted sender picks a new session and starts over.
+        let mut restarted = StreamReceiver::new(&sessions);
+        restarted.hello(43);
+        assert!(restarted.is_new(from, 1));
+        restarted.delivered(Some(from), 1);
+        assert_eq!(sessions.resume(from, 42), 0);
+        assert_eq!(sessions.resume(from, 43), 1);
+    }
+
+    #[test]
+    fn test_unverified_streams_are_not_remembered() {
+        let sessions = StreamSessions::default();
+        let from = Participant::from(1);
+        for session in 0..100 {
+            let mut receiver = StreamReceiver::new(&sessions);
+            receiver.hello(session);
+            assert!(receiver.is_new(from, 5));
+            receiver.delivered(None, 5);
+            assert!(!receiver.is_new(from, 5));
+        }
+        assert!(sessions.delivered.lock().unwrap().is_empty());
+        assert_eq!(sessions.resume(from, 0), 0);
+    }
+
+    #[test]
+    fn test_downgraded_peer_requeues_undelivered_batches() {
+        use crate::protocol::message::GeneratingMessage;
+        use crate::protocol::MpcMessage;
+
+        let transport = Transport::default();
+        let info = ParticipantInfo::new(1);
+        let peer = Peer {
+            participant: Participant::from(1),
+            replay_guard: transport.replay_guard(),
+            requeued: transport.requeued.clone(),
+            account_id: info.account_id.to_string(),
+            url: info.url.clone(),
+            tls: None,
+            mode: Arc::default(),
+            format: Arc::default(),
+        };
+        let queued = |data: u8| {
+            let message = MpcMessage::Generating(GeneratingMessage {
+                from: Participant::from(0),
+                data: vec![data],
+            });
+            vec![(info.clone(), message, std::time::Instant::now())]
+        };
+
+        // One batch sent but not acknowledged, and one still waiting to be sent.
+        let mut unacked = VecDeque::from([(1, Vec::new(), queued(1))]);
+        let (sender, mut outgoing) = mpsc::channel(PEER_QUEUE_CAPACITY);
+        sender
+            .try_send(Batch {
+                messages: Vec::new(),
+                queued: queued(2),
+            })
+            .unwrap();
+
+        peer.requeue(&mut unacked, &mut outgoing);
+        assert!(unacked.is_empty());
+        let requeued: Vec<_> = transport
+            .take_requeued()
+            .into_iter()
+            .map(|(_, message, _)| match message {
+                MpcMessage::Generating(message) => message.data,
+                message => panic!("unexpected message: {message:?}"),
+            })
+            .collect();
+        assert_eq!(requeued, [vec![1], vec![2]]);
+        // Nothing more gets queued on the stream.
+        assert!(sender.is_closed());
+        assert!(transport.take_requeued().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_legacy_peer_is_only_checked_again_after_backoff() {
+        let (sender, receiver) = mpsc::channel(1);
+        drop(receiver);
+        let url = "http://localhost:3000".to_string();
+        let mode = Arc::new(Mutex::new(PeerMode::Legacy {
+            recheck_at: Instant::now() + LEGACY_RECHECK_MIN_DELAY,
+            backoff: LEGACY_RECHECK_MIN_DELAY,
+        }));
+        let peer = PeerStream {
+            url: url.clone(),
+            sender,
+            mode: mode.clone(),
+            format: Arc::default(),
+            task: tokio::spawn(async {}),
+        };
+        assert!(!peer.is_stale(&url));
+        assert!(peer.is_stale("http://localhost:3001"));
+
+        *mode.lock().unwrap() = PeerMode::Legacy {
+            recheck_at: Instant::now(),
+            backoff: LEGACY_RECHECK_MIN_DELAY,
+        };
+        assert!(peer.is_stale(&url));
+
+        // A stream that went away is connected again right away.
+        *mode.lock().unwrap() = PeerMode::Streaming;
+        assert!(peer.is_stale(&url));
+    }
+}
@@ -2,19 +2,21 @@ use std::sync::Arc;
 use std::time::Duration;
 
 use cait_sith::protocol::{InitializationError, Participant};
-use cait_sith::triples::TripleGenerationOutput;
+use cait_sith::triples::TripleGenerationOutputMany;
 use cait_sith::{protocol::Protocol, KeygenOutput};
 use cait_sith::{FullSignature, PresignOutput};
 use crypto_shared::PublicKey;
 use k256::{elliptic_curve::CurveArithmetic, Secp256k1};
 use tokio::sync::{RwLock, RwLockWriteGuard};
 
-use crate::gcp::error::ConvertError;
+use crate::gcp::error::{ConvertError, DatastoreStorageError};
 use crate::gcp::value::{FromValue, IntoValue, Value};
 use crate::gcp::{DatastoreResult, GcpService, KeyKind};
 use crate::protocol::contract::ResharingContractState;
+use crate::storage::local_db::LocalDb;
 
 use near_account_id::AccountId;
+use rusqlite::OptionalExtension;
 
 /// Default timeout for triple generation protocols. Times out after 20 minutes of being alive.
 pub const PROTOCOL_TRIPLE_TIMEOUT: Duration = Duration::from_secs(20 * 60);
@@ -25,6 +27,10 @@ pub const PROTOCOL_PRESIG_TIMEOUT: Duration = Duration::from_secs(60);
 /// Default timeout for signature generation protocol. Times out after 1 minute of being alive since this should be shorted lived.
 pub const PROTOCOL_SIGNATURE_TIMEOUT: Duration = Duration::from_secs(60);
 
+/// How long signature messages for a request this node has not indexed yet are kept around,
+/// waiting for the indexer to catch up, before being dropped.
+pub const PROTOCOL_SIGNATURE_UNINDEXED_TIMEOUT: Duration = Duration::from_secs(30);
+
 /// Default invalidation time for failed triples: 2 hrs
 pub const FAILED_TRIPLES_TIMEOUT: Duration = Duration::from_secs(120 * 60);
 
@@ -33,7 +39,7 @@ pub const TAKEN_TIMEOUT: Duration = Duration::from_secs(120 * 60);
 
 pub type SecretKeyShare = <Secp256k1 as CurveArithmetic>::Scalar;
 pub type TripleProtocol =
-    Box<dyn Protocol<Output = TripleGenerationOutput<Secp256k1>> + Send + Sync>;
+    Box<dyn Protocol<Output = TripleGenerationOutputMany<Secp256k1>> + Send + Sync>;
 pub type PresignatureProtocol = Box<dyn Protocol<Output = PresignOutput<Secp256k1>> + Send + Sync>;
 pub type SignatureProtocol = Box<dyn Protocol<Output = FullSignature<Secp256k1>> + Send + Sync>;
 
@@ -145,11 +151,59 @@ pub struct LatestBlockHeight {
     pub block_height: near_primitives::types::BlockHeight,
 }
 
+/// Where the [`LatestBlockHeight`] is persisted. The local database takes precedence over the
+/// datastore, as it does for the rest of the node's storage.
+#[derive(Clone)]
+pub enum BlockHeightStorage {
+    LocalDb(LocalDb),
+    Datastore(GcpService),
+    /// Nothing is persisted, so the indexer starts over from its configured height.
+    Memory,
+}
+
+impl BlockHeightStorage {
+    pub fn init(gcp_service: Option<&GcpService>, local_db: Option<&LocalDb>) -> Self {
+        match (local_db, gcp_service) {
+            (Some(db), _) => BlockHeightStorage::LocalDb(db.clone()),
+            (None, Some(gcp)) => BlockHeightStorage::Datastore(gcp.clone()),
+            (None, None) => BlockHeightStorage::Memory,
+        }
+    }
+}
+
 impl LatestBlockHeight {
-    pub async fn fetch(gcp: &GcpService) -> DatastoreResult<Self> {
-        gcp.datastore
-            .get(format!("{}/latest-block-height", gcp.account_id))
-            .await
+    pub async fn fetch(
+        storage: &BlockHeightStorage,
+        account_id: &AccountId,
+    ) -> DatastoreResult<Self> {
+        let not_found =
+            || DatastoreStorageError::EntityNotFound(format!("{account_id}/latest-block-height"));
+        match storage {
+            BlockHeightStorage::LocalDb(db) => {
+                let account_id = account_id.clone();
+                db.call(move |conn| -> DatastoreResult<_> {
+                    let block_height: Option<i64> = conn
+                        .query_row(
+                            "SELECT block_height FROM latest_block_height WHERE account_id = ?1",
+                            [account_id.as_str()],
+                            |row| row.get(0),
+                        )
+                        .optional()?;
+                    Ok(block_height.map(|block_height| LatestBlockHeight {
+                        account_id,
+                        block_height: block_height as u64,
+                    }))
+                })
+                .await?
+                .ok_or_else(not_found)
+            }
+            BlockHeightStorage::Datastore(gcp) => {
+                gcp.datastore
+                    .get(format!("{account_id}/latest-block-height"))
+                    .await
+            }
+            BlockHeightStorage::Memory => Err(not_found()),
+        }
     }
 
     pub fn set(&mut self, block_height: near_primitives::types::BlockHeight) -> &mut Self {
@@ -157,8 +211,23 @@ impl LatestBlockHeight {
         self
     }
 
-    pub async fn store(&self, gcp: &GcpService) -> DatastoreResult<()> {
-        gcp.datastore.upsert(self).await
+    pub async fn store(&self, storage: &BlockHeightStorage) -> DatastoreResult<()> {
+        match storage {
+            BlockHeightStorage::LocalDb(db) => {
+                let row = (self.account_id.to_string(), self.block_height as i64);
+                db.call(move |conn| -> DatastoreResult<()> {
+                    conn.execute(
+                        "INSERT OR REPLACE INTO latest_block_height (account_id, block_height) \
+                        VALUES (?1, ?2)",
+                        row,
+                    )?;
+                    Ok(())
+                })
+                .await
+            }
+            BlockHeightStorage::Datastore(gcp) => gcp.datastore.upsert(self).await,
+            BlockHeightStorage::Memory => Ok(()),
+        }
     }
 }
 
@@ -1,16 +1,23 @@
 mod error;
 
 use self::error::Error;
-use crate::protocol::message::SignedMessage;
-use crate::protocol::{MpcMessage, NodeState};
+use crate::mesh::connection::{LivenessView, PeerLiveness, SlowView};
+use crate::protocol::encoding::MessageFormat;
+use crate::protocol::message::{ReplayGuard, SignedMessage, WireMessage};
+use crate::protocol::{CryptographicError, MpcMessage, NodeState};
+use crate::tls::NodeTls;
+use crate::transport::{Frame, StreamReceiver, StreamSessions};
 use crate::web::error::Result;
 use anyhow::Context;
+use axum::extract::ws::{self, WebSocket, WebSocketUpgrade};
 use axum::http::StatusCode;
+use axum::response::Response;
 use axum::routing::{get, post};
 use axum::{Extension, Json, Router};
 use axum_extra::extract::WithRejection;
+use axum_server::tls_rustls::RustlsConfig;
 use cait_sith::protocol::Participant;
-use mpc_keys::hpke::{self, Ciphered};
+use mpc_keys::hpke;
 use prometheus::{Encoder, TextEncoder};
 use serde::{Deserialize, Serialize};
 use std::{net::SocketAddr, sync::Arc};
@@ -20,6 +27,10 @@ struct AxumState {
     sender: Sender<MpcMessage>,
     protocol_state: Arc<RwLock<NodeState>>,
     cipher_sk: hpke::SecretKey,
+    slow_view: SlowView,
+    liveness_view: LivenessView,
+    stream_sessions: StreamSessions,
+    replay_guard: Arc<ReplayGuard>,
 }
 
 pub async fn run(
@@ -27,15 +38,25 @@ pub async fn run(
     sender: Sender<MpcMessage>,
     cipher_sk: hpke::SecretKey,
     protocol_state: Arc<RwLock<NodeState>>,
+    slow_view: SlowView,
+    liveness_view: LivenessView,
+    replay_guard: Arc<ReplayGuard>,
+    tls: Option<NodeTls>,
+    public_port: Option<u16>,
 ) -> anyhow::Result<()> {
     tracing::debug!("running a node");
     let axum_state = AxumState {
         sender,
         protocol_state,
         cipher_sk,
+        slow_view,
+        liveness_view,
+        stream_sessions: StreamSessions::default(),
+        replay_guard,
     };
 
-    let app = Router::new()
+    // Endpoints for load balancers and metric scrapers, which hold no peer certificate.
+    let public = Router::new()
         // healthcheck endpoint
         .route(
             "/",
@@ -44,17 +65,41 @@ pub async fn run(
                 StatusCode::OK
             }),
         )
+        .route("/metrics", get(metrics));
+    let app = Router::new()
         .route("/msg", post(msg))
+        .route("/msg/stream", get(msg_stream))
         .route("/state", get(state))
-        .route("/metrics", get(metrics))
-        .layer(Extension(Arc::new(axum_state)));
+        .layer(Extension(Arc::new(axum_state)))
+        .merge(public.clone());
 
     let addr = SocketAddr::from(([0, 0, 0, 0], port));
-    tracing::info!(?addr, "starting http server");
-    axum::Server::bind(&addr)
-        .serve(app.into_make_service())
-        .await
-        .unwrap();
+    if let Some(tls) = tls {
+        let public_port = public_port.context("mutual tls requires a public port")?;
+        let public_addr = SocketAddr::from(([0, 0, 0, 0], public_port));
+        tracing::info!(?addr, ?public_addr, "starting https server with mutual tls");
+        let config = RustlsConfig::from_config(tls.server_config());
+        tokio::try_join!(
+            async {
+                axum_server::bind_rustls(addr, config)
+                    .serve(app.into_make_service())
+                    .await
+                    .context("https server failed")
+            },
+            async {
+                axum::Server::bind(&public_addr)
+                    .serve(public.into_make_service())
+                    .await
+                    .context("public http server failed")
+            },
+        )?;
+    } else {
+        tracing::info!(?addr, "starting http server");
+        axum::Server::bind(&addr)
+            .serve(app.into_make_service())
+            .await
+            .unwrap();
+    }
 
     Ok(())
 }
@@ -68,17 +113,38 @@ pub struct MsgRequest {
 #[tracing::instrument(level = "debug", skip_all)]
 async fn msg(
     Extension(state): Extension<Arc<AxumState>>,
-    WithRejection(Json(encrypted), _): WithRejection<Json<Vec<Ciphered>>, Error>,
+    WithRejection(Json(encrypted), _): WithRejection<Json<Vec<WireMessage>>, Error>,
 ) -> Result<()> {
     for encrypted in encrypted.into_iter() {
-        let message = match SignedMessage::decrypt(
-            &state.cipher_sk,
-            &state.protocol_state,
-            encrypted,
-        )
-        .await
-        {
+        let decrypted = match encrypted {
+            WireMessage::Sealed(sealed) => {
+                SignedMessage::decrypt(
+                    &state.cipher_sk,
+                    &state.protocol_state,
+                    &state.replay_guard,
+                    sealed,
+                )
+                .await
+            }
+            WireMessage::Legacy(ciphered) => {
+                SignedMessage::decrypt_legacy(
+                    &state.cipher_sk,
+                    &state.protocol_state,
+                    &state.replay_guard,
+                    ciphered,
+                )
+                .await
+            }
+        };
+        let message = match decrypted {
             Ok(msg) => msg,
+            // Replays are dropped without failing the request, which would have the sender resend
+            // the messages we already accepted. Most are retries of requests that failed after
+            // we already took their messages.
+            Err(CryptographicError::Replayed(reason)) => {
+                tracing::debug!(%reason, "rejected a replayed message");
+                continue;
+            }
             Err(err) => {
                 tracing::error!(?err, "failed to decrypt or verify an encrypted message");
                 return Err(err.into());
@@ -93,6 +159,95 @@ async fn msg(
     Ok(())
 }
 
+#[tracing::instrument(level = "debug", skip_all)]
+async fn msg_stream(Extension(state): Extension<Arc<AxumState>>, ws: WebSocketUpgrade) -> Response {
+    ws.on_upgrade(move |socket| serve_msg_stream(state, socket))
+}
+
+/// Hands the batches of a participant's message stream to the protocol loop, acknowledging each
+/// once delivered. While the protocol loop is full no more frames are read, which pushes back on
+/// the sender.
+async fn serve_msg_stream(state: Arc<AxumState>, mut socket: WebSocket) {
+    let mut receiver = StreamReceiver::new(&state.stream_sessions);
+    while let Some(Ok(frame)) = socket.recv().await {
+        let bytes = match frame {
+            ws::Message::Binary(bytes) => bytes,
+            ws::Message::Close(_) => break,
+            _ => continue,
+        };
+        match Frame::decode(&bytes) {
+            Ok(Frame::Hello {
+                session: id,
+                formats,
+            }) => {
+                receiver.hello(id);
+                // Senders that do not offer any formats do not expect an answer.
+                if !formats.is_empty() {
+                    let format = MessageFormat::negotiate(&formats);
+                    let answer = Frame::Format { format }.encode();
+                    if socket.send(ws::Message::Binary(answer)).await.is_err() {
+                        break;
+                    }
+                }
+            }
+            Ok(Frame::Batch { seq, messages }) => {
+                // Batches resent after a reconnect may have been delivered already.
+                let sender = messages.first().map(|message| message.header.from);
+                if sender.is_some_and(|sender| receiver.is_new(sender, seq)) {
+                    // Only senders with a fresh message that verifies get their session
+                    // remembered.
+                    let mut verified = None;
+                    for encrypted in messages {
+                        let from = encrypted.header.from;
+                        let message = match SignedMessage::decrypt(
+                            &state.cipher_sk,
+                            &state.protocol_state,
+                            &state.replay_guard,
+                            encrypted,
+                        )
+                        .await
+                        {
+                            Ok(msg) => msg,
+                            // Messages of batches resent after a reconnect that the previous
+                            // connection already delivered in part. Anyone who captured them
+                            // could send them again, so they do not advance the session.
+                            Err(CryptographicError::Replayed(reason)) => {
+                                tracing::debug!(%reason, "rejected a replayed message");
+                                continue;
+                            }
+                            Err(err) => {
+                                tracing::error!(
+                                    ?err,
+                                    "failed to decrypt or verify an encrypted message"
+                                );
+                                continue;
+                            }
+                        };
+                        if let Err(err) = state.sender.send(message).await {
+                            tracing::error!(
+                                ?err,
+                                "failed to forward an encrypted protocol message"
+                            );
+                            return;
+                        }
+                        verified = Some(from);
+                    }
+                    receiver.delivered(verified, seq);
+                }
+                let ack = Frame::Ack { seq }.encode();
+                if socket.send(ws::Message::Binary(ack)).await.is_err() {
+                    break;
+                }
+            }
+            Ok(frame) => tracing::debug!(?frame, "unexpected frame from the sender"),
+            Err(err) => {
+                tracing::warn!(?err, "received a malformed message stream frame");
+                break;
+            }
+        }
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 #[serde(tag = "type")]
 #[serde(rename_all = "snake_case")]
@@ -105,6 +260,12 @@ pub enum StateView {
         presignature_count: usize,
         presignature_mine_count: usize,
         presignature_potential_count: usize,
+        /// Participants this node measured as slow to respond.
+        #[serde(default)]
+        slow_participants: Vec<Participant>,
+        /// Liveness of every participant as measured by this node.
+        #[serde(default)]
+        peers: Vec<PeerLiveness>,
     },
     NotRunning,
 }
@@ -112,6 +273,8 @@ pub enum StateView {
 #[tracing::instrument(level = "debug", skip_all)]
 async fn state(Extension(state): Extension<Arc<AxumState>>) -> Result<Json<StateView>> {
     tracing::debug!("fetching state");
+    let slow_participants = state.slow_view.read().await.iter().copied().collect();
+    let peers = state.liveness_view.read().await.clone();
     let protocol_state = state.protocol_state.read().await;
     match &*protocol_state {
         NodeState::Running(state) => {
@@ -131,6 +294,8 @@ async fn state(Extension(state): Extension<Arc<AxumState>>) -> Result<Json<State
                 presignature_count,
                 presignature_mine_count,
                 presignature_potential_count,
+                slow_participants,
+                peers,
             }))
         }
         _ => {
@@ -52,12 +52,15 @@ impl<'a> Node<'a> {
         let near_rpc = ctx.lake_indexer.rpc_host_address_proxied.clone();
         let mpc_contract_id = ctx.mpc_contract.id().clone();
         let indexer_options = mpc_recovery_node::indexer::Options {
+            source: mpc_recovery_node::indexer::SourceKind::Lake,
+            rpc_url: None,
+            light_client_checkpoint: None,
             s3_bucket: ctx.localstack.s3_bucket.clone(),
             s3_region: ctx.localstack.s3_region.clone(),
             s3_url: Some(ctx.localstack.s3_host_address.clone()),
             start_block_height: 0,
         };
-        let args = mpc_recovery_node::cli::Cli::Start {
+        let cli = mpc_recovery_node::cli::Cli::Start {
             near_rpc: near_rpc.clone(),
             mpc_contract_id: mpc_contract_id.clone(),
             account_id: account_id.clone(),
@@ -73,15 +76,25 @@ impl<'a> Node<'a> {
             max_triples: cfg.triple_cfg.max_triples,
             max_concurrent_introduction: cfg.triple_cfg.max_concurrent_introduction,
             max_concurrent_generation: cfg.triple_cfg.max_concurrent_generation,
+            max_triple_batch_size: cfg.triple_cfg.max_batch_size,
             min_presignatures: cfg.presig_cfg.min_presignatures,
             max_presignatures: cfg.presig_cfg.max_presignatures,
-        }
-        .into_str_args();
-        let image: GenericImage = GenericImage::new("near/mpc-recovery-node", "latest")
+            compute_threads: None,
+            key_share_retention_secs: None,
+            refresh_interval_secs: None,
+            mutual_tls: false,
+            public_port: None,
+        };
+        let envs = cli.str_envs();
+        let args = cli.into_str_args();
+        let mut image: GenericImage = GenericImage::new("near/mpc-recovery-node", "latest")
             .with_wait_for(WaitFor::Nothing)
             .with_exposed_port(Self::CONTAINER_PORT)
             .with_env_var("RUST_LOG", "mpc_recovery_node=DEBUG")
             .with_env_var("RUST_BACKTRACE", "1");
+        for (key, value) in envs {
+            image = image.with_env_var(key, value);
+        }
         let image: RunnableImage<GenericImage> = (image, args).into();
         let image = image.with_network(&ctx.docker_network);
         let container = ctx.docker_client.cli.run(image);
@@ -137,6 +150,9 @@ impl<'a> Node<'a> {
         let near_rpc = ctx.lake_indexer.rpc_host_address_proxied.clone();
         let mpc_contract_id = ctx.mpc_contract.id().clone();
         let indexer_options = mpc_recovery_node::indexer::Options {
+            source: mpc_recovery_node::indexer::SourceKind::Lake,
+            rpc_url: None,
+            light_client_checkpoint: None,
             s3_bucket: ctx.localstack.s3_bucket.clone(),
             s3_region: ctx.localstack.s3_region.clone(),
             s3_url: Some(ctx.localstack.s3_host_address.clone()),
@@ -144,7 +160,7 @@ impl<'a> Node<'a> {
         };
         let sign_sk =
             near_crypto::SecretKey::from_seed(near_crypto::KeyType::ED25519, "integration-test");
-        let args = mpc_recovery_node::cli::Cli::Start {
+        let cli = mpc_recovery_node::cli::Cli::Start {
             near_rpc: near_rpc.clone(),
             mpc_contract_id: mpc_contract_id.clone(),
             account_id: account_id.clone(),
@@ -159,16 +175,26 @@ impl<'a> Node<'a> {
             max_triples: cfg.triple_cfg.max_triples,
             max_concurrent_introduction: cfg.triple_cfg.max_concurrent_introduction,
             max_concurrent_generation: cfg.triple_cfg.max_concurrent_generation,
+            max_triple_batch_size: cfg.triple_cfg.max_batch_size,
             min_presignatures: cfg.presig_cfg.min_presignatures,
             max_presignatures: cfg.presig_cfg.max_presignatures,
+            compute_threads: None,
+            key_share_retention_secs: None,
+            refresh_interval_secs: None,
+            mutual_tls: false,
+            public_port: None,
             sign_sk: Some(sign_sk),
-        }
-        .into_str_args();
-        let image: GenericImage = GenericImage::new("near/mpc-recovery-node", "latest")
+        };
+        let envs = cli.str_envs();
+        let args = cli.into_str_args();
+        let mut image: GenericImage = GenericImage::new("near/mpc-recovery-node", "latest")
             .with_wait_for(WaitFor::Nothing)
             .with_exposed_port(Self::CONTAINER_PORT)
             .with_env_var("RUST_LOG", "mpc_recovery_node=DEBUG")
             .with_env_var("RUST_BACKTRACE", "1");
+        for (key, value) in envs {
+            image = image.with_env_var(key, value);
+        }
         let image: RunnableImage<GenericImage> = (image, args).into();
         let image = image.with_network(&ctx.docker_network);
         let container = ctx.docker_client.cli.run(image);
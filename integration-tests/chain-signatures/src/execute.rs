@@ -32,10 +32,12 @@ pub fn spawn_multichain(
     let executable = executable(release, PACKAGE_MULTICHAIN)
         .with_context(|| format!("could not find target dir while starting {node} node"))?;
 
+    let envs = cli.str_envs();
     async_process::Command::new(&executable)
         .args(cli.into_str_args())
         .env("RUST_LOG", "mpc_recovery_node=INFO")
         .envs(std::env::vars())
+        .envs(envs)
         .stdout(async_process::Stdio::inherit())
         .stderr(async_process::Stdio::inherit())
         .kill_on_drop(true)
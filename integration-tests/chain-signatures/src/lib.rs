@@ -17,6 +17,7 @@ use mpc_recovery_node::gcp::GcpService;
 use mpc_recovery_node::protocol::presignature::PresignatureConfig;
 use mpc_recovery_node::protocol::triple::TripleConfig;
 use mpc_recovery_node::storage;
+use mpc_recovery_node::storage::cipher::StorageCipher;
 use mpc_recovery_node::storage::triple_storage::TripleNodeStorageBox;
 use near_crypto::KeyFile;
 use near_workspaces::network::{Sandbox, ValidatorKey};
@@ -45,6 +46,7 @@ impl Default for MultichainConfig {
                 max_triples: 80,
                 max_concurrent_introduction: 8,
                 max_concurrent_generation: 24,
+                max_batch_size: 4,
             },
             presig_cfg: PresignatureConfig {
                 min_presignatures: 2,
@@ -206,9 +208,22 @@ impl Nodes<'_> {
         &self,
         account_id: &AccountId,
     ) -> anyhow::Result<TripleNodeStorageBox> {
+        let cipher_sk = match self {
+            Nodes::Local { nodes, .. } => nodes
+                .iter()
+                .find(|node| node.account_id == *account_id)
+                .map(|node| node.cipher_sk.to_bytes()),
+            Nodes::Docker { nodes, .. } => nodes
+                .iter()
+                .find(|node| node.account_id == *account_id)
+                .map(|node| node.cipher_sk.to_bytes()),
+        }
+        .with_context(|| format!("no running node for {account_id}"))?;
         let gcp_service = GcpService::init(account_id, &self.ctx().storage_options).await?;
         Ok(storage::triple_storage::init(
             Some(&gcp_service),
+            None,
+            &StorageCipher::derive(&cipher_sk),
             account_id,
         ))
     }
@@ -279,6 +294,9 @@ pub async fn setup(docker_client: &DockerClient) -> anyhow::Result<Context<'_>>
         sk_share_secret_id: None,
         gcp_datastore_url: Some(datastore.local_address.clone()),
         sk_share_local_path: Some(sk_share_local_path),
+        sk_share_passphrase: Some("multichain-integration".to_string()),
+        sk_share_keyfile: None,
+        local_db_path: None,
     };
     Ok(Context {
         docker_client,
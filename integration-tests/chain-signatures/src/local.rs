@@ -10,7 +10,7 @@ pub struct Node {
     pub account_sk: near_workspaces::types::SecretKey,
     pub sign_sk: near_crypto::SecretKey,
     pub cipher_pk: hpke::PublicKey,
-    cipher_sk: hpke::SecretKey,
+    pub cipher_sk: hpke::SecretKey,
     cfg: MultichainConfig,
     web_port: u16,
 
@@ -41,6 +41,9 @@ impl Node {
 
         let storage_options = ctx.storage_options.clone();
         let indexer_options = mpc_recovery_node::indexer::Options {
+            source: mpc_recovery_node::indexer::SourceKind::Lake,
+            rpc_url: None,
+            light_client_checkpoint: None,
             s3_bucket: ctx.localstack.s3_bucket.clone(),
             s3_region: ctx.localstack.s3_region.clone(),
             s3_url: Some(ctx.localstack.s3_host_address.clone()),
@@ -64,8 +67,14 @@ impl Node {
             max_triples: cfg.triple_cfg.max_triples,
             max_concurrent_introduction: cfg.triple_cfg.max_concurrent_introduction,
             max_concurrent_generation: cfg.triple_cfg.max_concurrent_generation,
+            max_triple_batch_size: cfg.triple_cfg.max_batch_size,
             min_presignatures: cfg.presig_cfg.min_presignatures,
             max_presignatures: cfg.presig_cfg.max_presignatures,
+            compute_threads: None,
+            key_share_retention_secs: None,
+            refresh_interval_secs: None,
+            mutual_tls: false,
+            public_port: None,
         };
 
         let mpc_node_id = format!("multichain/{account_id}", account_id = account_id);
@@ -97,6 +106,9 @@ impl Node {
         let account_sk = config.account_sk;
         let storage_options = ctx.storage_options.clone();
         let indexer_options = mpc_recovery_node::indexer::Options {
+            source: mpc_recovery_node::indexer::SourceKind::Lake,
+            rpc_url: None,
+            light_client_checkpoint: None,
             s3_bucket: ctx.localstack.s3_bucket.clone(),
             s3_region: ctx.localstack.s3_region.clone(),
             s3_url: Some(ctx.localstack.s3_host_address.clone()),
@@ -122,8 +134,14 @@ impl Node {
             max_triples: cfg.triple_cfg.max_triples,
             max_concurrent_introduction: cfg.triple_cfg.max_concurrent_introduction,
             max_concurrent_generation: cfg.triple_cfg.max_concurrent_generation,
+            max_triple_batch_size: cfg.triple_cfg.max_batch_size,
             min_presignatures: cfg.presig_cfg.min_presignatures,
             max_presignatures: cfg.presig_cfg.max_presignatures,
+            compute_threads: None,
+            key_share_retention_secs: None,
+            refresh_interval_secs: None,
+            mutual_tls: false,
+            public_port: None,
         };
 
         let mpc_node_id = format!("multichain/{account_id}", account_id = account_id);
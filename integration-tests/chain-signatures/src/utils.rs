@@ -57,6 +57,26 @@ pub async fn vote_leave(
     futures::future::join_all(vote_futures).await
 }
 
+pub async fn vote_refresh(
+    accounts: Vec<Account>,
+    mpc_contract: &AccountId,
+    epoch: u64,
+) -> Vec<Result<ExecutionFinalResult, near_workspaces::error::Error>> {
+    let vote_futures = accounts
+        .iter()
+        .map(|account| {
+            account
+                .call(mpc_contract, "vote_refresh")
+                .args_json(serde_json::json!({
+                    "epoch": epoch
+                }))
+                .transact()
+        })
+        .collect::<Vec<_>>();
+
+    futures::future::join_all(vote_futures).await
+}
+
 pub async fn get<U>(uri: U) -> anyhow::Result<StatusCode>
 where
     Uri: TryFrom<U>,
@@ -58,6 +58,7 @@ pub async fn request_sign(
         payload: payload_hashed,
         path: "test".to_string(),
         key_version: 0,
+        child_path: vec![],
     };
     let tx_hash = ctx
         .jsonrpc_client
@@ -227,6 +228,7 @@ pub async fn request_sign_non_random(
         payload: payload_hashed,
         path: "test".to_string(),
         key_version: 0,
+        child_path: vec![],
     };
 
     let tx_hash = ctx
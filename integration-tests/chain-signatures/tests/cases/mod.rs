@@ -38,6 +38,19 @@ async fn test_multichain_reshare() -> anyhow::Result<()> {
     .await
 }
 
+#[test(tokio::test)]
+async fn test_multichain_refresh() -> anyhow::Result<()> {
+    with_multichain_nodes(MultichainConfig::default(), |mut ctx| {
+        Box::pin(async move {
+            wait_for::running_mpc(&ctx, Some(0)).await?;
+            ctx.refresh_shares().await?;
+            wait_for::running_mpc(&ctx, Some(1)).await?;
+            Ok(())
+        })
+    })
+    .await
+}
+
 #[test(tokio::test)]
 async fn test_triples_and_presignatures() -> anyhow::Result<()> {
     with_multichain_nodes(MultichainConfig::default(), |ctx| {
@@ -121,6 +134,8 @@ async fn test_signature_large_stockpile() -> anyhow::Result<()> {
         max_concurrent_introduction: 4,
         // This is the maximum amount of triples that can be generated concurrently by the whole system.
         max_concurrent_generation: 24,
+        // This is the maximum amount of triples a single protocol generates at once.
+        max_batch_size: 8,
     };
     let presig_cfg = PresignatureConfig {
         // this is the min presignatures required by each node
@@ -1,12 +1,12 @@
 mod actions;
 mod cases;
 
-use crate::actions::wait_for;
+use crate::actions::{self, wait_for};
 
 use anyhow::anyhow;
 use futures::future::BoxFuture;
 use integration_tests_chain_signatures::containers::DockerClient;
-use integration_tests_chain_signatures::utils::{vote_join, vote_leave};
+use integration_tests_chain_signatures::utils::{vote_join, vote_leave, vote_refresh};
 use integration_tests_chain_signatures::{run, utils, MultichainConfig, Nodes};
 use near_jsonrpc_client::JsonRpcClient;
 
@@ -125,6 +125,46 @@ impl MultichainTestContext<'_> {
         self.nodes.kill_node(leaving_account_id).await.unwrap();
         Ok(())
     }
+
+    pub async fn refresh_shares(&mut self) -> anyhow::Result<()> {
+        let state = wait_for::running_mpc(self, None).await?;
+        let voting_accounts = self
+            .participant_accounts()
+            .await?
+            .into_iter()
+            .take(state.threshold)
+            .collect::<Vec<Account>>();
+        tracing::info!(epoch = state.epoch, "Refreshing key shares");
+
+        let results = vote_refresh(
+            voting_accounts,
+            self.nodes.ctx().mpc_contract.id(),
+            state.epoch,
+        )
+        .await;
+        if results
+            .iter()
+            .any(|result| !result.as_ref().unwrap().failures().is_empty())
+        {
+            return Err(anyhow!("Failed to vote_refresh"));
+        }
+
+        let new_state = wait_for::running_mpc(self, Some(state.epoch + 1)).await?;
+        assert_eq!(
+            state.participants.keys().collect::<Vec<_>>(),
+            new_state.participants.keys().collect::<Vec<_>>(),
+            "participants must stay the same"
+        );
+        assert_eq!(
+            state.public_key, new_state.public_key,
+            "public key must stay the same"
+        );
+
+        // Signatures made with the refreshed shares must still verify against the original key.
+        wait_for::has_at_least_triples(self, 2).await?;
+        wait_for::has_at_least_presignatures(self, 2).await?;
+        actions::single_signature_production(self, &state).await
+    }
 }
 
 pub async fn with_multichain_nodes<F>(cfg: MultichainConfig, f: F) -> anyhow::Result<()>